@@ -0,0 +1,211 @@
+//! Baseline overlay protocols, run as device executables in place of Dither so the same topology and workload can be measured against well-known designs.
+//! Baselines speak the device protocol: they take the same DitherCommands and answer GetNodeInfo with a [`BaselineInfo`] instead of Dither's NodeInfo.
+//! Peers exchange RON-encoded datagrams on the device port, timers follow the simulation's time scale.
+//! The simulation runs them like any other device build, e.g. `MachineProgram::device("target/debug/kademlia")`,
+//! or hosts them in-process through a [`BaselineHost`], which does no I/O of its own.
+//! Bootstrapping ignores the Dither node ID, churn bootstraps new baselines off of a peer's address alone.
+
+pub mod kademlia;
+pub mod vivaldi;
+
+use std::{net::{IpAddr, Ipv6Addr, SocketAddr}, str::FromStr, sync::Arc, time::{Duration, Instant}};
+
+use anyhow::Context;
+use async_std::{net::UdpSocket, stream, task};
+use futures::{FutureExt, SinkExt, StreamExt, channel::mpsc};
+use rand::{SeedableRng, rngs::SmallRng};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+
+use crate::{BaselineInfo, DeviceCommand, DeviceEvent, DitherCommand, Ipv6Config, OracleBootstrap, OracleResponse, DEVICE_PORT, ORACLE_BOOTSTRAP_ENV, TIME_SCALE_ENV};
+
+/// Overlay protocol driven by [`run`]
+pub trait Baseline: Send + 'static {
+	type Message: Serialize + DeserializeOwned + Send;
+	/// Virtual time between two ticks
+	const TICK: Duration;
+	/// Join the overlay through a peer known only by its address
+	fn bootstrap(&mut self, net: &mut Net<Self::Message>, addr: SocketAddr);
+	fn receive(&mut self, net: &mut Net<Self::Message>, from: SocketAddr, message: Self::Message);
+	fn tick(&mut self, net: &mut Net<Self::Message>);
+	/// Start looking up an ID, returns false if the protocol has no lookups
+	fn lookup(&mut self, _net: &mut Net<Self::Message>, _target: u64) -> bool { false }
+	fn info(&self, net: &Net<Self::Message>) -> BaselineInfo;
+}
+
+/// Datagram sent between baselines, carries the address it was sent to so the receiver learns its public address
+#[derive(Serialize, Deserialize)]
+struct Envelope<M> {
+	to: SocketAddr,
+	message: M,
+}
+
+/// Clock, randomness and outgoing messages of a baseline
+pub struct Net<M> {
+	now: Duration,
+	pub rng: SmallRng,
+	/// Address the device was told it has
+	pub local_addr: Option<SocketAddr>,
+	/// Address the last peer that wrote to the device sent to
+	pub public_addr: Option<SocketAddr>,
+	outgoing: Vec<(SocketAddr, M)>,
+}
+impl<M> Net<M> {
+	/// Virtual time, set by the host before every call into the baseline
+	pub fn now(&self) -> Duration { self.now }
+	pub fn send(&mut self, to: SocketAddr, message: M) { self.outgoing.push((to, message)); }
+}
+
+/// IPv4 peers reach the dual-stack socket through mapped addresses, unmap them so every peer has one address
+fn canonical(addr: SocketAddr) -> SocketAddr {
+	match addr.ip() {
+		IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+			Some(ip) => SocketAddr::new(IpAddr::V4(ip), addr.port()),
+			None => addr,
+		},
+		IpAddr::V4(_) => addr,
+	}
+}
+
+/// Baseline along with its network state, driven by a host that moves datagrams and keeps time
+pub struct BaselineHost<B: Baseline> {
+	baseline: B,
+	net: Net<B::Message>,
+	/// Set once the host was told to bootstrap off of the oracle
+	oracle: Option<OracleBootstrap>,
+}
+impl<B: Baseline> BaselineHost<B> {
+	pub fn new(init: impl FnOnce(&mut SmallRng) -> B, mut rng: SmallRng) -> Self {
+		let baseline = init(&mut rng);
+		BaselineHost { baseline, net: Net { now: Duration::ZERO, rng, local_addr: None, public_addr: None, outgoing: Vec::new() }, oracle: None }
+	}
+	/// Ask the oracle for a peer to bootstrap off of, again on every tick after the backoff until it names one. Returns the first request.
+	pub fn bootstrap_from_oracle(&mut self) -> DeviceEvent {
+		let (oracle, request) = OracleBootstrap::start();
+		self.oracle = Some(oracle);
+		request
+	}
+	/// Advance virtual time seen by the baseline
+	pub fn set_now(&mut self, now: Duration) { self.net.now = now; }
+	/// Handle command, returns the event it is answered with, if any
+	pub fn command(&mut self, command: DeviceCommand) -> Option<DeviceEvent> {
+		match command {
+			DeviceCommand::DitherCommand(DitherCommand::Bootstrap(_, addr)) | DeviceCommand::Bootstrap(addr) => self.baseline.bootstrap(&mut self.net, addr),
+			DeviceCommand::Lookup(target) => if !self.baseline.lookup(&mut self.net, target) {
+				return Some(DeviceEvent::Error("Baseline has no lookups".to_string()))
+			},
+			DeviceCommand::DitherCommand(DitherCommand::GetNodeInfo) => return Some(DeviceEvent::BaselineInfo(self.baseline.info(&self.net))),
+			DeviceCommand::OracleResponse(_, OracleResponse::RandomPeer(Some((addr, _)))) => {
+				if let Some(oracle) = &mut self.oracle { oracle.bootstrapped(); }
+				self.baseline.bootstrap(&mut self.net, addr);
+			}
+			DeviceCommand::OracleResponse(_, OracleResponse::RandomPeer(None)) => {
+				let message = match &mut self.oracle {
+					Some(oracle) => format!("Oracle knows no peer to bootstrap off of, asking again in {:?}", oracle.failed(self.net.now)),
+					None => "Oracle knows no peer to bootstrap off of".to_string(),
+				};
+				return Some(DeviceEvent::Debug(message))
+			}
+			DeviceCommand::ConfigureIpv6 { addr, .. } => {
+				if self.net.local_addr.map_or(true, |local|local.is_ipv6()) { self.net.local_addr = Some(SocketAddr::new(IpAddr::V6(addr), DEVICE_PORT)); }
+			}
+			DeviceCommand::AddressChanged { ipv4, ipv6 } => {
+				self.net.local_addr = ipv4.map(IpAddr::V4).or(ipv6.map(IpAddr::V6)).map(|ip|SocketAddr::new(ip, DEVICE_PORT));
+			}
+			command => return Some(DeviceEvent::Error(format!("Baseline doesn't support {:?}", command))),
+		}
+		None
+	}
+	/// Handle datagram, invalid ones are dropped
+	pub fn receive(&mut self, from: SocketAddr, datagram: &[u8]) {
+		let envelope = std::str::from_utf8(datagram).ok().and_then(|string|ron::from_str::<Envelope<B::Message>>(string).ok());
+		match envelope {
+			Some(Envelope { to, message }) => {
+				self.net.public_addr = Some(to);
+				self.baseline.receive(&mut self.net, from, message);
+			}
+			None => log::debug!("Dropping invalid datagram from {}", from),
+		}
+	}
+	/// Called every [`Baseline::TICK`] of virtual time, returns an oracle request if one is due
+	pub fn tick(&mut self) -> Option<DeviceEvent> {
+		self.baseline.tick(&mut self.net);
+		self.oracle.as_mut()?.poll(self.net.now)
+	}
+	/// Encode and take datagrams the baseline sent since the last call
+	pub fn outgoing(&mut self) -> anyhow::Result<Vec<(SocketAddr, Vec<u8>)>> {
+		self.net.outgoing.drain(..).map(|(to, message)|{
+			let datagram = ron::to_string(&Envelope { to, message }).context("failed to encode message")?;
+			Ok((to, datagram.into_bytes()))
+		}).collect()
+	}
+}
+
+fn emit(event: DeviceEvent) {
+	println!("<{}", event); // Print to stdout, requires '<' to be marked as event
+}
+
+/// Run baseline as a device until stdin closes
+pub async fn run<B: Baseline>(init: impl FnOnce(&mut SmallRng) -> B) -> anyhow::Result<()> {
+	emit(DeviceEvent::Started { pid: std::process::id() });
+	let scale = std::env::var(TIME_SCALE_ENV).ok().and_then(|scale|scale.parse::<f64>().ok()).unwrap_or(1.0);
+	let start = Instant::now();
+	let mut host = BaselineHost::new(init, SmallRng::from_entropy());
+	if std::env::var_os(ORACLE_BOOTSTRAP_ENV).is_some() { emit(host.bootstrap_from_oracle()); }
+
+	let (mut command_sender, mut command_receiver) = mpsc::channel(20);
+	task::spawn(async move {
+		let stdin = async_std::io::stdin();
+		let mut input = String::new();
+		while let Ok(read) = stdin.read_line(&mut input).await {
+			if read == 0 { break }
+			match DeviceCommand::from_str(&input) {
+				Ok(command) => if command_sender.send(command).await.is_err() { break },
+				Err(_) => println!("Invalid DeviceCommand (must be RON-formatted string): {:?}", input),
+			}
+			input.clear();
+		}
+	});
+
+	// Listening on the IPv6 wildcard also accepts IPv4 datagrams, so dual-stack and single-stack machines work alike
+	let socket = Arc::new(UdpSocket::bind(SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), DEVICE_PORT)).await.context("failed to bind baseline socket")?);
+	let (mut datagram_sender, mut datagram_receiver) = mpsc::channel(20);
+	let receiving_socket = socket.clone();
+	task::spawn(async move {
+		let mut buf = vec![0; u16::MAX as usize];
+		while let Ok((len, from)) = receiving_socket.recv_from(&mut buf).await {
+			if datagram_sender.send((canonical(from), buf[..len].to_vec())).await.is_err() { break }
+		}
+	});
+
+	let mut ticks = stream::interval(B::TICK.div_f64(scale));
+	let mut ipv6 = Ipv6Config::default();
+	loop {
+		futures::select! {
+			command = command_receiver.next().fuse() => {
+				let command = match command { Some(command) => command, None => break };
+				host.set_now(start.elapsed().mul_f64(scale));
+				// The host only tracks addresses, the interface is configured here
+				let configured = match &command {
+					DeviceCommand::ConfigureIpv6 { addr, prefix } => ipv6.set(Some((*addr, *prefix))),
+					DeviceCommand::AddressChanged { ipv6: None, .. } => ipv6.set(None),
+					_ => Ok(()),
+				};
+				if let Err(err) = configured { emit(DeviceEvent::Error(format!("{:?}", err))); }
+				if let Some(event) = host.command(command) { emit(event); }
+			},
+			datagram = datagram_receiver.next().fuse() => {
+				let (from, datagram) = datagram.context("baseline socket closed")?;
+				host.set_now(start.elapsed().mul_f64(scale));
+				host.receive(from, &datagram);
+			}
+			_ = ticks.next().fuse() => {
+				host.set_now(start.elapsed().mul_f64(scale));
+				if let Some(event) = host.tick() { emit(event); }
+			}
+		}
+		for (to, datagram) in host.outgoing()? {
+			if let Err(err) = socket.send_to(&datagram, to).await { log::debug!("Failed to send to {}: {}", to, err); }
+		}
+	}
+	Ok(())
+}
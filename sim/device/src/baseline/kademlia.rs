@@ -0,0 +1,226 @@
+//! Kademlia DHT with 64-bit IDs: XOR distance, k-buckets and iterative lookups querying `ALPHA` nodes at a time.
+//! Nodes look themselves up after bootstrapping and look up a random ID every refresh interval, the latest lookup is reported in the node's info.
+
+use std::{collections::{BTreeMap, HashMap}, net::SocketAddr, time::Duration};
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use super::{Baseline, Net};
+use crate::{BaselineInfo, BaselineProtocol, LookupStats};
+
+const ID_BITS: usize = 64;
+/// Bucket size and number of closest nodes a lookup converges on
+const K: usize = 20;
+/// Requests in flight per lookup
+const ALPHA: usize = 3;
+/// Virtual time after which an unanswered request fails and its contact is evicted
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+/// Virtual time between random lookups
+const REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Message {
+	Ping { id: u64, nonce: u64 },
+	Pong { id: u64, nonce: u64 },
+	FindNode { id: u64, nonce: u64, target: u64 },
+	Nodes { id: u64, nonce: u64, nodes: Vec<(u64, SocketAddr)> },
+}
+
+struct Contact {
+	id: u64,
+	addr: SocketAddr,
+	last_seen: Duration,
+}
+
+struct Request {
+	to: SocketAddr,
+	sent: Duration,
+	/// Lookup the request belongs to, None for pings
+	lookup: Option<u64>,
+}
+
+struct Lookup {
+	target: u64,
+	started: Duration,
+	/// Known nodes by distance to the target, along with the hop they were discovered at and whether they were queried
+	candidates: BTreeMap<u64, (u64, SocketAddr, u32, bool)>,
+	pending: usize,
+	queried: u32,
+}
+
+pub struct Kademlia {
+	id: u64,
+	/// Bucket `i` holds contacts whose distance has its highest set bit at `i`, least recently seen first
+	buckets: Vec<Vec<Contact>>,
+	requests: HashMap<u64, Request>,
+	lookups: HashMap<u64, Lookup>,
+	next_nonce: u64,
+	last_refresh: Duration,
+	last_lookup: Option<LookupStats>,
+}
+
+fn bucket_index(distance: u64) -> usize {
+	ID_BITS - 1 - distance.leading_zeros() as usize
+}
+
+impl Kademlia {
+	pub fn new(rng: &mut impl Rng) -> Self {
+		Kademlia {
+			id: rng.gen(),
+			buckets: (0..ID_BITS).map(|_|Vec::new()).collect(),
+			requests: HashMap::new(),
+			lookups: HashMap::new(),
+			next_nonce: 0,
+			last_refresh: Duration::ZERO,
+			last_lookup: None,
+		}
+	}
+	fn contacts(&self) -> impl Iterator<Item = &Contact> { self.buckets.iter().flatten() }
+	fn request(&mut self, net: &mut Net<Message>, to: SocketAddr, lookup: Option<u64>, message: impl FnOnce(u64) -> Message) -> u64 {
+		let nonce = self.next_nonce;
+		self.next_nonce += 1;
+		self.requests.insert(nonce, Request { to, sent: net.now(), lookup });
+		net.send(to, message(nonce));
+		nonce
+	}
+	/// Record that a node answered or wrote to us. Full buckets keep their old contacts, as long-lived nodes are likely to stay.
+	fn seen(&mut self, net: &Net<Message>, id: u64, addr: SocketAddr) {
+		if id == self.id { return }
+		let now = net.now();
+		let bucket = &mut self.buckets[bucket_index(id ^ self.id)];
+		if let Some(position) = bucket.iter().position(|contact|contact.id == id) {
+			let mut contact = bucket.remove(position);
+			contact.addr = addr;
+			contact.last_seen = now;
+			bucket.push(contact);
+		} else if bucket.len() < K {
+			bucket.push(Contact { id, addr, last_seen: now });
+		}
+	}
+	fn evict(&mut self, addr: SocketAddr) {
+		for bucket in &mut self.buckets { bucket.retain(|contact|contact.addr != addr); }
+	}
+	fn closest(&self, target: u64, exclude: u64) -> Vec<(u64, SocketAddr)> {
+		let mut contacts: Vec<(u64, SocketAddr)> = self.contacts().filter(|contact|contact.id != exclude).map(|contact|(contact.id, contact.addr)).collect();
+		contacts.sort_by_key(|(id, _)|id ^ target);
+		contacts.truncate(K);
+		contacts
+	}
+	fn start_lookup(&mut self, net: &mut Net<Message>, target: u64) {
+		let lookup_id = self.next_nonce;
+		let candidates = self.closest(target, self.id).into_iter().map(|(id, addr)|(id ^ target, (id, addr, 1, false))).collect();
+		self.lookups.insert(lookup_id, Lookup { target, started: net.now(), candidates, pending: 0, queried: 0 });
+		self.next_nonce += 1;
+		self.advance(net, lookup_id);
+	}
+	/// Query the closest unqueried candidates, or finish the lookup once the closest `K` all answered or failed
+	fn advance(&mut self, net: &mut Net<Message>, lookup_id: u64) {
+		let lookup = match self.lookups.get_mut(&lookup_id) { Some(lookup) => lookup, None => return };
+		let mut queries = Vec::new();
+		for (_, (_, addr, _, queried)) in lookup.candidates.iter_mut().take(K) {
+			if lookup.pending + queries.len() >= ALPHA { break }
+			if !*queried { *queried = true; queries.push(*addr); }
+		}
+		lookup.pending += queries.len();
+		lookup.queried += queries.len() as u32;
+		if lookup.pending == 0 {
+			let lookup = self.lookups.remove(&lookup_id).unwrap();
+			self.last_lookup = Some(LookupStats {
+				target: lookup.target,
+				hops: lookup.candidates.values().next().map_or(0, |&(_, _, hop, _)|hop),
+				queried: lookup.queried,
+				duration: net.now() - lookup.started,
+			});
+			return
+		}
+		let (id, target) = (self.id, lookup.target);
+		for addr in queries {
+			self.request(net, addr, Some(lookup_id), |nonce|Message::FindNode { id, nonce, target });
+		}
+	}
+	/// Request ended by an answer or a timeout, lets its lookup go on
+	fn complete(&mut self, net: &mut Net<Message>, nonce: u64, nodes: Option<Vec<(u64, SocketAddr)>>) {
+		let request = match self.requests.remove(&nonce) { Some(request) => request, None => return };
+		let lookup_id = match request.lookup { Some(lookup_id) => lookup_id, None => return };
+		let own_id = self.id;
+		if let Some(lookup) = self.lookups.get_mut(&lookup_id) {
+			lookup.pending -= 1;
+			let hop = lookup.candidates.values().find(|(_, addr, _, _)|*addr == request.to).map_or(1, |&(_, _, hop, _)|hop);
+			match nodes {
+				Some(nodes) => for (id, addr) in nodes.into_iter().filter(|&(id, _)|id != own_id) {
+					lookup.candidates.entry(id ^ lookup.target).or_insert((id, addr, hop + 1, false));
+				},
+				None => { lookup.candidates.retain(|_, (_, addr, _, _)|*addr != request.to); }
+			}
+		}
+		self.advance(net, lookup_id);
+	}
+}
+
+impl Baseline for Kademlia {
+	type Message = Message;
+	const TICK: Duration = Duration::from_millis(500);
+
+	fn bootstrap(&mut self, net: &mut Net<Message>, addr: SocketAddr) {
+		let id = self.id;
+		self.request(net, addr, None, |nonce|Message::Ping { id, nonce });
+	}
+	fn receive(&mut self, net: &mut Net<Message>, from: SocketAddr, message: Message) {
+		let was_empty = self.contacts().next().is_none();
+		match message {
+			Message::Ping { id, nonce } => {
+				self.seen(net, id, from);
+				net.send(from, Message::Pong { id: self.id, nonce });
+			}
+			Message::Pong { id, nonce } => {
+				self.seen(net, id, from);
+				self.complete(net, nonce, None);
+			}
+			Message::FindNode { id, nonce, target } => {
+				self.seen(net, id, from);
+				net.send(from, Message::Nodes { id: self.id, nonce, nodes: self.closest(target, id) });
+			}
+			Message::Nodes { id, nonce, nodes } => {
+				self.seen(net, id, from);
+				self.complete(net, nonce, Some(nodes));
+			}
+		}
+		// Joining nodes look themselves up to fill their buckets and announce themselves
+		if was_empty && self.contacts().next().is_some() {
+			self.last_refresh = net.now();
+			self.start_lookup(net, self.id);
+		}
+	}
+	fn tick(&mut self, net: &mut Net<Message>) {
+		let now = net.now();
+		let expired: Vec<u64> = self.requests.iter().filter(|(_, request)|now - request.sent > REQUEST_TIMEOUT).map(|(&nonce, _)|nonce).collect();
+		for nonce in expired {
+			if let Some(request) = self.requests.get(&nonce) { let addr = request.to; self.evict(addr); }
+			self.complete(net, nonce, None);
+		}
+		if now - self.last_refresh > REFRESH_INTERVAL && self.contacts().next().is_some() {
+			self.last_refresh = now;
+			let target = net.rng.gen();
+			self.start_lookup(net, target);
+		}
+	}
+	fn lookup(&mut self, net: &mut Net<Message>, target: u64) -> bool {
+		self.start_lookup(net, target);
+		true
+	}
+	fn info(&self, net: &Net<Message>) -> BaselineInfo {
+		let now = net.now();
+		BaselineInfo {
+			protocol: BaselineProtocol::Kademlia,
+			node_id: self.id,
+			coord: None,
+			coord_error: None,
+			local_addr: net.local_addr,
+			public_addr: net.public_addr,
+			remotes: self.contacts().count(),
+			active_remotes: self.contacts().filter(|contact|now - contact.last_seen <= REFRESH_INTERVAL).count(),
+			last_lookup: self.last_lookup.clone(),
+		}
+	}
+}
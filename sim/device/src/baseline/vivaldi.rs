@@ -0,0 +1,147 @@
+//! Vivaldi network coordinates: 2D Euclidean coordinates plus a height, nudged after every round trip as if peers were connected by springs.
+//! Nodes ping a random neighbor every tick, neighbors are learned from the bootstrap peer and by gossip.
+
+use std::{collections::{BTreeMap, HashMap}, net::SocketAddr, time::Duration};
+
+use rand::{Rng, seq::IteratorRandom};
+use serde::{Deserialize, Serialize};
+
+use super::{Baseline, Net};
+use crate::{BaselineInfo, BaselineProtocol};
+
+/// Weight of a new sample in the error estimate
+const CE: f64 = 0.25;
+/// Largest fraction of the remaining error corrected by one sample
+const CC: f64 = 0.25;
+/// Heights can't shrink below this, so coordinates never collapse onto the plane (milliseconds)
+const MIN_HEIGHT: f64 = 0.1;
+const MAX_NEIGHBORS: usize = 32;
+/// Neighbors passed along with every ping and pong
+const GOSSIP: usize = 4;
+/// Virtual time after which an unanswered ping fails
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+/// Neighbors failing this many pings in a row are dropped
+const MAX_FAILURES: u32 = 3;
+
+/// Position as x, y and height, measured in milliseconds
+type Coord = [f64; 3];
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Message {
+	Ping { nonce: u64, peers: Vec<SocketAddr> },
+	Pong { nonce: u64, coord: Coord, error: f64, peers: Vec<SocketAddr> },
+}
+
+#[derive(Default)]
+struct Neighbor {
+	last_seen: Option<Duration>,
+	failures: u32,
+}
+
+pub struct Vivaldi {
+	id: u64,
+	coord: Coord,
+	/// Relative error of the coordinate, starts out at 1 (no confidence)
+	error: f64,
+	neighbors: BTreeMap<SocketAddr, Neighbor>,
+	/// Pings in flight by nonce, with the neighbor and time they were sent at
+	pings: HashMap<u64, (SocketAddr, Duration)>,
+	next_nonce: u64,
+}
+
+/// Predicted round trip time between two coordinates
+fn distance(a: &Coord, b: &Coord) -> f64 {
+	((a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2)).sqrt() + a[2] + b[2]
+}
+
+impl Vivaldi {
+	pub fn new(rng: &mut impl Rng) -> Self {
+		Vivaldi { id: rng.gen(), coord: [0.0, 0.0, MIN_HEIGHT], error: 1.0, neighbors: BTreeMap::new(), pings: HashMap::new(), next_nonce: 0 }
+	}
+	fn gossip(&self, net: &mut Net<Message>, exclude: SocketAddr) -> Vec<SocketAddr> {
+		self.neighbors.iter().filter(|(&addr, neighbor)|addr != exclude && neighbor.last_seen.is_some()).map(|(&addr, _)|addr).choose_multiple(&mut net.rng, GOSSIP)
+	}
+	fn learn(&mut self, net: &Net<Message>, peers: Vec<SocketAddr>) {
+		for addr in peers {
+			if self.neighbors.len() >= MAX_NEIGHBORS { break }
+			if Some(addr) != net.public_addr && Some(addr) != net.local_addr { self.neighbors.entry(addr).or_default(); }
+		}
+	}
+	fn ping(&mut self, net: &mut Net<Message>, to: SocketAddr) {
+		let nonce = self.next_nonce;
+		self.next_nonce += 1;
+		self.pings.insert(nonce, (to, net.now()));
+		let peers = self.gossip(net, to);
+		net.send(to, Message::Ping { nonce, peers });
+	}
+	/// Move towards or away from a neighbor so that the predicted round trip time gets closer to the measured one
+	fn update(&mut self, net: &mut Net<Message>, rtt: f64, remote: Coord, remote_error: f64) {
+		if rtt <= 0.0 { return }
+		let weight = self.error / (self.error + remote_error).max(f64::EPSILON);
+		let predicted = distance(&self.coord, &remote);
+		let sample_error = (predicted - rtt).abs() / rtt;
+		self.error = (sample_error * CE * weight + self.error * (1.0 - CE * weight)).min(1.0);
+
+		// Unit vector pointing away from the neighbor, random if both sit in the same spot
+		let mut direction = [self.coord[0] - remote[0], self.coord[1] - remote[1], self.coord[2] + remote[2]];
+		let planar = (direction[0].powi(2) + direction[1].powi(2)).sqrt();
+		if planar < f64::EPSILON {
+			direction[0] = net.rng.gen_range(-1.0..1.0);
+			direction[1] = net.rng.gen_range(-1.0..1.0);
+		}
+		let length = (direction[0].powi(2) + direction[1].powi(2)).sqrt() + direction[2];
+		let force = CC * weight * (rtt - predicted);
+		for (i, component) in direction.iter().enumerate() { self.coord[i] += force * component / length; }
+		self.coord[2] = self.coord[2].max(MIN_HEIGHT);
+	}
+}
+
+impl Baseline for Vivaldi {
+	type Message = Message;
+	const TICK: Duration = Duration::from_secs(1);
+
+	fn bootstrap(&mut self, net: &mut Net<Message>, addr: SocketAddr) {
+		self.neighbors.entry(addr).or_default();
+		self.ping(net, addr);
+	}
+	fn receive(&mut self, net: &mut Net<Message>, from: SocketAddr, message: Message) {
+		match message {
+			Message::Ping { nonce, peers } => {
+				self.learn(net, vec![from]);
+				self.learn(net, peers);
+				let peers = self.gossip(net, from);
+				net.send(from, Message::Pong { nonce, coord: self.coord, error: self.error, peers });
+			}
+			Message::Pong { nonce, coord, error, peers } => {
+				let (addr, sent) = match self.pings.remove(&nonce) { Some(ping) => ping, None => return };
+				let rtt = (net.now() - sent).as_secs_f64() * 1000.0;
+				if let Some(neighbor) = self.neighbors.get_mut(&addr) { *neighbor = Neighbor { last_seen: Some(net.now()), failures: 0 }; }
+				self.update(net, rtt, coord, error);
+				self.learn(net, peers);
+			}
+		}
+	}
+	fn tick(&mut self, net: &mut Net<Message>) {
+		let now = net.now();
+		let expired: Vec<u64> = self.pings.iter().filter(|(_, (_, sent))|now - *sent > REQUEST_TIMEOUT).map(|(&nonce, _)|nonce).collect();
+		for nonce in expired {
+			let (addr, _) = self.pings.remove(&nonce).unwrap();
+			if let Some(neighbor) = self.neighbors.get_mut(&addr) { neighbor.failures += 1; }
+		}
+		self.neighbors.retain(|_, neighbor|neighbor.failures < MAX_FAILURES);
+		if let Some(&addr) = self.neighbors.keys().choose(&mut net.rng) { self.ping(net, addr); }
+	}
+	fn info(&self, net: &Net<Message>) -> BaselineInfo {
+		BaselineInfo {
+			protocol: BaselineProtocol::Vivaldi,
+			node_id: self.id,
+			coord: Some(self.coord),
+			coord_error: Some(self.error),
+			local_addr: net.local_addr,
+			public_addr: net.public_addr,
+			remotes: self.neighbors.len(),
+			active_remotes: self.neighbors.values().filter(|neighbor|neighbor.last_seen.is_some() && neighbor.failures == 0).count(),
+			last_lookup: None,
+		}
+	}
+}
@@ -0,0 +1,8 @@
+//! Kademlia baseline device, run by the simulation in place of the device executable to compare against Dither
+
+use device::baseline::{self, kademlia::Kademlia};
+
+#[async_std::main]
+async fn main() -> anyhow::Result<()> {
+	baseline::run(Kademlia::new).await
+}
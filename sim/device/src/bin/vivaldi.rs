@@ -0,0 +1,8 @@
+//! Vivaldi baseline device, run by the simulation in place of the device executable to compare its coordinates against Dither's
+
+use device::baseline::{self, vivaldi::Vivaldi};
+
+#[async_std::main]
+async fn main() -> anyhow::Result<()> {
+	baseline::run(Vivaldi::new).await
+}
@@ -0,0 +1,47 @@
+//! Bootstrapping off of a random peer the oracle picks, for devices started with [`ORACLE_BOOTSTRAP_ENV`](crate::ORACLE_BOOTSTRAP_ENV) and in-process apps.
+//! The oracle knows no usable peer while the device is the first one running, so it is asked again with exponential backoff until it names one.
+
+use std::time::Duration;
+
+use crate::{DeviceEvent, OracleRequest};
+
+/// Wait before asking again after the first empty answer, doubled after every further one
+const FIRST_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// Id of the oracle requests asking for a bootstrap peer
+pub const BOOTSTRAP_REQUEST: u64 = 0;
+
+/// Retry state, time is virtual time as seen by the device
+#[derive(Debug)]
+pub struct OracleBootstrap {
+	backoff: Duration,
+	/// Time the next request is due at, None while one is in flight or once bootstrapped
+	retry_at: Option<Duration>,
+	bootstrapped: bool,
+}
+impl OracleBootstrap {
+	/// Start bootstrapping, the returned request has to be emitted right away
+	pub fn start() -> (Self, DeviceEvent) {
+		(OracleBootstrap { backoff: FIRST_BACKOFF, retry_at: None, bootstrapped: false }, Self::request())
+	}
+	/// Request asking the oracle for a peer, for hosts that schedule retries themselves
+	pub fn request() -> DeviceEvent { DeviceEvent::OracleRequest(BOOTSTRAP_REQUEST, OracleRequest::RandomPeer) }
+	/// Oracle named a peer the device bootstrapped off of
+	pub fn bootstrapped(&mut self) {
+		self.bootstrapped = true;
+		self.retry_at = None;
+	}
+	/// Oracle named no usable peer at `now`, returns how long until it is asked again
+	pub fn failed(&mut self, now: Duration) -> Duration {
+		let wait = self.backoff;
+		if !self.bootstrapped { self.retry_at = Some(now + wait); }
+		self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+		wait
+	}
+	/// Request that is due by `now`, if any
+	pub fn poll(&mut self, now: Duration) -> Option<DeviceEvent> {
+		if self.retry_at.map_or(true, |retry_at|retry_at > now) { return None }
+		self.retry_at = None;
+		Some(Self::request())
+	}
+}
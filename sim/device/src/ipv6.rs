@@ -0,0 +1,32 @@
+use std::{net::Ipv6Addr, process::Command};
+
+use anyhow::{Context, anyhow};
+
+/// IPv6 configuration of the machine's (only) non-loopback interface.
+/// The interface is a point-to-point link to the network's router so no gateway address is needed.
+#[derive(Default)]
+pub struct Ipv6Config {
+	current: Option<(Ipv6Addr, u8)>,
+}
+impl Ipv6Config {
+	/// Replace configured address, removing the previous one
+	pub fn set(&mut self, addr: Option<(Ipv6Addr, u8)>) -> anyhow::Result<()> {
+		if self.current == addr { return Ok(()) }
+		let interface = std::fs::read_dir("/sys/class/net")?
+			.filter_map(|entry|entry.ok()?.file_name().into_string().ok())
+			.find(|name|name != "lo").ok_or(anyhow!("no network interface to configure"))?;
+		let run = |args: &[&str]| -> anyhow::Result<()> {
+			let status = Command::new("ip").args(args).status().context("failed to run ip")?;
+			if status.success() { Ok(()) } else { Err(anyhow!("ip {:?} failed: {}", args, status)) }
+		};
+		if let Some((old, prefix)) = self.current.take() {
+			run(&["-6", "addr", "del", &format!("{old}/{prefix}"), "dev", &interface])?;
+		}
+		if let Some((addr, prefix)) = addr {
+			run(&["-6", "addr", "replace", &format!("{addr}/{prefix}"), "dev", &interface])?;
+			run(&["-6", "route", "replace", "default", "dev", &interface])?;
+		}
+		self.current = addr;
+		Ok(())
+	}
+}
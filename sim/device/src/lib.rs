@@ -1,4 +1,9 @@
 
 mod types;
-pub use types::{DeviceCommand, DeviceEvent};
+mod ipv6;
+mod bootstrap;
+pub mod baseline;
+pub use types::{DeviceCommand, DeviceEvent, BaselineInfo, BaselineProtocol, LookupStats, OracleRequest, OracleResponse, DEVICE_PORT, TIME_SCALE_ENV, ORACLE_BOOTSTRAP_ENV};
+pub use ipv6::Ipv6Config;
+pub use bootstrap::{OracleBootstrap, BOOTSTRAP_REQUEST};
 pub use libdither::{DitherCommand, DitherEvent, Address, node::net::{Network, NodeInfo}};
\ No newline at end of file
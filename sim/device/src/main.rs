@@ -2,14 +2,13 @@
 
 #![feature(try_blocks)]
 
-use std::{net::SocketAddr, str::FromStr};
+use std::{net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr}, str::FromStr, time::Instant};
 use async_std::{task};
 use futures::{FutureExt, StreamExt, SinkExt, channel::mpsc};
 
 use libdither::{DitherCore, commands::DitherCommand};
 
-mod types;
-pub use types::{DeviceCommand, DeviceEvent};
+use device::{DeviceCommand, DeviceEvent, Ipv6Config, OracleBootstrap, OracleResponse, DEVICE_PORT, ORACLE_BOOTSTRAP_ENV, TIME_SCALE_ENV};
 
 use anyhow::{Context, anyhow};
 
@@ -29,6 +28,21 @@ async fn main() -> anyhow::Result<()> {
 		}
 	});
 
+	event_sender.try_send(DeviceEvent::Started { pid: std::process::id() })?;
+	// Timers inside Dither don't follow the simulation's time scale yet, report it so a mismatch is visible
+	let scale = std::env::var(TIME_SCALE_ENV).ok().and_then(|scale|scale.parse::<f64>().ok());
+	if let Some(scale) = scale {
+		event_sender.try_send(DeviceEvent::Debug(format!("Simulation running at {}x speed", scale)))?;
+	}
+	// Oracle bootstrap retries do follow it
+	let (scale, start) = (scale.unwrap_or(1.0), Instant::now());
+	let mut bootstrap = None;
+	if std::env::var_os(ORACLE_BOOTSTRAP_ENV).is_some() {
+		let (oracle, request) = OracleBootstrap::start();
+		event_sender.try_send(request)?;
+		bootstrap = Some(oracle);
+	}
+
 	let (mut command_sender, mut command_receiver) = mpsc::channel(20);
 	// Stdin parsing thread
 	let parse_input_commands = task::spawn(async move {
@@ -45,7 +59,8 @@ async fn main() -> anyhow::Result<()> {
 		()
 	});
 	
-	let listen_addr = SocketAddr::from_str("/ip4/0.0.0.0/tcp/3000")?;
+	// Listening on the IPv6 wildcard also accepts IPv4 connections, so dual-stack and single-stack machines work alike
+	let listen_addr = SocketAddr::from_str(&format!("/ip6/::/tcp/{DEVICE_PORT}"))?;
 	let (dither_core, mut dither_event_receiver) = DitherCore::init(listen_addr)?;
 	let (mut dither_command_sender, dither_command_receiver) = mpsc::channel(20);
 	let dither_core_thread = task::spawn(async move {
@@ -54,6 +69,7 @@ async fn main() -> anyhow::Result<()> {
 
 	// Main Thread for Device
 	let main_thread = task::spawn(async move {
+		let mut ipv6 = Ipv6Config::default();
 		loop {
 			futures::select! {
 				dither_event = dither_event_receiver.next().fuse() => {
@@ -73,6 +89,38 @@ async fn main() -> anyhow::Result<()> {
 							DeviceCommand::DitherCommand(dither_command) => {
 								dither_command_sender.try_send(dither_command)?;
 							},
+							DeviceCommand::ConfigureIpv6 { addr, prefix } => ipv6.set(Some((addr, prefix)))?,
+							DeviceCommand::AddressChanged { ipv4, ipv6: new_ipv6 } => {
+								// New IPv6 address (if any) is configured through ConfigureIpv6
+								if new_ipv6.is_none() { ipv6.set(None)?; }
+								// Listener is bound to the wildcard address, Dither only needs to re-announce itself under the new address
+								if let Some(public_ip) = ipv4.map(IpAddr::V4).or(new_ipv6.map(IpAddr::V6)) {
+									dither_command_sender.try_send(DitherCommand::SetPublicIp(public_ip))?;
+								}
+								event_sender.try_send(DeviceEvent::Debug(format!("Address changed to {}", describe_addrs(ipv4, new_ipv6))))?;
+							}
+							DeviceCommand::Input(line) => Err(anyhow!("Device doesn't take plain input: {:?}", line))?,
+							DeviceCommand::Bootstrap(addr) => Err(anyhow!("Dither needs the node ID of {} to bootstrap off of it", addr))?,
+							DeviceCommand::Lookup(target) => Err(anyhow!("Lookup of {} isn't supported, Dither has no lookups yet", target))?,
+							DeviceCommand::OracleResponse(_, OracleResponse::RandomPeer(Some((addr, Some(node_id))))) => {
+								if let Some(bootstrap) = &mut bootstrap { bootstrap.bootstrapped(); }
+								dither_command_sender.try_send(DitherCommand::Bootstrap(node_id, addr))?;
+							}
+							// Peers that haven't reported a node ID yet can't be bootstrapped off of either
+							DeviceCommand::OracleResponse(_, OracleResponse::RandomPeer(_)) if bootstrap.is_some() => {
+								if let Some(bootstrap) = &mut bootstrap {
+									let wait = bootstrap.failed(start.elapsed().mul_f64(scale));
+									let mut retry_sender = event_sender.clone();
+									task::spawn(async move {
+										task::sleep(wait.div_f64(scale)).await;
+										let _ = retry_sender.send(OracleBootstrap::request()).await;
+									});
+									event_sender.try_send(DeviceEvent::Debug(format!("Oracle knows no peer to bootstrap off of, asking again in {:?}", wait)))?;
+								}
+							}
+							DeviceCommand::OracleResponse(id, response) => {
+								event_sender.try_send(DeviceEvent::Debug(format!("Oracle answered request {}: {:?}", id, response)))?;
+							}
 							// command => Err(anyhow!("Unimplemented DeviceCommand: {:?}", command))?,
 						}
 					};
@@ -91,3 +139,11 @@ async fn main() -> anyhow::Result<()> {
 	Ok(())
 }
 
+fn describe_addrs(ipv4: Option<Ipv4Addr>, ipv6: Option<Ipv6Addr>) -> String {
+	match (ipv4, ipv6) {
+		(Some(ipv4), Some(ipv6)) => format!("{ipv4} / {ipv6}"),
+		(Some(ipv4), None) => ipv4.to_string(),
+		(None, Some(ipv6)) => ipv6.to_string(),
+		(None, None) => "none".to_string(),
+	}
+}
@@ -1,18 +1,113 @@
 use serde::{Serialize, Deserialize};
-use std::{fmt::Display, str::FromStr};
+use std::{fmt::Display, net::{Ipv4Addr, Ipv6Addr, SocketAddr}, str::FromStr, time::Duration};
 
 use libdither::commands::{DitherCommand, DitherEvent};
+use libdither::node::{NodeID, RouteCoord};
+
+/// Port devices listen on, both for Dither and baseline overlays
+pub const DEVICE_PORT: u16 = 3000;
+/// Environment variable the simulation passes its time scale (multiple of wall-clock speed) to devices through
+pub const TIME_SCALE_ENV: &str = "DITHER_TIME_SCALE";
+/// Devices started with this environment variable set ask the oracle for a random peer until it names one and bootstrap off of it, the simulation sets it while oracle bootstrapping is enabled
+pub const ORACLE_BOOTSTRAP_ENV: &str = "DITHER_ORACLE_BOOTSTRAP";
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum DeviceCommand {
 	DitherCommand(DitherCommand),
+	/// Assign IPv6 address to the device's interface and route all IPv6 traffic through it
+	ConfigureIpv6 { addr: Ipv6Addr, prefix: u8 },
+	/// Device was moved to a different network while running and now has new addresses
+	AddressChanged { ipv4: Option<Ipv4Addr>, ipv6: Option<Ipv6Addr> },
+	/// Plain line for programs speaking the line protocol instead of the device protocol
+	Input(String),
+	/// Answer to the oracle request with the same id
+	OracleResponse(u64, OracleResponse),
+	/// Join the overlay through a peer known only by its address, devices needing the peer's node ID reject it
+	Bootstrap(SocketAddr),
+	/// Look up an ID in the overlay as workload, baselines report the outcome as their info's last lookup
+	Lookup(u64),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum DeviceEvent {
+	/// Device process started, reports its process id so the simulation can freeze it
+	Started { pid: u32 },
 	DitherEvent(DitherEvent),
 	Debug(String),
 	Error(String),
+	/// Line printed by a program speaking the line protocol
+	Output(String),
+	/// Answer of a baseline device to GetNodeInfo
+	BaselineInfo(BaselineInfo),
+	/// Ask the simulation's oracle, the answer carries the same id
+	OracleRequest(u64, OracleRequest),
+}
+
+/// Question about the ground truth only the simulation knows, devices would otherwise have to discover it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum OracleRequest {
+	/// True position of the device's own machine
+	Position,
+	/// Random running machine other than the asking one
+	RandomPeer,
+	/// Route coordinate a node last reported or published
+	RouteCoord(NodeID),
+	/// Route coordinates of every node
+	RouteCoordTable,
+	/// Publish own node ID and route coordinate, answered with the previously known route coordinate
+	PublishRouteCoord(NodeID, RouteCoord),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum OracleResponse {
+	/// Position on the simulation field in microlightseconds
+	Position(i32, i32),
+	/// Device address of the peer along with its node ID if it reported one, None if no other machine is running
+	RandomPeer(Option<(SocketAddr, Option<NodeID>)>),
+	RouteCoord(NodeID, Option<RouteCoord>),
+	RouteCoordTable(Vec<(NodeID, RouteCoord)>),
+	Published(Option<RouteCoord>),
+}
+
+/// Overlay protocol a baseline device runs instead of Dither
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BaselineProtocol {
+	Kademlia,
+	Vivaldi,
+}
+
+/// Counterpart of Dither's NodeInfo for baseline devices
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BaselineInfo {
+	pub protocol: BaselineProtocol,
+	/// ID in the protocol's own ID space
+	pub node_id: u64,
+	/// Network coordinate as x, y and height in milliseconds (Vivaldi only)
+	pub coord: Option<[f64; 3]>,
+	/// Relative error the node estimates its coordinate to have (Vivaldi only)
+	pub coord_error: Option<f64>,
+	/// Address the device was told it has
+	pub local_addr: Option<SocketAddr>,
+	/// Address peers see the device at
+	pub public_addr: Option<SocketAddr>,
+	/// Peers in the routing table or neighbor set
+	pub remotes: usize,
+	/// Peers that answered recently
+	pub active_remotes: usize,
+	/// Most recent completed lookup (Kademlia only)
+	pub last_lookup: Option<LookupStats>,
+}
+
+/// Outcome of an iterative lookup
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LookupStats {
+	pub target: u64,
+	/// Hops it took to discover the closest node found
+	pub hops: u32,
+	/// Nodes queried in total
+	pub queried: u32,
+	/// Virtual time the lookup took
+	pub duration: Duration,
 }
 
 impl Display for DeviceCommand {
@@ -4,7 +4,7 @@ pub use iced::Settings;
 use iced::{executor, Command, Subscription, pure::{Application, Element}};
 
 use iced_native::command::Action;
-use sim::{InternetAction, InternetError};
+use sim::InternetAction;
 
 use crate::subscription::{self, Event};
 
@@ -83,10 +83,8 @@ impl Application for NetSimApp {
 								));
 							}
 							Event::Error(error) => {
-								match error {
-									InternetError::Other(err) => log::error!("InternetError: {}", err),
-									_ => log::error!("Received InternetError {:?} in Loading state, but error doesn't apply", error),
-								}
+								log::error!("Failed to load Internet: {}", error);
+								state.load_failed(error);
 							}
 							_ => log::error!("Received subscription event {:?} in Loading state, state should have already switched to Loaded", event)
 						}
@@ -1,12 +1,13 @@
-use iced::pure::{container, column, text_input, Element};
+use iced::{Alignment, pure::{checkbox, container, column, row, text_input, Element}};
 use libdither::DitherCommand;
-use sim::{FieldPosition, InternetAction, InternetEvent, NodeIdx, NodeType};
+use sim::{DesInternet, FieldPosition, InternetAction, InternetEvent, NodeIdx, NodeType};
 use futures::channel::mpsc;
 
 use crate::{subscription::InternetRecipe, tabs::{self, TabBar, dither_tab, network_tab}};
 
 #[derive(Default)]
 pub struct TopBar {
+	/// Whether the simulation is running (i.e. not paused)
 	toggle_sim: bool,
 	action_box_text: String,
 }
@@ -53,11 +54,16 @@ impl State {
 			internet_action,
 			internet_recipe,
 			tabs: TabBar::new(),
-			top_bar: TopBar::default(),
+			top_bar: TopBar { toggle_sim: true, ..Default::default() },
 			field_position: Default::default(),
 		}
 	}
 	pub fn net_action(&mut self, action: InternetAction) {
+		// Deterministic Internets run from a seed and don't offer everything the namespace backend does
+		if self.internet_recipe.seed.is_some() && !DesInternet::supports(&action) {
+			log::error!("Action not available in deterministic simulation: {:?}", action);
+			return;
+		}
 		match self.internet_action.try_send(action) {
 			Err(err) => log::error!("Failed to send internet action: {}", err), Ok(_) => {},
 		}
@@ -86,6 +92,10 @@ impl State {
 					InternetEvent::NewNetwork(id) => {
 						self.process_network_tab_msg(network_tab::Message::AddNode(id, NodeType::Network))
 					},
+					InternetEvent::RemoveNode(id) => {
+						self.process_dither_tab_msg(dither_tab::Message::RemoveNode(id));
+						self.process_network_tab_msg(network_tab::Message::RemoveNode(id))
+					},
 					InternetEvent::NodeInfo(id, info) => {
 						self.process_network_tab_msg(network_tab::Message::UpdateNode(id, info))
 					},
@@ -93,6 +103,9 @@ impl State {
 						// self.process_network_tab_msg(network_tab::Message::UpdateMachine(id, info))
 						self.process_dither_tab_msg(dither_tab::Message::UpdateMachine(id, info))
 					},
+					InternetEvent::BaselineInfo(id, info) => {
+						log::info!("{:?} machine {}: {} remotes ({} active), coordinate {:?}, last lookup {:?}", info.protocol, id, info.remotes, info.active_remotes, info.coord, info.last_lookup); None
+					},
 					InternetEvent::NetworkInfo(id, info) => {
 						self.process_network_tab_msg(network_tab::Message::UpdateNetwork(id, info))
 					},
@@ -102,6 +115,36 @@ impl State {
 					InternetEvent::RemoveConnection(wire_idx) => {
 						self.process_network_tab_msg(network_tab::Message::RemoveConnection(wire_idx))
 					}
+					InternetEvent::PartitionStarted(time, groups, wires) => {
+						log::info!("[{:?}] Internet partitioned into groups {:?}, suspended {} wires", time, groups, wires.len()); None
+					}
+					InternetEvent::PartitionHealed(time, wires) => {
+						log::info!("[{:?}] Partition healed, restored {} wires", time, wires.len()); None
+					}
+					InternetEvent::Fault(time, fault) => {
+						log::info!("[{:?}] Injected fault: {:?}", time, fault); None
+					}
+					InternetEvent::Population(time, sample) => {
+						log::info!("[{:?}] Population {:?} into churn: {} machines ({} joined, {} left)", time, sample.time, sample.machines, sample.joined, sample.left); None
+					}
+					InternetEvent::DeviceChanged(time, id, device) => {
+						log::info!("[{:?}] Machine {} switched to {:?}", time, id, device); None
+					}
+					InternetEvent::UpgradeScheduled(time, build, machines) => {
+						log::info!("[{:?}] Upgrading {} machines to {:?}", time, machines.len(), build); None
+					}
+					InternetEvent::Clock(time, scale) => {
+						log::info!("Virtual time {:?} at {}x", time, scale); None
+					}
+					InternetEvent::Stretch(time, report) => {
+						log::info!("[{:?}] Route stretch over {} samples: mean {:?}, median {:?}, p90 {:?}, p99 {:?}, {:.1}% failed, {} pairs unreachable", time, report.samples.len(), report.mean, report.median, report.p90, report.p99, report.failure_rate * 100.0, report.unreachable); None
+					}
+					InternetEvent::Embedding(time, report) => {
+						log::info!("[{:?}] Embedding error over {} pairs {:?} into the analysis: median {:?}, p90 {:?}, {} of {} triangles violated", time, report.pairs, report.time, report.median_error, report.p90_error, report.violations, report.triangles); None
+					}
+					InternetEvent::Paused(paused) => {
+						self.top_bar.toggle_sim = !paused; None
+					}
 					InternetEvent::Error(err) => { match *err {
 						sim::InternetError::NodeConnectionError => { log::warn!("Internet Error: Cannot connect two machines to each other"); },
 						_ => log::error!("received InternetError: {}", *err),
@@ -116,6 +159,7 @@ impl State {
 			// Locally triggered events
 			Message::ToggleSim(toggle) => {
 				self.top_bar.toggle_sim = toggle;
+				self.net_action(if toggle { InternetAction::Resume } else { InternetAction::Pause });
 				None
 			}
 			Message::ActionBoxUpdate(string) => {
@@ -150,6 +194,9 @@ impl State {
 				self.net_action(InternetAction::SetPosition(index, new_position));
 				None
 			},
+			Message::RemoveNode(index) => {
+				self.net_action(InternetAction::RemoveNode(index)); None
+			}
 			Message::ConnectNode(from, to) => {
 				self.net_action(InternetAction::ConnectNodes(from, to)); None
 			}
@@ -171,15 +218,10 @@ impl State {
 	}
 	pub fn view(&self) -> Element<Message> {
 		column()
-			/* .push(
-				Row::new().push(
-					Text::new("Top Bar")
-				)/* .push( // Step Network Button
-					Button::new(&mut self.top_bar.step_sim, Text::new("Step Network"))
-                    	.on_press(Message::StepNetwork)
-				) */.push( // Run Network Continuously Toggle
-					Checkbox::new(self.top_bar.toggle_sim, String::from("Run Network"), Message::ToggleSim)
-				).spacing(10).align_items(Align::Center).padding(3)
+			.push(
+				row().push( // Pause or resume the whole simulation
+					checkbox("Run Network", self.top_bar.toggle_sim, Message::ToggleSim)
+				).spacing(10).align_items(Alignment::Center).padding(3)
 				/* .push(
 					Button::new(&mut self.top_bar.add_machine, Text::new("Add Machine"))
         			.on_press(Message::TriggerAddMachine)
@@ -189,7 +231,7 @@ impl State {
 				).push(
 					Text::new(format!("({}, {})", self.tabs.network_tab.map.translation.x, self.tabs.network_tab.map.translation.y))
 				) */
-			) */
+			)
 			.push(
 				text_input("DebugPrint", &self.top_bar.action_box_text, Message::ActionBoxUpdate)
 				.on_submit(Message::ActionBoxSubmit)
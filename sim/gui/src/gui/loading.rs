@@ -1,4 +1,5 @@
-use iced::{Alignment, pure::{Element, button, column, row, text, text_input}};
+use iced::{Alignment, pure::{Element, button, checkbox, column, row, text, text_input}};
+use sim::{InternetError, SaveError};
 
 use crate::subscription::InternetRecipe;
 
@@ -6,14 +7,25 @@ use crate::subscription::InternetRecipe;
 pub struct State {
 	pub text_input_string: String,
 	pub valid_file: bool,
+	/// Run on the deterministic backend instead of network namespaces
+	pub deterministic: bool,
+	pub seed_string: String,
 
 	pub currently_loading_recipe: Option<InternetRecipe>,
+	/// Why the last load failed
+	pub load_error: Option<String>,
+	/// The last load failed on a save this build can't read, which can be replaced by a fresh topology
+	pub offer_fresh: bool,
 }
 
 #[derive(Debug, Clone)]
 pub enum Message {
 	TextBoxUpdate(String),
+	ToggleDeterministic(bool),
+	SeedUpdate(String),
 	TriggerLoad,
+	/// Start a new topology in place of a save that couldn't be read
+	TriggerFresh,
 }
 
 impl State {
@@ -24,22 +36,57 @@ impl State {
 				self.valid_file = std::path::Path::new(&self.text_input_string).exists();
 				None
 			}
-			Message::TriggerLoad => {
-				self.currently_loading_recipe = Some(InternetRecipe {
-					path: self.valid_file.then(|| self.text_input_string.clone())
-				});
-				Some(super::Message::LoadInternet)
-			},
+			Message::ToggleDeterministic(deterministic) => { self.deterministic = deterministic; None }
+			Message::SeedUpdate(string) => { self.seed_string = string; None }
+			Message::TriggerLoad => self.load(false),
+			Message::TriggerFresh => self.load(true),
 		}
 	}
+	/// Seed typed by the user, None unless it is a whole number that fits a u64
+	fn seed(&self) -> Option<u64> { self.seed_string.trim().parse().ok() }
+	fn load(&mut self, fresh: bool) -> Option<super::Message> {
+		self.load_error = None;
+		self.offer_fresh = false;
+		// Falling back to some seed would silently run a different simulation than asked for
+		let seed = match (self.deterministic, self.seed()) {
+			(false, _) => None,
+			(true, Some(seed)) => Some(seed),
+			(true, None) => {
+				self.load_error = Some(format!("Invalid seed {:?}, must be a whole number between 0 and {}", self.seed_string, u64::MAX));
+				return None;
+			}
+		};
+		self.currently_loading_recipe = Some(InternetRecipe {
+			path: self.valid_file.then(|| self.text_input_string.clone()),
+			seed,
+			fresh,
+		});
+		Some(super::Message::LoadInternet)
+	}
+	/// Show why loading failed, saves from older or other builds can be replaced by a fresh topology
+	pub fn load_failed(&mut self, error: InternetError) {
+		let loaded_save = self.currently_loading_recipe.take().map_or(false, |recipe|recipe.path.is_some() && !recipe.fresh);
+		self.offer_fresh = loaded_save && matches!(error,
+			InternetError::SaveError(SaveError::UnknownFormat(_) | SaveError::UnsupportedVersion { .. } | SaveError::Encoding(_)));
+		self.load_error = Some(error.to_string());
+	}
 
 	pub fn view(&self) -> Element<Message> {
-		column().align_items(Alignment::Center).padding(20).spacing(20).push(
+		let view = column().align_items(Alignment::Center).padding(20).spacing(20).push(
 			row()
 				.push(text_input("Simulation Binary File", &self.text_input_string, |string| Message::TextBoxUpdate(string),))
 		.push(text(if self.valid_file { "Valid" } else { "Unknown File" }))
+		).push(
+			row().spacing(10).align_items(Alignment::Center)
+				.push(checkbox("Deterministic", self.deterministic, Message::ToggleDeterministic))
+				.push(text_input("Seed", &self.seed_string, Message::SeedUpdate))
+				.push(text(if !self.deterministic || self.seed().is_some() { "" } else { "Invalid Seed" }))
 		).push(
 			button("Load Simulation").on_press(Message::TriggerLoad),
-		).into()
+		);
+		let view = match &self.load_error { Some(error) => view.push(text(error)), None => view };
+		if self.offer_fresh {
+			view.push(button("Start Fresh Topology").on_press(Message::TriggerFresh)).into()
+		} else { view.into() }
 	}
 }
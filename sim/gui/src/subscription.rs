@@ -2,7 +2,7 @@
 use std::pin::Pin;
 
 use iced_futures::subscription::Recipe;
-use sim::{Internet, InternetAction, InternetError, InternetEvent};
+use sim::{DesInternet, Internet, InternetAction, InternetError, InternetEvent};
 use futures::{StreamExt, channel::mpsc};
 use async_std::task::{self, JoinHandle};
 
@@ -10,6 +10,10 @@ use async_std::task::{self, JoinHandle};
 #[derive(Debug, Clone)]
 pub struct InternetRecipe {
 	pub path: Option<String>,
+	/// Seed of the deterministic backend, None runs on network namespaces
+	pub seed: Option<u64>,
+	/// Start a new Internet instead of loading the file at path, it is still saved there
+	pub fresh: bool,
 }
 
 impl<H, E> Recipe<H, E> for InternetRecipe where H: std::hash::Hasher {
@@ -20,33 +24,22 @@ impl<H, E> Recipe<H, E> for InternetRecipe where H: std::hash::Hasher {
 		
 		std::any::TypeId::of::<Self>().hash(state);
 		self.path.hash(state);
+		self.seed.hash(state);
+		self.fresh.hash(state);
 	}
 
 	fn stream(self: Box<Self>, _input: Pin<Box<(dyn futures::Stream<Item = E> + std::marker::Send + 'static)>>) -> Pin<Box<(dyn futures::Stream<Item = Self::Output> + std::marker::Send + 'static)>> {
 		Box::pin(futures::stream::unfold(
-			State::Initialize(self.path),
+			State::Initialize(self.path.filter(|_|!self.fresh), self.seed),
 			move |state| async move {
 				match state {
-					State::Initialize(path) => {
+					State::Initialize(path, seed) => {
 						log::debug!("Initializing Network Subscription from: {:?}", path);
-						match if let Some(path) = path {
-							Internet::load(&path)
-						} else { Ok(Internet::new("./target/debug/device")) } {
-							Ok(mut internet) =>{
-								match internet.init().await {
-									Ok((runtime, receiver, sender)) => {
-										let join = task::spawn(internet.run(runtime));
-										Some((
-											Event::Init(sender),
-											State::Running(receiver, join)
-										))
-									}
-									Err(err) =>  Some((
-										Event::Error(err),
-										State::Finished,
-									)),
-								}
-							},
+						match start(path, seed).await {
+							Ok((sender, receiver, join)) => Some((
+								Event::Init(sender),
+								State::Running(receiver, join)
+							)),
 							Err(err) => Some((
 								Event::Error(err),
 								State::Finished,
@@ -86,8 +79,24 @@ pub enum Event {
 	Closed,
 }
 
+/// Load or create Internet on the chosen backend and start running it
+async fn start(path: Option<String>, seed: Option<u64>) -> Result<(mpsc::Sender<InternetAction>, mpsc::Receiver<InternetEvent>, JoinHandle<()>), InternetError> {
+	match seed {
+		Some(seed) => {
+			let mut internet = match path { Some(path) => DesInternet::load(&path)?, None => DesInternet::new(seed) };
+			let (runtime, receiver, sender) = internet.init().await?;
+			Ok((sender, receiver, task::spawn(internet.run(runtime))))
+		}
+		None => {
+			let mut internet = match path { Some(path) => Internet::load(&path)?, None => Internet::new("./target/debug/device") };
+			let (runtime, receiver, sender) = internet.init().await?;
+			Ok((sender, receiver, task::spawn(internet.run(runtime))))
+		}
+	}
+}
+
 enum State {
-	Initialize(Option<String>),
+	Initialize(Option<String>, Option<u64>),
 	Running(mpsc::Receiver<InternetEvent>, JoinHandle<()>),
 	Finished,
 }
\ No newline at end of file
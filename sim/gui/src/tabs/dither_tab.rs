@@ -1,4 +1,4 @@
-use std::net::{Ipv4Addr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 
 use super::{Icon, Tab};
 use anyhow::Context;
@@ -17,6 +17,7 @@ pub struct DitherTabNode {
 	route_coord: RouteCoord,
 	known_self_addr: Option<Address>,
 	network_ip: Option<Ipv4Addr>,
+	network_ip6: Option<Ipv6Addr>,
 }
 impl DitherTabNode {
 	fn new(id: NodeIdx, info: MachineInfo, index: usize) -> DitherTabNode {
@@ -26,6 +27,7 @@ impl DitherTabNode {
 			route_coord: info.route_coord,
 			known_self_addr: info.public_addr,
 			network_ip: info.network_ip,
+			network_ip6: info.network_ip6,
 		}
 	}
 }
@@ -56,8 +58,12 @@ impl NetworkNode for DitherTabNode {
 		if hover { node_color = Color::from_rgb8(200, 200, 200); }
 		frame.fill(&Path::circle(point.clone(), radius), node_color);
 
-		let label = if let Some(addr) = self.network_ip { format!("{addr}") }
-		else { format!("{}", self.id) };
+		let label = match (self.network_ip, self.network_ip6) {
+			(Some(addr), Some(addr6)) => format!("{addr}\n{addr6}"),
+			(Some(addr), None) => format!("{addr}"),
+			(None, Some(addr6)) => format!("{addr6}"),
+			(None, None) => format!("{}", self.id),
+		};
 		frame.fill_text(canvas::Text { content:
 			label,
 			position: point, color: Color::from_rgb8(0, 0, 0), size: radius,
@@ -180,9 +186,11 @@ impl DitherTab {
 					match netmap_msg {
 						DitherMapMessage::TriggerConnection(from, to) => {
 							let node = self.map.node(to).ok_or(anyhow!("No node: {}", to))?;
+							// Prefer IPv4 for dual-stack nodes
+							let ip = node.network_ip.map(IpAddr::from).or(node.network_ip6.map(IpAddr::from));
 							let network_ip = SocketAddr::new(
-								node.network_ip.clone().ok_or(anyhow!("Node {:?} does not have a network ip", to))?.into(),
-								3000
+								ip.ok_or(anyhow!("Node {:?} does not have a network ip", to))?,
+								sim::DEVICE_PORT
 							);
 							log::debug!("Connecting node: {:?} to {:?}", from, node);
 							return Some(loaded::Message::DitherCommand(from, DitherCommand::Bootstrap(node.node_id.clone(), network_ip)));
@@ -1,4 +1,4 @@
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, Ipv6Addr};
 
 use super::{Icon, Tab};
 use iced::{Color, Length, Point, Row, Vector, alignment::{Horizontal, Vertical}, button, pure::{Element, column, container, widget::canvas::{self, Path, Stroke, event}}, keyboard};
@@ -14,10 +14,12 @@ pub struct NetworkTabNode {
 	node_type: NodeType,
 	field_position: FieldPosition,
 	ip_addr: Option<Ipv4Addr>,
+	ip6_addr: Option<Ipv6Addr>,
+	powered: bool,
 }
 impl NetworkTabNode {
 	fn new(id: NodeIdx, node_type: NodeType) -> NetworkTabNode {
-		Self { id, node_type, field_position: Default::default(), ip_addr: None }
+		Self { id, node_type, field_position: Default::default(), ip_addr: None, ip6_addr: None, powered: true }
 	}
 }
 impl NetworkNode for NetworkTabNode {
@@ -36,14 +38,22 @@ impl NetworkNode for NetworkTabNode {
 			frame.fill(&Path::circle(point.clone(), radius + 5.0), Color::from_rgb8(255, 255, 0));
 		}
 
-		let node_color = match self.node_type { NodeType::Machine => Color::from_rgb8(39, 245, 230), NodeType::Network => Color::from_rgb8(84, 245, 39) };
+		let node_color = match self.node_type {
+			_ if !self.powered => Color::from_rgb8(120, 120, 120),
+			NodeType::Machine => Color::from_rgb8(39, 245, 230),
+			NodeType::Network => Color::from_rgb8(84, 245, 39),
+		};
 		if hover { let node_color = Color::from_rgb8(200, 200, 200); }
 		frame.fill(&Path::circle(point.clone(), radius), node_color);
 
-		let fp_str = format!("({}, {})", self.field_position.x, self.field_position.y);
+		let fp_str = format!("({}, {}){}", self.field_position.x, self.field_position.y, if self.powered { "" } else { "\npowered off" });
 
-		let label = if let Some(addr) = self.ip_addr { format!("{addr}\n{}", fp_str) }
-		else { format!("{}\n{}", self.id, fp_str) };
+		let label = match (self.ip_addr, self.ip6_addr) {
+			(Some(addr), Some(addr6)) => format!("{addr}\n{addr6}\n{}", fp_str),
+			(Some(addr), None) => format!("{addr}\n{}", fp_str),
+			(None, Some(addr6)) => format!("{addr6}\n{}", fp_str),
+			(None, None) => format!("{}\n{}", self.id, fp_str),
+		};
 		frame.fill_text(canvas::Text { content:
 			label,
 			position: point, color: Color::BLACK, size: radius / 2.0,
@@ -165,6 +175,8 @@ impl NetworkTab {
 				};
 				node.field_position = info.position;
 				node.ip_addr = info.local_address;
+				node.ip6_addr = info.local_address6;
+				node.powered = info.powered;
 				self.map.trigger_update();
 			}
 			Message::RemoveNode(idx) => {
@@ -4,39 +4,83 @@
 /// Contains all necessary componenets to create a virtual network on a given computer and spawn devices running the Dither protocol
 
 use std::fmt::{self, Debug};
-use std::fs::File;
-use std::io::BufReader;
 use std::ops::Range;
 use std::sync::Arc;
 use std::time::Duration;
 
-use anyhow::Context;
 use async_std::task;
-use futures::StreamExt;
+use futures::{SinkExt, StreamExt, future::AbortHandle};
 use slotmap::{SecondaryMap, SlotMap, new_key_type};
 use serde::Deserialize;
 use futures::channel::mpsc;
 
-use netsim_embed::Ipv4RangeIter;
+use netsim_embed::Ipv4Range;
 
 use device::{DeviceCommand, DeviceEvent, DitherCommand, DitherEvent};
+pub use device::{BaselineInfo, BaselineProtocol, LookupStats};
 pub use node::{self, RouteCoord, NodeID, net};
 
 mod netsim_ext;
 mod internet_node;
+mod packet;
+mod nat;
+mod firewall;
+mod routing;
+mod addressing;
+mod ipv6;
+mod latency;
+mod trace;
+mod mobility;
+mod chaos;
+mod churn;
+mod clock;
+mod des;
+mod apps;
+mod devices;
+mod upgrade;
+mod oracle;
+mod stretch;
+mod embedding;
+mod save;
 use netsim_ext::*;
 
-pub use internet_node::{FieldPosition, InternetNetwork, InternetMachine, InternetNode, NodeType, NodeInfo, MachineInfo, NetworkInfo, Latency, NodeVariant};
+pub use internet_node::{FieldPosition, InternetNetwork, InternetMachine, InternetNode, InternetWire, WireLatency, SuspendReason, PowerOffMode, NodeType, NodeInfo, MachineInfo, MachineAddrs, NetworkInfo, NetworkConnection, Latency, NodeVariant};
+pub use nat::{NatConfig, NatError, NatMode};
+pub use firewall::{Firewall, FirewallAction, FirewallEdit, FirewallRule, FirewallStats};
+pub use netsim_ext::Direction;
+pub use addressing::{NetworkRange, DEFAULT_NETWORK_PREFIX};
+pub use ipv6::{IpStack, Ipv6Range};
+pub use trace::{TraceSource, WireTrace, TraceError};
+pub use mobility::{Mobility, MobilityModel, MobilityError};
+pub use chaos::{ChaosConfig, ChaosError, FailureRates, Fault, LinkClass};
+pub use churn::{ChurnConfig, ChurnError, Distribution, PopulationSample};
+pub use clock::{SimClock, MIN_TIME_SCALE, MAX_TIME_SCALE};
+pub use des::{DesInternet, DesRuntime, DesApp, DesContext, AppFactory};
+pub use apps::{BaselineApp, baseline_apps};
+pub use devices::{Device, DeviceConfig, DeviceRegistry, MachineProgram, StdioProtocol};
+pub use upgrade::RollingUpgrade;
+pub use stretch::{StretchConfig, StretchReport, StretchSample};
+pub use embedding::{EmbeddingConfig, EmbeddingReport, RttSource};
+pub use latency::{LatencyModel, LatencyModelConfig, Euclidean, EuclideanPerHop, GreatCircle, LatencyMatrix, LatencyMatrixError};
+pub use routing::{RoutingMode, PathVectorConfig, PathRoute, RouteAdvertisement, RouteTableEntry, ConnectionRoutes};
+pub use packet::Protocol;
+pub use save::SaveError;
 
 /// All Dither Nodes and Routing Nodes will be organized on a field
 /// Internet Simulation Field Dimensions (Measured in Microlightseconds): 64ms x 26ms
 pub const FIELD_DIMENSIONS: (Range<i32>, Range<i32>) = (-320000..320000, -130000..130000);
 
+/// A moving machine only hands over to a closer network if it is closer by this fraction, prevents flapping between equidistant networks
+pub const HANDOVER_HYSTERESIS: f64 = 0.1;
+
+/// How often latency traces loaded from files are sampled
+pub const DEFAULT_TRACE_INTERVAL: Duration = Duration::from_secs(1);
+
+pub use device::DEVICE_PORT;
+
 /// Cache file to save network configuration
 pub const DEFAULT_CACHE_FILE: &str = "./net.cache";
 
-/// Max number of networks allowed (represents how many slices the global IP space is split into).
-pub const MAX_NETWORKS: u16 = u16::MAX;
 
 /// Internet Simulation Actions, use this structure to control the simulation thread
 #[derive(Debug, Serialize, Deserialize)]
@@ -48,8 +92,14 @@ pub enum InternetAction {
 	ConnectAllMachines(NodeIdx), // Send connection requests from all machines to given NodeIdx to organize network
 	/// Add Machine at a specific position in simulation space
 	AddMachine(FieldPosition),
+	/// Add Machine running a specific device, e.g. any other program or an in-process app instead of the device executable
+	AddMachineWithDevice(FieldPosition, DeviceConfig),
 	/// Add Network at a specific position in simulation space
 	AddNetwork(FieldPosition),
+	/// Add Network with a custom address range or range size
+	AddNetworkWithRange(FieldPosition, NetworkRange),
+	/// Remove node along with all of its connections, machines are shut down
+	RemoveNode(NodeIdx),
 	/// Get info about a given node, machine or network (takes node ID) -> NodeInfo
 	GetNodeInfo(NodeIdx), // Get info about node
 	/// Get info about a given Machine running Dither -> MachineInfo
@@ -62,8 +112,66 @@ pub enum InternetAction {
 
 	/// Change position of a given node in the network
 	SetPosition(NodeIdx, FieldPosition),
+	/// Start moving a machine according to a mobility model, or stop it (None)
+	SetMobility(NodeIdx, Option<Mobility>),
 	/// Connect two nodes
 	ConnectNodes(NodeIdx, NodeIdx),
+	/// Power a machine off or back on, it keeps its node, position and connection but its wire drops all packets while off
+	SetPowered(NodeIdx, bool),
+	/// Set whether a machine's device is stopped or frozen while powered off, takes effect the next time it is powered off
+	SetPowerOffMode(NodeIdx, PowerOffMode),
+	/// Make a program available to machines under a build name (e.g. a release version), replacing any build of that name
+	RegisterBuild(String, MachineProgram),
+	/// Switch a machine to another device, a running device is restarted and loses its state
+	SetDevice(NodeIdx, DeviceConfig),
+	/// Switch a share of machines to a registered build, restarting them one after the other
+	RollingUpgrade(RollingUpgrade),
+	/// Move a running machine to a different network, it gets new addresses and is told about them without being restarted
+	Rehome(NodeIdx, NodeIdx),
+	/// Split the Internet into groups of nodes, wires between groups drop all packets until healed. Nodes not listed form a group of their own.
+	Partition(Vec<Vec<NodeIdx>>),
+	/// Restore all wires suspended by the current partition
+	Heal,
+	/// Start randomly failing wires and crashing machines, or stop (None) and undo all injected faults
+	SetChaos(Option<ChaosConfig>),
+	/// Start spawning and removing machines according to session and arrival distributions, or stop (None)
+	SetChurn(Option<ChurnConfig>),
+	/// Whether device processes started from now on bootstrap off of a random peer the oracle picks, asking again until it names one.
+	/// In-process apps always do.
+	SetOracleBootstrap(bool),
+	/// Override latency of a wire in either direction
+	SetWireLatency(WireIdx, WireLatency),
+	/// Drive latency of a wire by a trace, or stop a running trace (None)
+	SetWireTrace(WireIdx, Option<WireTrace>),
+	/// Drive latency of a wire by a trace loaded from a CSV file of `seconds,milliseconds` rows
+	LoadWireTrace(WireIdx, String),
+	/// Enable, change or disable (None) NAT on a network
+	SetNat(NodeIdx, Option<NatConfig>),
+	/// Set which IP versions machines connected to a network get addresses for
+	SetIpStack(NodeIdx, IpStack),
+	/// Modify firewall rules of a network
+	EditFirewall(NodeIdx, FirewallEdit),
+	/// Change how routes between networks are computed
+	SetRoutingMode(RoutingMode),
+	/// Change how wire latencies are derived from node positions
+	SetLatencyModel(LatencyModelConfig),
+	/// Use measured latency matrix loaded from a JSON file as latency model
+	LoadLatencyMatrix(String),
+	/// Change how fast virtual time runs relative to wall-clock time (between MIN_TIME_SCALE and MAX_TIME_SCALE).
+	/// Devices only learn the new scale when they are (re)started.
+	SetTimeScale(f64),
+	/// Get current virtual time -> Clock
+	GetClock,
+	/// Stop virtual time and freeze every device, wires hold their packets until resumed
+	Pause,
+	/// Continue a paused simulation where it left off
+	Resume,
+	/// Run a paused simulation for a duration of virtual time, then pause it again. Fails with [`InternetError::NotPaused`] while running.
+	Step(Duration),
+	/// Compare greedy routes over reported route coordinates with shortest paths for sampled machine pairs -> Stretch
+	MeasureStretch(StretchConfig),
+	/// Start comparing reported route coordinates with round-trip times every interval, or stop (None) -> Embedding
+	SetEmbeddingAnalysis(Option<EmbeddingConfig>),
 
 	/// Send Device command (Dither-specific or otherwise)
 	DeviceCommand(NodeIdx, DeviceCommand),
@@ -74,6 +182,31 @@ pub enum InternetAction {
 
 	// From Devices
 	HandleDeviceEvent(NodeIdx, DeviceEvent),
+	// From routing protocol
+	/// Route advertisements arriving at network through wire
+	RoutingUpdate(NodeIdx, WireIdx, Vec<RouteAdvertisement>),
+	/// Network noticed that wire went down
+	RoutingLinkDown(NodeIdx, WireIdx),
+	// From latency traces
+	/// New latency sampled from a wire's trace
+	TraceSample(WireIdx, Latency),
+	// From mobility models
+	/// Machine moved to new position
+	MobilityStep(NodeIdx, FieldPosition),
+	// From chaos engine
+	/// Time to draw new faults
+	ChaosTick,
+	// From churn driver
+	/// New machine joins
+	ChurnArrival,
+	/// Session of a machine spawned by churn ended
+	ChurnDeparture(NodeIdx),
+	// From embedding analysis
+	/// Time for the next run
+	EmbeddingTick,
+	// From rolling upgrades
+	/// Turn of a machine to be switched to a build
+	UpgradeMachine(NodeIdx, String),
 	DebugPrint,
 }
 
@@ -84,17 +217,42 @@ pub enum InternetEvent {
 	NewMachine(NodeIdx),
 	/// Net network was created
 	NewNetwork(NodeIdx),
+	/// Node was removed along with its connections
+	RemoveNode(NodeIdx),
 	/* /// Connection between two nodes created
 	NewConnection(WireIdx), */
 	/// General Node info 
 	NodeInfo(NodeIdx, NodeInfo),
 	/// General machine info
 	MachineInfo(NodeIdx, MachineInfo),
+	/// Info of a machine running a baseline protocol instead of Dither
+	BaselineInfo(NodeIdx, BaselineInfo),
 	/// General network info
 	NetworkInfo(NodeIdx, NetworkInfo),
 	/// Connection Info
 	ConnectionInfo(WireIdx, NodeIdx, NodeIdx), // Whether or not to activate / deactivate a connection between two nodes
 	RemoveConnection(WireIdx),
+	// Events of things happening in the simulation carry the virtual time they happened at
+	/// Internet was partitioned into groups, lists the wires suspended between them
+	PartitionStarted(Duration, Vec<Vec<NodeIdx>>, Vec<WireIdx>),
+	/// Partition ended, lists the wires that were restored
+	PartitionHealed(Duration, Vec<WireIdx>),
+	/// Chaos engine injected or lifted a fault
+	Fault(Duration, Fault),
+	/// Number of machines changed through churn
+	Population(Duration, PopulationSample),
+	/// Machine was switched to another device
+	DeviceChanged(Duration, NodeIdx, DeviceConfig),
+	/// Rolling upgrade to a build started, lists the machines in the order they are upgraded
+	UpgradeScheduled(Duration, String, Vec<NodeIdx>),
+	/// Current virtual time and time scale
+	Clock(Duration, f64),
+	/// Simulation was paused (true) or resumed (false)
+	Paused(bool),
+	/// Route stretch measured for sampled machine pairs
+	Stretch(Duration, StretchReport),
+	/// Embedding error of route coordinates measured by a run of the embedding analysis
+	Embedding(Duration, EmbeddingReport),
 
 	/// Reset 
 	ClearUI,
@@ -127,8 +285,43 @@ pub enum InternetError {
 	#[error("can't connect machines directly to each other")]
 	NodeConnectionError,
 
-	#[error("spawned too many networks, not enough addresses (see MAX_NETWORKS)")]
+	#[error("wire {index} connects to unknown node")]
+	DanglingWire { index: WireIdx },
+
+	#[error("spawned too many networks, no free address range left")]
 	TooManyNetworks,
+	#[error("address range {range:?} overlaps with range of network {index}")]
+	RangeOverlap { range: Ipv4Range, index: NodeIdx },
+	#[error("address range {range:?} overlaps with reserved range {reserved:?}")]
+	RangeReserved { range: Ipv4Range, reserved: Ipv4Range },
+	#[error("invalid network prefix length /{0}, must be between /1 and /30")]
+	InvalidPrefix(u8),
+	#[error("address range {0:?} isn't aligned to its prefix length")]
+	UnalignedRange(Ipv4Range),
+
+	#[error("{} machines failed to freeze or thaw, first: {:?}", .0.len(), .0.first())]
+	FreezeFailed(Vec<(NodeIdx, internet_node::MachineError)>),
+
+	#[error("action isn't supported by this backend: {0}")]
+	UnsupportedAction(String),
+	#[error("action is only accepted while the simulation is paused")]
+	NotPaused,
+
+	#[error("time scale {0} out of range, must be between {MIN_TIME_SCALE} and {MAX_TIME_SCALE}")]
+	InvalidTimeScale(f64),
+
+	#[error(transparent)]
+	LatencyMatrixError(#[from] LatencyMatrixError),
+	#[error(transparent)]
+	TraceError(#[from] TraceError),
+	#[error(transparent)]
+	SaveError(#[from] SaveError),
+	#[error(transparent)]
+	ChurnError(#[from] ChurnError),
+	#[error(transparent)]
+	MobilityError(#[from] MobilityError),
+	#[error(transparent)]
+	ChaosError(#[from] ChaosError),
 
 	#[error(transparent)]
 	Other(#[from] anyhow::Error),
@@ -145,23 +338,60 @@ impl fmt::Display for WireIdx { fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt
 impl WireIdx { pub fn as_ffi(&self) -> usize { self.0.as_ffi() as usize } }
 
 /// Internet object, contains handles to the network and machine threads
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Derivative, Serialize, Deserialize)]
+#[derivative(Debug)]
 pub struct Internet {
 	nodes: SlotMap<NodeIdx, InternetNode>,
-	wires: SlotMap<WireIdx, (NodeIdx, NodeIdx)>,
+	wires: SlotMap<WireIdx, InternetWire>,
 	device_exec: String,
-	ip_range_iter: Ipv4RangeIter,
+	routing_mode: RoutingMode,
+	latency_model: LatencyModelConfig,
+	/// Speed of virtual time relative to wall-clock time
+	time_scale: f64,
+	/// Device builds and in-process apps machines can run, apps aren't saved and need to be registered before init()
+	registry: DeviceRegistry,
+	/// Seed of the oracle and in-process devices
+	seed: u64,
 }
 
 pub struct InternetRuntime {
+	/// Virtual time all delays and timers are measured in
+	clock: SimClock,
 	node_locations: SecondaryMap<NodeIdx, FieldPosition>,
 	wire_handles: SecondaryMap<WireIdx, WireHandle>,
+	/// Running latency trace tasks
+	traces: SecondaryMap<WireIdx, AbortHandle>,
+	/// Running mobility model tasks
+	mobility: SecondaryMap<NodeIdx, AbortHandle>,
+	/// Currently active partition
+	partition: Option<Partition>,
+	/// Running chaos engine
+	chaos: Option<chaos::Chaos>,
+	/// Running churn driver
+	churn: Option<churn::Churn>,
+	/// Ground truth devices can ask for
+	oracle: oracle::Oracle,
+	/// Running periodic embedding analysis
+	embedding: Option<embedding::EmbeddingAnalysis>,
 
 	action_receiver: Option<mpsc::Receiver<InternetAction>>,
 	action_sender: mpsc::Sender<InternetAction>,
 	pub event_sender: mpsc::Sender<InternetEvent>,
 }
 
+/// Groups of an active partition and the wires suspended between them
+struct Partition {
+	groups: Vec<Vec<NodeIdx>>,
+	wires: Vec<WireIdx>,
+}
+impl Partition {
+	/// Group a node belongs to, unlisted nodes share an implicit extra group
+	fn group(&self, node: NodeIdx) -> usize {
+		self.groups.iter().position(|group|group.contains(&node)).unwrap_or(self.groups.len())
+	}
+	fn crosses(&self, wire: &InternetWire) -> bool { self.group(wire.a) != self.group(wire.b) }
+}
+
 impl InternetRuntime {
 	/// Send event function (used internally by run())
 	fn send_event(&mut self, event: InternetEvent) -> Result<(), InternetError> {
@@ -176,6 +406,17 @@ impl InternetRuntime {
 	fn wire_handle(&mut self, wire_idx: WireIdx) -> Result<&mut WireHandle, InternetError> {
 		self.wire_handles.get_mut(wire_idx).ok_or(InternetError::UnknownWire { index: wire_idx })
 	}
+	/// Send action to the simulation after a delay in virtual time
+	fn schedule(&self, delay: Duration, action: InternetAction) {
+		let mut action_sender = self.action_sender.clone();
+		let clock = self.clock.clone();
+		task::spawn(async move {
+			clock.sleep(delay).await;
+			if let Err(err) = action_sender.send(action).await {
+				log::error!("Failed to send scheduled action: {:?}", err);
+			}
+		});
+	}
 }
 
 impl Internet {
@@ -185,21 +426,36 @@ impl Internet {
 			nodes: SlotMap::default(),
 			wires: SlotMap::default(),
 			device_exec: device_exec.into(),
-			ip_range_iter: Ipv4RangeIter::new(MAX_NETWORKS as u32),
+			routing_mode: RoutingMode::default(),
+			latency_model: LatencyModelConfig::default(),
+			time_scale: 1.0,
+			registry: DeviceRegistry::default(),
+			seed: rand::random(),
 		}
 	}
+	/// Seed the oracle and in-process devices, so runs with the same seed draw the same random numbers
+	pub fn with_seed(mut self, seed: u64) -> Self {
+		self.seed = seed;
+		self
+	}
+	/// Make an app available to in-process devices under a name
+	pub fn register_app(&mut self, name: impl Into<String>, app_factory: AppFactory) {
+		self.registry.register_app(name.into(), app_factory);
+	}
+	/// Make a program available to machines under a build name, machines running an existing build of that name get the new program when restarted
+	pub fn register_build(&mut self, name: impl Into<String>, program: MachineProgram) {
+		self.registry.register_build(name.into(), program);
+	}
+	/// Device new machines run unless told otherwise
+	fn default_device(&self) -> DeviceConfig {
+		DeviceConfig::Process(MachineProgram::device(self.device_exec.clone()))
+	}
 	pub fn save(&self, filepath: &str) -> Result<(), InternetError> {
-		use std::io::Write;
-		let mut file = File::create(filepath).context("failed to create file (check perms) at {}")?;
-		let data = bincode::serialize(&self).context("failed to serialize network")?;
-		file.write_all(&data).context("failed to write to file")?;
-		Ok(())
+		Ok(save::save(filepath, save::INTERNET_FORMAT, self)?)
 	}
 	pub fn load(filepath: &str) -> Result<Self, InternetError> {
 		log::debug!("Loading Internet from: {:?}", filepath);
-		let file = File::open(filepath).context("failed to open file (check perms)")?;
-		let internet: Internet = bincode::deserialize_from(BufReader::new(file)).context("failed to deserialize network")?;
-		Ok(internet)
+		Ok(save::load(filepath, save::INTERNET_FORMAT)?)
 	}
 	fn node(&self, idx: NodeIdx) -> Result<&InternetNode, InternetError> {
 		self.nodes.get(idx).ok_or(InternetError::UnknownNode { index: idx })
@@ -219,6 +475,133 @@ impl Internet {
 	pub fn network_mut(&mut self, index: NodeIdx) -> Result<&mut InternetNetwork, InternetError> {
 		self.node_mut(index)?.network_mut().ok_or(InternetError::InvalidNodeType { index, expected: NodeType::Network })
 	}
+	fn wire(&self, wire_idx: WireIdx) -> Result<&InternetWire, InternetError> {
+		self.wires.get(wire_idx).ok_or(InternetError::UnknownWire { index: wire_idx })
+	}
+	/// Latency of each direction of a wire (a to b, b to a), measured in microseconds
+	fn wire_delays(&self, wire_idx: WireIdx) -> Result<(Latency, Latency), InternetError> {
+		let wire = self.wire(wire_idx)?;
+		let (node1, node2) = match (self.nodes.get(wire.a), self.nodes.get(wire.b)) {
+			(Some(node1), Some(node2)) => (node1, node2),
+			_ => return Err(InternetError::DanglingWire { index: wire_idx }),
+		};
+		let model = self.latency_model.model();
+		let base = |from: &InternetNode, to: &InternetNode|wire.trace_latency.unwrap_or_else(||model.latency(&from.position, &to.position));
+		Ok((
+			wire.latency.a_to_b.unwrap_or_else(||base(node1, node2)),
+			wire.latency.b_to_a.unwrap_or_else(||base(node2, node1)),
+		))
+	}
+	/// Latency of a wire (measured in microseconds), the mean of both directions for asymmetric wires
+	fn wire_latency(&self, wire_idx: WireIdx) -> Result<Latency, InternetError> {
+		let (a_to_b, b_to_a) = self.wire_delays(wire_idx)?;
+		Ok((a_to_b + b_to_a) / 2)
+	}
+	fn wire_config(&self, wire_idx: WireIdx) -> Result<Wire, InternetError> {
+		let (a_to_b, b_to_a) = self.wire_delays(wire_idx)?;
+		Ok(Wire { a_to_b: Duration::from_micros(a_to_b), b_to_a: Duration::from_micros(b_to_a) })
+	}
+	/// Push current delays of a wire to its running handle
+	async fn update_wire_delay(&self, runtime: &mut InternetRuntime, wire_idx: WireIdx) -> Result<(), InternetError> {
+		let Wire { a_to_b, b_to_a } = self.wire_config(wire_idx)?;
+		runtime.wire_handle(wire_idx)?.set_delays(a_to_b, b_to_a).await;
+		Ok(())
+	}
+	/// Override latency of a wire
+	async fn set_wire_latency(&mut self, runtime: &mut InternetRuntime, wire_idx: WireIdx, latency: WireLatency) -> Result<(), InternetError> {
+		self.wires.get_mut(wire_idx).ok_or(InternetError::UnknownWire { index: wire_idx })?.latency = latency;
+		self.wire_latency_changed(runtime, wire_idx).await
+	}
+	/// Start or stop (None) a wire's latency trace
+	async fn set_wire_trace(&mut self, runtime: &mut InternetRuntime, wire_idx: WireIdx, trace: Option<WireTrace>) -> Result<(), InternetError> {
+		if let Some(trace) = &trace { trace.validate()?; }
+		let wire = self.wires.get_mut(wire_idx).ok_or(InternetError::UnknownWire { index: wire_idx })?;
+		wire.trace = trace.clone();
+		wire.trace_latency = None;
+		if let Some(abort_handle) = runtime.traces.remove(wire_idx) { abort_handle.abort(); }
+		match trace {
+			Some(trace) => { runtime.traces.insert(wire_idx, trace::spawn(wire_idx, trace, runtime.action_sender.clone(), runtime.clock.clone())); }
+			None => self.set_trace_latency(runtime, wire_idx, None).await?,
+		}
+		Ok(())
+	}
+	async fn set_trace_latency(&mut self, runtime: &mut InternetRuntime, wire_idx: WireIdx, latency: Option<Latency>) -> Result<(), InternetError> {
+		self.wires.get_mut(wire_idx).ok_or(InternetError::UnknownWire { index: wire_idx })?.trace_latency = latency;
+		self.wire_latency_changed(runtime, wire_idx).await
+	}
+	/// Update running wire and routes after a wire's latency changed
+	async fn wire_latency_changed(&mut self, runtime: &mut InternetRuntime, wire_idx: WireIdx) -> Result<(), InternetError> {
+		self.update_wire_delay(runtime, wire_idx).await?;
+		let (node1, node2) = self.wire(wire_idx)?.nodes();
+		if self.node(node1)?.network().is_some() && self.node(node2)?.network().is_some() {
+			self.routing_latency_changed(runtime, node1)?;
+		}
+		Ok(())
+	}
+	/// Add or lift a reason for a wire to be suspended, the running wire and routing are only updated when the wire goes down or comes back up
+	async fn set_wire_suspended(&mut self, runtime: &mut InternetRuntime, wire_idx: WireIdx, reason: SuspendReason, suspended: bool) -> Result<(), InternetError> {
+		let wire = self.wires.get_mut(wire_idx).ok_or(InternetError::UnknownWire { index: wire_idx })?;
+		let was_up = wire.is_up();
+		if suspended { wire.suspended.insert(reason); } else { wire.suspended.remove(&reason); }
+		let (up, (node1, node2)) = (wire.is_up(), wire.nodes());
+		if up == was_up { return Ok(()) }
+
+		runtime.wire_handle(wire_idx)?.set_suspended(!up).await;
+		if self.node(node1)?.network().is_some() && self.node(node2)?.network().is_some() {
+			if up { self.routing_link_up(runtime, wire_idx)?; } else { self.routing_link_down(runtime, wire_idx, node1, node2)?; }
+		}
+		Ok(())
+	}
+	/// Suspend every wire between different groups of nodes, replacing any active partition
+	async fn partition(&mut self, runtime: &mut InternetRuntime, groups: Vec<Vec<NodeIdx>>) -> Result<(), InternetError> {
+		for &index in groups.iter().flatten() { self.node(index)?; }
+		self.heal(runtime).await?;
+
+		let mut partition = Partition { groups, wires: Vec::new() };
+		partition.wires = self.wires.iter().filter(|(_, wire)|partition.crosses(wire)).map(|(wire_idx, _)|wire_idx).collect();
+		for &wire_idx in &partition.wires {
+			self.set_wire_suspended(runtime, wire_idx, SuspendReason::Partition, true).await?;
+		}
+		log::debug!("Partitioned Internet into {} groups, suspending {} wires", partition.groups.len() + 1, partition.wires.len());
+		runtime.send_event(InternetEvent::PartitionStarted(runtime.clock.now(), partition.groups.clone(), partition.wires.clone()))?;
+		runtime.partition = Some(partition);
+		Ok(())
+	}
+	/// End active partition, if any
+	async fn heal(&mut self, runtime: &mut InternetRuntime) -> Result<(), InternetError> {
+		let partition = match runtime.partition.take() { Some(partition) => partition, None => return Ok(()) };
+		// Wires removed during the partition are gone already
+		let wires: Vec<WireIdx> = partition.wires.into_iter().filter(|&wire_idx|self.wires.contains_key(wire_idx)).collect();
+		for &wire_idx in &wires {
+			self.set_wire_suspended(runtime, wire_idx, SuspendReason::Partition, false).await?;
+		}
+		runtime.send_event(InternetEvent::PartitionHealed(runtime.clock.now(), wires))
+	}
+	/// Suspend a newly connected wire if it crosses the active partition
+	async fn partition_new_wire(&mut self, runtime: &mut InternetRuntime, wire_idx: WireIdx) -> Result<(), InternetError> {
+		let crosses = match &runtime.partition { Some(partition) => partition.crosses(self.wire(wire_idx)?), None => false };
+		if crosses {
+			self.set_wire_suspended(runtime, wire_idx, SuspendReason::Partition, true).await?;
+			if let Some(partition) = &mut runtime.partition { partition.wires.push(wire_idx); }
+		}
+		Ok(())
+	}
+	/// Switch latency model, updating the delay of every wire
+	async fn set_latency_model(&mut self, runtime: &mut InternetRuntime, latency_model: LatencyModelConfig) -> Result<(), InternetError> {
+		self.latency_model = latency_model;
+		let wires: Vec<WireIdx> = self.wires.keys().collect();
+		for wire_idx in wires {
+			self.update_wire_delay(runtime, wire_idx).await?;
+		}
+		match self.routing_mode {
+			RoutingMode::Static => self.update_routes(),
+			RoutingMode::PathVector(_) => {
+				let networks: Vec<NodeIdx> = self.nodes.iter().filter(|(_, node)|node.network().is_some()).map(|(idx, _)|idx).collect();
+				for network_idx in networks { self.routing_latency_changed(runtime, network_idx)?; }
+				Ok(())
+			}
+		}
+	}
 
 	pub async fn init(&mut self) -> Result<(InternetRuntime, mpsc::Receiver<InternetEvent>, mpsc::Sender<InternetAction>), InternetError> {
 		let (event_sender, event_receiver) = mpsc::channel(100);
@@ -227,8 +610,16 @@ impl Internet {
 		let action_sender_ret = action_sender.clone();
 
 		let mut runtime = InternetRuntime {
+			clock: SimClock::new(self.time_scale),
 			node_locations: SecondaryMap::default(),
 			wire_handles: SecondaryMap::default(),
+			traces: SecondaryMap::default(),
+			mobility: SecondaryMap::default(),
+			partition: None,
+			chaos: None,
+			churn: None,
+			oracle: oracle::Oracle::new(self.seed),
+			embedding: None,
 			action_receiver: Some(action_receiver),
 			action_sender,
 			event_sender,
@@ -238,22 +629,42 @@ impl Internet {
 			runtime.node_locations.insert(node_idx, node.position.clone());
 			match &mut node.variant {
 				NodeVariant::Machine(machine) => {
-					machine.init(action_sender_ret.clone());
+					machine.init(action_sender_ret.clone(), runtime.clock.clone(), self.registry.clone(), self.seed);
 				}
 				NodeVariant::Network(network) => {
-					network.init();
+					network.init(runtime.clock.clone());
 				}
 			}
 		}
 
 		// Init Wire Handles
-		for (wire_idx, (node1, node2)) in self.wires.clone().into_iter() {
+		let wires: Vec<(WireIdx, (NodeIdx, NodeIdx))> = self.wires.iter().map(|(wire_idx, wire)|(wire_idx, wire.nodes())).collect();
+		for (wire_idx, (node1, node2)) in wires {
 			log::debug!("wire: {} connecting {} and {}", wire_idx, node1, node2);
-			let delay = Duration::from_micros(InternetNode::latency_distance(&self.node(node1)?.position, &self.node(node2)?.position));
+			let wire = self.wire_config(wire_idx)?;
 			let plug_a = self.node_mut(node1)?.init_plug(wire_idx)?;
 			let plug_b = self.node_mut(node2)?.init_plug(wire_idx)?;
-			runtime.wire_handles.insert(wire_idx, Wire::connect(Wire { delay }, plug_a, plug_b));
+			runtime.wire_handles.insert(wire_idx, wire.connect(runtime.clock.clone(), plug_a, plug_b));
+			if let Some(trace) = self.wire(wire_idx)?.trace.clone() {
+				runtime.traces.insert(wire_idx, trace::spawn(wire_idx, trace, runtime.action_sender.clone(), runtime.clock.clone()));
+			}
+		}
+		// Wires of powered off machines drop packets again
+		let powered_off: Vec<WireIdx> = self.nodes.values().filter_map(|node|node.machine())
+			.filter(|machine|!machine.powered).filter_map(|machine|machine.connection.map(|(wire_idx, _, _)|wire_idx)).collect();
+		for wire_idx in powered_off {
+			self.set_wire_suspended(&mut runtime, wire_idx, SuspendReason::PoweredOff, true).await?;
 		}
+		// Devices were restarted, so they need their IPv6 addresses again
+		for (node_idx, node) in self.nodes.iter() {
+			if let Some(machine) = node.machine() {
+				self.configure_machine_ipv6(machine)?;
+				if let Some(mobility) = machine.mobility.clone() {
+					runtime.mobility.insert(node_idx, mobility::spawn(node_idx, node.position, mobility, runtime.action_sender.clone(), runtime.clock.clone()));
+				}
+			}
+		}
+		self.start_routing(&mut runtime)?;
 		if self.nodes.len() > 0 {
 			runtime.action(InternetAction::RequestAllNodes)?;
 		}
@@ -278,6 +689,8 @@ impl Internet {
 					}
 					InternetAction::RequestAllNodes => {
 						runtime.send_event(InternetEvent::ClearUI)?;
+						runtime.send_event(InternetEvent::Clock(runtime.clock.now(), runtime.clock.scale()))?;
+						runtime.send_event(InternetEvent::Paused(runtime.clock.is_paused()))?;
 						for (idx, node) in self.nodes.iter() {
 							let node_info = node.node_info();
 							runtime.send_event(InternetEvent::NodeInfo(idx, node_info))?;
@@ -286,35 +699,164 @@ impl Internet {
 								NodeVariant::Network(network) => runtime.send_event(InternetEvent::NetworkInfo(idx, network.network_info()))?,
 							}
 						}
-						for (wire_idx, &(node1, node2)) in self.wires.iter() {
-							runtime.send_event(InternetEvent::ConnectionInfo(wire_idx, node1, node2))?;
+						for (wire_idx, wire) in self.wires.iter() {
+							runtime.send_event(InternetEvent::ConnectionInfo(wire_idx, wire.a, wire.b))?;
 						}
 					}
 					/* InternetAction::ConnectAllMachines(node_idx) => {
 						self.machine(node_idx)?.
 					} */
 					InternetAction::AddNetwork(position) => {
-						let idx = self.spawn_network(runtime, position)?;
-						runtime.send_event(InternetEvent::NewNetwork(idx))?;
-						runtime.action(InternetAction::GetNodeInfo(idx))?;
-						runtime.action(InternetAction::GetNetworkInfo(idx))?;
-						log::debug!("Added Network Node: {:?}", idx);
+						self.add_network(runtime, position, NetworkRange::Prefix(DEFAULT_NETWORK_PREFIX))?;
+					}
+					InternetAction::AddNetworkWithRange(position, range) => {
+						self.add_network(runtime, position, range)?;
 					}
 					InternetAction::AddMachine(position) => {
-						let idx = self.spawn_machine(runtime, position)?;
-						runtime.send_event(InternetEvent::NewMachine(idx))?;
-						runtime.action(InternetAction::GetNodeInfo(idx))?;
-						runtime.action(InternetAction::GetMachineInfo(idx))?;
-						log::debug!("Added Machine Node: {:?}", idx);
+						let device = self.default_device();
+						self.add_machine(runtime, position, device)?;
+					}
+					InternetAction::AddMachineWithDevice(position, device) => {
+						self.add_machine(runtime, position, device)?;
+					}
+					InternetAction::RemoveNode(index) => {
+						self.remove_node(runtime, index)?;
+						log::debug!("Removed Node: {:?}", index);
 					}
 					InternetAction::ConnectNodes(from, to) => {
 						let wire_idx = self.connect(runtime, from, to).await?;
-						runtime.send_event(InternetEvent::ConnectionInfo(wire_idx, from, to))?;
+						let (node1, node2) = self.wire(wire_idx)?.nodes();
+						runtime.send_event(InternetEvent::ConnectionInfo(wire_idx, node1, node2))?;
+					}
+					InternetAction::SetPowered(index, powered) => {
+						self.set_powered(runtime, index, powered).await?;
+					}
+					InternetAction::SetPowerOffMode(index, mode) => {
+						self.machine_mut(index)?.power_off_mode = mode;
+					}
+					InternetAction::Rehome(machine_idx, network_idx) => {
+						self.rehome(runtime, machine_idx, network_idx).await?;
+					}
+					InternetAction::Partition(groups) => {
+						self.partition(runtime, groups).await?;
+					}
+					InternetAction::Heal => {
+						self.heal(runtime).await?;
+					}
+					InternetAction::SetChaos(config) => {
+						if let Some(config) = &config { config.validate()?; }
+						self.set_chaos(runtime, config).await?;
+					}
+					InternetAction::ChaosTick => {
+						self.chaos_tick(runtime).await?;
+					}
+					InternetAction::SetChurn(config) => {
+						self.set_churn(runtime, config)?;
+					}
+					InternetAction::SetOracleBootstrap(enabled) => {
+						self.registry.set_oracle_bootstrap(enabled);
+					}
+					InternetAction::ChurnArrival => {
+						self.churn_arrival(runtime).await?;
+					}
+					InternetAction::ChurnDeparture(index) => {
+						self.churn_departure(runtime, index)?;
+					}
+					InternetAction::MeasureStretch(config) => {
+						self.measure_stretch(runtime, config)?;
+					}
+					InternetAction::SetEmbeddingAnalysis(config) => {
+						self.set_embedding_analysis(runtime, config)?;
+					}
+					InternetAction::EmbeddingTick => {
+						self.embedding_tick(runtime)?;
+					}
+					InternetAction::RegisterBuild(name, program) => {
+						self.register_build(name, program);
+					}
+					InternetAction::SetDevice(index, device) => {
+						self.set_machine_device(runtime, index, device)?;
+					}
+					InternetAction::RollingUpgrade(upgrade) => {
+						self.rolling_upgrade(runtime, upgrade)?;
+					}
+					InternetAction::UpgradeMachine(index, build) => {
+						self.upgrade_machine(runtime, index, build)?;
+					}
+					InternetAction::SetWireLatency(wire_idx, latency) => {
+						self.set_wire_latency(runtime, wire_idx, latency).await?;
+					}
+					InternetAction::SetWireTrace(wire_idx, trace) => {
+						self.set_wire_trace(runtime, wire_idx, trace).await?;
+					}
+					InternetAction::LoadWireTrace(wire_idx, path) => {
+						let trace = WireTrace { source: TraceSource::load_csv(&path)?, interval: DEFAULT_TRACE_INTERVAL };
+						self.set_wire_trace(runtime, wire_idx, Some(trace)).await?;
+					}
+					InternetAction::TraceSample(wire_idx, latency) => {
+						// Wire may have been removed or its trace stopped while the sample was in flight
+						if self.wires.get(wire_idx).map_or(false, |wire|wire.trace.is_some()) {
+							self.set_trace_latency(runtime, wire_idx, Some(latency)).await?;
+						}
+					}
+					InternetAction::SetNat(index, nat) => {
+						self.network_mut(index)?.set_nat(nat)?;
+						runtime.send_event(InternetEvent::NetworkInfo(index, self.network(index)?.network_info()))?;
+					}
+					InternetAction::SetIpStack(index, stack) => {
+						self.network_mut(index)?.set_ip_stack(stack)?;
+						runtime.send_event(InternetEvent::NetworkInfo(index, self.network(index)?.network_info()))?;
+					}
+					InternetAction::EditFirewall(index, edit) => {
+						self.network_mut(index)?.edit_firewall(edit)?;
+						runtime.send_event(InternetEvent::NetworkInfo(index, self.network(index)?.network_info()))?;
+					}
+					InternetAction::SetRoutingMode(mode) => {
+						self.set_routing_mode(runtime, mode)?;
+					}
+					InternetAction::RoutingUpdate(network_idx, wire_idx, advertisements) => {
+						self.handle_route_advertisements(runtime, network_idx, wire_idx, advertisements)?;
+					}
+					InternetAction::RoutingLinkDown(network_idx, wire_idx) => {
+						self.handle_route_link_down(runtime, network_idx, wire_idx)?;
+					}
+					InternetAction::SetLatencyModel(latency_model) => {
+						self.set_latency_model(runtime, latency_model).await?;
+					}
+					InternetAction::SetTimeScale(scale) => {
+						if !(MIN_TIME_SCALE..=MAX_TIME_SCALE).contains(&scale) { Err(InternetError::InvalidTimeScale(scale))? }
+						self.time_scale = scale;
+						runtime.clock.set_scale(scale);
+						runtime.send_event(InternetEvent::Clock(runtime.clock.now(), scale))?;
+					}
+					InternetAction::GetClock => {
+						runtime.send_event(InternetEvent::Clock(runtime.clock.now(), runtime.clock.scale()))?;
+					}
+					InternetAction::Pause => self.set_paused(runtime, true)?,
+					InternetAction::Resume => self.set_paused(runtime, false)?,
+					InternetAction::Step(duration) => {
+						if !runtime.clock.is_paused() { Err(InternetError::NotPaused)? }
+						self.set_paused(runtime, false)?;
+						runtime.schedule(duration, InternetAction::Pause);
+					}
+					InternetAction::LoadLatencyMatrix(path) => {
+						let matrix = LatencyMatrix::load(&path)?;
+						self.set_latency_model(runtime, LatencyModelConfig::Matrix(matrix)).await?;
 					}
 					InternetAction::SetPosition(index, position) => {
-						let node = self.node_mut(index)?;
-						node.update_position(runtime, position).await?;
-						runtime.send_event(InternetEvent::NodeInfo(index, node.node_info()))?;
+						self.move_node(runtime, index, position).await?;
+					}
+					InternetAction::SetMobility(index, mobility) => {
+						self.set_mobility(runtime, index, mobility)?;
+					}
+					InternetAction::MobilityStep(index, position) => {
+						// Machine may have been removed or stopped while the step was in flight
+						if self.machine(index).map_or(false, |machine|machine.mobility.is_some()) {
+							self.move_node(runtime, index, position).await?;
+							if self.machine(index)?.mobility.as_ref().map_or(false, |mobility|mobility.handover) {
+								self.handover(runtime, index).await?;
+							}
+						}
 					}
 					InternetAction::GetNodeInfo(index) => {
 						runtime.send_event(InternetEvent::NodeInfo(index, self.node(index)?.node_info()))?;
@@ -327,20 +869,37 @@ impl Internet {
 						runtime.send_event(InternetEvent::NetworkInfo(index, self.network(index)?.network_info()))?;
 					}
 					InternetAction::GetConnectionInfo(wire_idx) => {
-						let (from, to) = self.wires.get(wire_idx).cloned().ok_or(InternetError::UnknownWire { index: wire_idx })?;
+						let (from, to) = self.wire(wire_idx)?.nodes();
 						runtime.send_event(InternetEvent::ConnectionInfo(wire_idx, from, to))?;
 					}
 					InternetAction::HandleDeviceEvent(index, DeviceEvent::DitherEvent(dither_event)) => {
 						match dither_event {
 							DitherEvent::NodeInfo(device::NodeInfo { route_coord, node_id, public_addr, remotes, active_remotes, local_addr } ) => {
-								let network_ip = self.machine(index)?.connection_ip();
+								self.churn_peer(runtime, index, Some(&node_id));
+								runtime.oracle.node_info(index, node_id.clone(), route_coord.clone());
+								let machine = self.machine(index)?;
+								let (network_ip, network_ip6) = (machine.connection_ip(), machine.connection_ip6());
 								runtime.send_event(InternetEvent::MachineInfo(index, MachineInfo {
-									route_coord, public_addr, node_id, remotes, active_remotes, network_ip, local_addr
+									route_coord, public_addr, node_id, remotes, active_remotes, network_ip, network_ip6, local_addr
 								}))?;
 							}
 							//_ => log::error!("Unhandled Device Event")
 						}
 					}
+					InternetAction::HandleDeviceEvent(index, DeviceEvent::Started { pid }) => {
+						self.machine_mut(index)?.set_pid(pid)?;
+					}
+					InternetAction::HandleDeviceEvent(index, DeviceEvent::Output(line)) => {
+						log::info!("Machine {}: {}", index, line);
+					}
+					InternetAction::HandleDeviceEvent(index, DeviceEvent::BaselineInfo(info)) => {
+						self.churn_peer(runtime, index, None);
+						runtime.oracle.baseline_info(index, &info);
+						runtime.send_event(InternetEvent::BaselineInfo(index, info))?;
+					}
+					InternetAction::HandleDeviceEvent(index, DeviceEvent::OracleRequest(id, request)) => {
+						self.answer_oracle(runtime, index, id, request)?;
+					}
 					InternetAction::DeviceCommand(node_idx, command) => {
 						self.machine(node_idx)?.device_command(command)?;
 					}
@@ -368,24 +927,72 @@ impl Internet {
 			}
 		}
 	}
+	/// Spawn machine and announce it along with its info
+	fn add_machine(&mut self, runtime: &mut InternetRuntime, position: FieldPosition, device: DeviceConfig) -> Result<NodeIdx, InternetError> {
+		let idx = self.spawn_machine(runtime, position, device)?;
+		runtime.send_event(InternetEvent::NewMachine(idx))?;
+		runtime.action(InternetAction::GetNodeInfo(idx))?;
+		runtime.action(InternetAction::GetMachineInfo(idx))?;
+		log::debug!("Added Machine Node: {:?}", idx);
+		Ok(idx)
+	}
+	/// Spawn network and announce it along with its info
+	fn add_network(&mut self, runtime: &mut InternetRuntime, position: FieldPosition, range: NetworkRange) -> Result<NodeIdx, InternetError> {
+		let idx = self.spawn_network(runtime, position, range)?;
+		runtime.send_event(InternetEvent::NewNetwork(idx))?;
+		runtime.action(InternetAction::GetNodeInfo(idx))?;
+		runtime.action(InternetAction::GetNetworkInfo(idx))?;
+		log::debug!("Added Network Node: {:?}", idx);
+		Ok(idx)
+	}
 	/// Spawn machine at position
-	fn spawn_machine(&mut self, runtime: &mut InternetRuntime, position: FieldPosition) -> Result<NodeIdx, InternetError> {
+	fn spawn_machine(&mut self, runtime: &mut InternetRuntime, position: FieldPosition, device: DeviceConfig) -> Result<NodeIdx, InternetError> {
+		self.registry.validate(&device)?;
 		let action_sender = runtime.action_sender.clone();
-		let executable = self.device_exec.clone();
-		Ok(self.nodes.insert_with_key(|key| {
-			let mut machine = task::block_on(InternetMachine::new(key, executable));
-			machine.init(action_sender);
+		let registry = self.registry.clone();
+		let (clock, seed) = (runtime.clock.clone(), self.seed);
+		let idx = self.nodes.insert_with_key(|key| {
+			let mut machine = task::block_on(InternetMachine::new(key, device));
+			machine.init(action_sender, clock, registry, seed);
 			InternetNode::from_machine(machine, position, key)
-		}))
+		});
+		runtime.node_locations.insert(idx, position);
+		Ok(idx)
+	}
+	/// Find address range for a new network, validating that it doesn't overlap with any other network
+	fn allocate_range(&self, range: NetworkRange) -> Result<Ipv4Range, InternetError> {
+		let taken: Vec<(NodeIdx, Ipv4Range)> = self.nodes.iter().filter_map(|(idx, node)|node.network().map(|network|(idx, network.range()))).collect();
+		match range {
+			NetworkRange::Prefix(prefix) => {
+				if !addressing::NETWORK_PREFIXES.contains(&prefix) { return Err(InternetError::InvalidPrefix(prefix)) }
+				addressing::find_free_range(prefix, taken.iter().map(|(_, range)|range)).ok_or(InternetError::TooManyNetworks)
+			}
+			NetworkRange::Exact(range) => {
+				let prefix = range.netmask_prefix_length();
+				if !addressing::NETWORK_PREFIXES.contains(&prefix) { return Err(InternetError::InvalidPrefix(prefix)) }
+				if !addressing::is_aligned(&range) { return Err(InternetError::UnalignedRange(range)) }
+				if let Some(reserved) = addressing::overlaps_reserved(&range) {
+					return Err(InternetError::RangeReserved { range, reserved });
+				}
+				if let Some((index, _)) = taken.iter().find(|(_, taken)|addressing::ranges_overlap(taken, &range)) {
+					return Err(InternetError::RangeOverlap { range, index: *index });
+				}
+				Ok(range)
+			}
+		}
 	}
 	/// Spawn network at position
-	fn spawn_network(&mut self, _runtime: &mut InternetRuntime, position: FieldPosition) -> Result<NodeIdx, InternetError> {
-		let range = self.ip_range_iter.next().ok_or(InternetError::TooManyNetworks)?;
-		Ok(self.nodes.insert_with_key(|key|{
-			let mut network = InternetNetwork::new(key, range);
-			network.init();
+	fn spawn_network(&mut self, runtime: &mut InternetRuntime, position: FieldPosition, range: NetworkRange) -> Result<NodeIdx, InternetError> {
+		let range = self.allocate_range(range)?;
+		let range6 = ipv6::find_free_range(self.nodes.values().filter_map(|node|node.network()).map(|network|network.range6()).collect::<Vec<_>>().iter())
+			.ok_or(InternetError::TooManyNetworks)?;
+		let idx = self.nodes.insert_with_key(|key|{
+			let mut network = InternetNetwork::new(key, range, range6);
+			network.init(runtime.clock.clone());
 			InternetNode::from_network(network, position, key)
-		}))
+		});
+		runtime.node_locations.insert(idx, position);
+		Ok(idx)
 	}
 	async fn connect(&mut self, runtime: &mut InternetRuntime, from: NodeIdx, to: NodeIdx) -> Result<WireIdx, InternetError> {
 		use NodeVariant::*;
@@ -393,14 +1000,17 @@ impl Internet {
 		let node2 = self.node(to)?;
 		match (&node1.variant, &node2.variant) {
 			(Network(net1), Network(net2)) => {
-				let delay = Duration::from_micros(InternetNode::latency_distance(&node1.position, &node2.position));
-				let (route1, route2) = (net1.route(), net2.route());
+				let route1 = ConnectionRoutes::new(vec![net1.route()], vec![net1.range6()]);
+				let route2 = ConnectionRoutes::new(vec![net2.route()], vec![net2.range6()]);
 
-				let wire_idx = self.wires.insert((from, to));
+				let wire_idx = self.wires.insert(InternetWire::new(from, to));
+				let wire = self.wire_config(wire_idx)?;
 				
-				let plug1 = self.network_mut(from)?.connect(wire_idx, to, vec![route1])?;
-				let plug2 = self.network_mut(to)?.connect(wire_idx, from, vec![route2])?;
-				runtime.wire_handles.insert(wire_idx, Wire { delay }.connect(plug1, plug2));
+				let plug1 = self.network_mut(from)?.connect(wire_idx, to, true, route2)?;
+				let plug2 = self.network_mut(to)?.connect(wire_idx, from, true, route1)?;
+				runtime.wire_handles.insert(wire_idx, wire.connect(runtime.clock.clone(), plug1, plug2));
+				self.partition_new_wire(runtime, wire_idx).await?;
+				self.routing_link_up(runtime, wire_idx)?;
 				Ok(wire_idx)
 			},
 			(Network(net), Machine(machine)) | (Machine(machine), Network(net)) => {
@@ -410,29 +1020,153 @@ impl Internet {
 					self.unwire(runtime, wire_idx)?;
 				}
 
-				let wire_idx = self.wires.insert((from, to));
+				// Network is always on side A of the wire
+				let wire_idx = self.wires.insert(InternetWire::new(network_id, machine_id));
 
 				// Connect
-				let network = self.network_mut(network_id)?;
-				let addr = network.unique_addr();
-				let net_plug = network.connect(wire_idx, machine_id, vec![addr.into()])?;
-				let machine_plug = self.machine_mut(machine_id)?.connect(wire_idx, network_id, addr).await?;
-				let delay = Duration::from_micros(InternetNode::latency_distance(&self.node(machine_id)?.position, &self.node(network_id)?.position));
-
-				//let delay = self.node(machine_id)?.position
-				runtime.wire_handles.insert(wire_idx, Wire::connect(Wire { delay }, net_plug, machine_plug));
+				let (net_plug, addrs) = match self.network_mut(network_id)?.connect_machine(wire_idx, machine_id) {
+					Ok(connection) => connection,
+					Err(err) => { self.wires.remove(wire_idx); Err(err)? }
+				};
+				let machine_plug = self.machine_mut(machine_id)?.connect(wire_idx, network_id, addrs).await?;
+				self.configure_machine_ipv6(self.machine(machine_id)?)?;
+				let wire = self.wire_config(wire_idx)?;
+				runtime.wire_handles.insert(wire_idx, wire.connect(runtime.clock.clone(), net_plug, machine_plug));
+				self.partition_new_wire(runtime, wire_idx).await?;
+				if !self.machine(machine_id)?.powered {
+					self.set_wire_suspended(runtime, wire_idx, SuspendReason::PoweredOff, true).await?;
+				}
 				Ok(wire_idx)
 			}
 			_ => Err(InternetError::NodeConnectionError),
 		}
 	}
+	/// Move node and update the delays of its wires
+	async fn move_node(&mut self, runtime: &mut InternetRuntime, index: NodeIdx, position: FieldPosition) -> Result<(), InternetError> {
+		let node = self.node_mut(index)?;
+		node.update_position(runtime, position)?;
+		let node_info = node.node_info();
+		for &wire_idx in &node_info.connections { self.update_wire_delay(runtime, wire_idx).await?; }
+		runtime.send_event(InternetEvent::NodeInfo(index, node_info))?;
+		// Moving a network changes latency of its uplinks
+		if self.node(index)?.network().is_some() { self.routing_latency_changed(runtime, index)?; }
+		Ok(())
+	}
+	/// Start or stop (None) moving a machine
+	fn set_mobility(&mut self, runtime: &mut InternetRuntime, index: NodeIdx, mobility: Option<Mobility>) -> Result<(), InternetError> {
+		if let Some(mobility) = &mobility { mobility.validate()?; }
+		self.machine_mut(index)?.mobility = mobility.clone();
+		if let Some(abort_handle) = runtime.mobility.remove(index) { abort_handle.abort(); }
+		if let Some(mobility) = mobility {
+			let position = self.node(index)?.position;
+			runtime.mobility.insert(index, mobility::spawn(index, position, mobility, runtime.action_sender.clone(), runtime.clock.clone()));
+		}
+		Ok(())
+	}
+	/// Reconnect machine to the closest network if it is sufficiently closer than the current one
+	async fn handover(&mut self, runtime: &mut InternetRuntime, machine_idx: NodeIdx) -> Result<(), InternetError> {
+		let position = self.node(machine_idx)?.position;
+		let model = self.latency_model.model();
+		let closest = self.nodes.iter().filter(|(_, node)|node.network().is_some())
+			.map(|(idx, node)|(idx, model.latency(&position, &node.position)))
+			.min_by_key(|(_, latency)|*latency);
+		let (closest, closest_latency) = match closest { Some(closest) => closest, None => return Ok(()) };
+
+		// Unconnected machines stay unconnected
+		let current = match self.machine(machine_idx)?.connection { Some((_, current, _)) => current, None => return Ok(()) };
+		if current == closest { return Ok(()) }
+		let current_latency = model.latency(&position, &self.node(current)?.position);
+		if closest_latency as f64 >= current_latency as f64 * (1.0 - HANDOVER_HYSTERESIS) { return Ok(()) }
+		log::debug!("Handing over machine {} to network {}", machine_idx, closest);
+		self.rehome(runtime, machine_idx, closest).await
+	}
+	/// Move running machine to another network and notify its device of the new addresses
+	async fn rehome(&mut self, runtime: &mut InternetRuntime, machine_idx: NodeIdx, network_idx: NodeIdx) -> Result<(), InternetError> {
+		self.machine(machine_idx)?;
+		self.network(network_idx)?;
+		// Connecting unwires the previous connection, releasing its addresses
+		let wire_idx = self.connect(runtime, network_idx, machine_idx).await?;
+		let (node1, node2) = self.wire(wire_idx)?.nodes();
+		runtime.send_event(InternetEvent::ConnectionInfo(wire_idx, node1, node2))?;
+
+		let machine = self.machine(machine_idx)?;
+		machine.device_command(DeviceCommand::AddressChanged { ipv4: machine.connection_ip(), ipv6: machine.connection_ip6() })?;
+		machine.request_machine_info()?;
+		runtime.send_event(InternetEvent::NodeInfo(machine_idx, self.node(machine_idx)?.node_info()))
+	}
+	/// Power machine off or on and suspend or restore its wire
+	async fn set_powered(&mut self, runtime: &mut InternetRuntime, index: NodeIdx, powered: bool) -> Result<(), InternetError> {
+		self.machine_mut(index)?.set_powered(powered, runtime.action_sender.clone())?;
+		let machine = self.machine(index)?;
+		// A device started from scratch needs its IPv6 address again
+		if powered { self.configure_machine_ipv6(machine)?; }
+		if let Some((wire_idx, _, _)) = machine.connection {
+			self.set_wire_suspended(runtime, wire_idx, SuspendReason::PoweredOff, !powered).await?;
+		}
+		runtime.send_event(InternetEvent::NodeInfo(index, self.node(index)?.node_info()))
+	}
+	/// Pause or resume virtual time and freeze or thaw every device along with it.
+	/// Packets a device sends before it is frozen wait on their wire with the rest.
+	fn set_paused(&mut self, runtime: &mut InternetRuntime, paused: bool) -> Result<(), InternetError> {
+		if runtime.clock.is_paused() == paused { return Ok(()) }
+		runtime.clock.set_paused(paused);
+		// A machine failing to freeze or thaw doesn't keep the others from following the clock
+		let failed: Vec<(NodeIdx, internet_node::MachineError)> = self.nodes.iter_mut()
+			.filter_map(|(index, node)|node.machine_mut()?.update_frozen().err().map(|err|(index, err)))
+			.collect();
+		runtime.send_event(InternetEvent::Paused(paused))?;
+		if failed.is_empty() { Ok(()) } else { Err(InternetError::FreezeFailed(failed)) }
+	}
+	/// Send IPv6 configuration of the network a machine is connected to down to its device
+	fn configure_machine_ipv6(&self, machine: &InternetMachine) -> Result<(), InternetError> {
+		if let Some((_, network_idx, _)) = machine.connection {
+			machine.configure_ipv6(self.network(network_idx)?.range6())?;
+		}
+		Ok(())
+	}
+	/// Remove node and all of its wires
+	fn remove_node(&mut self, runtime: &mut InternetRuntime, index: NodeIdx) -> Result<(), InternetError> {
+		for wire_idx in self.node(index)?.node_info().connections { self.unwire(runtime, wire_idx)?; }
+		if let Some(abort_handle) = runtime.mobility.remove(index) { abort_handle.abort(); }
+		runtime.oracle.remove(index);
+		if let Some(machine) = self.node_mut(index)?.machine_mut() { machine.shutdown(); }
+		self.nodes.remove(index);
+		runtime.node_locations.remove(index);
+		runtime.send_event(InternetEvent::RemoveNode(index))
+	}
 	fn unwire(&mut self, runtime: &mut InternetRuntime, wire_idx: WireIdx) -> Result<(), InternetError> {
-		runtime.wire_handles.remove(wire_idx);
-		if let Some((node1, node2)) = self.wires.remove(wire_idx) {
+		if let Some(wire_handle) = runtime.wire_handles.remove(wire_idx) { task::block_on(wire_handle.force_disconnect()); }
+		if let Some(abort_handle) = runtime.traces.remove(wire_idx) { abort_handle.abort(); }
+		if let Some(InternetWire { a: node1, b: node2, .. }) = self.wires.remove(wire_idx) {
 			runtime.send_event(InternetEvent::RemoveConnection(wire_idx))?;
 			self.node_mut(node1)?.disconnect(wire_idx)?;
 			self.node_mut(node2)?.disconnect(wire_idx)?;
+			if self.node(node1)?.network().is_some() && self.node(node2)?.network().is_some() {
+				self.routing_link_down(runtime, wire_idx, node1, node2)?;
+			}
 		}
 		Ok(())
 	}
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn move_node_added_at_runtime() {
+		task::block_on(async {
+			let mut internet = Internet::new("device");
+			let (mut runtime, _events, _actions) = internet.init().await.unwrap();
+			let idx = internet.spawn_network(&mut runtime, FieldPosition::new(100, 0), NetworkRange::Prefix(addressing::DEFAULT_NETWORK_PREFIX)).unwrap();
+
+			internet.move_node(&mut runtime, idx, FieldPosition::new(200, 50)).await.unwrap();
+			assert_eq!(internet.node(idx).unwrap().position, FieldPosition::new(200, 50));
+			assert_eq!(runtime.node_locations[idx], FieldPosition::new(200, 50));
+
+			// Removed nodes forget their location
+			internet.remove_node(&mut runtime, idx).unwrap();
+			assert!(runtime.node_locations.get(idx).is_none());
+		});
+	}
+}
@@ -0,0 +1,219 @@
+//! IPv4 address management: allocation of network ranges out of the global address space and of host addresses within a network.
+
+use std::collections::BTreeSet;
+use std::net::Ipv4Addr;
+
+use netsim_embed::Ipv4Range;
+
+/// Default prefix length of ranges given to new networks
+pub const DEFAULT_NETWORK_PREFIX: u8 = 16;
+/// Prefix lengths networks can be given, longer prefixes leave no room for host addresses
+pub const NETWORK_PREFIXES: std::ops::RangeInclusive<u8> = 1..=30;
+
+/// Ranges that are never handed out to networks
+fn reserved_ranges() -> [Ipv4Range; 4] {
+	[
+		Ipv4Range::new(Ipv4Addr::new(0, 0, 0, 0), 8),
+		Ipv4Range::new(Ipv4Addr::new(10, 0, 0, 0), 8), // NAT private addresses
+		Ipv4Range::new(Ipv4Addr::new(127, 0, 0, 0), 8),
+		Ipv4Range::new(Ipv4Addr::new(224, 0, 0, 0), 3), // Multicast & reserved
+	]
+}
+
+/// How the address range of a new network is chosen
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum NetworkRange {
+	/// Allocate first free range with the given prefix length
+	Prefix(u8),
+	/// Use exactly this range
+	Exact(Ipv4Range),
+}
+
+/// Two CIDR ranges overlap if one contains the start of the other
+pub fn ranges_overlap(a: &Ipv4Range, b: &Ipv4Range) -> bool {
+	a.contains(b.base_addr()) || b.contains(a.base_addr())
+}
+
+/// Returns reserved range that overlaps with given range
+pub fn overlaps_reserved(range: &Ipv4Range) -> Option<Ipv4Range> {
+	reserved_ranges().into_iter().find(|reserved|ranges_overlap(reserved, range))
+}
+
+/// Whether the range's base address has no bits set beyond its prefix
+pub fn is_aligned(range: &Ipv4Range) -> bool {
+	// Shifting out the prefix leaves only the host bits
+	u32::from(range.base_addr()).checked_shl(range.netmask_prefix_length() as u32).unwrap_or(0) == 0
+}
+
+/// Find the first range of a given prefix length that doesn't overlap any reserved or taken range
+pub fn find_free_range<'a>(prefix: u8, taken: impl Iterator<Item = &'a Ipv4Range> + Clone) -> Option<Ipv4Range> {
+	if !NETWORK_PREFIXES.contains(&prefix) { return None }
+	let block_size = 1u64 << (32 - prefix);
+	let mut base = 0u64;
+	while base < (1u64 << 32) {
+		let candidate = Ipv4Range::new(Ipv4Addr::from(base as u32), prefix);
+		// Skip to the end of whatever range is in the way
+		let blocking = overlaps_reserved(&candidate).or_else(||taken.clone().find(|range|ranges_overlap(range, &candidate)).copied());
+		match blocking {
+			Some(blocking) => {
+				let blocking_end = u32::from(blocking.base_addr()) as u64 + (1u64 << (32 - blocking.netmask_prefix_length()));
+				base = ((blocking_end + block_size - 1) / block_size * block_size).max(base + block_size);
+			}
+			None => return Some(candidate),
+		}
+	}
+	None
+}
+
+/// Hands out host addresses within a range, reusing released addresses
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AddressPool {
+	range: Ipv4Range,
+	/// Next never-allocated host index
+	next: u32,
+	/// Released host indices, lowest are reused first
+	free: BTreeSet<u32>,
+}
+
+impl AddressPool {
+	pub fn new(range: Ipv4Range) -> Self {
+		AddressPool { range, next: 0, free: BTreeSet::new() }
+	}
+	pub fn range(&self) -> Ipv4Range { self.range }
+	/// Number of host addresses, excluding network, gateway and broadcast addresses
+	pub fn capacity(&self) -> u32 {
+		let size = 1u64 << (32 - self.range.netmask_prefix_length());
+		size.saturating_sub(3).min(u32::MAX as u64) as u32
+	}
+	/// Number of addresses currently handed out
+	pub fn used(&self) -> u32 { self.next - self.free.len() as u32 }
+
+	/// Host address for an index, skipping network and gateway addresses
+	pub fn host(&self, index: u32) -> Ipv4Addr {
+		Ipv4Addr::from(u32::from(self.range.base_addr()) + 2 + index)
+	}
+	pub fn allocate(&mut self) -> Option<Ipv4Addr> {
+		let index = match self.free.iter().next().copied() {
+			Some(index) => { self.free.remove(&index); index }
+			None if self.next < self.capacity() => { self.next += 1; self.next - 1 }
+			None => return None,
+		};
+		Some(self.host(index))
+	}
+	/// Return address to the pool so it can be handed out again
+	pub fn release(&mut self, addr: Ipv4Addr) {
+		let index = u32::from(addr).wrapping_sub(u32::from(self.range.base_addr()) + 2);
+		if index < self.next { self.free.insert(index); }
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn range(a: u8, b: u8, c: u8, d: u8, prefix: u8) -> Ipv4Range {
+		Ipv4Range::new(Ipv4Addr::new(a, b, c, d), prefix)
+	}
+	/// Range with host bits set in its base address, like ranges loaded from a save or sent by the GUI
+	fn unaligned(addr: Ipv4Addr, prefix: u8) -> Ipv4Range {
+		let json = serde_json::to_string(&Ipv4Range::new(Ipv4Addr::UNSPECIFIED, prefix)).unwrap().replace("0.0.0.0", &addr.to_string());
+		serde_json::from_str(&json).unwrap()
+	}
+
+	#[test]
+	fn reserved_ranges_overlap() {
+		assert_eq!(overlaps_reserved(&range(10, 1, 0, 0, 16)), Some(range(10, 0, 0, 0, 8)));
+		assert_eq!(overlaps_reserved(&range(127, 0, 0, 1, 32)), Some(range(127, 0, 0, 0, 8)));
+		assert_eq!(overlaps_reserved(&range(240, 0, 0, 0, 4)), Some(range(224, 0, 0, 0, 3)));
+		// Larger ranges containing a reserved one
+		assert!(overlaps_reserved(&range(8, 0, 0, 0, 6)).is_some());
+		assert!(overlaps_reserved(&range(0, 0, 0, 0, 1)).is_some());
+		// Directly adjacent
+		assert_eq!(overlaps_reserved(&range(9, 255, 0, 0, 16)), None);
+		assert_eq!(overlaps_reserved(&range(11, 0, 0, 0, 8)), None);
+		assert_eq!(overlaps_reserved(&range(223, 255, 255, 0, 24)), None);
+		assert_eq!(overlaps_reserved(&range(1, 0, 0, 0, 8)), None);
+	}
+
+	#[test]
+	fn alignment() {
+		assert!(is_aligned(&range(100, 64, 0, 0, 16)));
+		assert!(!is_aligned(&unaligned(Ipv4Addr::new(100, 64, 0, 1), 16)));
+		assert!(!is_aligned(&unaligned(Ipv4Addr::new(100, 64, 128, 0), 16)));
+		assert!(is_aligned(&unaligned(Ipv4Addr::new(100, 64, 128, 0), 17)));
+		// Prefix lengths at both ends, shifting by 32 must not overflow
+		assert!(is_aligned(&unaligned(Ipv4Addr::new(100, 64, 0, 1), 32)));
+		assert!(is_aligned(&range(0, 0, 0, 0, 0)));
+		assert!(!is_aligned(&unaligned(Ipv4Addr::new(1, 0, 0, 0), 0)));
+	}
+
+	#[test]
+	fn free_ranges() {
+		let none: [Ipv4Range; 0] = [];
+		// Prefix lengths without room for hosts, or spanning the whole address space
+		for prefix in [0, 31, 32] { assert_eq!(find_free_range(prefix, none.iter()), None); }
+		assert_eq!(find_free_range(30, none.iter()), Some(range(1, 0, 0, 0, 30)));
+		assert_eq!(find_free_range(16, none.iter()), Some(range(1, 0, 0, 0, 16)));
+		// Every /1 overlaps a reserved range
+		assert_eq!(find_free_range(1, none.iter()), None);
+		// 64.0.0.0/2 contains loopback
+		assert_eq!(find_free_range(2, none.iter()), Some(range(128, 0, 0, 0, 2)));
+
+		let taken = [range(1, 0, 0, 0, 16)];
+		assert_eq!(find_free_range(16, taken.iter()), Some(range(1, 1, 0, 0, 16)));
+		// Skips past larger taken ranges and stays aligned after smaller ones
+		let taken = [range(1, 0, 0, 0, 9)];
+		assert_eq!(find_free_range(16, taken.iter()), Some(range(1, 128, 0, 0, 16)));
+		let taken = [range(1, 0, 0, 0, 24)];
+		assert_eq!(find_free_range(16, taken.iter()), Some(range(1, 1, 0, 0, 16)));
+		// Skips over reserved ranges
+		let taken: Vec<Ipv4Range> = (1..10).map(|a|range(a, 0, 0, 0, 8)).collect();
+		assert_eq!(find_free_range(8, taken.iter()), Some(range(11, 0, 0, 0, 8)));
+		let taken: Vec<Ipv4Range> = (1..=223).filter(|&a|a != 10 && a != 127).map(|a|range(a, 0, 0, 0, 8)).collect();
+		assert_eq!(find_free_range(8, taken.iter()), None);
+	}
+
+	#[test]
+	fn address_pool() {
+		let mut pool = AddressPool::new(range(100, 64, 0, 0, 29));
+		assert_eq!(pool.capacity(), 5);
+		let addrs: Vec<Ipv4Addr> = std::iter::from_fn(||pool.allocate()).collect();
+		assert_eq!(addrs, (2..=6).map(|d|Ipv4Addr::new(100, 64, 0, d)).collect::<Vec<_>>());
+		assert_eq!(pool.used(), 5);
+
+		// Lowest released address is reused first
+		pool.release(Ipv4Addr::new(100, 64, 0, 4));
+		pool.release(Ipv4Addr::new(100, 64, 0, 3));
+		pool.release(Ipv4Addr::new(100, 64, 0, 3));
+		assert_eq!(pool.used(), 3);
+		assert_eq!(pool.allocate(), Some(Ipv4Addr::new(100, 64, 0, 3)));
+		assert_eq!(pool.allocate(), Some(Ipv4Addr::new(100, 64, 0, 4)));
+		assert_eq!(pool.allocate(), None);
+
+		// Addresses never handed out are ignored
+		for addr in [Ipv4Addr::new(100, 64, 0, 0), Ipv4Addr::new(100, 64, 0, 1), Ipv4Addr::new(100, 64, 0, 7), Ipv4Addr::new(100, 65, 0, 2)] {
+			pool.release(addr);
+		}
+		assert_eq!(pool.used(), 5);
+		assert_eq!(pool.allocate(), None);
+	}
+
+	#[test]
+	fn address_pool_bounds() {
+		// Room for a single host between gateway and broadcast address
+		let mut pool = AddressPool::new(range(100, 64, 0, 0, 30));
+		assert_eq!(pool.capacity(), 1);
+		assert_eq!(pool.allocate(), Some(Ipv4Addr::new(100, 64, 0, 2)));
+		assert_eq!(pool.allocate(), None);
+		pool.release(Ipv4Addr::new(100, 64, 0, 2));
+		assert_eq!(pool.allocate(), Some(Ipv4Addr::new(100, 64, 0, 2)));
+
+		let mut pool = AddressPool::new(range(100, 64, 0, 1, 32));
+		assert_eq!(pool.capacity(), 0);
+		assert_eq!(pool.allocate(), None);
+		pool.release(Ipv4Addr::new(100, 64, 0, 3));
+		assert_eq!(pool.used(), 0);
+
+		assert_eq!(AddressPool::new(range(0, 0, 0, 0, 0)).capacity(), u32::MAX - 2);
+	}
+}
@@ -0,0 +1,65 @@
+//! Apps shipped with the simulation.
+//! Baseline overlays are hosted as [`DesApp`]s, so the deterministic backend and in-process devices run the same protocol code as baseline device processes.
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use device::{BaselineProtocol, DeviceCommand, DeviceEvent};
+use device::baseline::{Baseline, BaselineHost, kademlia::Kademlia, vivaldi::Vivaldi};
+use rand::{SeedableRng, rngs::SmallRng};
+
+use super::AppFactory;
+use super::des::{DesApp, DesContext};
+
+/// Timer the baseline's ticks run on
+const TICK_TIMER: u64 = 0;
+
+/// Baseline overlay running as an app, it asks the oracle for a peer to bootstrap off of once started and keeps asking until it names one.
+/// Apps always bootstrap this way, the deterministic backend has nothing else to bootstrap them.
+pub struct BaselineApp<B: Baseline> {
+	init: fn(&mut SmallRng) -> B,
+	/// Created once the app starts, from the machine's random generator
+	host: Option<BaselineHost<B>>,
+}
+impl<B: Baseline> BaselineApp<B> {
+	pub fn new(init: fn(&mut SmallRng) -> B) -> Self {
+		BaselineApp { init, host: None }
+	}
+	/// Run call on the host at the context's time and send everything the baseline sent
+	fn drive(&mut self, ctx: &mut DesContext, call: impl FnOnce(&mut BaselineHost<B>) -> Option<DeviceEvent>) {
+		let host = match &mut self.host { Some(host) => host, None => return };
+		host.set_now(ctx.now());
+		if let Some(event) = call(host) { ctx.emit(event); }
+		match host.outgoing() {
+			Ok(datagrams) => for (to, datagram) in datagrams { ctx.send(to, datagram); },
+			Err(err) => ctx.emit(DeviceEvent::Error(format!("{:?}", err))),
+		}
+	}
+}
+impl<B: Baseline> DesApp for BaselineApp<B> {
+	fn start(&mut self, ctx: &mut DesContext) {
+		self.host = Some(BaselineHost::new(self.init, SmallRng::from_rng(ctx.rng()).expect("SmallRng seeding is infallible")));
+		let ipv4 = match ctx.local_addr().ip() { IpAddr::V4(ip) => Some(ip), IpAddr::V6(_) => None };
+		self.drive(ctx, |host|host.command(DeviceCommand::AddressChanged { ipv4, ipv6: None }));
+		self.drive(ctx, |host|Some(host.bootstrap_from_oracle()));
+		ctx.set_timer(B::TICK, TICK_TIMER);
+	}
+	fn receive(&mut self, ctx: &mut DesContext, from: SocketAddr, data: Vec<u8>) {
+		self.drive(ctx, |host|{ host.receive(from, &data); None });
+	}
+	fn timer(&mut self, ctx: &mut DesContext, _timer: u64) {
+		self.drive(ctx, |host|host.tick());
+		ctx.set_timer(B::TICK, TICK_TIMER);
+	}
+	fn command(&mut self, ctx: &mut DesContext, command: DeviceCommand) {
+		self.drive(ctx, |host|host.command(command));
+	}
+}
+
+/// App factory running a baseline on every machine
+pub fn baseline_apps(protocol: BaselineProtocol) -> AppFactory {
+	match protocol {
+		BaselineProtocol::Kademlia => Arc::new(|_|Box::new(BaselineApp::new(Kademlia::new)) as Box<dyn DesApp>),
+		BaselineProtocol::Vivaldi => Arc::new(|_|Box::new(BaselineApp::new(Vivaldi::new)) as Box<dyn DesApp>),
+	}
+}
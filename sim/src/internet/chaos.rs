@@ -0,0 +1,329 @@
+//! Chaos engine, randomly fails and restores wires and crashes and restarts machines.
+//! Faults are drawn every tick from a seeded generator, so the same seed applied to the same topology injects the same faults.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::time::Duration;
+
+use async_std::task;
+use futures::{SinkExt, channel::mpsc, future::{self, AbortHandle}};
+use rand::{Rng, SeedableRng, rngs::SmallRng};
+
+use super::{Internet, InternetAction, InternetError, InternetEvent, InternetRuntime, NodeIdx, SuspendReason, WireIdx};
+use super::clock::SimClock;
+
+/// Kinds of wires that can be given different failure rates
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum LinkClass {
+	/// Wire between two networks
+	Uplink,
+	/// Wire between a network and a machine
+	Access,
+}
+
+/// Failure rates of a link class, failures and repairs are memoryless (exponentially distributed)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FailureRates {
+	/// Mean time between failures
+	pub mtbf: Duration,
+	/// Mean time to repair
+	pub mttr: Duration,
+}
+impl FailureRates {
+	/// Probability of an event with a given mean time happening within a tick
+	fn probability(mean: Duration, tick: Duration) -> f64 {
+		if mean.is_zero() { 1.0 } else { 1.0 - (-tick.as_secs_f64() / mean.as_secs_f64()).exp() }
+	}
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChaosConfig {
+	pub seed: u64,
+	/// Time between fault draws
+	pub tick: Duration,
+	/// Failure rates of each link class, classes without rates never fail
+	pub links: HashMap<LinkClass, FailureRates>,
+	/// Probability of each running machine crashing within a tick
+	pub crash_probability: f64,
+	/// Probabilities of single machines crashing within a tick, overriding `crash_probability`
+	pub machine_crash_probability: HashMap<NodeIdx, f64>,
+	/// Time until a crashed machine is restarted
+	pub restart_delay: Duration,
+}
+impl Default for ChaosConfig {
+	fn default() -> Self {
+		ChaosConfig {
+			seed: 0,
+			tick: Duration::from_secs(1),
+			links: HashMap::new(),
+			crash_probability: 0.0,
+			machine_crash_probability: HashMap::new(),
+			restart_delay: Duration::from_secs(10),
+		}
+	}
+}
+
+#[derive(Debug, Error)]
+pub enum ChaosError {
+	#[error("chaos tick must be longer than zero")]
+	ZeroTick,
+	#[error("crash probability {0} must be between 0 and 1")]
+	InvalidCrashProbability(f64),
+	#[error("crash probability {probability} of machine {machine} must be between 0 and 1")]
+	InvalidMachineCrashProbability { machine: NodeIdx, probability: f64 },
+}
+
+impl ChaosConfig {
+	/// Reject configs whose ticker would spin without ever sleeping or whose faults can't be drawn
+	pub fn validate(&self) -> Result<(), ChaosError> {
+		if self.tick.is_zero() { return Err(ChaosError::ZeroTick) }
+		if !(0.0..=1.0).contains(&self.crash_probability) { return Err(ChaosError::InvalidCrashProbability(self.crash_probability)) }
+		for (&machine, &probability) in &self.machine_crash_probability {
+			if !(0.0..=1.0).contains(&probability) { return Err(ChaosError::InvalidMachineCrashProbability { machine, probability }) }
+		}
+		Ok(())
+	}
+	fn crash_probability(&self, machine_idx: NodeIdx) -> f64 {
+		self.machine_crash_probability.get(&machine_idx).copied().unwrap_or(self.crash_probability)
+	}
+}
+
+/// Fault injected or lifted by the chaos engine
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Fault {
+	LinkFailed(WireIdx, LinkClass),
+	LinkRestored(WireIdx, LinkClass),
+	MachineCrashed(NodeIdx),
+	MachineRestarted(NodeIdx),
+}
+
+/// Running chaos engine
+pub(crate) struct Chaos {
+	faults: FaultDraws,
+	ticker: AbortHandle,
+}
+impl Chaos {
+	fn new(config: ChaosConfig, action_sender: mpsc::Sender<InternetAction>, clock: SimClock) -> Self {
+		let ticker = spawn_ticker(config.tick, action_sender, clock);
+		Chaos { faults: FaultDraws::new(config), ticker }
+	}
+}
+
+/// Faults drawn so far, kept apart from the ticker so the same draws can be replayed without a running Internet
+struct FaultDraws {
+	config: ChaosConfig,
+	rng: SmallRng,
+	/// Wires currently failed by the engine
+	failed: BTreeSet<WireIdx>,
+	/// Crashed machines and the ticks left until they restart
+	crashed: BTreeMap<NodeIdx, u32>,
+}
+impl FaultDraws {
+	fn new(config: ChaosConfig) -> Self {
+		FaultDraws { rng: SmallRng::seed_from_u64(config.seed), config, failed: BTreeSet::new(), crashed: BTreeMap::new() }
+	}
+	fn restart_ticks(&self) -> u32 {
+		(self.config.restart_delay.as_secs_f64() / self.config.tick.as_secs_f64()).ceil() as u32
+	}
+	/// Draw faults of a tick from the existing wires and machines (along with whether they are running), in the order they are applied.
+	/// Wires and machines that no longer exist are forgotten.
+	fn draw(&mut self, wires: &[(WireIdx, LinkClass)], machines: &[(NodeIdx, bool)]) -> Vec<Fault> {
+		let tick = self.config.tick;
+		let mut faults = Vec::new();
+
+		// Restart machines whose restart delay is over
+		self.crashed.retain(|machine_idx, _|machines.iter().any(|(idx, _)|idx == machine_idx));
+		for (&machine_idx, ticks) in self.crashed.iter_mut() {
+			*ticks = ticks.saturating_sub(1);
+			if *ticks == 0 { faults.push(Fault::MachineRestarted(machine_idx)); }
+		}
+		for fault in &faults {
+			if let Fault::MachineRestarted(machine_idx) = fault { self.crashed.remove(machine_idx); }
+		}
+
+		// Fail or restore wires
+		self.failed.retain(|wire_idx|wires.iter().any(|(idx, _)|idx == wire_idx));
+		for &(wire_idx, class) in wires {
+			let rates = match self.config.links.get(&class) { Some(rates) => rates, None => continue };
+			if self.failed.contains(&wire_idx) {
+				if self.rng.gen_bool(FailureRates::probability(rates.mttr, tick)) {
+					self.failed.remove(&wire_idx);
+					faults.push(Fault::LinkRestored(wire_idx, class));
+				}
+			} else if self.rng.gen_bool(FailureRates::probability(rates.mtbf, tick)) {
+				self.failed.insert(wire_idx);
+				faults.push(Fault::LinkFailed(wire_idx, class));
+			}
+		}
+
+		// Crash machines, including those restarted this tick
+		let restart_ticks = self.restart_ticks().max(1);
+		for &(machine_idx, running) in machines {
+			let running = running || faults.contains(&Fault::MachineRestarted(machine_idx));
+			let probability = self.config.crash_probability(machine_idx);
+			if running && probability > 0.0 && self.rng.gen_bool(probability) {
+				self.crashed.insert(machine_idx, restart_ticks);
+				faults.push(Fault::MachineCrashed(machine_idx));
+			}
+		}
+		faults
+	}
+}
+
+/// Spawn task sending [`InternetAction::ChaosTick`]s
+fn spawn_ticker(tick: Duration, mut action_sender: mpsc::Sender<InternetAction>, clock: SimClock) -> AbortHandle {
+	let (task, abort_handle) = future::abortable(async move {
+		loop {
+			clock.sleep(tick).await;
+			if action_sender.send(InternetAction::ChaosTick).await.is_err() { break }
+		}
+	});
+	task::spawn(task);
+	abort_handle
+}
+
+impl Internet {
+	fn link_class(&self, wire_idx: WireIdx) -> Result<LinkClass, InternetError> {
+		let (node1, node2) = self.wire(wire_idx)?.nodes();
+		Ok(if self.node(node1)?.network().is_some() && self.node(node2)?.network().is_some() { LinkClass::Uplink } else { LinkClass::Access })
+	}
+	/// Start chaos engine with a config or stop it (None). Stopping restores every failed wire and restarts every crashed machine.
+	pub(crate) async fn set_chaos(&mut self, runtime: &mut InternetRuntime, config: Option<ChaosConfig>) -> Result<(), InternetError> {
+		if let Some(chaos) = runtime.chaos.take() {
+			chaos.ticker.abort();
+			for wire_idx in chaos.faults.failed {
+				if self.wires.contains_key(wire_idx) { self.restore_link(runtime, wire_idx).await?; }
+			}
+			for (machine_idx, _) in chaos.faults.crashed {
+				if self.machine(machine_idx).is_ok() { self.restart_machine(runtime, machine_idx)?; }
+			}
+		}
+		if let Some(config) = config {
+			runtime.chaos = Some(Chaos::new(config, runtime.action_sender.clone(), runtime.clock.clone()));
+		}
+		Ok(())
+	}
+	/// Draw faults for a tick of the chaos engine
+	pub(crate) async fn chaos_tick(&mut self, runtime: &mut InternetRuntime) -> Result<(), InternetError> {
+		let mut chaos = match runtime.chaos.take() { Some(chaos) => chaos, None => return Ok(()) };
+		let result = self.draw_faults(runtime, &mut chaos).await;
+		runtime.chaos = Some(chaos);
+		result
+	}
+	async fn draw_faults(&mut self, runtime: &mut InternetRuntime, chaos: &mut Chaos) -> Result<(), InternetError> {
+		let wires = self.wires.keys().map(|wire_idx|Ok((wire_idx, self.link_class(wire_idx)?))).collect::<Result<Vec<_>, InternetError>>()?;
+		let machines: Vec<(NodeIdx, bool)> = self.nodes.iter()
+			.filter_map(|(idx, node)|node.machine().map(|machine|(idx, machine.powered && machine.is_running()))).collect();
+		for fault in chaos.faults.draw(&wires, &machines) {
+			match fault {
+				Fault::MachineRestarted(machine_idx) => self.restart_machine(runtime, machine_idx)?,
+				Fault::LinkRestored(wire_idx, _) => self.restore_link(runtime, wire_idx).await?,
+				Fault::LinkFailed(wire_idx, _) => {
+					self.set_wire_suspended(runtime, wire_idx, SuspendReason::Chaos, true).await?;
+					runtime.send_event(InternetEvent::Fault(runtime.clock.now(), fault))?;
+				}
+				Fault::MachineCrashed(machine_idx) => {
+					self.machine_mut(machine_idx)?.crash()?;
+					runtime.send_event(InternetEvent::Fault(runtime.clock.now(), fault))?;
+				}
+			}
+		}
+		Ok(())
+	}
+	async fn restore_link(&mut self, runtime: &mut InternetRuntime, wire_idx: WireIdx) -> Result<(), InternetError> {
+		let class = self.link_class(wire_idx)?;
+		self.set_wire_suspended(runtime, wire_idx, SuspendReason::Chaos, false).await?;
+		runtime.send_event(InternetEvent::Fault(runtime.clock.now(), Fault::LinkRestored(wire_idx, class)))
+	}
+	/// Start crashed machine's device again, it needs its IPv6 configuration again as it starts from scratch
+	fn restart_machine(&mut self, runtime: &mut InternetRuntime, machine_idx: NodeIdx) -> Result<(), InternetError> {
+		self.machine_mut(machine_idx)?.restart(runtime.action_sender.clone())?;
+		self.configure_machine_ipv6(self.machine(machine_idx)?)?;
+		runtime.send_event(InternetEvent::Fault(runtime.clock.now(), Fault::MachineRestarted(machine_idx)))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use slotmap::SlotMap;
+
+	use super::*;
+
+	/// Draw faults for a number of ticks, machines stop running while crashed
+	fn draw_ticks(config: ChaosConfig, wires: &[(WireIdx, LinkClass)], machines: &[NodeIdx], ticks: usize) -> Vec<Vec<Fault>> {
+		let mut faults = FaultDraws::new(config);
+		let mut running: BTreeMap<NodeIdx, bool> = machines.iter().map(|&idx|(idx, true)).collect();
+		(0..ticks).map(|_|{
+			let drawn = faults.draw(wires, &running.iter().map(|(&idx, &running)|(idx, running)).collect::<Vec<_>>());
+			for fault in &drawn {
+				match fault {
+					Fault::MachineCrashed(idx) => { running.insert(*idx, false); }
+					Fault::MachineRestarted(idx) => { running.insert(*idx, true); }
+					_ => {}
+				}
+			}
+			drawn
+		}).collect()
+	}
+
+	#[test]
+	fn probability() {
+		let tick = Duration::from_secs(1);
+		assert_eq!(FailureRates::probability(Duration::ZERO, tick), 1.0);
+		let probability = FailureRates::probability(Duration::from_secs(10), tick);
+		assert!((probability - (1.0 - (-0.1f64).exp())).abs() < 1e-12);
+	}
+
+	#[test]
+	fn same_seed_same_faults() {
+		let mut wire_keys = SlotMap::<WireIdx, ()>::with_key();
+		let mut node_keys = SlotMap::<NodeIdx, ()>::with_key();
+		let wires: Vec<_> = (0..8).map(|i|(wire_keys.insert(()), if i % 2 == 0 { LinkClass::Uplink } else { LinkClass::Access })).collect();
+		let machines: Vec<_> = (0..8).map(|_|node_keys.insert(())).collect();
+		let config = ChaosConfig {
+			seed: 7,
+			links: HashMap::from([
+				(LinkClass::Uplink, FailureRates { mtbf: Duration::from_secs(20), mttr: Duration::from_secs(5) }),
+				(LinkClass::Access, FailureRates { mtbf: Duration::from_secs(10), mttr: Duration::from_secs(2) }),
+			]),
+			crash_probability: 0.05,
+			restart_delay: Duration::from_secs(3),
+			..Default::default()
+		};
+		let faults = draw_ticks(config.clone(), &wires, &machines, 200);
+		assert!(faults.iter().flatten().any(|fault|matches!(fault, Fault::LinkFailed(..))));
+		assert!(faults.iter().flatten().any(|fault|matches!(fault, Fault::MachineRestarted(_))));
+		assert_eq!(faults, draw_ticks(config.clone(), &wires, &machines, 200));
+		assert_ne!(faults, draw_ticks(ChaosConfig { seed: 8, ..config }, &wires, &machines, 200));
+	}
+
+	#[test]
+	fn crashed_machine_restarts() {
+		let mut node_keys = SlotMap::<NodeIdx, ()>::with_key();
+		let (machine, other) = (node_keys.insert(()), node_keys.insert(()));
+		let config = ChaosConfig {
+			restart_delay: Duration::from_secs(3),
+			machine_crash_probability: HashMap::from([(machine, 1.0)]),
+			..Default::default()
+		};
+		config.validate().unwrap();
+		let mut faults = FaultDraws::new(config);
+		assert_eq!(faults.restart_ticks(), 3);
+
+		// Only the machine with an override crashes
+		assert_eq!(faults.draw(&[], &[(machine, true), (other, true)]), vec![Fault::MachineCrashed(machine)]);
+		faults.config.machine_crash_probability.clear();
+		for _ in 1..faults.restart_ticks() {
+			assert_eq!(faults.draw(&[], &[(machine, false), (other, true)]), vec![]);
+		}
+		assert_eq!(faults.draw(&[], &[(machine, false), (other, true)]), vec![Fault::MachineRestarted(machine)]);
+		assert!(faults.crashed.is_empty());
+	}
+
+	#[test]
+	fn invalid_machine_crash_probability() {
+		let mut node_keys = SlotMap::<NodeIdx, ()>::with_key();
+		let machine = node_keys.insert(());
+		let config = ChaosConfig { machine_crash_probability: HashMap::from([(machine, 1.5)]), ..Default::default() };
+		assert!(matches!(config.validate(), Err(ChaosError::InvalidMachineCrashProbability { probability, .. }) if probability == 1.5));
+	}
+}
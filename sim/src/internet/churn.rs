@@ -0,0 +1,256 @@
+//! Machine churn, machines join the Internet and leave it again after their session ends.
+//! Arrival times are drawn by a background task, everything else (session lengths, networks and bootstrap peers) is drawn by the simulation from the same seed.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use async_std::task;
+use device::{DeviceCommand, DitherCommand};
+use futures::{SinkExt, channel::mpsc, future::{self, AbortHandle}};
+use rand::{Rng, SeedableRng, rngs::SmallRng, seq::IteratorRandom};
+
+use super::{Internet, InternetAction, InternetError, InternetEvent, InternetRuntime, NodeID, NodeIdx, DEVICE_PORT};
+use super::clock::SimClock;
+
+/// Probability distribution of a duration, sampled by inverse transform
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Distribution {
+	Exponential { mean: Duration },
+	/// Heavy-tailed, most samples are close to `scale` but some are much longer
+	Pareto { scale: Duration, shape: f64 },
+	Weibull { scale: Duration, shape: f64 },
+}
+
+#[derive(Debug, Error)]
+pub enum ChurnError {
+	#[error("distribution mean or scale must be longer than zero")]
+	ZeroScale,
+	#[error("distribution shape {0} must be a positive number")]
+	InvalidShape(f64),
+}
+
+impl Distribution {
+	/// Reject distributions that only yield zero durations or can't be sampled
+	pub fn validate(&self) -> Result<(), ChurnError> {
+		match *self {
+			Distribution::Exponential { mean } if mean.is_zero() => Err(ChurnError::ZeroScale),
+			Distribution::Pareto { scale, .. } | Distribution::Weibull { scale, .. } if scale.is_zero() => Err(ChurnError::ZeroScale),
+			Distribution::Pareto { shape, .. } | Distribution::Weibull { shape, .. } if !(shape.is_finite() && shape > 0.0) => Err(ChurnError::InvalidShape(shape)),
+			_ => Ok(()),
+		}
+	}
+	pub fn sample(&self, rng: &mut impl Rng) -> Duration {
+		// Uniform in (0, 1] so the logarithm stays finite
+		let u = 1.0 - rng.gen::<f64>();
+		let secs = match *self {
+			Distribution::Exponential { mean } => -mean.as_secs_f64() * u.ln(),
+			Distribution::Pareto { scale, shape } => scale.as_secs_f64() / u.powf(1.0 / shape),
+			Distribution::Weibull { scale, shape } => scale.as_secs_f64() * (-u.ln()).powf(1.0 / shape),
+		};
+		Duration::try_from_secs_f64(secs).unwrap_or(Duration::MAX)
+	}
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChurnConfig {
+	pub seed: u64,
+	/// Time between two machines joining
+	pub inter_arrival: Distribution,
+	/// Time a machine stays in the Internet
+	pub session_length: Distribution,
+	/// No machines join while this many machines exist
+	pub max_population: Option<usize>,
+}
+
+/// Number of machines in the Internet at a point in time
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PopulationSample {
+	/// Virtual time since churn started
+	pub time: Duration,
+	pub machines: usize,
+	/// Machines joined and left through churn so far
+	pub joined: u64,
+	pub left: u64,
+}
+
+/// Running churn driver
+pub(crate) struct Churn {
+	config: ChurnConfig,
+	rng: SmallRng,
+	/// Virtual time churn started at
+	start: Duration,
+	/// Machines spawned by churn whose session hasn't ended yet
+	sessions: BTreeSet<NodeIdx>,
+	/// Machines whose device reported info, along with the Dither node ID needed to bootstrap off of them (baselines have none)
+	peers: BTreeMap<NodeIdx, Option<NodeID>>,
+	joined: u64,
+	left: u64,
+	arrivals: AbortHandle,
+}
+
+/// Spawn task sending an [`InternetAction::ChurnArrival`] after every inter-arrival time
+fn spawn_arrivals(inter_arrival: Distribution, seed: u64, mut action_sender: mpsc::Sender<InternetAction>, clock: SimClock) -> AbortHandle {
+	let (task, abort_handle) = future::abortable(async move {
+		let mut rng = SmallRng::seed_from_u64(seed);
+		loop {
+			clock.sleep(inter_arrival.sample(&mut rng)).await;
+			if action_sender.send(InternetAction::ChurnArrival).await.is_err() { break }
+		}
+	});
+	task::spawn(task);
+	abort_handle
+}
+
+/// Bootstrap off of a peer through Dither if its node ID is known, otherwise through the device (e.g. baselines)
+fn bootstrap_command(node_id: Option<NodeID>, addr: SocketAddr) -> DeviceCommand {
+	match node_id {
+		Some(node_id) => DeviceCommand::DitherCommand(DitherCommand::Bootstrap(node_id, addr)),
+		None => DeviceCommand::Bootstrap(addr),
+	}
+}
+
+impl Internet {
+	/// Start churn with a config or stop it (None), machines spawned by a stopped churn driver stay
+	pub(crate) fn set_churn(&mut self, runtime: &mut InternetRuntime, config: Option<ChurnConfig>) -> Result<(), InternetError> {
+		if let Some(config) = &config {
+			config.inter_arrival.validate()?;
+			config.session_length.validate()?;
+		}
+		if let Some(churn) = runtime.churn.take() { churn.arrivals.abort(); }
+		if let Some(config) = config {
+			let arrivals = spawn_arrivals(config.inter_arrival.clone(), config.seed, runtime.action_sender.clone(), runtime.clock.clone());
+			runtime.churn = Some(Churn {
+				rng: SmallRng::seed_from_u64(config.seed.wrapping_add(1)), config,
+				start: runtime.clock.now(), sessions: BTreeSet::new(), peers: BTreeMap::new(), joined: 0, left: 0, arrivals,
+			});
+			// Learn which machines that are already running can be bootstrapped off of
+			for node in self.nodes.values() {
+				if let Some(machine) = node.machine() { if machine.is_running() { machine.request_machine_info()?; } }
+			}
+			self.population_changed(runtime)?;
+		}
+		Ok(())
+	}
+	/// Remember machine whose device reported info as peer for bootstrapping, with its node ID if it runs Dither
+	pub(crate) fn churn_peer(&self, runtime: &mut InternetRuntime, machine_idx: NodeIdx, node_id: Option<&NodeID>) {
+		if let Some(churn) = &mut runtime.churn { churn.peers.insert(machine_idx, node_id.cloned()); }
+	}
+	/// Spawn machine on a random network and bootstrap it off of a random live peer
+	pub(crate) async fn churn_arrival(&mut self, runtime: &mut InternetRuntime) -> Result<(), InternetError> {
+		let mut churn = match runtime.churn.take() { Some(churn) => churn, None => return Ok(()) };
+		let result = self.join_machine(runtime, &mut churn).await;
+		runtime.churn = Some(churn);
+		result?;
+		self.population_changed(runtime)
+	}
+	async fn join_machine(&mut self, runtime: &mut InternetRuntime, churn: &mut Churn) -> Result<(), InternetError> {
+		let population = self.nodes.values().filter(|node|node.machine().is_some()).count();
+		if churn.config.max_population.map_or(false, |max|population >= max) { return Ok(()) }
+		let network_idx = match self.nodes.iter().filter(|(_, node)|node.network().is_some()).map(|(idx, _)|idx).choose(&mut churn.rng) {
+			Some(network_idx) => network_idx,
+			None => { log::warn!("Churn: no network for new machine to join"); return Ok(()) }
+		};
+
+		// Pick peer before spawning so the new machine can't pick itself
+		let peer = churn.peers.iter().filter_map(|(&idx, node_id)|{
+			let machine = self.machine(idx).ok().filter(|machine|machine.is_running())?;
+			let ip = machine.connection_ip().map(IpAddr::from).or(machine.connection_ip6().map(IpAddr::from))?;
+			Some((idx, node_id.clone(), SocketAddr::new(ip, DEVICE_PORT)))
+		}).choose(&mut churn.rng);
+
+		let position = self.node(network_idx)?.position;
+		let machine_idx = self.spawn_machine(runtime, position, self.default_device())?;
+		runtime.send_event(InternetEvent::NewMachine(machine_idx))?;
+		let wire_idx = self.connect(runtime, network_idx, machine_idx).await?;
+		runtime.send_event(InternetEvent::ConnectionInfo(wire_idx, network_idx, machine_idx))?;
+		runtime.send_event(InternetEvent::NodeInfo(machine_idx, self.node(machine_idx)?.node_info()))?;
+
+		let machine = self.machine(machine_idx)?;
+		if let Some((peer_idx, node_id, addr)) = peer {
+			log::debug!("Churn: machine {} joins network {}, bootstrapping off of {}", machine_idx, network_idx, peer_idx);
+			machine.device_command(bootstrap_command(node_id, addr))?;
+		}
+		machine.request_machine_info()?;
+
+		let session = churn.config.session_length.sample(&mut churn.rng);
+		churn.sessions.insert(machine_idx);
+		churn.joined += 1;
+		runtime.schedule(session, InternetAction::ChurnDeparture(machine_idx));
+		Ok(())
+	}
+	/// End session of a machine spawned by churn
+	pub(crate) fn churn_departure(&mut self, runtime: &mut InternetRuntime, machine_idx: NodeIdx) -> Result<(), InternetError> {
+		// Churn may have been stopped or the machine removed by hand in the meantime
+		let churn = match &mut runtime.churn { Some(churn) => churn, None => return Ok(()) };
+		if !churn.sessions.remove(&machine_idx) { return Ok(()) }
+		churn.peers.remove(&machine_idx);
+		churn.left += 1;
+		if self.machine(machine_idx).is_ok() { self.remove_node(runtime, machine_idx)?; }
+		self.population_changed(runtime)
+	}
+	fn population_changed(&self, runtime: &mut InternetRuntime) -> Result<(), InternetError> {
+		let churn = match &runtime.churn { Some(churn) => churn, None => return Ok(()) };
+		let sample = PopulationSample {
+			time: runtime.clock.now() - churn.start,
+			machines: self.nodes.values().filter(|node|node.machine().is_some()).count(),
+			joined: churn.joined,
+			left: churn.left,
+		};
+		runtime.send_event(InternetEvent::Population(runtime.clock.now(), sample))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::net::Ipv4Addr;
+
+	use super::*;
+
+	/// Samples of a distribution, all of them must be finite
+	fn samples(distribution: &Distribution) -> Vec<f64> {
+		distribution.validate().unwrap();
+		let mut rng = SmallRng::seed_from_u64(3);
+		(0..10_000).map(|_|distribution.sample(&mut rng)).map(|sample|{
+			assert!(sample < Duration::MAX, "{:?} sampled an infinite duration", distribution);
+			sample.as_secs_f64()
+		}).collect()
+	}
+
+	#[test]
+	fn exponential_support() {
+		let samples = samples(&Distribution::Exponential { mean: Duration::from_secs(10) });
+		let mean = samples.iter().sum::<f64>() / samples.len() as f64;
+		assert!((mean - 10.0).abs() < 0.5, "mean {}", mean);
+	}
+
+	#[test]
+	fn pareto_support() {
+		let samples = samples(&Distribution::Pareto { scale: Duration::from_secs(5), shape: 1.5 });
+		// Never shorter than the scale, but sometimes much longer
+		assert!(samples.iter().all(|&sample|sample >= 5.0));
+		assert!(samples.iter().any(|&sample|sample > 50.0));
+	}
+
+	#[test]
+	fn weibull_support() {
+		let samples = samples(&Distribution::Weibull { scale: Duration::from_secs(5), shape: 0.7 });
+		assert!(samples.iter().all(|&sample|sample >= 0.0));
+		let median = { let mut sorted = samples.clone(); sorted.sort_by(f64::total_cmp); sorted[sorted.len() / 2] };
+		// Median of a Weibull distribution is scale * ln(2)^(1/shape)
+		assert!((median - 5.0 * 2f64.ln().powf(1.0 / 0.7)).abs() < 0.3, "median {}", median);
+	}
+
+	#[test]
+	fn invalid_distributions() {
+		assert!(matches!(Distribution::Exponential { mean: Duration::ZERO }.validate(), Err(ChurnError::ZeroScale)));
+		assert!(matches!(Distribution::Pareto { scale: Duration::from_secs(1), shape: 0.0 }.validate(), Err(ChurnError::InvalidShape(_))));
+		assert!(matches!(Distribution::Weibull { scale: Duration::from_secs(1), shape: f64::NAN }.validate(), Err(ChurnError::InvalidShape(_))));
+	}
+
+	#[test]
+	fn bootstrap_without_node_id() {
+		let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(100, 64, 0, 2)), DEVICE_PORT);
+		assert!(matches!(bootstrap_command(None, addr), DeviceCommand::Bootstrap(peer) if peer == addr));
+	}
+}
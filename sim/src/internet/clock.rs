@@ -0,0 +1,221 @@
+//! Simulation clock.
+//! Virtual time runs at an adjustable multiple of wall-clock time. All delays and timers in the simulation are measured in virtual time,
+//! so changing the scale speeds up or slows down the whole simulation, including packets already in flight.
+//! Pausing the clock stops virtual time altogether, timers keep their remaining delay until it is resumed.
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_std::task;
+use futures::{FutureExt, channel::oneshot, future, select};
+
+/// Slowest supported time scale
+pub const MIN_TIME_SCALE: f64 = 0.1;
+/// Fastest supported time scale
+pub const MAX_TIME_SCALE: f64 = 10.0;
+
+#[derive(Debug)]
+struct ClockState {
+	scale: f64,
+	paused: bool,
+	/// Virtual time at the moment the scale last changed or the clock was paused or resumed
+	base_virtual: Duration,
+	base_real: Instant,
+	/// Sleepers to wake up when the scale changes or the clock is paused or resumed
+	subscribers: Vec<oneshot::Sender<()>>,
+}
+
+/// Shared handle to the simulation clock
+#[derive(Debug, Clone)]
+pub struct SimClock {
+	state: Arc<Mutex<ClockState>>,
+}
+impl SimClock {
+	pub fn new(scale: f64) -> Self {
+		SimClock { state: Arc::new(Mutex::new(ClockState {
+			scale: scale.clamp(MIN_TIME_SCALE, MAX_TIME_SCALE),
+			paused: false,
+			base_virtual: Duration::ZERO,
+			base_real: Instant::now(),
+			subscribers: Vec::new(),
+		})) }
+	}
+	fn state(&self) -> std::sync::MutexGuard<'_, ClockState> {
+		self.state.lock().expect("clock mutex should not be poisoned")
+	}
+	/// Virtual time since the clock was created, excluding time spent paused
+	pub fn now(&self) -> Duration {
+		let state = self.state();
+		if state.paused { state.base_virtual } else { state.base_virtual + state.base_real.elapsed().mul_f64(state.scale) }
+	}
+	pub fn scale(&self) -> f64 { self.state().scale }
+	pub fn is_paused(&self) -> bool { self.state().paused }
+	/// Change how fast virtual time runs, sleeping timers are rescheduled
+	pub fn set_scale(&self, scale: f64) {
+		self.update(|state|state.scale = scale.clamp(MIN_TIME_SCALE, MAX_TIME_SCALE));
+	}
+	/// Stop or restart virtual time, sleeping timers wait until the clock is resumed
+	pub fn set_paused(&self, paused: bool) {
+		self.update(|state|state.paused = paused);
+	}
+	/// Jump ahead in virtual time, lets tests run into timeouts without waiting for them
+	#[cfg(test)]
+	pub(crate) fn advance(&self, duration: Duration) {
+		self.update(|state|state.base_virtual += duration);
+	}
+	/// Rebase clock at the current virtual time, apply a change and wake up all sleepers
+	fn update(&self, change: impl FnOnce(&mut ClockState)) {
+		let now = self.now();
+		let mut state = self.state();
+		state.base_virtual = now;
+		state.base_real = Instant::now();
+		change(&mut state);
+		for subscriber in state.subscribers.drain(..) { let _ = subscriber.send(()); }
+	}
+	/// Receiver completing on the next scale change, pause or resume
+	fn subscribe(&self) -> oneshot::Receiver<()> {
+		let (sender, receiver) = oneshot::channel();
+		let mut state = self.state();
+		state.subscribers.retain(|subscriber|!subscriber.is_canceled());
+		state.subscribers.push(sender);
+		receiver
+	}
+	/// Sleep until a point in virtual time
+	pub async fn sleep_until(&self, deadline: Duration) {
+		loop {
+			let now = self.now();
+			if now >= deadline { return }
+			let changed = self.subscribe();
+			let (scale, paused) = { let state = self.state(); (state.scale, state.paused) };
+			if paused { let _ = changed.await; continue }
+			let real = (deadline - now).div_f64(scale);
+			select! {
+				_ = task::sleep(real).fuse() => {},
+				_ = changed.fuse() => {},
+			}
+		}
+	}
+	/// Sleep for a duration of virtual time
+	pub async fn sleep(&self, duration: Duration) {
+		self.sleep_until(self.now() + duration).await
+	}
+}
+
+/// Item scheduled for a point in virtual time
+struct Delayed<T> {
+	deadline: Duration,
+	/// Insertion order, keeps items with equal deadlines in order
+	seq: u64,
+	item: T,
+}
+impl<T> PartialEq for Delayed<T> { fn eq(&self, other: &Self) -> bool { (self.deadline, self.seq) == (other.deadline, other.seq) } }
+impl<T> Eq for Delayed<T> {}
+impl<T> PartialOrd for Delayed<T> { fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> { Some(self.cmp(other)) } }
+impl<T> Ord for Delayed<T> { fn cmp(&self, other: &Self) -> std::cmp::Ordering { (self.deadline, self.seq).cmp(&(other.deadline, other.seq)) } }
+
+/// Queue releasing items after a delay in virtual time
+pub struct DelayQueue<T> {
+	clock: SimClock,
+	queue: BinaryHeap<Reverse<Delayed<T>>>,
+	next_seq: u64,
+}
+impl<T> DelayQueue<T> {
+	pub fn new(clock: SimClock) -> Self {
+		DelayQueue { clock, queue: BinaryHeap::new(), next_seq: 0 }
+	}
+	pub fn insert(&mut self, item: T, delay: Duration) {
+		let deadline = self.clock.now() + delay;
+		self.queue.push(Reverse(Delayed { deadline, seq: self.next_seq, item }));
+		self.next_seq += 1;
+	}
+	pub fn is_empty(&self) -> bool { self.queue.is_empty() }
+	/// When the next item becomes due
+	pub fn next_deadline(&self) -> Option<Duration> {
+		self.queue.peek().map(|Reverse(delayed)|delayed.deadline)
+	}
+	/// Take next item if it is due
+	pub fn pop_due(&mut self) -> Option<T> {
+		match self.queue.peek() {
+			Some(Reverse(delayed)) if delayed.deadline <= self.clock.now() => self.queue.pop().map(|Reverse(delayed)|delayed.item),
+			_ => None,
+		}
+	}
+}
+
+/// Sleep until the earliest of some deadlines, never completes if there are none
+pub async fn sleep_until_any(clock: SimClock, deadlines: impl IntoIterator<Item = Option<Duration>>) {
+	match deadlines.into_iter().flatten().min() {
+		Some(deadline) => clock.sleep_until(deadline).await,
+		None => future::pending().await,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use async_std::future::timeout;
+
+	use super::*;
+
+	/// Real time spent paused in each test, long enough that counting it by mistake would show
+	const PAUSE: Duration = Duration::from_millis(100);
+
+	#[test]
+	fn pause_stops_virtual_time() {
+		let clock = SimClock::new(1.0);
+		clock.set_paused(true);
+		let paused_at = clock.now();
+		std::thread::sleep(PAUSE);
+		assert_eq!(clock.now(), paused_at);
+
+		// Resumes from the time it was paused at
+		clock.set_paused(false);
+		let resumed_at = clock.now();
+		assert!(resumed_at >= paused_at && resumed_at < paused_at + PAUSE);
+		std::thread::sleep(Duration::from_millis(10));
+		assert!(clock.now() > resumed_at);
+	}
+
+	#[test]
+	fn pause_holds_sleepers() {
+		task::block_on(async {
+			let clock = SimClock::new(1.0);
+			let deadline = clock.now() + Duration::from_millis(20);
+			clock.set_paused(true);
+			let mut sleeper = Box::pin(clock.sleep_until(deadline));
+			assert!(timeout(PAUSE, &mut sleeper).await.is_err());
+
+			clock.set_paused(false);
+			timeout(Duration::from_secs(5), sleeper).await.expect("sleeper should wake up once resumed");
+			// Woke up at its deadline in virtual time, not a pause's worth of real time late
+			assert!(clock.now() >= deadline && clock.now() < deadline + PAUSE);
+		});
+	}
+
+	#[test]
+	fn pause_holds_delay_queue() {
+		task::block_on(async {
+			let clock = SimClock::new(1.0);
+			let mut queue = DelayQueue::new(clock.clone());
+			clock.set_paused(true);
+			let paused_at = clock.now();
+			queue.insert("second", Duration::from_millis(20));
+			queue.insert("first", Duration::from_millis(10));
+			assert_eq!(queue.next_deadline(), Some(paused_at + Duration::from_millis(10)));
+
+			let due = sleep_until_any(clock.clone(), [queue.next_deadline()]);
+			assert!(timeout(PAUSE, due).await.is_err());
+			assert_eq!(queue.pop_due(), None);
+
+			clock.set_paused(false);
+			let mut released = Vec::new();
+			while !queue.is_empty() {
+				timeout(Duration::from_secs(5), sleep_until_any(clock.clone(), [queue.next_deadline()])).await.expect("queue should release items once resumed");
+				released.extend(queue.pop_due());
+			}
+			assert_eq!(released, vec!["first", "second"]);
+			assert!(clock.now() < paused_at + Duration::from_millis(20) + PAUSE);
+		});
+	}
+}
@@ -0,0 +1,854 @@
+//! Deterministic discrete-event backend.
+//! Runs the whole Internet inside the simulation process: machines host an in-process [`DesApp`] instead of a device process
+//! and packets are delivered from a virtual event queue instead of through wires, along the lowest-latency path between two machines.
+//! Given the same seed and the same actions at the same virtual times, every run produces the same events.
+//! The backend starts paused and only accepts actions changing the simulation while paused, so a run driven through [`InternetAction::Step`] is reproducible.
+//! Machines run a baseline overlay unless other apps are set. libdither's core is bound to real sockets and timers, so it can't be hosted here.
+//! Actions that need device processes or network internals are rejected, see [`DesInternet::supports`].
+
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use device::{BaselineProtocol, DeviceCommand, DeviceEvent, DitherCommand, DitherEvent};
+use futures::{FutureExt, StreamExt, channel::mpsc, pin_mut, select};
+use rand::{SeedableRng, rngs::SmallRng};
+use slotmap::{SecondaryMap, SlotMap};
+
+use super::{FieldPosition, InternetAction, InternetError, InternetEvent, Latency, LatencyMatrix, LatencyModelConfig, MachineInfo, Mobility, NodeIdx, NodeInfo, NodeType, Partition, PowerOffMode, TraceSource, WireIdx, WireLatency, WireTrace};
+use super::{DEFAULT_TRACE_INTERVAL, DEVICE_PORT, HANDOVER_HYSTERESIS, MIN_TIME_SCALE, MAX_TIME_SCALE};
+use super::apps::baseline_apps;
+use super::clock::{self, SimClock};
+use super::internet_node::{MachineError, machine_seed};
+use super::mobility::MobilityState;
+use super::oracle::Oracle;
+use super::save;
+use super::trace::TraceState;
+
+/// First address handed out to machines (10.0.0.1)
+const FIRST_MACHINE_ADDRESS: u32 = 0x0A00_0001;
+
+/// Protocol running in-process on a machine, driven entirely by the simulation through its [`DesContext`].
+/// Hosted by machines of the deterministic backend and by in-process devices of the namespace backend.
+pub trait DesApp: Send {
+	/// Machine started
+	fn start(&mut self, _ctx: &mut DesContext) {}
+	/// Datagram arrived
+	fn receive(&mut self, ctx: &mut DesContext, from: SocketAddr, data: Vec<u8>);
+	/// Timer set through [`DesContext::set_timer`] fired
+	fn timer(&mut self, _ctx: &mut DesContext, _timer: u64) {}
+	/// Command sent to the machine. Queries like [`DitherCommand::GetNodeInfo`] also arrive while the simulation runs, answering them shouldn't change the app's state.
+	fn command(&mut self, ctx: &mut DesContext, command: DeviceCommand) {
+		ctx.emit(DeviceEvent::Error(format!("Unsupported DeviceCommand: {:?}", command)));
+	}
+}
+
+/// Creates the app of each new machine
+pub type AppFactory = Arc<dyn Fn(NodeIdx) -> Box<dyn DesApp> + Send + Sync>;
+
+pub(crate) enum Effect {
+	Send(SocketAddr, Vec<u8>),
+	Timer(Duration, u64),
+	Event(DeviceEvent),
+}
+
+/// Everything an app can see of and do to the simulation, effects are applied once the app returns
+pub struct DesContext<'a> {
+	now: Duration,
+	local_addr: SocketAddr,
+	rng: &'a mut SmallRng,
+	effects: Vec<Effect>,
+}
+impl<'a> DesContext<'a> {
+	pub(crate) fn new(now: Duration, local_addr: SocketAddr, rng: &'a mut SmallRng) -> Self {
+		DesContext { now, local_addr, rng, effects: Vec::new() }
+	}
+	pub(crate) fn into_effects(self) -> Vec<Effect> { self.effects }
+	/// Current virtual time
+	pub fn now(&self) -> Duration { self.now }
+	pub fn local_addr(&self) -> SocketAddr { self.local_addr }
+	/// Random generator of this machine, seeded from the Internet's seed
+	pub fn rng(&mut self) -> &mut SmallRng { self.rng }
+	/// Send datagram, it is dropped if there is no path to the destination
+	pub fn send(&mut self, to: SocketAddr, data: Vec<u8>) { self.effects.push(Effect::Send(to, data)); }
+	/// Call [`DesApp::timer`] with `timer` after a delay
+	pub fn set_timer(&mut self, delay: Duration, timer: u64) { self.effects.push(Effect::Timer(delay, timer)); }
+	/// Report event, handled like events of device processes
+	pub fn emit(&mut self, event: DeviceEvent) { self.effects.push(Effect::Event(event)); }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum DesNodeKind {
+	Network,
+	Machine { address: Ipv4Addr },
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct DesNode {
+	position: FieldPosition,
+	kind: DesNodeKind,
+	connections: Vec<WireIdx>,
+	/// Powered off machines drop datagrams sent to them
+	powered: bool,
+	power_off_mode: PowerOffMode,
+}
+impl DesNode {
+	fn new(position: FieldPosition, kind: DesNodeKind) -> Self {
+		DesNode { position, kind, connections: Vec::new(), powered: true, power_off_mode: PowerOffMode::default() }
+	}
+	fn address(&self) -> Option<Ipv4Addr> {
+		match self.kind { DesNodeKind::Machine { address } => Some(address), DesNodeKind::Network => None }
+	}
+	fn node_info(&self) -> NodeInfo {
+		NodeInfo {
+			position: self.position,
+			powered: self.powered,
+			internal_latency: 0,
+			local_address: self.address(),
+			local_address6: None,
+			node_type: match self.kind { DesNodeKind::Network => NodeType::Network, DesNodeKind::Machine { .. } => NodeType::Machine },
+			connections: self.connections.clone(),
+		}
+	}
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct DesWire {
+	a: NodeIdx,
+	b: NodeIdx,
+	latency: WireLatency,
+}
+impl DesWire {
+	fn other(&self, node: NodeIdx) -> NodeIdx { if self.a == node { self.b } else { self.a } }
+	/// Latency override when leaving a node through the wire
+	fn latency_from(&self, node: NodeIdx) -> Option<Latency> {
+		if self.a == node { self.latency.a_to_b } else { self.latency.b_to_a }
+	}
+}
+
+enum DesEvent {
+	/// Datagram arriving at a machine
+	Deliver { to: NodeIdx, from: SocketAddr, data: Vec<u8> },
+	/// Timers and commands only reach the app instance they were scheduled for, not one restarted since
+	Timer { machine: NodeIdx, generation: u64, timer: u64 },
+	/// Command handed to a machine's app, e.g. an oracle response
+	Command { machine: NodeIdx, generation: u64, command: DeviceCommand },
+	/// Trace samples and mobility steps only apply to the run that scheduled them, not one started since
+	TraceSample { wire: WireIdx, run: u64 },
+	MobilityStep { machine: NodeIdx, run: u64 },
+}
+/// Event due at a point in virtual time
+struct Scheduled {
+	time: Duration,
+	/// Scheduling order, keeps events at the same time in order
+	seq: u64,
+	event: DesEvent,
+}
+impl PartialEq for Scheduled { fn eq(&self, other: &Self) -> bool { (self.time, self.seq) == (other.time, other.seq) } }
+impl Eq for Scheduled {}
+impl PartialOrd for Scheduled { fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> { Some(self.cmp(other)) } }
+impl Ord for Scheduled { fn cmp(&self, other: &Self) -> std::cmp::Ordering { (self.time, self.seq).cmp(&(other.time, other.seq)) } }
+
+/// App of a running machine along with its random generator
+struct HostedApp {
+	app: Box<dyn DesApp>,
+	rng: SmallRng,
+	/// Number of apps started before this one, tells events of a restarted app apart
+	generation: u64,
+}
+
+/// Latency trace running on a wire
+struct RunningTrace {
+	state: TraceState,
+	/// Virtual time the trace started at
+	start: Duration,
+	interval: Duration,
+	/// Latest sample, replaces the latency model's value for the wire
+	latency: Option<Latency>,
+	run: u64,
+}
+/// Mobility model moving a machine
+struct RunningMobility {
+	state: MobilityState,
+	interval: Duration,
+	handover: bool,
+	run: u64,
+}
+
+/// Deterministic Internet, a second backend accepting the same actions and producing the same events as [`super::Internet`].
+/// Wire traces and mobility models only live as long as the runtime, they aren't saved.
+#[derive(Derivative, Serialize, Deserialize)]
+#[derivative(Debug)]
+pub struct DesInternet {
+	seed: u64,
+	nodes: SlotMap<NodeIdx, DesNode>,
+	wires: SlotMap<WireIdx, DesWire>,
+	latency_model: LatencyModelConfig,
+	time_scale: f64,
+	/// Address handed out to the next machine
+	next_address: u32,
+	/// Baseline machines run if no other apps are set
+	baseline: BaselineProtocol,
+	#[serde(skip)]
+	#[derivative(Debug="ignore")]
+	app_factory: Option<AppFactory>,
+}
+
+pub struct DesRuntime {
+	/// Paces virtual time against wall-clock time
+	clock: SimClock,
+	/// Virtual time events have been processed up to
+	now: Duration,
+	/// Virtual time skipped ahead of the clock through steps
+	offset: Duration,
+	queue: BinaryHeap<Reverse<Scheduled>>,
+	next_seq: u64,
+	apps: SecondaryMap<NodeIdx, HostedApp>,
+	next_generation: u64,
+	/// Number of times each machine's app was started, seeds apps like the namespace backend seeds devices
+	starts: SecondaryMap<NodeIdx, u64>,
+	traces: SecondaryMap<WireIdx, RunningTrace>,
+	mobility: SecondaryMap<NodeIdx, RunningMobility>,
+	/// Number of traces and mobility models started so far
+	next_run: u64,
+	/// Timers and commands of frozen machines, rescheduled once they are powered on
+	held: SecondaryMap<NodeIdx, Vec<DesEvent>>,
+	partition: Option<Partition>,
+	/// Latency of the lowest-latency path between two machines, cleared whenever the topology changes
+	paths: HashMap<(NodeIdx, NodeIdx), Option<Latency>>,
+	/// Ground truth apps can ask for, seeded like everything else
+	oracle: Oracle,
+
+	action_receiver: Option<mpsc::Receiver<InternetAction>>,
+	action_sender: mpsc::Sender<InternetAction>,
+	pub event_sender: mpsc::Sender<InternetEvent>,
+}
+impl DesRuntime {
+	fn send_event(&mut self, event: InternetEvent) -> Result<(), InternetError> {
+		self.event_sender.try_send(event).map_err(|_|InternetError::EventReceiverClosed)
+	}
+	fn schedule(&mut self, delay: Duration, event: DesEvent) {
+		self.queue.push(Reverse(Scheduled { time: self.now + delay, seq: self.next_seq, event }));
+		self.next_seq += 1;
+	}
+	/// Virtual time the clock has reached
+	fn target(&self) -> Duration { self.clock.now() + self.offset }
+	/// Take next event if it is due by a point in virtual time
+	fn pop_due(&mut self, until: Duration) -> Option<Scheduled> {
+		match self.queue.peek() {
+			Some(Reverse(scheduled)) if scheduled.time <= until => self.queue.pop().map(|Reverse(scheduled)|scheduled),
+			_ => None,
+		}
+	}
+	fn next_run(&mut self) -> u64 {
+		self.next_run += 1;
+		self.next_run - 1
+	}
+	/// Apply next sample of a wire's trace, a finished trace keeps its last latency
+	fn sample_trace(&mut self, wire_idx: WireIdx, run: u64) {
+		let now = self.now;
+		let trace = match self.traces.get_mut(wire_idx) { Some(trace) if trace.run == run => trace, _ => return };
+		let sample = trace.state.sample(now - trace.start);
+		trace.latency = sample.or_else(||trace.state.final_latency()).or(trace.latency);
+		let interval = trace.interval;
+		self.paths.clear();
+		if sample.is_some() { self.schedule(interval, DesEvent::TraceSample { wire: wire_idx, run }); }
+	}
+}
+
+impl DesInternet {
+	pub fn new(seed: u64) -> DesInternet {
+		DesInternet {
+			seed,
+			nodes: SlotMap::default(),
+			wires: SlotMap::default(),
+			latency_model: LatencyModelConfig::default(),
+			time_scale: 1.0,
+			next_address: FIRST_MACHINE_ADDRESS,
+			baseline: BaselineProtocol::Kademlia,
+			app_factory: None,
+		}
+	}
+	/// Set baseline machines run, Kademlia by default
+	pub fn with_baseline(mut self, baseline: BaselineProtocol) -> Self {
+		self.baseline = baseline;
+		self
+	}
+	/// Run other apps than a baseline on machines, apps aren't saved and have to be set again after loading
+	pub fn with_apps(mut self, app_factory: AppFactory) -> Self {
+		self.app_factory = Some(app_factory);
+		self
+	}
+	pub fn save(&self, filepath: &str) -> Result<(), InternetError> {
+		Ok(save::save(filepath, save::DES_FORMAT, self)?)
+	}
+	pub fn load(filepath: &str) -> Result<Self, InternetError> {
+		log::debug!("Loading deterministic Internet from: {:?}", filepath);
+		Ok(save::load(filepath, save::DES_FORMAT)?)
+	}
+	fn node(&self, index: NodeIdx) -> Result<&DesNode, InternetError> {
+		self.nodes.get(index).ok_or(InternetError::UnknownNode { index })
+	}
+	fn node_mut(&mut self, index: NodeIdx) -> Result<&mut DesNode, InternetError> {
+		self.nodes.get_mut(index).ok_or(InternetError::UnknownNode { index })
+	}
+	fn machine_address(&self, index: NodeIdx) -> Result<Ipv4Addr, InternetError> {
+		self.node(index)?.address().ok_or(InternetError::InvalidNodeType { index, expected: NodeType::Machine })
+	}
+
+	pub async fn init(&mut self) -> Result<(DesRuntime, mpsc::Receiver<InternetEvent>, mpsc::Sender<InternetAction>), InternetError> {
+		let (event_sender, event_receiver) = mpsc::channel(100);
+		let (action_sender, action_receiver) = mpsc::channel(100);
+
+		let action_sender_ret = action_sender.clone();
+
+		let clock = SimClock::new(self.time_scale);
+		clock.set_paused(true);
+		let mut runtime = DesRuntime {
+			clock,
+			now: Duration::ZERO,
+			offset: Duration::ZERO,
+			queue: BinaryHeap::new(),
+			next_seq: 0,
+			apps: SecondaryMap::default(),
+			next_generation: 0,
+			starts: SecondaryMap::default(),
+			traces: SecondaryMap::default(),
+			mobility: SecondaryMap::default(),
+			next_run: 0,
+			held: SecondaryMap::default(),
+			partition: None,
+			paths: HashMap::new(),
+			oracle: Oracle::new(self.seed),
+			action_receiver: Some(action_receiver),
+			action_sender,
+			event_sender,
+		};
+		// Frontends assume a running simulation until told otherwise
+		runtime.send_event(InternetEvent::Paused(true))?;
+		let machines: Vec<NodeIdx> = self.nodes.iter().filter(|(_, node)|node.address().is_some() && node.powered).map(|(idx, _)|idx).collect();
+		for machine_idx in machines { self.start_app(&mut runtime, machine_idx)?; }
+		if self.nodes.len() > 0 {
+			runtime.action_sender.try_send(InternetAction::RequestAllNodes).map_err(|_|InternetError::ActionSenderClosed)?;
+		}
+		Ok((runtime, event_receiver, action_sender_ret))
+	}
+	/// Run simulation, virtual time advances at the clock's pace and events are processed in the order they are due.
+	/// Actions are applied at the virtual time events have been processed up to, actions changing the simulation are rejected unless it is paused.
+	pub async fn run(mut self, mut runtime: DesRuntime) {
+		let runtime = &mut runtime;
+
+		let mut action_receiver = runtime.action_receiver.take().expect("there should be an action receiver here");
+		loop {
+			let next_event = runtime.queue.peek().map(|Reverse(scheduled)|scheduled.time.saturating_sub(runtime.offset));
+			let due = clock::sleep_until_any(runtime.clock.clone(), [next_event]).fuse();
+			pin_mut!(due);
+			let action = select! {
+				action = action_receiver.next() => match action { Some(action) => Some(action), None => break },
+				_ = due => None,
+			};
+			let res: Result<(), InternetError> = try {
+				match action {
+					None => self.advance(runtime, runtime.target())?,
+					Some(action) => {
+						log::debug!("Received InternetAction: {:?}", action);
+						if !runtime.clock.is_paused() && changes_simulation(&action) { Err(InternetError::NotPaused)? }
+						self.handle_action(runtime, action)?;
+					}
+				}
+			};
+			if let Err(err) = res {
+				if let Err(err) = runtime.send_event(InternetEvent::Error(Arc::new(err))) {
+					log::error!("InternetEvent receiver closed unexpectedly when sending error: {:?}", err);
+					break;
+				}
+			}
+		}
+	}
+	/// Process every event due by a point in virtual time
+	fn advance(&mut self, runtime: &mut DesRuntime, until: Duration) -> Result<(), InternetError> {
+		while let Some(Scheduled { time, event, .. }) = runtime.pop_due(until) {
+			runtime.now = time;
+			match event {
+				DesEvent::Deliver { to, .. } if !self.is_powered(to) => {}
+				DesEvent::Deliver { to, from, data } => self.call_app(runtime, to, |app, ctx|app.receive(ctx, from, data))?,
+				DesEvent::TraceSample { wire, run } => runtime.sample_trace(wire, run),
+				DesEvent::MobilityStep { machine, run } => self.step_mobility(runtime, machine, run)?,
+				DesEvent::Timer { machine, generation, .. } | DesEvent::Command { machine, generation, .. }
+					if runtime.apps.get(machine).map_or(true, |hosted|hosted.generation != generation) => {}
+				event @ (DesEvent::Timer { machine, .. } | DesEvent::Command { machine, .. }) if !self.is_powered(machine) => {
+					if let Some(held) = runtime.held.entry(machine).map(|entry|entry.or_default()) { held.push(event); }
+				}
+				DesEvent::Timer { machine, timer, .. } => self.call_app(runtime, machine, |app, ctx|app.timer(ctx, timer))?,
+				DesEvent::Command { machine, command, .. } => self.call_app(runtime, machine, |app, ctx|app.command(ctx, command))?,
+			}
+		}
+		runtime.now = runtime.now.max(until);
+		Ok(())
+	}
+	fn handle_action(&mut self, runtime: &mut DesRuntime, action: InternetAction) -> Result<(), InternetError> {
+		match action {
+			InternetAction::SaveInternet(location) => {
+				self.save(&location)?;
+				log::debug!("Saved Network");
+			}
+			InternetAction::RequestAllNodes => {
+				runtime.send_event(InternetEvent::ClearUI)?;
+				runtime.send_event(InternetEvent::Clock(runtime.now, runtime.clock.scale()))?;
+				runtime.send_event(InternetEvent::Paused(runtime.clock.is_paused()))?;
+				for (idx, node) in self.nodes.iter() {
+					runtime.send_event(InternetEvent::NodeInfo(idx, node.node_info()))?;
+				}
+				for (wire_idx, wire) in self.wires.iter() {
+					runtime.send_event(InternetEvent::ConnectionInfo(wire_idx, wire.a, wire.b))?;
+				}
+			}
+			InternetAction::AddMachine(position) => {
+				let address = Ipv4Addr::from(self.next_address);
+				self.next_address += 1;
+				let idx = self.nodes.insert(DesNode::new(position, DesNodeKind::Machine { address }));
+				self.start_app(runtime, idx)?;
+				runtime.send_event(InternetEvent::NewMachine(idx))?;
+				runtime.send_event(InternetEvent::NodeInfo(idx, self.node(idx)?.node_info()))?;
+			}
+			// Networks only route, so address ranges don't matter here
+			InternetAction::AddNetwork(position) | InternetAction::AddNetworkWithRange(position, _) => {
+				let idx = self.nodes.insert(DesNode::new(position, DesNodeKind::Network));
+				runtime.send_event(InternetEvent::NewNetwork(idx))?;
+				runtime.send_event(InternetEvent::NodeInfo(idx, self.node(idx)?.node_info()))?;
+			}
+			InternetAction::RemoveNode(index) => {
+				let connections = self.node(index)?.connections.clone();
+				for wire_idx in connections { self.remove_wire(runtime, wire_idx)?; }
+				self.nodes.remove(index);
+				// Events still queued for the machine are dropped when they come due
+				runtime.apps.remove(index);
+				runtime.held.remove(index);
+				runtime.starts.remove(index);
+				runtime.mobility.remove(index);
+				runtime.oracle.remove(index);
+				runtime.send_event(InternetEvent::RemoveNode(index))?;
+			}
+			InternetAction::GetNodeInfo(index) => {
+				runtime.send_event(InternetEvent::NodeInfo(index, self.node(index)?.node_info()))?;
+			}
+			// Answered by the app through its events
+			InternetAction::GetMachineInfo(index) | InternetAction::DitherCommand(index, DitherCommand::GetNodeInfo) => {
+				self.machine_address(index)?;
+				self.query_app(runtime, index, DeviceCommand::DitherCommand(DitherCommand::GetNodeInfo))?;
+			}
+			InternetAction::GetConnectionInfo(wire_idx) => {
+				let wire = self.wires.get(wire_idx).ok_or(InternetError::UnknownWire { index: wire_idx })?;
+				runtime.send_event(InternetEvent::ConnectionInfo(wire_idx, wire.a, wire.b))?;
+			}
+			InternetAction::SetPosition(index, position) => self.set_position(runtime, index, position)?,
+			InternetAction::SetMobility(index, mobility) => self.set_mobility(runtime, index, mobility)?,
+			InternetAction::ConnectNodes(from, to) => {
+				self.connect(runtime, from, to)?;
+			}
+			InternetAction::SetPowered(index, powered) => {
+				self.machine_address(index)?;
+				self.set_powered(runtime, index, powered)?;
+				runtime.send_event(InternetEvent::NodeInfo(index, self.node(index)?.node_info()))?;
+			}
+			InternetAction::SetPowerOffMode(index, mode) => {
+				self.machine_address(index)?;
+				self.node_mut(index)?.power_off_mode = mode;
+			}
+			InternetAction::Rehome(machine_idx, network_idx) => self.rehome(runtime, machine_idx, network_idx)?,
+			InternetAction::Partition(groups) => {
+				for &index in groups.iter().flatten() { self.node(index)?; }
+				self.heal(runtime)?;
+				let mut partition = Partition { groups, wires: Vec::new() };
+				partition.wires = self.wires.iter().filter(|(_, wire)|partition.group(wire.a) != partition.group(wire.b)).map(|(wire_idx, _)|wire_idx).collect();
+				runtime.paths.clear();
+				runtime.send_event(InternetEvent::PartitionStarted(runtime.now, partition.groups.clone(), partition.wires.clone()))?;
+				runtime.partition = Some(partition);
+			}
+			InternetAction::Heal => self.heal(runtime)?,
+			InternetAction::SetWireLatency(wire_idx, latency) => {
+				self.wires.get_mut(wire_idx).ok_or(InternetError::UnknownWire { index: wire_idx })?.latency = latency;
+				runtime.paths.clear();
+			}
+			InternetAction::SetWireTrace(wire_idx, trace) => self.set_wire_trace(runtime, wire_idx, trace)?,
+			InternetAction::LoadWireTrace(wire_idx, path) => {
+				let trace = WireTrace { source: TraceSource::load_csv(&path)?, interval: DEFAULT_TRACE_INTERVAL };
+				self.set_wire_trace(runtime, wire_idx, Some(trace))?;
+			}
+			InternetAction::DeviceCommand(index, command) => {
+				self.machine_address(index)?;
+				self.call_app(runtime, index, |app, ctx|app.command(ctx, command))?;
+			}
+			InternetAction::DitherCommand(index, command) => {
+				self.machine_address(index)?;
+				self.call_app(runtime, index, |app, ctx|app.command(ctx, DeviceCommand::DitherCommand(command)))?;
+			}
+			InternetAction::TellIp(index) => {
+				let ipv4 = Some(self.machine_address(index)?);
+				self.call_app(runtime, index, |app, ctx|app.command(ctx, DeviceCommand::AddressChanged { ipv4, ipv6: None }))?;
+			}
+			InternetAction::HandleDeviceEvent(index, event) => self.handle_device_event(runtime, index, event)?,
+			InternetAction::SetLatencyModel(model) => {
+				self.latency_model = model;
+				runtime.paths.clear();
+			}
+			InternetAction::LoadLatencyMatrix(path) => {
+				self.latency_model = LatencyModelConfig::Matrix(LatencyMatrix::load(&path)?);
+				runtime.paths.clear();
+			}
+			InternetAction::SetTimeScale(scale) => {
+				if !(MIN_TIME_SCALE..=MAX_TIME_SCALE).contains(&scale) { Err(InternetError::InvalidTimeScale(scale))? }
+				self.time_scale = scale;
+				runtime.clock.set_scale(scale);
+				runtime.send_event(InternetEvent::Clock(runtime.now, scale))?;
+			}
+			InternetAction::GetClock => {
+				runtime.send_event(InternetEvent::Clock(runtime.now, runtime.clock.scale()))?;
+			}
+			InternetAction::Pause => self.set_paused(runtime, true)?,
+			InternetAction::Resume => self.set_paused(runtime, false)?,
+			// Stepping doesn't wait for the clock, a paused simulation can be run through a scenario as fast as it is computed
+			InternetAction::Step(duration) => {
+				if !runtime.clock.is_paused() { Err(InternetError::NotPaused)? }
+				runtime.offset += duration;
+				self.advance(runtime, runtime.target())?;
+				runtime.send_event(InternetEvent::Clock(runtime.now, runtime.clock.scale()))?;
+			}
+			InternetAction::DebugPrint => {
+				log::debug!("Internet State: {:#?}", &self);
+			}
+			action => {
+				debug_assert!(!Self::supports(&action), "supported action {:?} isn't handled", action);
+				Err(InternetError::UnsupportedAction(format!("{:?}", action)))?
+			}
+		}
+		Ok(())
+	}
+	/// Whether this backend handles an action, frontends shouldn't offer the others.
+	/// Machines have no device processes and networks no addressing, firewalls or routing, which chaos, churn, rolling upgrades and the analyses build on.
+	/// Actions the namespace backend sends itself from background tasks are events in the queue here.
+	pub fn supports(action: &InternetAction) -> bool {
+		!matches!(action,
+			InternetAction::ConnectAllMachines(_) | InternetAction::AddMachineWithDevice(..) | InternetAction::GetNetworkInfo(_)
+			| InternetAction::RegisterBuild(..) | InternetAction::SetDevice(..) | InternetAction::RollingUpgrade(_)
+			| InternetAction::SetChaos(_) | InternetAction::SetChurn(_) | InternetAction::SetOracleBootstrap(_)
+			| InternetAction::SetNat(..) | InternetAction::SetIpStack(..) | InternetAction::EditFirewall(..) | InternetAction::SetRoutingMode(_)
+			| InternetAction::MeasureStretch(_) | InternetAction::SetEmbeddingAnalysis(_)
+			| InternetAction::RoutingUpdate(..) | InternetAction::RoutingLinkDown(..) | InternetAction::TraceSample(..) | InternetAction::MobilityStep(..)
+			| InternetAction::ChaosTick | InternetAction::ChurnArrival | InternetAction::ChurnDeparture(_) | InternetAction::EmbeddingTick
+			| InternetAction::UpgradeMachine(..)
+		)
+	}
+	/// Stop or restart virtual time, there are no device processes to freeze
+	fn set_paused(&mut self, runtime: &mut DesRuntime, paused: bool) -> Result<(), InternetError> {
+		if runtime.clock.is_paused() == paused { return Ok(()) }
+		// Catch up with the clock first, so the simulation stops at the virtual time it reports
+		if paused { self.advance(runtime, runtime.target())?; }
+		runtime.clock.set_paused(paused);
+		runtime.send_event(InternetEvent::Paused(paused))?;
+		runtime.send_event(InternetEvent::Clock(runtime.now, runtime.clock.scale()))
+	}
+	fn is_powered(&self, index: NodeIdx) -> bool {
+		self.nodes.get(index).map_or(false, |node|node.powered)
+	}
+	/// Power machine off or on. Stopped machines lose their app and start a new one, frozen ones hold their timers and commands.
+	fn set_powered(&mut self, runtime: &mut DesRuntime, index: NodeIdx, powered: bool) -> Result<(), InternetError> {
+		let node = self.node_mut(index)?;
+		if node.powered == powered { return Ok(()) }
+		node.powered = powered;
+		let mode = node.power_off_mode;
+		if !powered {
+			if mode == PowerOffMode::Stop { runtime.apps.remove(index); }
+		} else if runtime.apps.contains_key(index) {
+			for event in runtime.held.remove(index).unwrap_or_default() { runtime.schedule(Duration::ZERO, event); }
+		} else {
+			self.start_app(runtime, index)?;
+		}
+		Ok(())
+	}
+	/// Connect a machine to a network or two networks
+	fn connect(&mut self, runtime: &mut DesRuntime, from: NodeIdx, to: NodeIdx) -> Result<(), InternetError> {
+		let (node1, node2) = (self.node(from)?, self.node(to)?);
+		if node1.address().is_some() && node2.address().is_some() { Err(InternetError::NodeConnectionError)? }
+		if [node1, node2].iter().any(|node|node.address().is_some() && !node.connections.is_empty()) {
+			Err(InternetError::InternetMachineError(MachineError::AlreadyConnected))?
+		}
+		let wire_idx = self.wires.insert(DesWire { a: from, b: to, latency: WireLatency::default() });
+		self.node_mut(from)?.connections.push(wire_idx);
+		self.node_mut(to)?.connections.push(wire_idx);
+		if let Some(partition) = &mut runtime.partition {
+			if partition.group(from) != partition.group(to) { partition.wires.push(wire_idx); }
+		}
+		runtime.paths.clear();
+		runtime.send_event(InternetEvent::ConnectionInfo(wire_idx, from, to))
+	}
+	fn remove_wire(&mut self, runtime: &mut DesRuntime, wire_idx: WireIdx) -> Result<(), InternetError> {
+		let wire = match self.wires.remove(wire_idx) { Some(wire) => wire, None => return Ok(()) };
+		runtime.traces.remove(wire_idx);
+		for node in [wire.a, wire.b] {
+			if let Some(node) = self.nodes.get_mut(node) { node.connections.retain(|&idx|idx != wire_idx); }
+		}
+		runtime.paths.clear();
+		runtime.send_event(InternetEvent::RemoveConnection(wire_idx))
+	}
+	fn set_position(&mut self, runtime: &mut DesRuntime, index: NodeIdx, position: FieldPosition) -> Result<(), InternetError> {
+		self.node_mut(index)?.position = position;
+		runtime.paths.clear();
+		runtime.send_event(InternetEvent::NodeInfo(index, self.node(index)?.node_info()))
+	}
+	/// Move machine to another network, machines keep their address so only their wire moves
+	fn rehome(&mut self, runtime: &mut DesRuntime, machine_idx: NodeIdx, network_idx: NodeIdx) -> Result<(), InternetError> {
+		self.machine_address(machine_idx)?;
+		if self.node(network_idx)?.address().is_some() { Err(InternetError::InvalidNodeType { index: network_idx, expected: NodeType::Network })? }
+		let connections = self.node(machine_idx)?.connections.clone();
+		for wire_idx in connections { self.remove_wire(runtime, wire_idx)?; }
+		self.connect(runtime, machine_idx, network_idx)
+	}
+	/// Start or stop (None) a wire's latency trace, its samples are queued like any other event
+	fn set_wire_trace(&mut self, runtime: &mut DesRuntime, wire_idx: WireIdx, trace: Option<WireTrace>) -> Result<(), InternetError> {
+		if let Some(trace) = &trace { trace.validate()?; }
+		if !self.wires.contains_key(wire_idx) { Err(InternetError::UnknownWire { index: wire_idx })? }
+		runtime.traces.remove(wire_idx);
+		runtime.paths.clear();
+		if let Some(WireTrace { source, interval }) = trace {
+			let run = runtime.next_run();
+			runtime.traces.insert(wire_idx, RunningTrace { state: TraceState::new(source), start: runtime.now, interval, latency: None, run });
+			runtime.schedule(Duration::ZERO, DesEvent::TraceSample { wire: wire_idx, run });
+		}
+		Ok(())
+	}
+	/// Start moving a machine or stop it (None), its steps are queued like any other event
+	fn set_mobility(&mut self, runtime: &mut DesRuntime, index: NodeIdx, mobility: Option<Mobility>) -> Result<(), InternetError> {
+		if let Some(mobility) = &mobility { mobility.validate()?; }
+		self.machine_address(index)?;
+		runtime.mobility.remove(index);
+		if let Some(Mobility { model, interval, handover }) = mobility {
+			let run = runtime.next_run();
+			runtime.mobility.insert(index, RunningMobility { state: MobilityState::new(model, self.node(index)?.position), interval, handover, run });
+			runtime.schedule(interval, DesEvent::MobilityStep { machine: index, run });
+		}
+		Ok(())
+	}
+	/// Move machine to the next position of its mobility model
+	fn step_mobility(&mut self, runtime: &mut DesRuntime, machine_idx: NodeIdx, run: u64) -> Result<(), InternetError> {
+		let mobility = match runtime.mobility.get_mut(machine_idx) { Some(mobility) if mobility.run == run => mobility, _ => return Ok(()) };
+		let (interval, handover) = (mobility.interval, mobility.handover);
+		let position = match mobility.state.step(interval) {
+			Some(position) => position,
+			None => { runtime.mobility.remove(machine_idx); return Ok(()) }
+		};
+		runtime.schedule(interval, DesEvent::MobilityStep { machine: machine_idx, run });
+		self.set_position(runtime, machine_idx, position)?;
+		if handover { self.handover(runtime, machine_idx)?; }
+		Ok(())
+	}
+	/// Reconnect machine to the closest network if it is sufficiently closer than the current one
+	fn handover(&mut self, runtime: &mut DesRuntime, machine_idx: NodeIdx) -> Result<(), InternetError> {
+		let position = self.node(machine_idx)?.position;
+		let model = self.latency_model.model();
+		let closest = self.nodes.iter().filter(|(_, node)|node.address().is_none())
+			.map(|(idx, node)|(idx, model.latency(&position, &node.position)))
+			.min_by_key(|(_, latency)|*latency);
+		let (closest, closest_latency) = match closest { Some(closest) => closest, None => return Ok(()) };
+
+		// Unconnected machines stay unconnected
+		let wire = self.node(machine_idx)?.connections.first().and_then(|&wire_idx|self.wires.get(wire_idx));
+		let current = match wire { Some(wire) => wire.other(machine_idx), None => return Ok(()) };
+		if current == closest { return Ok(()) }
+		let current_latency = model.latency(&position, &self.node(current)?.position);
+		if closest_latency as f64 >= current_latency as f64 * (1.0 - HANDOVER_HYSTERESIS) { return Ok(()) }
+		log::debug!("Handing over machine {} to network {}", machine_idx, closest);
+		self.rehome(runtime, machine_idx, closest)
+	}
+	/// End active partition, if any
+	fn heal(&mut self, runtime: &mut DesRuntime) -> Result<(), InternetError> {
+		let partition = match runtime.partition.take() { Some(partition) => partition, None => return Ok(()) };
+		let wires = partition.wires.into_iter().filter(|&wire_idx|self.wires.contains_key(wire_idx)).collect();
+		runtime.paths.clear();
+		runtime.send_event(InternetEvent::PartitionHealed(runtime.now, wires))
+	}
+	/// Create and start app of a machine, its random generator only depends on the seed, the machine's index and how often it was started
+	fn start_app(&mut self, runtime: &mut DesRuntime, machine_idx: NodeIdx) -> Result<(), InternetError> {
+		let app = match &self.app_factory { Some(app_factory) => app_factory(machine_idx), None => baseline_apps(self.baseline)(machine_idx) };
+		// Restarted machines draw different numbers than before
+		let starts = runtime.starts.get(machine_idx).copied().unwrap_or(0);
+		runtime.starts.insert(machine_idx, starts + 1);
+		let rng = SmallRng::seed_from_u64(machine_seed(self.seed, machine_idx, starts));
+		runtime.apps.insert(machine_idx, HostedApp { app, rng, generation: runtime.next_generation });
+		runtime.next_generation += 1;
+		self.call_app(runtime, machine_idx, |app, ctx|app.start(ctx))
+	}
+	/// Queue a query for a machine's app at the current virtual time and answer it right away, in order with the events due then
+	fn query_app(&mut self, runtime: &mut DesRuntime, machine_idx: NodeIdx, command: DeviceCommand) -> Result<(), InternetError> {
+		let generation = match runtime.apps.get(machine_idx) { Some(hosted) => hosted.generation, None => return Ok(()) };
+		runtime.schedule(Duration::ZERO, DesEvent::Command { machine: machine_idx, generation, command });
+		self.advance(runtime, runtime.now)
+	}
+	/// Call into a machine's app and apply its effects, events for removed machines are ignored
+	fn call_app(&mut self, runtime: &mut DesRuntime, machine_idx: NodeIdx, call: impl FnOnce(&mut dyn DesApp, &mut DesContext)) -> Result<(), InternetError> {
+		let address = match self.nodes.get(machine_idx).and_then(DesNode::address) { Some(address) => address, None => return Ok(()) };
+		let hosted = match runtime.apps.get_mut(machine_idx) { Some(hosted) => hosted, None => return Ok(()) };
+		let generation = hosted.generation;
+		let local_addr = SocketAddr::new(IpAddr::V4(address), DEVICE_PORT);
+		let mut ctx = DesContext::new(runtime.now, local_addr, &mut hosted.rng);
+		call(hosted.app.as_mut(), &mut ctx);
+		let effects = ctx.into_effects();
+
+		for effect in effects {
+			match effect {
+				Effect::Send(to, data) => {
+					let destination = match to.ip() {
+						IpAddr::V4(ip) => self.nodes.iter().find(|(_, node)|node.address() == Some(ip)).map(|(idx, _)|idx),
+						IpAddr::V6(_) => None,
+					};
+					match destination.and_then(|destination|Some((destination, self.path_latency(runtime, machine_idx, destination)?))) {
+						Some((to, latency)) => runtime.schedule(Duration::from_micros(latency), DesEvent::Deliver { to, from: local_addr, data }),
+						None => log::trace!("Dropping datagram from {} to unreachable {}", local_addr, to),
+					}
+				}
+				Effect::Timer(delay, timer) => runtime.schedule(delay, DesEvent::Timer { machine: machine_idx, generation, timer }),
+				Effect::Event(event) => self.handle_device_event(runtime, machine_idx, event)?,
+			}
+		}
+		Ok(())
+	}
+	fn handle_device_event(&self, runtime: &mut DesRuntime, index: NodeIdx, event: DeviceEvent) -> Result<(), InternetError> {
+		match event {
+			DeviceEvent::Debug(string) => log::debug!("Machine {}: {}", index, string),
+			DeviceEvent::Error(string) => log::error!("Machine {}: {}", index, string),
+			DeviceEvent::Output(line) => log::info!("Machine {}: {}", index, line),
+			DeviceEvent::Started { .. } => {}
+			DeviceEvent::BaselineInfo(info) => {
+				runtime.oracle.baseline_info(index, &info);
+				runtime.send_event(InternetEvent::BaselineInfo(index, info))?;
+			}
+			// Answered in a separate event at the same virtual time, as apps can't be called into while they are running
+			DeviceEvent::OracleRequest(id, request) => {
+				let position = self.node(index)?.position;
+				let peers = runtime.apps.keys().filter(|&idx|self.is_powered(idx))
+					.filter_map(|idx|Some((idx, SocketAddr::new(IpAddr::V4(self.nodes.get(idx)?.address()?), DEVICE_PORT)))).collect();
+				let response = runtime.oracle.answer(index, request, position, peers);
+				let generation = match runtime.apps.get(index) { Some(hosted) => hosted.generation, None => return Ok(()) };
+				runtime.schedule(Duration::ZERO, DesEvent::Command { machine: index, generation, command: DeviceCommand::OracleResponse(id, response) });
+			}
+			DeviceEvent::DitherEvent(DitherEvent::NodeInfo(device::NodeInfo { route_coord, node_id, public_addr, remotes, active_remotes, local_addr })) => {
+				runtime.oracle.node_info(index, node_id.clone(), route_coord.clone());
+				let network_ip = Some(self.machine_address(index)?);
+				runtime.send_event(InternetEvent::MachineInfo(index, MachineInfo {
+					route_coord, public_addr, node_id, remotes, active_remotes, network_ip, network_ip6: None, local_addr
+				}))?;
+			}
+		}
+		Ok(())
+	}
+	/// Latency of the lowest-latency path between two machines (measured in microseconds), None if they aren't connected.
+	/// Machines don't forward, so paths only pass through networks.
+	fn path_latency(&self, runtime: &mut DesRuntime, from: NodeIdx, to: NodeIdx) -> Option<Latency> {
+		if from == to { return Some(0) }
+		if let Some(latency) = runtime.paths.get(&(from, to)) { return *latency }
+
+		let model = self.latency_model.model();
+		let mut distances: BTreeMap<NodeIdx, Latency> = BTreeMap::from([(from, 0)]);
+		let mut frontier = BinaryHeap::from([Reverse((0, from))]);
+		let mut result = None;
+		while let Some(Reverse((distance, idx))) = frontier.pop() {
+			if idx == to { result = Some(distance); break }
+			if distances.get(&idx).map_or(false, |&best|best < distance) { continue }
+			let node = match self.nodes.get(idx) { Some(node) => node, None => continue };
+			if idx != from && node.address().is_some() { continue }
+			for wire_idx in &node.connections {
+				if runtime.partition.as_ref().map_or(false, |partition|partition.wires.contains(wire_idx)) { continue }
+				let wire = match self.wires.get(*wire_idx) { Some(wire) => wire, None => continue };
+				let next = wire.other(idx);
+				let next_node = match self.nodes.get(next) { Some(next_node) => next_node, None => continue };
+				// Overrides take precedence over traces, traces over the latency model
+				let trace = runtime.traces.get(*wire_idx).and_then(|trace|trace.latency);
+				let next_distance = distance + wire.latency_from(idx).or(trace).unwrap_or_else(||model.latency(&node.position, &next_node.position));
+				if distances.get(&next).map_or(true, |&best|next_distance < best) {
+					distances.insert(next, next_distance);
+					frontier.push(Reverse((next_distance, next)));
+				}
+			}
+		}
+		runtime.paths.insert((from, to), result);
+		result
+	}
+}
+
+/// Whether an action changes the simulation, rather than only querying it or controlling time
+fn changes_simulation(action: &InternetAction) -> bool {
+	!matches!(action,
+		InternetAction::SaveInternet(_) | InternetAction::RequestAllNodes | InternetAction::GetNodeInfo(_) | InternetAction::GetMachineInfo(_)
+		| InternetAction::GetNetworkInfo(_) | InternetAction::GetConnectionInfo(_) | InternetAction::DitherCommand(_, DitherCommand::GetNodeInfo)
+		| InternetAction::GetClock | InternetAction::SetTimeScale(_) | InternetAction::Pause | InternetAction::Resume | InternetAction::Step(_)
+		| InternetAction::DebugPrint
+	)
+}
+
+#[cfg(test)]
+mod tests {
+	use async_std::task;
+
+	use super::*;
+
+	/// Apply action, returns the node it added if any
+	fn apply(internet: &mut DesInternet, runtime: &mut DesRuntime, action: InternetAction) -> Option<NodeIdx> {
+		let before: Vec<NodeIdx> = internet.nodes.keys().collect();
+		internet.handle_action(runtime, action).unwrap();
+		internet.nodes.keys().find(|idx|!before.contains(idx))
+	}
+
+	/// Run the same script of actions on a fresh Internet, returns every event it produced
+	fn run_script(seed: u64, baseline: BaselineProtocol) -> Vec<String> {
+		let mut internet = DesInternet::new(seed).with_baseline(baseline);
+		let (mut runtime, _, _) = task::block_on(internet.init()).unwrap();
+		// Events are only read at the end, so there has to be room for all of them
+		let (event_sender, mut events) = mpsc::channel(100_000);
+		runtime.event_sender = event_sender;
+		let runtime = &mut runtime;
+		let step = |internet: &mut DesInternet, runtime: &mut DesRuntime, secs|apply(internet, runtime, InternetAction::Step(Duration::from_secs(secs)));
+
+		let networks: Vec<NodeIdx> = [(0, 0), (1000, 0), (2000, 500)].into_iter()
+			.map(|(x, y)|apply(&mut internet, runtime, InternetAction::AddNetwork(FieldPosition::new(x, y))).unwrap()).collect();
+		apply(&mut internet, runtime, InternetAction::ConnectNodes(networks[0], networks[1]));
+		apply(&mut internet, runtime, InternetAction::ConnectNodes(networks[1], networks[2]));
+		let machines: Vec<NodeIdx> = (0..8).map(|i|{
+			let machine = apply(&mut internet, runtime, InternetAction::AddMachine(FieldPosition::new(i * 250, i * 60))).unwrap();
+			apply(&mut internet, runtime, InternetAction::ConnectNodes(machine, networks[i as usize % networks.len()]));
+			machine
+		}).collect();
+		step(&mut internet, runtime, 10);
+
+		apply(&mut internet, runtime, InternetAction::DeviceCommand(machines[0], DeviceCommand::Lookup(42)));
+		// Cut the first network and the machines on it off from the rest
+		let (mut first, mut rest) = (vec![networks[0]], networks[1..].to_vec());
+		for (i, &machine) in machines.iter().enumerate() {
+			if i % networks.len() == 0 { first.push(machine) } else { rest.push(machine) }
+		}
+		apply(&mut internet, runtime, InternetAction::Partition(vec![first, rest]));
+		apply(&mut internet, runtime, InternetAction::SetPowered(machines[3], false));
+		step(&mut internet, runtime, 5);
+		apply(&mut internet, runtime, InternetAction::Heal);
+		apply(&mut internet, runtime, InternetAction::SetPowered(machines[3], true));
+		apply(&mut internet, runtime, InternetAction::RemoveNode(machines[5]));
+		step(&mut internet, runtime, 20);
+		for &machine in machines.iter().filter(|&&machine|machine != machines[5]) {
+			apply(&mut internet, runtime, InternetAction::GetMachineInfo(machine));
+		}
+
+		let mut log = Vec::new();
+		while let Ok(Some(event)) = events.try_next() { log.push(format!("{:?}", event)); }
+		log
+	}
+
+	#[test]
+	fn same_seed_same_events() {
+		for baseline in [BaselineProtocol::Kademlia, BaselineProtocol::Vivaldi] {
+			let events = run_script(7, baseline);
+			// Machines found each other, so the events depend on the traffic between them
+			assert!(events.iter().any(|event|event.starts_with("BaselineInfo") && !event.contains(", remotes: 0,")), "{:#?}", events);
+			assert_eq!(events, run_script(7, baseline));
+			assert_ne!(events, run_script(8, baseline));
+		}
+	}
+}
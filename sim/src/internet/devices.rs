@@ -0,0 +1,393 @@
+//! Devices run on machines, a device accepts [`DeviceCommand`]s, emits [`DeviceEvent`]s and owns the plug leading to its machine's network.
+//! Device processes run a program in the machine's network namespace, either the device executable or any other command.
+//! In-process devices host a [`DesApp`], e.g. a baseline overlay, on a minimal UDP-only stack inside the simulation process,
+//! so thousands of machines don't need thousands of OS processes.
+//! They don't run Dither itself: DitherCore opens its own TCP sockets, so Dither nodes always run as device processes.
+//! Machines can also reference a named build, so machines running different device versions can share one Internet.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::{Arc, RwLock, atomic::{AtomicU64, Ordering}};
+
+use async_std::task;
+use device::{DeviceCommand, DeviceEvent, ORACLE_BOOTSTRAP_ENV, TIME_SCALE_ENV};
+use futures::{FutureExt, SinkExt, StreamExt, channel::mpsc, future::{self, AbortHandle}, pin_mut, select, stream::BoxStream};
+use netsim_embed::{Machine, MachineId, Plug};
+use nix::{sys::signal::{self, Signal}, unistd::Pid};
+use rand::{SeedableRng, rngs::SmallRng};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use super::{AppFactory, MachineAddrs, NodeIdx, DEVICE_PORT};
+use super::clock::{self, DelayQueue, SimClock};
+use super::des::{DesApp, DesContext, Effect};
+use super::internet_node::MachineError;
+use super::packet::{self, PacketHeader, Protocol};
+
+/// Named device builds and in-process apps machines can run.
+/// Clones share their entries, so devices (re)started after a build is registered again run the new program.
+#[derive(Clone, Default)]
+pub struct DeviceRegistry(Arc<RwLock<Registry>>);
+#[derive(Default, Serialize, Deserialize)]
+struct Registry {
+	/// Programs by build name, e.g. `v0.3` and `main` pointing to different device executables
+	builds: BTreeMap<String, MachineProgram>,
+	/// Apps can't be saved and need to be registered again after loading
+	#[serde(skip)]
+	apps: HashMap<String, AppFactory>,
+	/// Whether programs are started with [`ORACLE_BOOTSTRAP_ENV`], an experiment setting like churn that isn't saved
+	#[serde(skip)]
+	oracle_bootstrap: bool,
+}
+impl DeviceRegistry {
+	fn read(&self) -> std::sync::RwLockReadGuard<'_, Registry> { self.0.read().unwrap() }
+	fn write(&self) -> std::sync::RwLockWriteGuard<'_, Registry> { self.0.write().unwrap() }
+	pub fn register_app(&self, name: String, app_factory: AppFactory) {
+		self.write().apps.insert(name, app_factory);
+	}
+	/// Register a build under a name, replacing any build of the same name
+	pub fn register_build(&self, name: String, program: MachineProgram) {
+		self.write().builds.insert(name, program);
+	}
+	pub fn remove_build(&self, name: &str) -> Option<MachineProgram> {
+		self.write().builds.remove(name)
+	}
+	pub fn build(&self, name: &str) -> Result<MachineProgram, MachineError> {
+		self.read().builds.get(name).cloned().ok_or_else(||MachineError::UnknownBuild(name.to_owned()))
+	}
+	pub fn builds(&self) -> BTreeMap<String, MachineProgram> { self.read().builds.clone() }
+	/// Let programs started from now on bootstrap off of a random peer the oracle picks
+	pub fn set_oracle_bootstrap(&self, enabled: bool) {
+		self.write().oracle_bootstrap = enabled;
+	}
+	pub fn oracle_bootstrap(&self) -> bool { self.read().oracle_bootstrap }
+	fn app(&self, name: &str) -> Result<AppFactory, MachineError> {
+		self.read().apps.get(name).cloned().ok_or_else(||MachineError::UnknownApp(name.to_owned()))
+	}
+	/// Check that the build or app a device config references is registered
+	pub fn validate(&self, device: &DeviceConfig) -> Result<(), MachineError> {
+		match device {
+			DeviceConfig::Process(_) => Ok(()),
+			DeviceConfig::Build(name) => self.build(name).map(|_|()),
+			DeviceConfig::InProcess { app } => self.app(app).map(|_|()),
+		}
+	}
+}
+impl fmt::Debug for DeviceRegistry {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let registry = self.read();
+		f.debug_struct("DeviceRegistry").field("builds", &registry.builds).field("apps", &registry.apps.keys().collect::<Vec<_>>()).finish()
+	}
+}
+impl Serialize for DeviceRegistry {
+	fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> { self.read().serialize(serializer) }
+}
+impl<'de> Deserialize<'de> for DeviceRegistry {
+	fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+		Ok(DeviceRegistry(Arc::new(RwLock::new(Registry::deserialize(deserializer)?))))
+	}
+}
+
+/// How the simulation talks to a program through its stdin and stdout
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StdioProtocol {
+	/// RON-formatted DeviceCommands and DeviceEvents, spoken by the device executable
+	Device,
+	/// [`DeviceCommand::Input`] is written to stdin as a plain line, every stdout line becomes a [`DeviceEvent::Output`]
+	Lines,
+	/// Program isn't controlled at all, it only uses the machine's network
+	None,
+}
+
+/// Command run on a machine
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MachineProgram {
+	pub command: String,
+	pub args: Vec<String>,
+	pub env: Vec<(String, String)>,
+	pub protocol: StdioProtocol,
+}
+impl MachineProgram {
+	/// Device executable speaking the device protocol
+	pub fn device(executable: impl Into<String>) -> Self {
+		MachineProgram { command: executable.into(), args: Vec::new(), env: Vec::new(), protocol: StdioProtocol::Device }
+	}
+}
+
+/// Which device a machine runs
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum DeviceConfig {
+	/// Program started in the machine's network namespace
+	Process(MachineProgram),
+	/// Build registered with the Internet under a name, resolved every time the device starts
+	Build(String),
+	/// [`DesApp`] registered with the Internet under a name, runs inside the simulation process on a UDP-only stack.
+	/// Only apps run this way, not the device executable or DitherCore.
+	InProcess { app: String },
+}
+
+/// Something that accepts DeviceCommands, emits DeviceEvents and owns a network plug. Dropping a device stops it.
+pub trait Device: Send {
+	fn command(&self, command: DeviceCommand) -> Result<(), MachineError>;
+	/// Device reported the id of the OS process it runs in
+	fn set_pid(&mut self, _pid: u32) {}
+	/// Stop or continue running without losing any state
+	fn set_frozen(&mut self, frozen: bool) -> Result<(), MachineError>;
+	/// Addresses of the machine changed, devices with their own network stack need to know
+	fn set_addresses(&mut self, _addrs: MachineAddrs) {}
+}
+
+impl DeviceConfig {
+	/// Start device on a plug, returns it along with the events it emits. In-process devices draw their random numbers from the seed.
+	pub async fn spawn(&self, machine_id: NodeIdx, plug: Plug, addrs: MachineAddrs, clock: &SimClock, registry: &DeviceRegistry, seed: u64) -> Result<(Box<dyn Device>, BoxStream<'static, DeviceEvent>), MachineError> {
+		match self {
+			DeviceConfig::Process(program) => Ok(spawn_program(program, machine_id, plug, clock, registry.oracle_bootstrap()).await),
+			DeviceConfig::Build(name) => Ok(spawn_program(&registry.build(name)?, machine_id, plug, clock, registry.oracle_bootstrap()).await),
+			DeviceConfig::InProcess { app } => {
+				let app_factory = registry.app(app)?;
+				let (device, events) = InProcessDevice::spawn(app_factory(machine_id), plug, addrs, clock.clone(), seed);
+				Ok((Box::new(device), events.boxed()))
+			}
+		}
+	}
+}
+
+/// Environment variable marking each spawned program, netsim starts the process itself and never hands out its id
+const SPAWN_MARKER_ENV: &str = "DITHER_SIM_SPAWN";
+static NEXT_SPAWN: AtomicU64 = AtomicU64::new(0);
+
+/// Find the process started with a marker, ignoring any children it spawned that inherited it
+fn find_marked_process(marker: &str) -> Option<u32> {
+	let entry = format!("{}={}", SPAWN_MARKER_ENV, marker);
+	let marked: Vec<u32> = std::fs::read_dir("/proc").ok()?
+		.filter_map(|dir|dir.ok()?.file_name().to_str()?.parse::<u32>().ok())
+		.filter(|pid|std::fs::read(format!("/proc/{}/environ", pid)).map_or(false, |environ|environ.split(|b|*b == 0).any(|var|var == entry.as_bytes())))
+		.collect();
+	let parent = |pid: u32| -> Option<u32> {
+		let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+		// Fields after the parenthesized command name: state, parent pid, ...
+		stat.rsplit_once(')')?.1.split_whitespace().nth(1)?.parse().ok()
+	};
+	topmost_process(&marked, parent)
+}
+/// Process among some whose parent isn't one of them
+fn topmost_process(pids: &[u32], parent: impl Fn(u32) -> Option<u32>) -> Option<u32> {
+	pids.iter().copied().find(|pid|parent(*pid).map_or(true, |parent|!pids.contains(&parent)))
+}
+
+/// Machines only pass on stdout lines marked with `<`, so plain programs get their output marked by a filter.
+/// The shell execs the program, which keeps the process id netsim kills, and the filter exits once its output closes.
+const MARK_LINES_SCRIPT: &str = r#"exec > >(exec sed -u 's/^/</'); exec "$@""#;
+
+/// Start program in a network namespace connected to a plug
+async fn spawn_program(program: &MachineProgram, machine_id: NodeIdx, plug: Plug, clock: &SimClock, oracle_bootstrap: bool) -> (Box<dyn Device>, BoxStream<'static, DeviceEvent>) {
+	let mut command = match program.protocol {
+		StdioProtocol::Lines => {
+			let mut command = async_process::Command::new("bash");
+			command.arg("-c").arg(MARK_LINES_SCRIPT).arg("bash").arg(&program.command);
+			command
+		}
+		_ => async_process::Command::new(&program.command),
+	};
+	command.args(&program.args).envs(program.env.iter().map(|(key, value)|(key, value)));
+	command.env(TIME_SCALE_ENV, clock.scale().to_string());
+	if oracle_bootstrap { command.env(ORACLE_BOOTSTRAP_ENV, "1"); }
+	let marker = format!("{}-{}", std::process::id(), NEXT_SPAWN.fetch_add(1, Ordering::Relaxed));
+	command.env(SPAWN_MARKER_ENV, &marker);
+	let machine_id = MachineId(machine_id.as_ffi());
+	let (stdio, events) = match program.protocol {
+		StdioProtocol::Device => {
+			let (machine, events) = Machine::new(machine_id, plug, command).await.take_rx();
+			(ProcessStdio::Device(machine), events.boxed())
+		}
+		StdioProtocol::Lines => {
+			let (machine, events) = Machine::<String, String>::new(machine_id, plug, command).await.take_rx();
+			(ProcessStdio::Lines(machine), events.map(DeviceEvent::Output).boxed())
+		}
+		StdioProtocol::None => {
+			// Events are drained so the machine can keep forwarding output to the log
+			let (machine, events) = Machine::<String, String>::new(machine_id, plug, command).await.take_rx();
+			(ProcessStdio::None(machine), events.filter_map(|_|future::ready(None)).boxed())
+		}
+	};
+	(Box::new(ProcessDevice { stdio, marker, pid: None, frozen: false }), events)
+}
+
+/// Running program along with the protocol it speaks, dropping the machine kills the process
+enum ProcessStdio {
+	Device(Machine<DeviceCommand, DeviceEvent>),
+	Lines(Machine<String, String>),
+	None(Machine<String, String>),
+}
+
+/// Program running in its own network namespace, commands and events are passed through its stdin and stdout
+struct ProcessDevice {
+	stdio: ProcessStdio,
+	/// Value of [`SPAWN_MARKER_ENV`] the program was started with
+	marker: String,
+	/// Process id, reported by programs speaking the device protocol or looked up through the marker
+	pid: Option<u32>,
+	frozen: bool,
+}
+impl Device for ProcessDevice {
+	fn command(&self, command: DeviceCommand) -> Result<(), MachineError> {
+		match (&self.stdio, command) {
+			(ProcessStdio::Device(machine), command) => machine.tx.unbounded_send(command).map_err(|_|MachineError::DeviceCommandSenderClosed),
+			(ProcessStdio::Lines(machine), DeviceCommand::Input(line)) => machine.tx.unbounded_send(line).map_err(|_|MachineError::DeviceCommandSenderClosed),
+			(_, command) => Err(MachineError::UnsupportedCommand(command)),
+		}
+	}
+	fn set_pid(&mut self, pid: u32) { self.pid = Some(pid); }
+	/// Freezes with SIGSTOP, does nothing while the process hasn't been started yet
+	fn set_frozen(&mut self, frozen: bool) -> Result<(), MachineError> {
+		if self.pid.is_none() { self.pid = find_marked_process(&self.marker); }
+		if let (Some(pid), true) = (self.pid, self.frozen != frozen) {
+			let signal = if frozen { Signal::SIGSTOP } else { Signal::SIGCONT };
+			signal::kill(Pid::from_raw(pid as i32), signal).map_err(|source|MachineError::SignalFailed { pid, signal, source })?;
+			self.frozen = frozen;
+		}
+		Ok(())
+	}
+}
+
+enum Control {
+	Command(DeviceCommand),
+	Frozen(bool),
+	Addresses(MachineAddrs),
+	Timer(u64),
+}
+
+/// App running on a minimal UDP stack: it receives datagrams sent to [`DEVICE_PORT`] and sends datagrams from it, everything else is dropped
+struct InProcessDevice {
+	control: mpsc::UnboundedSender<Control>,
+	task: AbortHandle,
+}
+impl InProcessDevice {
+	fn spawn(mut app: Box<dyn DesApp>, plug: Plug, addrs: MachineAddrs, clock: SimClock, seed: u64) -> (Self, mpsc::UnboundedReceiver<DeviceEvent>) {
+		let (control, mut control_receiver) = mpsc::unbounded();
+		let (event_sender, event_receiver) = mpsc::unbounded();
+		let timers = DelayQueue::new(clock.clone());
+		let mut stack = UdpStack { address: addrs.v4, rng: SmallRng::seed_from_u64(seed), clock, timers, event_sender, outgoing: Vec::new() };
+		let (task, abort_handle) = future::abortable(async move {
+			let (mut plug_tx, mut plug_rx) = plug.split();
+			let mut frozen = false;
+			// Commands and timers arriving while frozen, handled once thawed
+			let mut held = VecDeque::new();
+			stack.call(&mut app, |app, ctx|app.start(ctx));
+			loop {
+				let ready = if frozen { None } else { held.pop_front().or_else(||stack.timers.pop_due().map(Control::Timer)) };
+				match ready {
+					Some(control) => stack.handle(&mut app, control),
+					None => {
+						// Packets wait in the plug and timers in their queue while frozen
+						let listening = !frozen;
+						let plug_rx = &mut plug_rx;
+						let incoming = async move { if listening { plug_rx.next().await } else { future::pending().await } }.fuse();
+						let deadline = if frozen { None } else { stack.timers.next_deadline() };
+						let timer_due = clock::sleep_until_any(stack.clock.clone(), [deadline]).fuse();
+						pin_mut!(incoming, timer_due);
+						select! {
+							control = control_receiver.next() => match control {
+								Some(Control::Frozen(new_frozen)) => frozen = new_frozen,
+								Some(control) if frozen => held.push_back(control),
+								Some(control) => stack.handle(&mut app, control),
+								None => break,
+							},
+							packet = incoming => match packet {
+								Some(packet) => stack.receive(&mut app, &packet),
+								None => break,
+							},
+							_ = timer_due => {},
+						}
+					}
+				}
+				for packet in stack.outgoing.drain(..) { let _ = plug_tx.send(packet).await; }
+			}
+		});
+		task::spawn(task);
+		(InProcessDevice { control, task: abort_handle }, event_receiver)
+	}
+}
+impl Device for InProcessDevice {
+	fn command(&self, command: DeviceCommand) -> Result<(), MachineError> {
+		self.control.unbounded_send(Control::Command(command)).map_err(|_|MachineError::DeviceCommandSenderClosed)
+	}
+	fn set_frozen(&mut self, frozen: bool) -> Result<(), MachineError> {
+		self.control.unbounded_send(Control::Frozen(frozen)).map_err(|_|MachineError::DeviceCommandSenderClosed)
+	}
+	fn set_addresses(&mut self, addrs: MachineAddrs) {
+		let _ = self.control.unbounded_send(Control::Addresses(addrs));
+	}
+}
+impl Drop for InProcessDevice {
+	fn drop(&mut self) { self.task.abort(); }
+}
+
+/// Network stack and timers of an in-process device, timers live in the device's task and stop with it
+struct UdpStack {
+	/// IPv4 address of the machine, nothing is sent without one
+	address: Option<Ipv4Addr>,
+	rng: SmallRng,
+	clock: SimClock,
+	timers: DelayQueue<u64>,
+	event_sender: mpsc::UnboundedSender<DeviceEvent>,
+	/// Packets to send once the app returned
+	outgoing: Vec<Vec<u8>>,
+}
+impl UdpStack {
+	fn handle(&mut self, app: &mut Box<dyn DesApp>, control: Control) {
+		match control {
+			Control::Command(command) => self.call(app, |app, ctx|app.command(ctx, command)),
+			Control::Timer(timer) => self.call(app, |app, ctx|app.timer(ctx, timer)),
+			Control::Addresses(addrs) => self.address = addrs.v4,
+			Control::Frozen(_) => {}
+		}
+	}
+	fn receive(&mut self, app: &mut Box<dyn DesApp>, packet: &[u8]) {
+		let header = match PacketHeader::parse(packet) { Some(header) => header, None => return };
+		match (header.protocol, header.src_endpoint(), header.ports, packet::udp_payload(packet)) {
+			(Protocol::Udp, Some(from), Some((_, DEVICE_PORT)), Some(payload)) => {
+				let payload = payload.to_vec();
+				self.call(app, |app, ctx|app.receive(ctx, SocketAddr::V4(from), payload));
+			}
+			_ => log::trace!("In-process device dropping packet from {}", header.src),
+		}
+	}
+	/// Call into the app and apply its effects
+	fn call(&mut self, app: &mut Box<dyn DesApp>, call: impl FnOnce(&mut dyn DesApp, &mut DesContext)) {
+		let local_addr = SocketAddr::new(IpAddr::V4(self.address.unwrap_or(Ipv4Addr::UNSPECIFIED)), DEVICE_PORT);
+		let mut ctx = DesContext::new(self.clock.now(), local_addr, &mut self.rng);
+		call(app.as_mut(), &mut ctx);
+		for effect in ctx.into_effects() {
+			match effect {
+				Effect::Send(SocketAddr::V4(to), data) => match self.address.and_then(|address|packet::udp_packet(SocketAddrV4::new(address, DEVICE_PORT), to, &data)) {
+					Some(packet) => self.outgoing.push(packet),
+					None => log::trace!("In-process device dropping datagram to {}", to),
+				},
+				Effect::Send(to, _) => log::trace!("In-process device can't send to {}, its stack only speaks IPv4", to),
+				Effect::Timer(delay, timer) => self.timers.insert(timer, delay),
+				Effect::Event(event) => { let _ = self.event_sender.unbounded_send(event); }
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn marked_parent_over_children() {
+		// Program 40 spawned 41, which spawned 42, all of them inherited the marker
+		let parents = HashMap::from([(40, 1), (41, 40), (42, 41)]);
+		let parent = |pid: u32|parents.get(&pid).copied();
+		for pids in [[40, 41, 42], [42, 41, 40], [41, 42, 40]] {
+			assert_eq!(topmost_process(&pids, parent), Some(40));
+		}
+		// Parent exited and the child was reparented
+		assert_eq!(topmost_process(&[41], parent), Some(41));
+		// Processes gone before their parent could be read count as topmost
+		assert_eq!(topmost_process(&[43], parent), Some(43));
+		assert_eq!(topmost_process(&[], parent), None);
+	}
+}
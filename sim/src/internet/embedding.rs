@@ -0,0 +1,247 @@
+//! Embedding error of route coordinates, how faithfully distances between the route coordinates devices report predict round-trip times.
+//! Baselines with coordinates (Vivaldi) are analysed alike, by the plane of their coordinate.
+//! The analysis runs periodically so convergence can be followed. Every run compares all machine pairs and samples machine triples
+//! for triangle inequality violations, which no 2D Euclidean embedding can reproduce.
+
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::time::Duration;
+
+use anyhow::Context;
+use async_std::task;
+use futures::{SinkExt, channel::mpsc, future::{self, AbortHandle}};
+use rand::{SeedableRng, rngs::SmallRng, seq::SliceRandom};
+
+use super::{Internet, InternetAction, InternetError, InternetEvent, InternetRuntime, NodeIdx};
+use super::clock::SimClock;
+use super::stretch::route_distance;
+
+/// Where the round-trip times coordinates are compared with come from
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum RttSource {
+	/// Twice the shortest-path latency through the wire graph. Shortest paths never violate the triangle inequality.
+	ShortestPath,
+	/// Twice the latency model's latency between the machines' positions.
+	/// Models derived from distances (Euclidean, great circle) are metrics and yield no violations either, a measured latency matrix does.
+	LatencyModel,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmbeddingConfig {
+	/// Virtual time between runs
+	pub interval: Duration,
+	pub rtt_source: RttSource,
+	/// Machine triples sampled for triangle inequality violations
+	pub triangle_samples: usize,
+	pub seed: u64,
+	/// Every run is appended to `<prefix>_summary.csv` and `<prefix>_cdf.csv`
+	pub csv_prefix: Option<String>,
+}
+impl Default for EmbeddingConfig {
+	fn default() -> Self {
+		EmbeddingConfig {
+			interval: Duration::from_secs(10),
+			rtt_source: RttSource::LatencyModel,
+			triangle_samples: 10000,
+			seed: 0,
+			csv_prefix: None,
+		}
+	}
+}
+
+/// Result of one run, errors are relative to the round-trip time
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmbeddingReport {
+	/// Virtual time since the analysis started
+	pub time: Duration,
+	/// Machine pairs compared
+	pub pairs: usize,
+	/// Microseconds of round-trip time a route coordinate distance of 1 stands for, fitted to this run's pairs by least squares.
+	/// None if all coordinates coincide, every prediction is 0 then.
+	pub fitted_scale: Option<f64>,
+	pub mean_error: Option<f64>,
+	pub median_error: Option<f64>,
+	pub p90_error: Option<f64>,
+	/// Relative error at every 5th percentile, as (quantile, error)
+	pub cdf: Vec<(f64, f64)>,
+	/// Triples sampled
+	pub triangles: usize,
+	/// Triples whose longest side is longer than the other two together
+	pub violations: usize,
+	/// How many times longer than the other two together the longest side of violating triples is, on average
+	pub mean_violation: Option<f64>,
+}
+impl EmbeddingReport {
+	fn append_csv(&self, prefix: &str) -> anyhow::Result<()> {
+		let open = |suffix: &str, header: &str| -> anyhow::Result<std::fs::File> {
+			let path = format!("{}_{}.csv", prefix, suffix);
+			let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+			if file.metadata()?.len() == 0 { writeln!(file, "{}", header)?; }
+			Ok(file)
+		};
+		let optional = |value: Option<f64>|value.map(|value|value.to_string()).unwrap_or_default();
+		let mut summary = open("summary", "time_s,pairs,fitted_scale,mean_error,median_error,p90_error,triangles,violations,mean_violation")?;
+		writeln!(summary, "{},{},{},{},{},{},{},{},{}", self.time.as_secs_f64(), self.pairs, optional(self.fitted_scale),
+			optional(self.mean_error), optional(self.median_error), optional(self.p90_error),
+			self.triangles, self.violations, optional(self.mean_violation))?;
+		let mut cdf = open("cdf", "time_s,quantile,error")?;
+		for (quantile, error) in &self.cdf { writeln!(cdf, "{},{},{}", self.time.as_secs_f64(), quantile, error)?; }
+		Ok(())
+	}
+}
+
+/// Running periodic analysis
+pub(crate) struct EmbeddingAnalysis {
+	config: EmbeddingConfig,
+	rng: SmallRng,
+	/// Virtual time the analysis started at
+	start: Duration,
+	ticker: AbortHandle,
+}
+
+/// Spawn task sending an [`InternetAction::EmbeddingTick`] every interval
+fn spawn_ticker(interval: Duration, mut action_sender: mpsc::Sender<InternetAction>, clock: SimClock) -> AbortHandle {
+	let (task, abort_handle) = future::abortable(async move {
+		loop {
+			clock.sleep(interval).await;
+			if action_sender.send(InternetAction::EmbeddingTick).await.is_err() { break }
+		}
+	});
+	task::spawn(task);
+	abort_handle
+}
+
+impl Internet {
+	/// Start analysing the embedding periodically or stop (None)
+	pub(crate) fn set_embedding_analysis(&mut self, runtime: &mut InternetRuntime, config: Option<EmbeddingConfig>) -> Result<(), InternetError> {
+		if let Some(analysis) = runtime.embedding.take() { analysis.ticker.abort(); }
+		if let Some(config) = config {
+			let ticker = spawn_ticker(config.interval, runtime.action_sender.clone(), runtime.clock.clone());
+			runtime.embedding = Some(EmbeddingAnalysis { rng: SmallRng::seed_from_u64(config.seed), config, start: runtime.clock.now(), ticker });
+			self.request_route_coords();
+		}
+		Ok(())
+	}
+	/// Ask every running device for its current route coordinate, answers arrive as machine info
+	fn request_route_coords(&self) {
+		for (idx, node) in self.nodes.iter() {
+			let machine = match node.machine() { Some(machine) if machine.is_running() => machine, _ => continue };
+			if let Err(err) = machine.request_machine_info() { log::debug!("Embedding analysis: no route coordinate from machine {}: {}", idx, err); }
+		}
+	}
+	pub(crate) fn embedding_tick(&mut self, runtime: &mut InternetRuntime) -> Result<(), InternetError> {
+		let mut analysis = match runtime.embedding.take() { Some(analysis) => analysis, None => return Ok(()) };
+		let result = self.analyze_embedding(runtime, &mut analysis);
+		runtime.embedding = Some(analysis);
+		// Coordinates reported until the next tick are analysed then
+		self.request_route_coords();
+		result
+	}
+	fn analyze_embedding(&self, runtime: &mut InternetRuntime, analysis: &mut EmbeddingAnalysis) -> Result<(), InternetError> {
+		let config = &analysis.config;
+		let coords = self.reported_route_coords(runtime);
+		let mut machines: Vec<NodeIdx> = coords.keys().copied().collect();
+		machines.sort();
+
+		// Round-trip times of all pairs that can reach each other, keyed by the lower index first
+		let mut paths = self.shortest_paths()?;
+		let model = self.latency_model.model();
+		let mut rtts = HashMap::new();
+		let mut pairs = Vec::new();
+		for (i, &a) in machines.iter().enumerate() {
+			for &b in &machines[i + 1..] {
+				let latency = match config.rtt_source {
+					RttSource::ShortestPath => paths.latency(a, b),
+					RttSource::LatencyModel => Some(model.latency(&self.node(a)?.position, &self.node(b)?.position)),
+				};
+				let rtt = match latency { Some(latency) if latency > 0 => 2.0 * latency as f64, _ => continue };
+				rtts.insert((a, b), rtt);
+				pairs.push((route_distance(&coords[&a], &coords[&b]), rtt));
+			}
+		}
+		let fitted_scale = fit_scale(&pairs);
+		let errors = relative_errors(&pairs, fitted_scale.unwrap_or(0.0));
+		let percentile = |p: f64|(!errors.is_empty()).then(||errors[((errors.len() - 1) as f64 * p).round() as usize]);
+
+		let mut triangles = 0;
+		let mut violations = Vec::new();
+		if machines.len() >= 3 {
+			for _ in 0..config.triangle_samples {
+				let mut triple: Vec<NodeIdx> = machines.choose_multiple(&mut analysis.rng, 3).copied().collect();
+				triple.sort();
+				let sides = [(triple[0], triple[1]), (triple[1], triple[2]), (triple[0], triple[2])].map(|pair|rtts.get(&pair).copied());
+				let sides = match sides { [Some(a), Some(b), Some(c)] => [a, b, c], _ => continue };
+				triangles += 1;
+				violations.extend(triangle_violation(sides));
+			}
+		}
+
+		let report = EmbeddingReport {
+			time: runtime.clock.now().saturating_sub(analysis.start),
+			pairs: errors.len(),
+			fitted_scale,
+			mean_error: (!errors.is_empty()).then(||errors.iter().sum::<f64>() / errors.len() as f64),
+			median_error: percentile(0.5),
+			p90_error: percentile(0.9),
+			cdf: (0..=20).filter_map(|step|{ let quantile = step as f64 / 20.0; Some((quantile, percentile(quantile)?)) }).collect(),
+			triangles,
+			violations: violations.len(),
+			mean_violation: (!violations.is_empty()).then(||violations.iter().sum::<f64>() / violations.len() as f64),
+		};
+		log::info!("Embedding error over {} pairs at {:?} (scale {:?}us per unit): median {:?}, p90 {:?}, {} of {} triangles violated", report.pairs, report.time, report.fitted_scale, report.median_error, report.p90_error, report.violations, report.triangles);
+		if let Some(prefix) = &config.csv_prefix { report.append_csv(prefix).context("failed to write embedding analysis")?; }
+		runtime.send_event(InternetEvent::Embedding(runtime.clock.now(), report))
+	}
+}
+
+/// Scale minimizing the squared differences between scaled coordinate distances and round-trip times of (distance, rtt) pairs
+fn fit_scale(pairs: &[(f64, f64)]) -> Option<f64> {
+	let (products, squares) = pairs.iter().fold((0.0, 0.0), |(products, squares), (distance, rtt)|(products + distance * rtt, squares + distance * distance));
+	(squares > 0.0).then(||products / squares)
+}
+
+/// Error of every scaled distance relative to its round-trip time, sorted
+fn relative_errors(pairs: &[(f64, f64)], scale: f64) -> Vec<f64> {
+	let mut errors: Vec<f64> = pairs.iter().map(|(distance, rtt)|(distance * scale - rtt).abs() / rtt).collect();
+	errors.sort_by(f64::total_cmp);
+	errors
+}
+
+/// How many times longer than the other two together the longest side of a triangle is, None if it isn't
+fn triangle_violation(mut sides: [f64; 3]) -> Option<f64> {
+	sides.sort_by(f64::total_cmp);
+	(sides[2] > sides[0] + sides[1]).then(||sides[2] / (sides[0] + sides[1]))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn scale_fits_round_trip_times() {
+		// Distances predicting round-trip times exactly at 2us per unit
+		let exact = [(1.0, 2.0), (5.0, 10.0), (20.0, 40.0)];
+		assert_eq!(fit_scale(&exact), Some(2.0));
+		assert_eq!(relative_errors(&exact, 2.0), vec![0.0; 3]);
+
+		// Same distance, round-trip times off in both directions
+		let noisy = [(1.0, 3.0), (1.0, 1.0)];
+		assert_eq!(fit_scale(&noisy), Some(2.0));
+		assert_eq!(relative_errors(&noisy, 2.0), vec![1.0 / 3.0, 1.0]);
+
+		// Coinciding coordinates predict nothing
+		assert_eq!(fit_scale(&[(0.0, 2.0), (0.0, 4.0)]), None);
+		assert_eq!(relative_errors(&[(0.0, 2.0)], 0.0), vec![1.0]);
+		assert_eq!(fit_scale(&[]), None);
+	}
+
+	#[test]
+	fn triangle_violations() {
+		assert_eq!(triangle_violation([1.0, 1.0, 3.0]), Some(1.5));
+		assert_eq!(triangle_violation([3.0, 1.0, 1.0]), Some(1.5));
+		assert_eq!(triangle_violation([3.0, 4.0, 5.0]), None);
+		// Degenerate triangles don't violate the inequality
+		assert_eq!(triangle_violation([1.0, 2.0, 3.0]), None);
+	}
+}
@@ -0,0 +1,354 @@
+//! Per-network firewall.
+//! An ordered list of rules is matched against every packet crossing the network's border through one of its uplinks, the first matching rule decides what happens to it.
+//! Inbound packets are entering the network from another network, outbound packets are leaving it. Traffic between machines of the same network isn't filtered.
+//! Transit traffic routed through the network is filtered once, when it enters.
+//! IPv6 packets pass through the same rules, but rules with an address range only ever match IPv4 packets.
+
+use std::net::{IpAddr, Ipv4Addr};
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex};
+
+use netsim_embed::Ipv4Range;
+
+use super::netsim_ext::{Direction, PacketStage, Verdict};
+use super::ipv6::Ipv6Range;
+use super::packet::{self, Ipv6Header, PacketHeader, Protocol};
+
+/// ICMP Destination Unreachable code: Communication Administratively Prohibited
+const ICMP_ADMIN_PROHIBITED: u8 = 13;
+/// ICMPv6 Destination Unreachable code: Communication with destination administratively prohibited
+const ICMP6_ADMIN_PROHIBITED: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FirewallAction {
+	/// Let packet through
+	Allow,
+	/// Silently drop packet
+	Drop,
+	/// Drop packet and notify sender with an ICMP unreachable message
+	Reject,
+}
+
+/// Firewall rule, fields that are None match anything
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FirewallRule {
+	pub direction: Option<Direction>,
+	pub src: Option<Ipv4Range>,
+	pub dst: Option<Ipv4Range>,
+	pub protocol: Option<Protocol>,
+	/// Destination port range, only matches TCP & UDP packets
+	pub ports: Option<RangeInclusive<u16>>,
+	pub action: FirewallAction,
+}
+impl FirewallRule {
+	pub fn matches(&self, direction: Direction, header: &PacketHeader) -> bool {
+		self.src.map_or(true, |range|range.contains(header.src))
+		&& self.dst.map_or(true, |range|range.contains(header.dst))
+		&& self.matches_transport(direction, header.protocol, header.ports)
+	}
+	/// Address ranges are IPv4, so only rules without them can match an IPv6 packet
+	pub fn matches_ipv6(&self, direction: Direction, header: &Ipv6Header) -> bool {
+		self.src.is_none() && self.dst.is_none() && self.matches_transport(direction, header.protocol, header.ports)
+	}
+	fn matches_transport(&self, direction: Direction, protocol: Protocol, ports: Option<(u16, u16)>) -> bool {
+		self.direction.map_or(true, |d|d == direction)
+		&& self.protocol.map_or(true, |p|p == protocol)
+		&& self.ports.as_ref().map_or(true, |range|ports.map_or(false, |(_, dst_port)|range.contains(&dst_port)))
+	}
+}
+
+/// Ordered firewall rule list, saved with the network
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Firewall {
+	pub rules: Vec<FirewallRule>,
+	/// Action taken when no rule matches
+	pub default_action: FirewallAction,
+}
+impl Default for Firewall {
+	fn default() -> Self {
+		Firewall { rules: Vec::new(), default_action: FirewallAction::Allow }
+	}
+}
+
+/// Modification of a network's firewall
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum FirewallEdit {
+	/// Insert rule at position in rule list
+	Insert(usize, FirewallRule),
+	/// Remove rule at position
+	Remove(usize),
+	/// Replace all rules
+	Replace(Vec<FirewallRule>),
+	SetDefault(FirewallAction),
+}
+
+#[derive(Debug, Error)]
+pub enum FirewallError {
+	#[error("Firewall rule index {0} is out of bounds")]
+	InvalidRuleIndex(usize),
+}
+
+impl Firewall {
+	pub fn apply(&mut self, edit: FirewallEdit) -> Result<(), FirewallError> {
+		match edit {
+			FirewallEdit::Insert(index, rule) => {
+				if index > self.rules.len() { return Err(FirewallError::InvalidRuleIndex(index)) }
+				self.rules.insert(index, rule);
+			}
+			FirewallEdit::Remove(index) => {
+				if index >= self.rules.len() { return Err(FirewallError::InvalidRuleIndex(index)) }
+				self.rules.remove(index);
+			}
+			FirewallEdit::Replace(rules) => self.rules = rules,
+			FirewallEdit::SetDefault(action) => self.default_action = action,
+		}
+		Ok(())
+	}
+}
+
+/// Number of packets each rule has matched
+#[derive(Debug, Clone, Default)]
+pub struct FirewallStats {
+	/// Hits per rule, in rule order
+	pub rule_hits: Vec<u64>,
+	/// Packets that didn't match any rule
+	pub default_hits: u64,
+}
+
+/// Firewall as seen by the running packet stages, shared between all connections of a network
+#[derive(Debug)]
+pub struct FirewallState {
+	firewall: Firewall,
+	stats: FirewallStats,
+	/// Address ICMP rejections are sent from
+	gateway: Ipv4Addr,
+	/// Ranges the network's own machines are addressed from, outbound packets from anywhere else are transit traffic
+	local: Vec<Ipv4Range>,
+	/// IPv6 range of the network, ICMPv6 rejections are sent from its gateway
+	local6: Ipv6Range,
+}
+impl FirewallState {
+	pub fn new(firewall: Firewall, gateway: Ipv4Addr, local: Vec<Ipv4Range>, local6: Ipv6Range) -> Self {
+		let stats = FirewallStats { rule_hits: vec![0; firewall.rules.len()], default_hits: 0 };
+		FirewallState { firewall, stats, gateway, local, local6 }
+	}
+	/// Replace firewall, resets hit counts
+	pub fn set_firewall(&mut self, firewall: Firewall) {
+		*self = FirewallState::new(firewall, self.gateway, std::mem::take(&mut self.local), self.local6);
+	}
+	/// Transit packets leaving the network were filtered when they entered it
+	fn is_transit(&self, direction: Direction, src: IpAddr) -> bool {
+		direction == Direction::Outbound && match src {
+			IpAddr::V4(src) => !self.local.iter().any(|range|range.contains(src)),
+			IpAddr::V6(src) => !self.local6.contains(src),
+		}
+	}
+	pub fn stats(&self) -> &FirewallStats { &self.stats }
+
+	/// Action of the first rule matching a packet
+	fn decide(&mut self, matches: impl Fn(&FirewallRule) -> bool) -> FirewallAction {
+		match self.firewall.rules.iter().position(matches) {
+			Some(index) => {
+				self.stats.rule_hits[index] += 1;
+				self.firewall.rules[index].action
+			}
+			None => {
+				self.stats.default_hits += 1;
+				self.firewall.default_action
+			}
+		}
+	}
+	/// ICMP or ICMPv6 message rejecting a packet
+	fn rejection(&self, packet: &[u8]) -> Option<Vec<u8>> {
+		match packet.first()? >> 4 {
+			4 => packet::icmp_unreachable(packet, self.gateway, ICMP_ADMIN_PROHIBITED),
+			_ => packet::icmp6_unreachable(packet, self.local6.gateway_addr(), ICMP6_ADMIN_PROHIBITED),
+		}
+	}
+}
+
+/// Packet stage placed on every uplink of both of a network's routers
+pub struct FirewallStage {
+	state: Arc<Mutex<FirewallState>>,
+}
+impl FirewallStage {
+	pub fn new(state: Arc<Mutex<FirewallState>>) -> Self { FirewallStage { state } }
+}
+impl PacketStage for FirewallStage {
+	fn process(&mut self, direction: Direction, packet: Vec<u8>) -> Verdict {
+		let mut state = self.state.lock().expect("firewall lock poisoned");
+		let action = if let Some(header) = PacketHeader::parse(&packet) {
+			if state.is_transit(direction, header.src.into()) { return Verdict::Forward(packet) }
+			state.decide(|rule|rule.matches(direction, &header))
+		} else if let Some(header) = Ipv6Header::parse(&packet) {
+			if state.is_transit(direction, header.src.into()) { return Verdict::Forward(packet) }
+			state.decide(|rule|rule.matches_ipv6(direction, &header))
+		} else { return Verdict::Forward(packet) };
+		match action {
+			FirewallAction::Allow => Verdict::Forward(packet),
+			FirewallAction::Drop => Verdict::Drop,
+			FirewallAction::Reject => match state.rejection(&packet) {
+				Some(response) => Verdict::Reply(response),
+				None => Verdict::Drop,
+			},
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::net::{Ipv6Addr, SocketAddrV4};
+
+	const GATEWAY: Ipv4Addr = Ipv4Addr::new(100, 64, 0, 1);
+	const LOCAL: Ipv4Addr = Ipv4Addr::new(100, 64, 0, 2);
+	const REMOTE: Ipv4Addr = Ipv4Addr::new(100, 65, 0, 2);
+
+	fn local6() -> Ipv6Range { Ipv6Range::new(Ipv6Addr::new(0xfd00, 0xd17e, 0, 1, 0, 0, 0, 0), 64) }
+	fn stage(firewall: Firewall) -> (FirewallStage, Arc<Mutex<FirewallState>>) {
+		let state = Arc::new(Mutex::new(FirewallState::new(firewall, GATEWAY, vec![Ipv4Range::new(Ipv4Addr::new(100, 64, 0, 0), 16)], local6())));
+		(FirewallStage::new(state.clone()), state)
+	}
+	fn rule(protocol: Option<Protocol>, ports: Option<RangeInclusive<u16>>, action: FirewallAction) -> FirewallRule {
+		FirewallRule { direction: None, src: None, dst: None, protocol, ports, action }
+	}
+	fn udp(src: Ipv4Addr, dst: Ipv4Addr, port: u16) -> Vec<u8> {
+		packet::udp_packet(SocketAddrV4::new(src, 4000), SocketAddrV4::new(dst, port), b"data").unwrap()
+	}
+	fn udp6(src: Ipv6Addr, dst: Ipv6Addr, port: u16) -> Vec<u8> {
+		let mut packet = vec![0u8; 40 + 8];
+		packet[0] = 0x60;
+		packet[4..6].copy_from_slice(&8u16.to_be_bytes());
+		packet[6] = 17;
+		packet[7] = 64;
+		packet[8..24].copy_from_slice(&src.octets());
+		packet[24..40].copy_from_slice(&dst.octets());
+		packet[40..42].copy_from_slice(&4000u16.to_be_bytes());
+		packet[42..44].copy_from_slice(&port.to_be_bytes());
+		packet
+	}
+	fn forwarded(verdict: &Verdict) -> bool { matches!(verdict, Verdict::Forward(_)) }
+	/// Internet checksum over data (and a pseudo header sum) adds up
+	fn checksum_valid(data: &[u8], initial: u32) -> bool {
+		let mut sum = data.chunks(2).fold(initial, |sum, chunk|sum + u16::from_be_bytes([chunk[0], *chunk.get(1).unwrap_or(&0)]) as u32);
+		while sum >> 16 != 0 { sum = (sum & 0xffff) + (sum >> 16); }
+		sum == 0xffff
+	}
+
+	#[test]
+	fn first_matching_rule_decides() {
+		let (mut stage, state) = stage(Firewall {
+			rules: vec![
+				rule(Some(Protocol::Udp), Some(80..=80), FirewallAction::Allow),
+				rule(Some(Protocol::Udp), None, FirewallAction::Drop),
+				rule(Some(Protocol::Udp), Some(80..=90), FirewallAction::Drop),
+			],
+			default_action: FirewallAction::Allow,
+		});
+		assert!(forwarded(&stage.process(Direction::Inbound, udp(REMOTE, LOCAL, 80))));
+		assert!(!forwarded(&stage.process(Direction::Inbound, udp(REMOTE, LOCAL, 85))));
+		assert!(!forwarded(&stage.process(Direction::Outbound, udp(LOCAL, REMOTE, 22))));
+		// ICMP doesn't match any UDP rule
+		let icmp = packet::icmp_unreachable(&udp(REMOTE, LOCAL, 80), REMOTE, 3).unwrap();
+		assert!(forwarded(&stage.process(Direction::Inbound, icmp)));
+
+		let stats = state.lock().unwrap().stats().clone();
+		// Third rule is shadowed by the second
+		assert_eq!(stats.rule_hits, vec![1, 2, 0]);
+		assert_eq!(stats.default_hits, 1);
+	}
+
+	#[test]
+	fn rule_fields() {
+		let inbound_only = FirewallRule { direction: Some(Direction::Inbound), ..rule(None, None, FirewallAction::Drop) };
+		let header = PacketHeader::parse(&udp(REMOTE, LOCAL, 80)).unwrap();
+		assert!(inbound_only.matches(Direction::Inbound, &header));
+		assert!(!inbound_only.matches(Direction::Outbound, &header));
+
+		let from_remote = FirewallRule { src: Some(Ipv4Range::new(Ipv4Addr::new(100, 65, 0, 0), 16)), ..rule(None, None, FirewallAction::Drop) };
+		assert!(from_remote.matches(Direction::Inbound, &header));
+		let to_remote = FirewallRule { dst: Some(Ipv4Range::new(Ipv4Addr::new(100, 65, 0, 0), 16)), ..rule(None, None, FirewallAction::Drop) };
+		assert!(!to_remote.matches(Direction::Inbound, &header));
+
+		// Port rules never match packets without ports
+		let icmp = packet::icmp_unreachable(&udp(REMOTE, LOCAL, 80), REMOTE, 3).unwrap();
+		assert!(!rule(None, Some(0..=u16::MAX), FirewallAction::Drop).matches(Direction::Inbound, &PacketHeader::parse(&icmp).unwrap()));
+
+		// Rules with address ranges only match IPv4
+		let header6 = Ipv6Header::parse(&udp6(Ipv6Addr::LOCALHOST, local6().gateway_addr(), 80)).unwrap();
+		assert!(!from_remote.matches_ipv6(Direction::Inbound, &header6));
+		assert!(rule(Some(Protocol::Udp), Some(80..=80), FirewallAction::Drop).matches_ipv6(Direction::Inbound, &header6));
+	}
+
+	#[test]
+	fn transit_traffic_filtered_once() {
+		let (mut stage, state) = stage(Firewall { rules: Vec::new(), default_action: FirewallAction::Drop });
+		// Entering the network is filtered, leaving it again isn't
+		assert!(!forwarded(&stage.process(Direction::Inbound, udp(REMOTE, Ipv4Addr::new(100, 66, 0, 2), 80))));
+		assert!(forwarded(&stage.process(Direction::Outbound, udp(REMOTE, Ipv4Addr::new(100, 66, 0, 2), 80))));
+		// Machines of the network are filtered on their way out
+		assert!(!forwarded(&stage.process(Direction::Outbound, udp(LOCAL, REMOTE, 80))));
+
+		let remote6 = Ipv6Addr::new(0xfd00, 0xd17e, 0, 2, 0, 0, 0, 2);
+		assert!(forwarded(&stage.process(Direction::Outbound, udp6(remote6, Ipv6Addr::LOCALHOST, 80))));
+		assert!(!forwarded(&stage.process(Direction::Outbound, udp6(local6().gateway_addr(), remote6, 80))));
+
+		let state = state.lock().unwrap();
+		assert!(state.is_transit(Direction::Outbound, REMOTE.into()));
+		assert!(!state.is_transit(Direction::Inbound, REMOTE.into()));
+		assert!(!state.is_transit(Direction::Outbound, LOCAL.into()));
+		assert_eq!(state.stats().default_hits, 3);
+	}
+
+	#[test]
+	fn reject_with_icmp() {
+		let (mut stage, _) = stage(Firewall { rules: Vec::new(), default_action: FirewallAction::Reject });
+		let original = udp(REMOTE, LOCAL, 80);
+		let reply = match stage.process(Direction::Inbound, original.clone()) { Verdict::Reply(reply) => reply, _ => panic!("expected ICMP reply") };
+		let header = PacketHeader::parse(&reply).unwrap();
+		assert_eq!((header.protocol, header.src, header.dst), (Protocol::Icmp, GATEWAY, REMOTE));
+		// Destination unreachable, administratively prohibited, quoting the original header and 8 bytes of payload
+		assert_eq!(reply[20..22], [3, ICMP_ADMIN_PROHIBITED]);
+		assert_eq!(reply[28..], original[..28]);
+		assert!(checksum_valid(&reply[..20], 0));
+		assert!(checksum_valid(&reply[20..], 0));
+
+		// ICMP is never answered with ICMP
+		assert!(matches!(stage.process(Direction::Inbound, reply), Verdict::Drop));
+	}
+
+	#[test]
+	fn reject_with_icmp6() {
+		let (mut stage, _) = stage(Firewall { rules: Vec::new(), default_action: FirewallAction::Reject });
+		let remote6 = Ipv6Addr::new(0xfd00, 0xd17e, 0, 2, 0, 0, 0, 2);
+		let original = udp6(remote6, local6().gateway_addr(), 80);
+		let reply = match stage.process(Direction::Inbound, original.clone()) { Verdict::Reply(reply) => reply, _ => panic!("expected ICMPv6 reply") };
+		let header = Ipv6Header::parse(&reply).unwrap();
+		assert_eq!((header.protocol, header.src, header.dst), (Protocol::Icmp, local6().gateway_addr(), remote6));
+		assert_eq!(reply[40..42], [1, ICMP6_ADMIN_PROHIBITED]);
+		assert_eq!(reply[48..], original[..]);
+		let icmp_len = reply.len() - 40;
+		assert_eq!(u16::from_be_bytes([reply[4], reply[5]]) as usize, icmp_len);
+		let pseudo_header = reply[8..40].chunks(2).map(|word|u16::from_be_bytes([word[0], word[1]]) as u32).sum::<u32>() + icmp_len as u32 + 58;
+		assert!(checksum_valid(&reply[40..], pseudo_header));
+
+		assert!(matches!(stage.process(Direction::Inbound, reply), Verdict::Drop));
+	}
+
+	#[test]
+	fn edits_reset_hits() {
+		let mut firewall = Firewall::default();
+		assert!(firewall.apply(FirewallEdit::Insert(1, rule(None, None, FirewallAction::Drop))).is_err());
+		assert!(firewall.apply(FirewallEdit::Remove(0)).is_err());
+		firewall.apply(FirewallEdit::Insert(0, rule(None, None, FirewallAction::Drop))).unwrap();
+		firewall.apply(FirewallEdit::Insert(0, rule(Some(Protocol::Tcp), None, FirewallAction::Allow))).unwrap();
+		assert_eq!(firewall.rules[0].protocol, Some(Protocol::Tcp));
+
+		let (mut stage, state) = stage(firewall.clone());
+		stage.process(Direction::Inbound, udp(REMOTE, LOCAL, 80));
+		assert_eq!(state.lock().unwrap().stats().rule_hits, vec![0, 1]);
+		firewall.apply(FirewallEdit::Remove(0)).unwrap();
+		state.lock().unwrap().set_firewall(firewall);
+		assert_eq!(state.lock().unwrap().stats().rule_hits, vec![0]);
+	}
+}
@@ -1,16 +1,26 @@
-use std::{net::Ipv4Addr, time::Duration};
+use std::{collections::HashSet, net::{Ipv4Addr, Ipv6Addr}, sync::{Arc, Mutex}, time::Duration};
 
 use async_std::task::{self, JoinHandle};
-use device::{Address, DeviceCommand, DeviceEvent, DitherCommand};
+use device::{Address, DeviceCommand, DitherCommand};
 use futures::{SinkExt, StreamExt, channel::mpsc};
 use nalgebra::Vector2;
-use netsim_embed::{Ipv4Range, Ipv4Route, Ipv4Router, Machine, MachineId, Plug};
+use netsim_embed::{Ipv4Range, Ipv4Route, Ipv4Router, Plug};
+use nix::{errno::Errno, sys::signal::Signal};
 use node::{NodeID, RouteCoord};
 use slotmap::SecondaryMap;
 
 use crate::internet::{InternetAction, InternetRuntime, InternetError, NodeIdx, WireIdx};
 
-use super::netsim_ext::{Wire, WireHandle};
+use super::addressing::AddressPool;
+use super::firewall::{Firewall, FirewallEdit, FirewallError, FirewallStage, FirewallState, FirewallStats};
+use super::nat::{self, NatConfig, NatError, NatStage, NatTable};
+use super::trace::WireTrace;
+use super::mobility::Mobility;
+use super::ipv6::{self, IpStack, Ipv6AddressPool, Ipv6Range, Ipv6Router};
+use super::routing::{ConnectionRoutes, Rib, RouteTableEntry};
+use super::netsim_ext::{PacketStage, Wire, WireHandle};
+use super::clock::SimClock;
+use super::devices::{Device, DeviceConfig, DeviceRegistry};
 
 pub type FieldPosition = Vector2<i32>;
 /// Measured in milliseconds
@@ -19,27 +29,100 @@ pub type Latency = u64;
 /// Default internal latency (measured in millilightseconds)
 pub const DEFAULT_INTERNAL_LATENCY: Latency = 20;
 
+/// Latency overrides of a wire, directions without an override use the Internet's latency model
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct WireLatency {
+	pub a_to_b: Option<Latency>,
+	pub b_to_a: Option<Latency>,
+}
+/// Why a wire is blackholed, a wire stays suspended until every reason is lifted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum SuspendReason {
+	/// Wire crosses the boundary of a network partition
+	Partition,
+	/// Wire was failed by the chaos engine
+	Chaos,
+	/// Wire leads to a powered off machine
+	PoweredOff,
+}
+/// Wire between two nodes, node `a` is connected to plug A of the running [`Wire`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InternetWire {
+	pub a: NodeIdx,
+	pub b: NodeIdx,
+	pub latency: WireLatency,
+	/// Trace driving the wire's latency, takes precedence over the latency model but not over overrides
+	pub trace: Option<WireTrace>,
+	/// Most recent latency sampled from the trace
+	#[serde(skip)]
+	pub trace_latency: Option<Latency>,
+	/// Reasons the wire is currently dropping all packets
+	#[serde(skip)]
+	pub suspended: HashSet<SuspendReason>,
+}
+impl InternetWire {
+	pub fn new(a: NodeIdx, b: NodeIdx) -> Self {
+		InternetWire { a, b, latency: WireLatency::default(), trace: None, trace_latency: None, suspended: HashSet::new() }
+	}
+	pub fn nodes(&self) -> (NodeIdx, NodeIdx) { (self.a, self.b) }
+	/// Whether the wire currently carries packets
+	pub fn is_up(&self) -> bool { self.suspended.is_empty() }
+}
+
 pub enum MachineConnection {
 	Unconnected,
-	Connected(WireIdx, Ipv4Addr),
+	Connected(WireIdx, MachineAddrs),
+}
+/// Addresses leased to a machine by the network it is connected to
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct MachineAddrs {
+	pub v4: Option<Ipv4Addr>,
+	pub v6: Option<Ipv6Addr>,
+}
+/// What happens to a machine's device while the machine is powered off
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PowerOffMode {
+	/// Device process is killed and started from scratch when powered on again
+	Stop,
+	/// Device process is frozen (SIGSTOP) and keeps its state, to its peers it looks connected but unresponsive
+	Freeze,
+}
+impl Default for PowerOffMode {
+	fn default() -> Self { PowerOffMode::Freeze }
 }
 #[derive(Derivative, Serialize, Deserialize)]
 #[derivative(Debug)]
 pub struct InternetMachine {
 	pub id: NodeIdx,
 	internal_latency: Latency,
-	executable: String,
+	/// Device running on the machine
+	pub device: DeviceConfig,
 	pub save_path: Option<String>,
-	pub connection: Option<(WireIdx, NodeIdx, Ipv4Addr)>,
+	pub connection: Option<(WireIdx, NodeIdx, MachineAddrs)>,
+	/// How the machine moves around the field, if at all
+	pub mobility: Option<Mobility>,
+	/// Powered off machines keep their node and connection but their device doesn't run and their wire drops all packets
+	pub powered: bool,
+	pub power_off_mode: PowerOffMode,
 	#[serde(skip)]
 	#[derivative(Debug="ignore")]
 	runtime: Option<MachineRuntime>,
 }
 struct MachineRuntime {
-	machine: Machine<DeviceCommand, DeviceEvent>,
-	event_join_handle: JoinHandle<()>,
+	/// Running device, None while crashed
+	device: Option<RunningDevice>,
 	internal_wire_handle: WireHandle,
 	temp_init_plug: Option<Plug>, // Plug fetched by InternetRuntime when connections are being established during init()
+	clock: SimClock,
+	/// Builds and apps devices can be started with
+	registry: DeviceRegistry,
+	/// Seed of the Internet, in-process devices are seeded from it, the machine and the number of devices started before
+	seed: u64,
+	starts: u64,
+}
+struct RunningDevice {
+	device: Box<dyn Device>,
+	event_join_handle: JoinHandle<()>,
 }
 #[derive(Debug, Error)]
 pub enum MachineError {
@@ -51,47 +134,133 @@ pub enum MachineError {
 	AlreadyDisconnected,
 	#[error("Device Command Sender Closed")]
 	DeviceCommandSenderClosed,
+	#[error("Device is not running")]
+	DeviceNotRunning,
 	#[error("No Init Plug")]
 	NoInitPlug,
+	#[error("Failed to send {signal} to device process {pid}: {source}")]
+	SignalFailed { pid: u32, signal: Signal, source: Errno },
+	#[error("No in-process app registered as {0:?}")]
+	UnknownApp(String),
+	#[error("No device build registered as {0:?}")]
+	UnknownBuild(String),
+	#[error("Program on machine doesn't understand {0:?}")]
+	UnsupportedCommand(DeviceCommand),
+}
+
+/// Seed of a machine's device or in-process app after a number of earlier starts, shared by both backends.
+/// Each input goes through a SplitMix64 round, xor-ing them together would give e.g. machines 1 and 2 on their first and second start the same seed.
+pub(crate) fn machine_seed(seed: u64, machine: NodeIdx, starts: u64) -> u64 {
+	let mix = |value: u64|{
+		let mut z = value.wrapping_add(0x9e37_79b9_7f4a_7c15);
+		z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+		z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+		z ^ (z >> 31)
+	};
+	mix(mix(mix(seed) ^ machine.as_ffi() as u64) ^ starts)
 }
 
 impl InternetMachine {
-	pub async fn new(machine_id: NodeIdx, executable: String) -> Self {
+	pub async fn new(machine_id: NodeIdx, device: DeviceConfig) -> Self {
 		InternetMachine {
 			id: machine_id,
 			internal_latency: DEFAULT_INTERNAL_LATENCY,
-			executable,
+			device,
 			save_path: None,
 			connection: None,
+			mobility: None,
+			powered: true,
+			power_off_mode: PowerOffMode::default(),
 			runtime: None,
 		}
 	}
-	pub fn init(&mut self, mut internet_action_sender: mpsc::Sender<InternetAction>) {
+	pub fn init(&mut self, internet_action_sender: mpsc::Sender<InternetAction>, clock: SimClock, registry: DeviceRegistry, seed: u64) {
 		log::debug!("Initiating Machine: {}", self.id);
 		task::block_on(async move {
-			let (machine_internal_plug, netsim_machine_plug) = netsim_embed::wire();
-
-			let (machine, mut device_event_receiver)
-			 = Machine::new(MachineId(self.id.as_ffi()), netsim_machine_plug, async_process::Command::new(self.executable.clone())).await.take_rx();
-
-			let machine_id = self.id;
-			let event_join_handle = task::spawn(async move {
-				while let Some(device_event) = device_event_receiver.next().await {
-					if let Err(err) = internet_action_sender.send(InternetAction::HandleDeviceEvent(machine_id, device_event)).await {
-						log::error!("Internet Action Sender closed: {:?}", err); break;
-					}
-				}
-			});
+			let (machine_internal_plug, device_plug) = netsim_embed::wire();
+			// A machine whose device fails to start behaves like a crashed one
+			let device = match self.spawn_device(internet_action_sender, device_plug, &clock, &registry, self.device_seed(seed, 0)).await {
+				Ok(device) => Some(device),
+				Err(err) => { log::error!("Failed to start device of machine {}: {}", self.id, err); None }
+			};
 	
 			let (outgoing_plug, outgoing_internal_plug) = netsim_embed::wire();
-			let internal_wire_handle = Wire { delay: Duration::from_micros(self.internal_latency) }.connect(outgoing_internal_plug, machine_internal_plug);
+			let internal_wire_handle = Wire::symmetric(Duration::from_micros(self.internal_latency)).connect(clock.clone(), outgoing_internal_plug, machine_internal_plug);
 			self.runtime = Some(MachineRuntime {
-				machine,
-				event_join_handle,
+				device,
 				internal_wire_handle,
 				temp_init_plug: Some(outgoing_plug),
+				clock,
+				registry,
+				seed,
+				starts: 1,
 			});
-		})
+		});
+		if !self.powered {
+			if let Err(err) = self.power_off() { log::error!("Failed to power off machine {}: {}", self.id, err); }
+		}
+	}
+	/// Seed of the machine's device after a number of earlier starts
+	fn device_seed(&self, seed: u64, starts: u64) -> u64 {
+		machine_seed(seed, self.id, starts)
+	}
+	/// Start device on a plug and forward its events to the Internet
+	async fn spawn_device(&self, mut internet_action_sender: mpsc::Sender<InternetAction>, plug: Plug, clock: &SimClock, registry: &DeviceRegistry, seed: u64) -> Result<RunningDevice, MachineError> {
+		let addrs = self.connection.map(|(_, _, addrs)|addrs).unwrap_or_default();
+		let (device, mut device_event_receiver) = self.device.spawn(self.id, plug, addrs, clock, registry, seed).await?;
+
+		let machine_id = self.id;
+		let event_join_handle = task::spawn(async move {
+			while let Some(device_event) = device_event_receiver.next().await {
+				if let Err(err) = internet_action_sender.send(InternetAction::HandleDeviceEvent(machine_id, device_event)).await {
+					log::error!("Internet Action Sender closed: {:?}", err); break;
+				}
+			}
+		});
+		Ok(RunningDevice { device, event_join_handle })
+	}
+	/// Whether the device is running (i.e. the machine was initiated and hasn't crashed)
+	pub fn is_running(&self) -> bool {
+		self.runtime.as_ref().map_or(false, |runtime|runtime.device.is_some())
+	}
+	/// Stop device, losing all of its state. The machine stays connected but nothing answers until it is restarted.
+	pub fn crash(&mut self) -> Result<(), MachineError> {
+		let runtime = self.runtime()?;
+		let running = runtime.device.take().ok_or(MachineError::DeviceNotRunning)?;
+		// Packets sent to the machine while crashed go nowhere
+		let (unplugged, _) = netsim_embed::wire();
+		task::block_on(async {
+			runtime.internal_wire_handle.swap_plug_b(unplugged).await;
+			running.event_join_handle.cancel().await;
+		});
+		// Dropping the device stops it, device processes are killed
+		drop(running.device);
+		Ok(())
+	}
+	/// Start a fresh device for a crashed machine, powered off machines stay off
+	pub fn restart(&mut self, internet_action_sender: mpsc::Sender<InternetAction>) -> Result<(), MachineError> {
+		if self.is_running() || !self.powered { return Ok(()) }
+		let runtime = self.runtime()?;
+		let (clock, registry) = (runtime.clock.clone(), runtime.registry.clone());
+		let seed = self.device_seed(runtime.seed, runtime.starts);
+		runtime.starts += 1;
+		task::block_on(async {
+			let (machine_internal_plug, device_plug) = netsim_embed::wire();
+			let device = self.spawn_device(internet_action_sender, device_plug, &clock, &registry, seed).await?;
+			let runtime = self.runtime()?;
+			runtime.internal_wire_handle.swap_plug_b(machine_internal_plug).await;
+			runtime.device = Some(device);
+			Ok::<(), MachineError>(())
+		})?;
+		// In-process devices don't report a process id, so they are frozen here if the simulation is paused
+		self.update_frozen()
+	}
+	/// Switch machine to another device. A running device is replaced right away, losing its state, others start with the new device when restarted.
+	pub fn set_device(&mut self, device: DeviceConfig, internet_action_sender: mpsc::Sender<InternetAction>) -> Result<(), MachineError> {
+		self.device = device;
+		if !self.is_running() { return Ok(()) }
+		self.crash()?;
+		self.restart(internet_action_sender)
 	}
 	pub fn init_plug(&mut self) -> Result<Plug, MachineError> {
 		self.runtime()?.temp_init_plug.take().ok_or(MachineError::NoInitPlug)
@@ -110,19 +279,22 @@ impl InternetMachine {
 		}
 	}
 	pub fn device_command(&self, command: DeviceCommand) -> Result<(), MachineError> {
-		if let Some(runtime) = &self.runtime {
-			runtime.machine.tx.unbounded_send(command).map_err(|_|MachineError::DeviceCommandSenderClosed)
-		} else { Err(MachineError::NoRuntime) }
+		match &self.runtime {
+			Some(MachineRuntime { device: Some(running), .. }) => running.device.command(command),
+			Some(_) => Err(MachineError::DeviceNotRunning),
+			None => Err(MachineError::NoRuntime),
+		}
 	}
 	pub fn request_machine_info(&self) -> Result<(), MachineError> {
 		self.device_command(DeviceCommand::DitherCommand(DitherCommand::GetNodeInfo))
 	}
 
-	pub async fn connect(&mut self, wire_idx: WireIdx, node_idx: NodeIdx, ip_addr: Ipv4Addr) -> Result<Plug, MachineError> {
+	pub async fn connect(&mut self, wire_idx: WireIdx, node_idx: NodeIdx, addrs: MachineAddrs) -> Result<Plug, MachineError> {
 		if None == self.connection {
 			let (outgoing_plug, outgoing_internal_plug) = netsim_embed::wire();
 			self.runtime()?.internal_wire_handle.swap_plug_a(outgoing_internal_plug).await;
-			self.connection = Some((wire_idx, node_idx, ip_addr));
+			self.connection = Some((wire_idx, node_idx, addrs));
+			self.update_device_addresses();
 			Ok(outgoing_plug)
 		} else { Err(MachineError::AlreadyConnected) }
 	}
@@ -131,19 +303,92 @@ impl InternetMachine {
 		if let Some((wire_idx, _, _)) = self.connection { Some(wire_idx) } else { None }
 	}
 	pub fn connection_ip(&self) -> Option<Ipv4Addr> {
-		self.connection.map(|(_,_,ip)|ip)
+		self.connection.and_then(|(_,_,addrs)|addrs.v4)
 	}
+	pub fn connection_ip6(&self) -> Option<Ipv6Addr> {
+		self.connection.and_then(|(_,_,addrs)|addrs.v6)
+	}
+	/// Tell device to configure its IPv6 address, the device sets it up on its own interface
+	pub fn configure_ipv6(&self, range: Ipv6Range) -> Result<(), MachineError> {
+		match self.connection_ip6() {
+			Some(addr) => self.device_command(DeviceCommand::ConfigureIpv6 { addr, prefix: range.prefix_length() }),
+			None => Ok(()),
+		}
+	}
+	/// Disconnect machine, its internal wire is unplugged from the network so that the stale wire can shut down
 	pub fn disconnect(&mut self) -> Result<(), MachineError> {
-		if self.connection.is_some() { self.connection = None; Ok(()) }
-		else { Err(MachineError::AlreadyDisconnected) }
+		if self.connection.is_none() { return Err(MachineError::AlreadyDisconnected) }
+		self.connection = None;
+		self.update_device_addresses();
+		if let Some(runtime) = &mut self.runtime {
+			// Packets sent by the device while unconnected go nowhere
+			let (unplugged, _) = netsim_embed::wire();
+			task::block_on(runtime.internal_wire_handle.swap_plug_a(unplugged));
+		}
+		Ok(())
+	}
+	/// Record process id reported by the device, a device started while powered off or paused is frozen right away
+	pub fn set_pid(&mut self, pid: u32) -> Result<(), MachineError> {
+		if let Some(running) = self.runtime.as_mut().and_then(|runtime|runtime.device.as_mut()) { running.device.set_pid(pid); }
+		if !self.powered { self.power_off()?; }
+		self.update_frozen()
+	}
+	/// Power machine off or on, the machine's wire is suspended separately
+	pub fn set_powered(&mut self, powered: bool, internet_action_sender: mpsc::Sender<InternetAction>) -> Result<(), MachineError> {
+		if self.powered == powered { return Ok(()) }
+		self.powered = powered;
+		if powered {
+			self.update_frozen()?;
+			self.restart(internet_action_sender)
+		} else { self.power_off() }
+	}
+	fn power_off(&mut self) -> Result<(), MachineError> {
+		match self.power_off_mode {
+			PowerOffMode::Stop if self.is_running() => self.crash(),
+			PowerOffMode::Stop => Ok(()),
+			PowerOffMode::Freeze => self.set_frozen(true),
+		}
+	}
+	/// Whether the device process should be frozen, either because the simulation is paused or because the machine is powered off
+	fn should_freeze(&self) -> bool {
+		let paused = self.runtime.as_ref().map_or(false, |runtime|runtime.clock.is_paused());
+		paused || (!self.powered && self.power_off_mode == PowerOffMode::Freeze)
+	}
+	/// Freeze or thaw device process after the simulation was paused or resumed
+	pub fn update_frozen(&mut self) -> Result<(), MachineError> {
+		self.set_frozen(self.should_freeze())
+	}
+	/// Freeze or thaw device, crashed machines have nothing to freeze
+	fn set_frozen(&mut self, frozen: bool) -> Result<(), MachineError> {
+		match self.runtime.as_mut().and_then(|runtime|runtime.device.as_mut()) {
+			Some(running) => running.device.set_frozen(frozen),
+			None => Ok(()),
+		}
+	}
+	/// Tell device about the machine's current addresses
+	fn update_device_addresses(&mut self) {
+		let addrs = self.connection.map(|(_, _, addrs)|addrs).unwrap_or_default();
+		if let Some(running) = self.runtime.as_mut().and_then(|runtime|runtime.device.as_mut()) { running.device.set_addresses(addrs); }
+	}
+	/// Stop device process and internal wire for good, the machine must be disconnected first
+	pub fn shutdown(&mut self) {
+		if let Some(runtime) = self.runtime.take() {
+			task::block_on(async move {
+				if let Some(running) = runtime.device { running.event_join_handle.cancel().await; }
+				runtime.internal_wire_handle.force_disconnect().await;
+			});
+		}
 	}
 }
 
 #[derive(Debug, Clone)]
 pub struct NodeInfo {
 	pub position: FieldPosition,
+	/// Always set for networks
+	pub powered: bool,
 	pub internal_latency: Latency,
 	pub local_address: Option<Ipv4Addr>,
+	pub local_address6: Option<Ipv6Addr>,
 	pub node_type: NodeType,
 	pub connections: Vec<WireIdx>,
 }
@@ -157,6 +402,7 @@ pub struct MachineInfo {
 	pub remotes: usize,
 	pub active_remotes: usize,
 	pub network_ip: Option<Ipv4Addr>,
+	pub network_ip6: Option<Ipv6Addr>,
 }
 
 #[derive(Derivative, Serialize, Deserialize)]
@@ -164,15 +410,41 @@ pub struct MachineInfo {
 pub struct InternetNetwork {
 	pub id: NodeIdx,
     range: Ipv4Range,
-    devices: u32,
-	pub connections: SecondaryMap<WireIdx, (NodeIdx, Vec<Ipv4Route>)>,
+	range6: Ipv6Range,
+	/// IP versions connected machines get addresses for
+	stack: IpStack,
+	/// Addresses handed out to machines (from the private range if NAT is enabled)
+	addresses: AddressPool,
+	addresses6: Ipv6AddressPool,
+	/// Addresses leased to connected machines
+	leases: SecondaryMap<WireIdx, MachineAddrs>,
+	pub connections: SecondaryMap<WireIdx, NetworkConnection>,
+	/// NAT configuration, if set, machines get private addresses and are translated at uplinks
+	nat: Option<NatConfig>,
+	firewall: Firewall,
+	/// Routes learned by the routing protocol (if enabled)
+	#[serde(skip)]
+	pub rib: Rib,
 	#[serde(skip)]
 	#[derivative(Debug="ignore")]
 	runtime: Option<NetworkRuntime>,
 }
+/// Connection from a network's router to another node
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NetworkConnection {
+	/// Node on the other side of the wire
+	pub node: NodeIdx,
+	/// Whether the connection leads to another network (i.e. is an uplink)
+	pub uplink: bool,
+	/// Routes the routers send down this connection
+	pub routes: ConnectionRoutes,
+}
 pub struct NetworkRuntime {
     router: Ipv4Router,
+	router6: Ipv6Router,
 	temp_plugs: SecondaryMap<WireIdx, Plug>,
+	nat_table: Arc<Mutex<NatTable>>,
+	firewall: Arc<Mutex<FirewallState>>,
 }
 #[derive(Debug, Error)]
 pub enum NetworkError {
@@ -181,63 +453,204 @@ pub enum NetworkError {
 	#[error("Plug was not returned from Ipv4Router")]
 	NoReturnedPlug,
 	#[error("No Init Plug for {0}")]
-	NoInitPlug(WireIdx)
+	NoInitPlug(WireIdx),
+	#[error("No connection for {0}")]
+	UnknownConnection(WireIdx),
+	#[error("Can't change how machines are addressed (NAT or IP stack) while machines are connected")]
+	MachinesConnected,
+	#[error("No free addresses left in network")]
+	AddressesExhausted,
+	#[error(transparent)]
+	FirewallError(#[from] FirewallError),
+	#[error(transparent)]
+	NatError(#[from] NatError),
 }
 
 #[derive(Debug, Clone)]
 pub struct NetworkInfo {
 	pub ip_range: Ipv4Range,
+	pub ip_range6: Ipv6Range,
+	pub ip_stack: IpStack,
+	/// Number of addresses currently leased to machines
+	pub addresses_used: u32,
+	/// Number of addresses that can be leased in total
+	pub address_capacity: u32,
+	/// Number of IPv6 addresses currently leased to machines
+	pub addresses6_used: u64,
 	pub connections: Vec<NodeIdx>,
+	pub nat: Option<NatConfig>,
+	/// Number of active NAT mappings
+	pub nat_mappings: usize,
+	pub firewall: Firewall,
+	pub firewall_stats: FirewallStats,
+	/// Routes currently installed in the network's router
+	pub route_table: Vec<RouteTableEntry>,
 }
 
 impl InternetNetwork {
-	pub fn new(id: NodeIdx, range: Ipv4Range) -> Self {
+	pub fn new(id: NodeIdx, range: Ipv4Range, range6: Ipv6Range) -> Self {
 		Self {
-			id, range, devices: 0,
-			connections: SecondaryMap::<WireIdx, (NodeIdx, Vec<Ipv4Route>)>::default(),
+			id, range, range6,
+			stack: IpStack::default(),
+			addresses: AddressPool::new(range),
+			addresses6: Ipv6AddressPool::new(range6),
+			leases: SecondaryMap::default(),
+			connections: SecondaryMap::<WireIdx, NetworkConnection>::default(),
+			nat: None,
+			firewall: Firewall::default(),
+			rib: Rib::default(),
 			runtime: None,
 		}
 	}
-	pub fn init(&mut self) {
+	pub fn init(&mut self, clock: SimClock) {
 		log::debug!("Initiating Network: {}", self.id);
 
 		let router = Ipv4Router::new(self.range.gateway_addr());
-		let temp_plugs = self.connections.iter().map(|(wire_idx, (node_idx, routes))|{
-			let (router_plug, outgoing_plug) = netsim_embed::wire();
-			router.add_connection(node_idx.as_ffi(), router_plug, routes.clone());
-			(wire_idx, outgoing_plug)
-		}).collect();
-		self.runtime = Some(NetworkRuntime { router, temp_plugs });
+		let router6 = Ipv6Router::new(self.range6.gateway_addr());
+		let nat_table = Arc::new(Mutex::new(NatTable::new(self.nat.clone(), self.public_addr(), clock)));
+		let firewall = Arc::new(Mutex::new(FirewallState::new(self.firewall.clone(), self.range.gateway_addr(), vec![self.range, nat::private_range()], self.range6)));
+		let mut runtime = NetworkRuntime { router, router6, temp_plugs: SecondaryMap::default(), nat_table, firewall };
+		for (wire_idx, connection) in self.connections.iter() {
+			let plug = runtime.add_router_connection(wire_idx, connection);
+			runtime.temp_plugs.insert(wire_idx, plug);
+		}
+		self.runtime = Some(runtime);
 	}
 	pub fn init_plug(&mut self, wire_idx: WireIdx) -> Result<Plug, NetworkError> {
 		self.runtime()?.temp_plugs.remove(wire_idx).ok_or(NetworkError::NoInitPlug(wire_idx))
 	}
 	pub fn id(&self) -> NodeIdx { self.id }
 	pub fn local_addr(&self) -> Ipv4Addr { self.range.base_addr() }
+	pub fn local_addr6(&self) -> Ipv6Addr { self.range6.base_addr() }
+	/// Address machines behind this network appear as when NAT is enabled
+	pub fn public_addr(&self) -> Ipv4Addr { AddressPool::new(self.range).host(0) }
+	pub fn range(&self) -> Ipv4Range { self.range }
+	pub fn range6(&self) -> Ipv6Range { self.range6 }
 	pub fn route(&self) -> Ipv4Route { self.range.into() }
-	pub fn unique_addr(&mut self) -> Ipv4Addr {
-		let addr = self.range.address_for(self.devices);
-        self.devices += 1;
-		addr
+	pub fn nat(&self) -> Option<&NatConfig> { self.nat.as_ref() }
+	pub fn ip_stack(&self) -> IpStack { self.stack }
+	/// Set which IP versions machines are given addresses for, only possible while no machines are connected
+	pub fn set_ip_stack(&mut self, stack: IpStack) -> Result<(), NetworkError> {
+		if self.stack != stack && !self.leases.is_empty() { return Err(NetworkError::MachinesConnected) }
+		self.stack = stack;
+		Ok(())
+	}
+	/// Set NAT configuration, NAT can only be toggled while no machines are connected as their addresses would change.
+	pub fn set_nat(&mut self, nat: Option<NatConfig>) -> Result<(), NetworkError> {
+		if let Some(nat) = &nat { nat.validate()?; }
+		if self.nat.is_some() != nat.is_some() {
+			if !self.leases.is_empty() { return Err(NetworkError::MachinesConnected) }
+			self.addresses = AddressPool::new(if nat.is_some() { nat::private_range() } else { self.range });
+		}
+		self.nat = nat;
+		if let Some(runtime) = &self.runtime {
+			runtime.nat_table.lock().expect("NAT table lock poisoned").set_config(self.nat.clone());
+		}
+		Ok(())
+	}
+	pub fn firewall(&self) -> &Firewall { &self.firewall }
+	/// Edit firewall rules, running firewall is updated and hit counts are reset
+	pub fn edit_firewall(&mut self, edit: FirewallEdit) -> Result<(), NetworkError> {
+		self.firewall.apply(edit)?;
+		if let Some(runtime) = &self.runtime {
+			runtime.firewall.lock().expect("firewall lock poisoned").set_firewall(self.firewall.clone());
+		}
+		Ok(())
 	}
 	pub fn network_info(&self) -> NetworkInfo {
 		NetworkInfo {
-			connections: self.connections.iter().map(|(_, (id, _))|*id).collect(),
-			ip_range: self.range.clone()
+			connections: self.connections.iter().map(|(_, connection)|connection.node).collect(),
+			ip_range: self.range.clone(),
+			ip_range6: self.range6,
+			ip_stack: self.stack,
+			addresses_used: self.addresses.used(),
+			address_capacity: self.addresses.capacity(),
+			addresses6_used: self.addresses6.used(),
+			nat: self.nat.clone(),
+			nat_mappings: self.runtime.as_ref().map(|runtime|runtime.nat_table.lock().expect("NAT table lock poisoned").mappings()).unwrap_or(0),
+			firewall: self.firewall.clone(),
+			firewall_stats: self.runtime.as_ref().map(|runtime|runtime.firewall.lock().expect("firewall lock poisoned").stats().clone()).unwrap_or_default(),
+			route_table: self.route_table(),
 		}
 	}
+	fn route_table(&self) -> Vec<RouteTableEntry> {
+		self.connections.iter().filter(|(_, connection)|connection.uplink).flat_map(|(wire_idx, connection)|{
+			connection.routes.v4.iter().zip(connection.routes.v6.iter()).map(move |(route, &range6)|{
+				let range = route.dest();
+				let (path, latency, changes) = match self.rib.route_info(&range) {
+					Some((path, latency, changes)) => (Some(path), Some(latency), changes),
+					None => (None, None, 0),
+				};
+				RouteTableEntry { range, range6, wire: wire_idx, next_hop: connection.node, path, latency, changes }
+			})
+		}).collect()
+	}
 	pub fn runtime(&mut self) -> Result<&mut NetworkRuntime, NetworkError> {
 		self.runtime.as_mut().ok_or(NetworkError::NoRuntime)
 	}
-	pub fn connect(&mut self, wire_idx: WireIdx, node_id: NodeIdx, routes: Vec<Ipv4Route>) -> Result<Plug, NetworkError> {
-		let (router_plug, outgoing_plug) = netsim_embed::wire();
-		self.connections.insert(wire_idx, (node_id, routes.clone()));
-		self.runtime()?.router.add_connection(node_id.as_ffi(), router_plug, routes); Ok(outgoing_plug)
+	pub fn connect(&mut self, wire_idx: WireIdx, node_id: NodeIdx, uplink: bool, routes: ConnectionRoutes) -> Result<Plug, NetworkError> {
+		let connection = NetworkConnection { node: node_id, uplink, routes };
+		let outgoing_plug = self.runtime()?.add_router_connection(wire_idx, &connection);
+		self.connections.insert(wire_idx, connection);
+		Ok(outgoing_plug)
+	}
+	/// Connect machine, leasing it an address for each IP version of the network's stack
+	pub fn connect_machine(&mut self, wire_idx: WireIdx, machine_id: NodeIdx) -> Result<(Plug, MachineAddrs), NetworkError> {
+		let mut addrs = MachineAddrs::default();
+		let result: Result<Plug, NetworkError> = try {
+			if self.stack.ipv4() { addrs.v4 = Some(self.addresses.allocate().ok_or(NetworkError::AddressesExhausted)?); }
+			if self.stack.ipv6() { addrs.v6 = Some(self.addresses6.allocate().ok_or(NetworkError::AddressesExhausted)?); }
+			let routes = ConnectionRoutes::new(addrs.v4.into_iter().map(Ipv4Route::from).collect(), addrs.v6.into_iter().map(Ipv6Range::host).collect());
+			self.connect(wire_idx, machine_id, false, routes)?
+		};
+		match result {
+			Ok(plug) => {
+				self.leases.insert(wire_idx, addrs);
+				Ok((plug, addrs))
+			}
+			Err(err) => { self.release(addrs); Err(err) }
+		}
+	}
+	fn release(&mut self, addrs: MachineAddrs) {
+		if let Some(addr) = addrs.v4 { self.addresses.release(addr); }
+		if let Some(addr) = addrs.v6 { self.addresses6.release(addr); }
 	}
 	pub fn disconnect(&mut self, idx: WireIdx) -> Result<(), NetworkError> {
-		let (node_id, _) = self.connections[idx];
 		self.connections.remove(idx);
-		task::block_on(self.runtime()?.router.remove_connection(node_id.as_ffi())); Ok(())
+		if let Some(addrs) = self.leases.remove(idx) { self.release(addrs); }
+		let runtime = self.runtime()?;
+		task::block_on(runtime.router.remove_connection(idx.as_ffi()));
+		task::block_on(runtime.router6.remove_connection(idx.as_ffi()));
+		Ok(())
+	}
+	/// Replace routes of a connection, the connection is re-added to the router if they changed
+	pub fn set_routes(&mut self, wire_idx: WireIdx, routes: ConnectionRoutes) -> Result<(), NetworkError> {
+		let connection = self.connections.get_mut(wire_idx).ok_or(NetworkError::UnknownConnection(wire_idx))?;
+		if connection.routes.same_destinations(&routes) { return Ok(()) }
+		connection.routes = routes.clone();
+
+		let runtime = self.runtime()?;
+		let plug = task::block_on(runtime.router.remove_connection(wire_idx.as_ffi())).ok_or(NetworkError::NoReturnedPlug)?;
+		runtime.router.add_connection(wire_idx.as_ffi(), plug, routes.v4);
+		let plug = task::block_on(runtime.router6.remove_connection(wire_idx.as_ffi())).ok_or(NetworkError::NoReturnedPlug)?;
+		runtime.router6.add_connection(wire_idx.as_ffi(), plug, routes.v6);
+		Ok(())
+	}
+}
+
+impl NetworkRuntime {
+	/// Add connection to both routers, traffic of both IP versions on uplinks passes through the firewall, IPv4 traffic then through the NAT
+	fn add_router_connection(&mut self, wire_idx: WireIdx, connection: &NetworkConnection) -> Plug {
+		let (router_plug, outgoing_plug) = netsim_embed::wire();
+		self.router.add_connection(wire_idx.as_ffi(), router_plug, connection.routes.v4.clone());
+		let (router6_plug, outgoing6_plug) = netsim_embed::wire();
+		self.router6.add_connection(wire_idx.as_ffi(), router6_plug, connection.routes.v6.clone());
+
+		let (outgoing_plug, outgoing6_plug) = if connection.uplink {
+			let outgoing_plug = FirewallStage::new(self.firewall.clone()).attach(outgoing_plug);
+			(NatStage::new(self.nat_table.clone()).attach(outgoing_plug), FirewallStage::new(self.firewall.clone()).attach(outgoing6_plug))
+		} else { (outgoing_plug, outgoing6_plug) };
+		ipv6::dual_stack(outgoing_plug, outgoing6_plug)
 	}
 }
 
@@ -277,18 +690,20 @@ impl InternetNode {
 		}
 	}
 	pub fn node_info(&self) -> NodeInfo {
-		let (internal_latency, local_address, node_type) = match &self.variant {
+		let (internal_latency, local_address, local_address6, node_type) = match &self.variant {
 			NodeVariant::Network(network) => {
-				(Latency::MIN, Some(network.local_addr()), NodeType::Network)
+				(Latency::MIN, Some(network.local_addr()), Some(network.local_addr6()), NodeType::Network)
 			},
 			NodeVariant::Machine(machine) => {
-				(machine.latency(), machine.connection.map(|(_, _, addr)|addr), NodeType::Machine)
+				(machine.latency(), machine.connection_ip(), machine.connection_ip6(), NodeType::Machine)
 			},
 		};
 		NodeInfo {
 			position: self.position.clone(),
+			powered: self.machine().map_or(true, |machine|machine.powered),
 			internal_latency,
 			local_address,
+			local_address6,
 			node_type,
 			connections: match &self.variant {
 				NodeVariant::Machine(machine) => if let Some((wire_idx, _, _)) = machine.connection { vec![wire_idx] } else { vec![] },
@@ -309,9 +724,6 @@ impl InternetNode {
 		}
 		Ok(())
 	}
-	pub fn latency_distance(from: &FieldPosition, to: &FieldPosition) -> Latency {
-		from.map(|v|v as f64).metric_distance(&to.map(|v|v as f64)) as Latency
-	}
 	pub fn machine(&self) -> Option<&InternetMachine> {
 		match &self.variant { NodeVariant::Machine(m) => Some(m), _ => None }
 	}
@@ -324,23 +736,10 @@ impl InternetNode {
 	pub fn network_mut(&mut self) -> Option<&mut InternetNetwork> {
 		match &mut self.variant { NodeVariant::Network(n) => Some(n), _ => None }
 	}
-	pub async fn update_position(&mut self, runtime: &mut InternetRuntime, position: FieldPosition) -> Result<(), InternetError> {
+	/// Move node, the delays of its wires must be updated afterwards
+	pub fn update_position(&mut self, runtime: &mut InternetRuntime, position: FieldPosition) -> Result<(), InternetError> {
 		self.position = position;
 		*runtime.location(self.id)? = position;
-		match &mut self.variant {
-			NodeVariant::Network(network) => {
-				for (wire_idx, (node_idx, _)) in network.connections.iter() {
-					let latency = InternetNode::latency_distance(runtime.location(node_idx.clone())?, &position);
-					runtime.wire_handle(wire_idx)?.set_delay(Duration::from_micros(latency)).await;
-				}
-			}
-			NodeVariant::Machine(machine) => {
-				if let Some((wire_idx, node_idx, _)) = machine.connection {
-					let latency = InternetNode::latency_distance(runtime.location(node_idx)?, &position);
-					runtime.wire_handle(wire_idx)?.set_delay(Duration::from_micros(latency)).await;
-				}
-			}
-		}
 		Ok(())
 	}
 }
@@ -0,0 +1,340 @@
+//! IPv6 support for the simulated Internet.
+//! Every network runs an IPv6 router next to its IPv4 router, packets arriving on a connection are split between the two by IP version.
+
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::net::Ipv6Addr;
+
+use async_std::task::{self, JoinHandle};
+use futures::{StreamExt, channel::{mpsc, oneshot}, select};
+use netsim_embed::Plug;
+
+/// Prefix all network IPv6 ranges are allocated from (unique local addresses)
+const NETWORK_PREFIX: Ipv6Addr = Ipv6Addr::new(0xfd00, 0xd17e, 0, 0, 0, 0, 0, 0);
+const NETWORK_PREFIX_LEN: u8 = 32;
+/// Prefix length of the range each network gets
+pub const NETWORK_RANGE_PREFIX: u8 = 64;
+
+/// IPv6 CIDR range
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Ipv6Range {
+	addr: Ipv6Addr,
+	bits: u8,
+}
+impl Ipv6Range {
+	pub fn new(addr: Ipv6Addr, bits: u8) -> Self {
+		let bits = bits.min(128);
+		Ipv6Range { addr: Ipv6Addr::from(u128::from(addr) & Self::mask_for(bits)), bits }
+	}
+	/// Range containing a single address
+	pub fn host(addr: Ipv6Addr) -> Self { Ipv6Range::new(addr, 128) }
+	fn mask_for(bits: u8) -> u128 { if bits == 0 { 0 } else { u128::MAX << (128 - bits as u32) } }
+	pub fn base_addr(&self) -> Ipv6Addr { self.addr }
+	pub fn prefix_length(&self) -> u8 { self.bits }
+	pub fn gateway_addr(&self) -> Ipv6Addr { Ipv6Addr::from(u128::from(self.addr) + 1) }
+	pub fn contains(&self, addr: Ipv6Addr) -> bool {
+		u128::from(addr) & Self::mask_for(self.bits) == u128::from(self.addr)
+	}
+	pub fn overlaps(&self, other: &Ipv6Range) -> bool {
+		self.contains(other.addr) || other.contains(self.addr)
+	}
+}
+impl fmt::Display for Ipv6Range {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { write!(f, "{}/{}", self.addr, self.bits) }
+}
+
+/// Find lowest network range not used by any other network
+pub fn find_free_range<'a>(taken: impl Iterator<Item = &'a Ipv6Range> + Clone) -> Option<Ipv6Range> {
+	let subnets = 1u64 << (NETWORK_RANGE_PREFIX - NETWORK_PREFIX_LEN);
+	(0..subnets).map(|subnet|{
+		let addr = u128::from(NETWORK_PREFIX) | ((subnet as u128) << (128 - NETWORK_RANGE_PREFIX as u32));
+		Ipv6Range::new(Ipv6Addr::from(addr), NETWORK_RANGE_PREFIX)
+	}).find(|candidate|!taken.clone().any(|range|range.overlaps(candidate)))
+}
+
+/// Hands out IPv6 host addresses within a range, reusing released addresses
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ipv6AddressPool {
+	range: Ipv6Range,
+	next: u64,
+	free: BTreeSet<u64>,
+}
+impl Ipv6AddressPool {
+	pub fn new(range: Ipv6Range) -> Self {
+		Ipv6AddressPool { range, next: 0, free: BTreeSet::new() }
+	}
+	pub fn used(&self) -> u64 { self.next - self.free.len() as u64 }
+	/// Host address for an index, skipping network and gateway addresses
+	pub fn host(&self, index: u64) -> Ipv6Addr {
+		Ipv6Addr::from(u128::from(self.range.base_addr()) + 2 + index as u128)
+	}
+	pub fn allocate(&mut self) -> Option<Ipv6Addr> {
+		let capacity = (1u128 << (128 - self.range.prefix_length() as u32).min(127)).saturating_sub(2);
+		let index = match self.free.iter().next().copied() {
+			Some(index) => { self.free.remove(&index); index }
+			None if (self.next as u128) < capacity => { self.next += 1; self.next - 1 }
+			None => return None,
+		};
+		Some(self.host(index))
+	}
+	pub fn release(&mut self, addr: Ipv6Addr) {
+		let index = u128::from(addr).wrapping_sub(u128::from(self.range.base_addr()) + 2);
+		if index < self.next as u128 { self.free.insert(index as u64); }
+	}
+}
+
+/// Which IP versions machines connected to a network are given addresses for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum IpStack {
+	Ipv4Only,
+	Ipv6Only,
+	DualStack,
+}
+impl Default for IpStack {
+	fn default() -> Self { IpStack::Ipv4Only }
+}
+impl IpStack {
+	pub fn ipv4(&self) -> bool { matches!(self, IpStack::Ipv4Only | IpStack::DualStack) }
+	pub fn ipv6(&self) -> bool { matches!(self, IpStack::Ipv6Only | IpStack::DualStack) }
+}
+
+/// IP version of a packet, read from the first nibble
+fn ip_version(packet: &[u8]) -> Option<u8> { packet.first().map(|byte|byte >> 4) }
+
+/// Destination address of an IPv6 packet
+fn destination(packet: &[u8]) -> Option<Ipv6Addr> {
+	if packet.len() < 40 || ip_version(packet) != Some(6) { return None }
+	let mut octets = [0u8; 16];
+	octets.copy_from_slice(&packet[24..40]);
+	Some(Ipv6Addr::from(octets))
+}
+
+/// Connection with the longest route matching an address, ties go to the lowest connection id so forwarding doesn't depend on map order
+fn best_route<'a>(routes: impl Iterator<Item = (usize, &'a [Ipv6Range])>, dest: Ipv6Addr) -> Option<usize> {
+	routes.filter_map(|(id, routes)|{
+		routes.iter().filter(|range|range.contains(dest)).map(|range|range.prefix_length()).max().map(|len|(len, Reverse(id)))
+	}).max().map(|(_, Reverse(id))|id)
+}
+
+/// Join an IPv4 and an IPv6 router-facing plug into a single plug carrying both.
+/// Packets coming in through the returned plug are sent to the plug matching their IP version, anything else is dropped.
+pub fn dual_stack(ipv4_plug: Plug, ipv6_plug: Plug) -> Plug {
+	let (outgoing_plug, stack_plug) = netsim_embed::wire();
+	let (v4_tx, mut v4_rx) = ipv4_plug.split();
+	let (v6_tx, mut v6_rx) = ipv6_plug.split();
+	let (out_tx, mut out_rx) = stack_plug.split();
+	task::spawn(async move {
+		loop {
+			let result = select! {
+				packet = v4_rx.next() => if let Some(packet) = packet { out_tx.unbounded_send(packet) } else { break },
+				packet = v6_rx.next() => if let Some(packet) = packet { out_tx.unbounded_send(packet) } else { break },
+				packet = out_rx.next() => match packet {
+					Some(packet) => match ip_version(&packet) {
+						Some(4) => v4_tx.unbounded_send(packet),
+						Some(6) => v6_tx.unbounded_send(packet),
+						_ => Ok(()),
+					}
+					None => break,
+				},
+			};
+			if result.is_err() { break }
+		}
+	});
+	outgoing_plug
+}
+
+enum RouterCtrl {
+	AddConnection(usize, Plug, Vec<Ipv6Range>),
+	RemoveConnection(usize, oneshot::Sender<Option<Plug>>),
+}
+
+struct RouterConnection {
+	routes: Vec<Ipv6Range>,
+	tx: mpsc::UnboundedSender<Vec<u8>>,
+	stop: oneshot::Sender<()>,
+	/// Task forwarding incoming packets to the router, returns the receiving half when stopped
+	reader: JoinHandle<mpsc::UnboundedReceiver<Vec<u8>>>,
+}
+
+/// IPv6 counterpart of [`netsim_embed::Ipv4Router`], forwards packets down the connection with the longest matching route.
+pub struct Ipv6Router {
+	ctrl: mpsc::UnboundedSender<RouterCtrl>,
+}
+impl Ipv6Router {
+	pub fn new(addr: Ipv6Addr) -> Self {
+		let (ctrl, mut ctrl_rx) = mpsc::unbounded();
+		task::spawn(async move {
+			let (incoming_tx, mut incoming_rx) = mpsc::unbounded::<(usize, Vec<u8>)>();
+			let mut connections = HashMap::<usize, RouterConnection>::new();
+			loop {
+				select! {
+					ctrl = ctrl_rx.next() => match ctrl {
+						Some(RouterCtrl::AddConnection(id, plug, routes)) => {
+							let (tx, mut rx) = plug.split();
+							let (stop, mut stopped) = oneshot::channel::<()>();
+							let incoming_tx = incoming_tx.clone();
+							let reader = task::spawn(async move {
+								loop {
+									select! {
+										packet = rx.next() => match packet {
+											Some(packet) => if incoming_tx.unbounded_send((id, packet)).is_err() { break },
+											None => break,
+										},
+										_ = (&mut stopped) => break,
+									}
+								}
+								rx
+							});
+							connections.insert(id, RouterConnection { routes, tx, stop, reader });
+						}
+						Some(RouterCtrl::RemoveConnection(id, ret)) => {
+							let plug = match connections.remove(&id) {
+								Some(RouterConnection { tx, stop, reader, .. }) => {
+									let _ = stop.send(());
+									Some(Plug::join(tx, reader.await))
+								}
+								None => None,
+							};
+							let _ = ret.send(plug);
+						}
+						None => break,
+					},
+					incoming = incoming_rx.next() => if let Some((_, packet)) = incoming {
+						Self::forward(&connections, addr, packet);
+					},
+				}
+			}
+		});
+		Ipv6Router { ctrl }
+	}
+	fn forward(connections: &HashMap<usize, RouterConnection>, addr: Ipv6Addr, mut packet: Vec<u8>) {
+		let dest = match destination(&packet) { Some(dest) => dest, None => return };
+		if dest == addr { return }
+		// Decrement hop limit
+		if packet[7] <= 1 { return }
+		packet[7] -= 1;
+		let routes = connections.iter().map(|(&id, connection)|(id, connection.routes.as_slice()));
+		match best_route(routes, dest).and_then(|id|connections.get(&id)) {
+			Some(connection) => { let _ = connection.tx.unbounded_send(packet); }
+			None => log::debug!("Ipv6Router {}: no route to {}", addr, dest),
+		}
+	}
+	pub fn add_connection(&self, id: usize, plug: Plug, routes: Vec<Ipv6Range>) {
+		let _ = self.ctrl.unbounded_send(RouterCtrl::AddConnection(id, plug, routes));
+	}
+	/// Remove connection, returns the plug that was passed to [`Ipv6Router::add_connection`]
+	pub async fn remove_connection(&self, id: usize) -> Option<Plug> {
+		let (ret, ret_rx) = oneshot::channel();
+		self.ctrl.unbounded_send(RouterCtrl::RemoveConnection(id, ret)).ok()?;
+		ret_rx.await.ok().flatten()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn addr(segments: [u16; 8]) -> Ipv6Addr {
+		let [a, b, c, d, e, f, g, h] = segments;
+		Ipv6Addr::new(a, b, c, d, e, f, g, h)
+	}
+	fn subnet(subnet: u16) -> Ipv6Range { Ipv6Range::new(addr([0xfd00, 0xd17e, 0, subnet, 0, 0, 0, 0]), 64) }
+
+	#[test]
+	fn range_contains() {
+		// Host bits are masked off
+		let range = Ipv6Range::new(addr([0xfd00, 0xd17e, 0, 1, 0, 0, 0, 5]), 64);
+		assert_eq!(range, subnet(1));
+		assert_eq!(range.gateway_addr(), addr([0xfd00, 0xd17e, 0, 1, 0, 0, 0, 1]));
+
+		assert!(range.contains(addr([0xfd00, 0xd17e, 0, 1, 0, 0, 0, 0])));
+		assert!(range.contains(addr([0xfd00, 0xd17e, 0, 1, 0xffff, 0xffff, 0xffff, 0xffff])));
+		assert!(!range.contains(addr([0xfd00, 0xd17e, 0, 2, 0, 0, 0, 0])));
+		assert!(!range.contains(addr([0xfd00, 0xd17e, 0, 0, 0xffff, 0xffff, 0xffff, 0xffff])));
+
+		// Prefix lengths at both ends
+		assert!(Ipv6Range::new(addr([0xfd00, 0, 0, 0, 0, 0, 0, 1]), 0).contains(Ipv6Addr::UNSPECIFIED));
+		assert_eq!(Ipv6Range::new(Ipv6Addr::LOCALHOST, 200).prefix_length(), 128);
+		let host = Ipv6Range::host(Ipv6Addr::LOCALHOST);
+		assert!(host.contains(Ipv6Addr::LOCALHOST));
+		assert!(!host.contains(Ipv6Addr::UNSPECIFIED));
+	}
+
+	#[test]
+	fn range_overlaps() {
+		let outer = Ipv6Range::new(addr([0xfd00, 0xd17e, 0, 0, 0, 0, 0, 0]), 48);
+		assert!(outer.overlaps(&subnet(1)));
+		assert!(subnet(1).overlaps(&outer));
+		assert!(subnet(1).overlaps(&subnet(1)));
+		assert!(!subnet(1).overlaps(&subnet(2)));
+		assert!(!outer.overlaps(&Ipv6Range::new(addr([0xfd00, 0xd17e, 1, 0, 0, 0, 0, 0]), 48)));
+		assert!(Ipv6Range::host(addr([0xfd00, 0xd17e, 0, 1, 0, 0, 0, 9])).overlaps(&subnet(1)));
+	}
+
+	#[test]
+	fn free_ranges() {
+		let none: [Ipv6Range; 0] = [];
+		assert_eq!(find_free_range(none.iter()), Some(subnet(0)));
+		assert_eq!(find_free_range([subnet(0), subnet(2)].iter()), Some(subnet(1)));
+		// Ranges outside of the network prefix don't matter
+		assert_eq!(find_free_range([Ipv6Range::new(addr([0xfd00, 0, 0, 0, 0, 0, 0, 0]), 64)].iter()), Some(subnet(0)));
+		// Larger taken ranges block all subnets inside them
+		let taken = [Ipv6Range::new(addr([0xfd00, 0xd17e, 0, 0, 0, 0, 0, 0]), 48)];
+		assert_eq!(find_free_range(taken.iter()), Some(Ipv6Range::new(addr([0xfd00, 0xd17e, 1, 0, 0, 0, 0, 0]), 64)));
+	}
+
+	#[test]
+	fn address_pool() {
+		let mut pool = Ipv6AddressPool::new(subnet(1));
+		let first = pool.allocate().unwrap();
+		let second = pool.allocate().unwrap();
+		assert_eq!((first, second), (addr([0xfd00, 0xd17e, 0, 1, 0, 0, 0, 2]), addr([0xfd00, 0xd17e, 0, 1, 0, 0, 0, 3])));
+		assert_eq!(pool.used(), 2);
+		pool.release(first);
+		assert_eq!(pool.used(), 1);
+		assert_eq!(pool.allocate(), Some(first));
+
+		// Gateway, addresses never handed out and other ranges are ignored
+		for other in [subnet(1).base_addr(), subnet(1).gateway_addr(), addr([0xfd00, 0xd17e, 0, 1, 0, 0, 0, 4]), addr([0xfd00, 0xd17e, 0, 2, 0, 0, 0, 2])] {
+			pool.release(other);
+		}
+		assert_eq!(pool.used(), 2);
+	}
+
+	#[test]
+	fn address_pool_bounds() {
+		// Four addresses, minus network and gateway address
+		let mut pool = Ipv6AddressPool::new(Ipv6Range::new(addr([0xfd00, 0xd17e, 0, 1, 0, 0, 0, 0]), 126));
+		assert_eq!(pool.allocate(), Some(addr([0xfd00, 0xd17e, 0, 1, 0, 0, 0, 2])));
+		assert_eq!(pool.allocate(), Some(addr([0xfd00, 0xd17e, 0, 1, 0, 0, 0, 3])));
+		assert_eq!(pool.allocate(), None);
+		pool.release(addr([0xfd00, 0xd17e, 0, 1, 0, 0, 0, 3]));
+		assert_eq!(pool.allocate(), Some(addr([0xfd00, 0xd17e, 0, 1, 0, 0, 0, 3])));
+
+		let mut pool = Ipv6AddressPool::new(Ipv6Range::host(Ipv6Addr::LOCALHOST));
+		assert_eq!(pool.allocate(), None);
+	}
+
+	#[test]
+	fn longest_prefix_match() {
+		let (wide, narrow, host) = (Ipv6Range::new(addr([0xfd00, 0xd17e, 0, 0, 0, 0, 0, 0]), 32), subnet(1), Ipv6Range::host(addr([0xfd00, 0xd17e, 0, 1, 0, 0, 0, 7])));
+		let routes: Vec<(usize, Vec<Ipv6Range>)> = vec![(0, vec![wide]), (1, vec![subnet(2), narrow]), (2, vec![host])];
+		let best = |dest|best_route(routes.iter().map(|(id, routes)|(*id, routes.as_slice())), dest);
+
+		assert_eq!(best(addr([0xfd00, 0xd17e, 0, 1, 0, 0, 0, 7])), Some(2));
+		assert_eq!(best(addr([0xfd00, 0xd17e, 0, 1, 0, 0, 0, 8])), Some(1));
+		assert_eq!(best(addr([0xfd00, 0xd17e, 0, 2, 0, 0, 0, 8])), Some(1));
+		assert_eq!(best(addr([0xfd00, 0xd17e, 0, 3, 0, 0, 0, 8])), Some(0));
+		assert_eq!(best(addr([0xfd00, 0xd17f, 0, 1, 0, 0, 0, 8])), None);
+	}
+
+	#[test]
+	fn equal_prefix_ties() {
+		// Lowest connection id wins regardless of iteration order
+		let routes: Vec<(usize, Vec<Ipv6Range>)> = vec![(5, vec![subnet(1)]), (3, vec![subnet(1)]), (9, vec![subnet(1)])];
+		let dest = addr([0xfd00, 0xd17e, 0, 1, 0, 0, 0, 2]);
+		assert_eq!(best_route(routes.iter().map(|(id, routes)|(*id, routes.as_slice())), dest), Some(3));
+		assert_eq!(best_route(routes.iter().rev().map(|(id, routes)|(*id, routes.as_slice())), dest), Some(3));
+	}
+}
@@ -0,0 +1,151 @@
+//! Latency models, determine the delay of a wire from the positions of the nodes it connects.
+
+use std::fs::File;
+use std::io::BufReader;
+
+use super::{FieldPosition, Latency, FIELD_DIMENSIONS};
+
+/// Mean Earth radius in kilometers
+const EARTH_RADIUS: f64 = 6371.0;
+
+pub trait LatencyModel {
+	/// Latency of a wire between nodes at two positions (measured in microseconds)
+	fn latency(&self, from: &FieldPosition, to: &FieldPosition) -> Latency;
+}
+
+/// Euclidean distance between field positions, one field unit is one microsecond
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Euclidean;
+impl LatencyModel for Euclidean {
+	fn latency(&self, from: &FieldPosition, to: &FieldPosition) -> Latency {
+		from.map(|v|v as f64).metric_distance(&to.map(|v|v as f64)) as Latency
+	}
+}
+
+/// Euclidean distance plus a constant per wire, models switching and queueing delay at each hop
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EuclideanPerHop {
+	/// Delay added to every wire (measured in microseconds)
+	pub per_hop: Latency,
+}
+impl LatencyModel for EuclideanPerHop {
+	fn latency(&self, from: &FieldPosition, to: &FieldPosition) -> Latency {
+		Euclidean.latency(from, to) + self.per_hop
+	}
+}
+
+/// Great-circle distance, field positions are interpreted as (longitude, latitude): the field's width spans 360 degrees and its height 180 degrees
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GreatCircle {
+	/// Signal propagation speed in kilometers per millisecond (~200 in optical fiber)
+	pub propagation_speed: f64,
+}
+impl Default for GreatCircle {
+	fn default() -> Self { GreatCircle { propagation_speed: 200.0 } }
+}
+impl GreatCircle {
+	/// Longitude and latitude in radians, positions outside of the field are clamped to its edges
+	fn lon_lat(position: &FieldPosition) -> (f64, f64) {
+		let (x_range, y_range) = FIELD_DIMENSIONS;
+		let scale = |value: i32, half_extent: i32, degrees: f64|(value as f64 / half_extent as f64 * degrees).clamp(-degrees, degrees).to_radians();
+		(scale(position.x, x_range.end, 180.0), scale(position.y, y_range.end, 90.0))
+	}
+	/// Distance in kilometers (haversine formula)
+	pub fn distance(from: &FieldPosition, to: &FieldPosition) -> f64 {
+		let ((lon1, lat1), (lon2, lat2)) = (Self::lon_lat(from), Self::lon_lat(to));
+		let a = ((lat2 - lat1) / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * ((lon2 - lon1) / 2.0).sin().powi(2);
+		2.0 * EARTH_RADIUS * a.sqrt().min(1.0).asin()
+	}
+}
+impl LatencyModel for GreatCircle {
+	fn latency(&self, from: &FieldPosition, to: &FieldPosition) -> Latency {
+		(Self::distance(from, to) / self.propagation_speed * 1000.0) as Latency
+	}
+}
+
+/// Measured latencies between a set of sites, every node is assigned to the site closest to it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LatencyMatrix {
+	/// Position of each site in the field
+	pub sites: Vec<FieldPosition>,
+	/// Latency from site to site (measured in microseconds), indexed in the same order as sites
+	pub latencies: Vec<Vec<Latency>>,
+}
+#[derive(Debug, Error)]
+pub enum LatencyMatrixError {
+	#[error("failed to read latency matrix: {0}")]
+	Io(#[from] std::io::Error),
+	#[error("failed to parse latency matrix: {0}")]
+	Parse(#[from] serde_json::Error),
+	#[error("latency matrix must be square with a row for each of the {sites} sites")]
+	InvalidShape { sites: usize },
+}
+impl LatencyMatrix {
+	/// Load matrix from JSON file with the fields `sites` and `latencies`
+	pub fn load(path: &str) -> Result<Self, LatencyMatrixError> {
+		let matrix: LatencyMatrix = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+		let sites = matrix.sites.len();
+		if sites == 0 || matrix.latencies.len() != sites || matrix.latencies.iter().any(|row|row.len() != sites) {
+			return Err(LatencyMatrixError::InvalidShape { sites });
+		}
+		Ok(matrix)
+	}
+	fn site(&self, position: &FieldPosition) -> usize {
+		self.sites.iter().enumerate()
+			.min_by_key(|(_, site)|Euclidean.latency(site, position))
+			.map(|(index, _)|index).unwrap_or(0)
+	}
+}
+impl LatencyModel for LatencyMatrix {
+	fn latency(&self, from: &FieldPosition, to: &FieldPosition) -> Latency {
+		self.latencies.get(self.site(from)).and_then(|row|row.get(self.site(to))).copied().unwrap_or(0)
+	}
+}
+
+/// Latency model used by an Internet
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum LatencyModelConfig {
+	Euclidean,
+	EuclideanPerHop(EuclideanPerHop),
+	GreatCircle(GreatCircle),
+	Matrix(LatencyMatrix),
+}
+impl Default for LatencyModelConfig {
+	fn default() -> Self { LatencyModelConfig::Euclidean }
+}
+impl LatencyModelConfig {
+	pub fn model(&self) -> &dyn LatencyModel {
+		match self {
+			LatencyModelConfig::Euclidean => &Euclidean,
+			LatencyModelConfig::EuclideanPerHop(model) => model,
+			LatencyModelConfig::GreatCircle(model) => model,
+			LatencyModelConfig::Matrix(model) => model,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn great_circle_field_corners() {
+		let (x_range, y_range) = FIELD_DIMENSIONS;
+		for x in [x_range.start, x_range.end] {
+			for y in [y_range.start, y_range.end] {
+				let (lon, lat) = GreatCircle::lon_lat(&FieldPosition::new(x, y));
+				assert!(lon.abs() <= std::f64::consts::PI + 1e-9, "longitude {} out of range at ({}, {})", lon.to_degrees(), x, y);
+				assert!(lat.abs() <= std::f64::consts::FRAC_PI_2 + 1e-9, "latitude {} out of range at ({}, {})", lat.to_degrees(), x, y);
+			}
+		}
+		// Positions beyond the field are clamped to its edges
+		assert_eq!(GreatCircle::lon_lat(&FieldPosition::new(x_range.end * 2, y_range.start * 2)), GreatCircle::lon_lat(&FieldPosition::new(x_range.end, y_range.start)));
+
+		// Both poles are a half circumference apart, all points on a pole are the same point
+		let (north, south) = (FieldPosition::new(0, y_range.end), FieldPosition::new(0, y_range.start));
+		assert!((GreatCircle::distance(&north, &south) - std::f64::consts::PI * EARTH_RADIUS).abs() < 1e-6);
+		assert!(GreatCircle::distance(&north, &FieldPosition::new(x_range.start, y_range.end)) < 1e-6);
+		// Left and right edge of the field meet at the antimeridian
+		assert!(GreatCircle::distance(&FieldPosition::new(x_range.start, 0), &FieldPosition::new(x_range.end, 0)) < 1e-6);
+	}
+}
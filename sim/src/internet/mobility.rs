@@ -0,0 +1,151 @@
+//! Mobility models for machines.
+//! Movement is simulated by a background task that periodically sends the machine's new position to the simulation.
+
+use std::time::Duration;
+
+use async_std::task;
+use futures::{SinkExt, channel::mpsc, future::{self, AbortHandle}};
+use nalgebra::Vector2;
+use rand::{Rng, SeedableRng, rngs::SmallRng};
+
+use super::{FIELD_DIMENSIONS, FieldPosition, InternetAction, NodeIdx};
+use super::clock::SimClock;
+
+/// How a machine moves, speeds are measured in field units per second
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum MobilityModel {
+	/// Move in a straight line to a random point in the field, pause there, repeat
+	RandomWaypoint { speed: f64, pause: Duration, seed: u64 },
+	/// Move in a random direction, picking a new direction every `turn_interval`. Bounces off the field edges.
+	RandomWalk { speed: f64, turn_interval: Duration, seed: u64 },
+	/// Follow a list of waypoints, starting over from the first one if `looping` is set
+	Path { waypoints: Vec<FieldPosition>, speed: f64, looping: bool },
+}
+
+/// Mobility configuration of a machine
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Mobility {
+	pub model: MobilityModel,
+	/// Time between position updates
+	pub interval: Duration,
+	/// Whether the machine reconnects to the closest network as it moves
+	pub handover: bool,
+}
+
+#[derive(Debug, Error)]
+pub enum MobilityError {
+	#[error("mobility interval must be longer than zero")]
+	ZeroInterval,
+	#[error("random walk turn interval must be longer than zero")]
+	ZeroTurnInterval,
+	#[error("mobility speed {0} must be a non-negative number")]
+	InvalidSpeed(f64),
+}
+
+impl Mobility {
+	/// Reject configurations whose task would spin without ever sleeping or that can't be moved along
+	pub fn validate(&self) -> Result<(), MobilityError> {
+		if self.interval.is_zero() { return Err(MobilityError::ZeroInterval) }
+		let speed = match self.model {
+			MobilityModel::RandomWalk { turn_interval, .. } if turn_interval.is_zero() => return Err(MobilityError::ZeroTurnInterval),
+			MobilityModel::RandomWaypoint { speed, .. } | MobilityModel::RandomWalk { speed, .. } | MobilityModel::Path { speed, .. } => speed,
+		};
+		if !(speed.is_finite() && speed >= 0.0) { return Err(MobilityError::InvalidSpeed(speed)) }
+		Ok(())
+	}
+}
+
+/// Running state of a mobility model
+pub(super) struct MobilityState {
+	model: MobilityModel,
+	position: Vector2<f64>,
+	/// Point currently moved towards
+	target: Option<Vector2<f64>>,
+	/// Direction of a random walk
+	direction: Vector2<f64>,
+	/// Time left pausing at a waypoint or until the next turn
+	timer: Duration,
+	next_waypoint: usize,
+	rng: SmallRng,
+}
+impl MobilityState {
+	pub(super) fn new(model: MobilityModel, position: FieldPosition) -> Self {
+		let seed = match &model {
+			MobilityModel::RandomWaypoint { seed, .. } | MobilityModel::RandomWalk { seed, .. } => *seed,
+			MobilityModel::Path { .. } => 0,
+		};
+		MobilityState {
+			model, position: position.map(|v|v as f64),
+			target: None, direction: Vector2::zeros(), timer: Duration::ZERO, next_waypoint: 0,
+			rng: SmallRng::seed_from_u64(seed),
+		}
+	}
+	fn random_point(&mut self) -> Vector2<f64> {
+		let (x, y) = FIELD_DIMENSIONS;
+		Vector2::new(self.rng.gen_range(x) as f64, self.rng.gen_range(y) as f64)
+	}
+	/// Move towards target, returns true once it is reached
+	fn move_towards(&mut self, target: Vector2<f64>, distance: f64) -> bool {
+		let offset = target - self.position;
+		if offset.norm() <= distance { self.position = target; true }
+		else { self.position += offset.normalize() * distance; false }
+	}
+	/// Advance model by a time step, returns None once the machine stops moving for good
+	pub(super) fn step(&mut self, dt: Duration) -> Option<FieldPosition> {
+		match self.model.clone() {
+			MobilityModel::RandomWaypoint { speed, pause, .. } => {
+				if self.timer > Duration::ZERO {
+					self.timer = self.timer.saturating_sub(dt);
+				} else {
+					let target = match self.target { Some(target) => target, None => self.random_point() };
+					self.target = Some(target);
+					if self.move_towards(target, speed * dt.as_secs_f64()) {
+						self.target = None;
+						self.timer = pause;
+					}
+				}
+			}
+			MobilityModel::RandomWalk { speed, turn_interval, .. } => {
+				if self.timer.is_zero() {
+					let angle = self.rng.gen_range(0.0..std::f64::consts::TAU);
+					self.direction = Vector2::new(angle.cos(), angle.sin());
+					self.timer = turn_interval;
+				}
+				self.timer = self.timer.saturating_sub(dt);
+				self.position += self.direction * speed * dt.as_secs_f64();
+				// Bounce off field edges
+				let (x, y) = FIELD_DIMENSIONS;
+				for (axis, range) in [(0, x), (1, y)] {
+					let (min, max) = (range.start as f64, (range.end - 1) as f64);
+					if self.position[axis] < min || self.position[axis] > max {
+						self.position[axis] = self.position[axis].clamp(min, max);
+						self.direction[axis] = -self.direction[axis];
+					}
+				}
+			}
+			MobilityModel::Path { waypoints, speed, looping } => {
+				let target = match waypoints.get(self.next_waypoint) {
+					Some(waypoint) => waypoint.map(|v|v as f64),
+					None if looping && !waypoints.is_empty() => { self.next_waypoint = 0; waypoints[0].map(|v|v as f64) }
+					None => return None,
+				};
+				if self.move_towards(target, speed * dt.as_secs_f64()) { self.next_waypoint += 1; }
+			}
+		}
+		Some(self.position.map(|v|v.round() as i32))
+	}
+}
+
+/// Spawn task moving a machine, positions are sent to the simulation as [`InternetAction::MobilityStep`]s
+pub fn spawn(machine_idx: NodeIdx, position: FieldPosition, mobility: Mobility, mut action_sender: mpsc::Sender<InternetAction>, clock: SimClock) -> AbortHandle {
+	let (task, abort_handle) = future::abortable(async move {
+		let mut state = MobilityState::new(mobility.model, position);
+		loop {
+			clock.sleep(mobility.interval).await;
+			let position = match state.step(mobility.interval) { Some(position) => position, None => break };
+			if action_sender.send(InternetAction::MobilityStep(machine_idx, position)).await.is_err() { break }
+		}
+	});
+	task::spawn(task);
+	abort_handle
+}
@@ -0,0 +1,382 @@
+//! Network Address Translation for InternetNetworks.
+//! Machines behind a NAT-ed network get private addresses, their traffic is translated to the network's public address at each uplink.
+
+use std::collections::{HashMap, HashSet};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use netsim_embed::Ipv4Range;
+
+use super::netsim_ext::{Direction, PacketStage, Verdict};
+use super::packet::{self, PacketHeader, Protocol};
+use super::clock::SimClock;
+
+/// Default time a NAT mapping stays alive without any traffic
+pub const DEFAULT_MAPPING_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// First port handed out for NAT mappings
+const FIRST_MAPPED_PORT: u16 = 1024;
+
+/// Address range machines behind a NAT are allocated from
+pub fn private_range() -> Ipv4Range { Ipv4Range::new(Ipv4Addr::new(10, 0, 0, 0), 8) }
+
+/// NAT behaviour, determines how mappings are created and which inbound packets are let through
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NatMode {
+	/// Any remote can send to a mapped port
+	FullCone,
+	/// Only remote addresses the machine has sent to can send to a mapped port
+	AddressRestricted,
+	/// Only remote address & port pairs the machine has sent to can send to a mapped port
+	PortRestricted,
+	/// New mapping for every remote endpoint, only that remote endpoint can reply
+	Symmetric,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NatConfig {
+	pub mode: NatMode,
+	/// How long an idle mapping is kept around
+	pub mapping_timeout: Duration,
+}
+impl NatConfig {
+	pub fn new(mode: NatMode) -> Self {
+		NatConfig { mode, mapping_timeout: DEFAULT_MAPPING_TIMEOUT }
+	}
+	/// Reject configs whose mappings expire before any reply could use them
+	pub fn validate(&self) -> Result<(), NatError> {
+		if self.mapping_timeout.is_zero() { return Err(NatError::ZeroMappingTimeout) }
+		Ok(())
+	}
+}
+
+#[derive(Debug, Error)]
+pub enum NatError {
+	#[error("NAT mapping timeout must be longer than zero")]
+	ZeroMappingTimeout,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct MappingKey {
+	protocol: Protocol,
+	private: SocketAddrV4,
+	/// Only set for symmetric NATs, where every remote endpoint gets its own mapping
+	remote: Option<SocketAddrV4>,
+}
+
+#[derive(Debug)]
+struct Mapping {
+	key: MappingKey,
+	/// Remote endpoints this mapping has sent packets to
+	contacted: HashSet<SocketAddrV4>,
+	/// Virtual time of last use
+	last_used: Duration,
+}
+
+/// NAT translation table shared between all uplinks of a network
+#[derive(Debug)]
+pub struct NatTable {
+	config: Option<NatConfig>,
+	public_addr: Ipv4Addr,
+	next_port: u16,
+	outbound: HashMap<MappingKey, u16>,
+	inbound: HashMap<(Protocol, u16), Mapping>,
+	clock: SimClock,
+}
+
+impl NatTable {
+	pub fn new(config: Option<NatConfig>, public_addr: Ipv4Addr, clock: SimClock) -> Self {
+		NatTable {
+			config, public_addr,
+			next_port: FIRST_MAPPED_PORT,
+			outbound: HashMap::new(),
+			inbound: HashMap::new(),
+			clock,
+		}
+	}
+	/// Change NAT configuration, clears all existing mappings
+	pub fn set_config(&mut self, config: Option<NatConfig>) {
+		self.config = config;
+		self.outbound.clear();
+		self.inbound.clear();
+	}
+	/// Number of currently active mappings
+	pub fn mappings(&self) -> usize { self.inbound.len() }
+
+	fn expire(&mut self, timeout: Duration) {
+		let now = self.clock.now();
+		let outbound = &mut self.outbound;
+		self.inbound.retain(|_, mapping| {
+			let alive = now.saturating_sub(mapping.last_used) < timeout;
+			if !alive { outbound.remove(&mapping.key); }
+			alive
+		});
+	}
+	fn allocate_port(&mut self, protocol: Protocol) -> Option<u16> {
+		for _ in FIRST_MAPPED_PORT..=u16::MAX {
+			let port = self.next_port;
+			self.next_port = if port == u16::MAX { FIRST_MAPPED_PORT } else { port + 1 };
+			if !self.inbound.contains_key(&(protocol, port)) { return Some(port) }
+		}
+		None
+	}
+
+	/// Translate packet leaving the network through an uplink. Returns false if the packet should be dropped.
+	pub fn translate_outbound(&mut self, packet: &mut [u8]) -> bool {
+		let config = match &self.config { Some(config) => config.clone(), None => return true };
+		let header = match PacketHeader::parse(packet) { Some(header) => header, None => return false };
+		// Traffic not originating from this network's machines is passed through untouched
+		if !private_range().contains(header.src) { return true }
+		let (private, remote) = match (header.src_endpoint(), header.dst_endpoint()) {
+			(Some(private), Some(remote)) => (private, remote),
+			_ => return false, // Only TCP & UDP can be translated
+		};
+
+		self.expire(config.mapping_timeout);
+		let key = MappingKey {
+			protocol: header.protocol, private,
+			remote: (config.mode == NatMode::Symmetric).then(|| remote),
+		};
+		let public_port = match self.outbound.get(&key) {
+			Some(port) => *port,
+			None => {
+				let port = match self.allocate_port(header.protocol) { Some(port) => port, None => return false };
+				self.outbound.insert(key.clone(), port);
+				self.inbound.insert((header.protocol, port), Mapping { key, contacted: HashSet::new(), last_used: self.clock.now() });
+				port
+			}
+		};
+		if let Some(mapping) = self.inbound.get_mut(&(header.protocol, public_port)) {
+			mapping.contacted.insert(remote);
+			mapping.last_used = self.clock.now();
+		}
+		packet::set_source(packet, SocketAddrV4::new(self.public_addr, public_port));
+		true
+	}
+
+	/// Translate packet entering the network through an uplink. Returns false if the packet should be dropped.
+	pub fn translate_inbound(&mut self, packet: &mut [u8]) -> bool {
+		let config = match &self.config { Some(config) => config.clone(), None => return true };
+		let header = match PacketHeader::parse(packet) { Some(header) => header, None => return false };
+		// Only packets addressed to the NAT itself are translated
+		if header.dst != self.public_addr { return true }
+		let (remote, public_port) = match (header.src_endpoint(), header.ports) {
+			(Some(remote), Some((_, public_port))) => (remote, public_port),
+			_ => return false,
+		};
+
+		self.expire(config.mapping_timeout);
+		let mapping = match self.inbound.get_mut(&(header.protocol, public_port)) { Some(mapping) => mapping, None => return false };
+		let allowed = match config.mode {
+			NatMode::FullCone => true,
+			NatMode::AddressRestricted => mapping.contacted.iter().any(|contacted|contacted.ip() == remote.ip()),
+			NatMode::PortRestricted | NatMode::Symmetric => mapping.contacted.contains(&remote),
+		};
+		if !allowed { return false }
+		mapping.last_used = self.clock.now();
+		packet::set_destination(packet, mapping.key.private);
+		true
+	}
+}
+
+/// Packet stage placed on every uplink of a NAT-ed network
+pub struct NatStage {
+	table: Arc<Mutex<NatTable>>,
+}
+impl NatStage {
+	pub fn new(table: Arc<Mutex<NatTable>>) -> Self { NatStage { table } }
+}
+impl PacketStage for NatStage {
+	fn process(&mut self, direction: Direction, mut packet: Vec<u8>) -> Verdict {
+		let mut table = self.table.lock().expect("NAT table lock poisoned");
+		let keep = match direction {
+			Direction::Outbound => table.translate_outbound(&mut packet),
+			Direction::Inbound => table.translate_inbound(&mut packet),
+		};
+		if keep { Verdict::Forward(packet) } else { Verdict::Drop }
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const PUBLIC: Ipv4Addr = Ipv4Addr::new(203, 0, 113, 1);
+
+	fn endpoint(ip: [u8; 4], port: u16) -> SocketAddrV4 { SocketAddrV4::new(Ipv4Addr::from(ip), port) }
+	fn udp(src: SocketAddrV4, dst: SocketAddrV4) -> Vec<u8> { packet::udp_packet(src, dst, b"ping").unwrap() }
+	/// Table on a paused clock, so virtual time only moves when a test advances it
+	fn nat_table(mode: NatMode) -> (NatTable, SimClock) {
+		let clock = SimClock::new(1.0);
+		clock.set_paused(true);
+		(NatTable::new(Some(NatConfig::new(mode)), PUBLIC, clock.clone()), clock)
+	}
+	/// Send packet from a private endpoint to a remote, returns the public endpoint it was mapped to
+	fn send_out(table: &mut NatTable, private: SocketAddrV4, remote: SocketAddrV4) -> SocketAddrV4 {
+		let mut packet = udp(private, remote);
+		assert!(table.translate_outbound(&mut packet));
+		PacketHeader::parse(&packet).unwrap().src_endpoint().unwrap()
+	}
+	/// Whether a packet from a remote to a public endpoint is let in
+	fn receive(table: &mut NatTable, remote: SocketAddrV4, public: SocketAddrV4) -> bool {
+		table.translate_inbound(&mut udp(remote, public))
+	}
+
+	/// One's complement sum of 16-bit words, independent of the implementation under test
+	fn ones_complement_sum(data: &[u8], initial: u32) -> u16 {
+		let mut sum = data.chunks(2).fold(initial, |sum, chunk|sum + u16::from_be_bytes([chunk[0], *chunk.get(1).unwrap_or(&0)]) as u32);
+		while sum >> 16 != 0 { sum = (sum & 0xffff) + (sum >> 16); }
+		sum as u16
+	}
+	/// Whether the IPv4 header checksum and the TCP/UDP checksum over the pseudo header add up
+	fn checksums_valid(packet: &[u8]) -> bool {
+		let header_len = (packet[0] & 0x0f) as usize * 4;
+		let pseudo_header = ones_complement_sum(&packet[12..20], packet[9] as u32 + (packet.len() - header_len) as u32) as u32;
+		ones_complement_sum(&packet[..header_len], 0) == 0xffff && ones_complement_sum(&packet[header_len..], pseudo_header) == 0xffff
+	}
+	fn tcp(src: SocketAddrV4, dst: SocketAddrV4) -> Vec<u8> {
+		let mut packet = vec![0u8; 40 + 4];
+		packet[0] = 0x45;
+		packet[2..4].copy_from_slice(&(packet.len() as u16).to_be_bytes());
+		packet[8] = 64;
+		packet[9] = 6;
+		packet[12..16].copy_from_slice(&src.ip().octets());
+		packet[16..20].copy_from_slice(&dst.ip().octets());
+		packet[20..22].copy_from_slice(&src.port().to_be_bytes());
+		packet[22..24].copy_from_slice(&dst.port().to_be_bytes());
+		packet[32] = 0x50; // Data offset of 5 words
+		packet[40..].copy_from_slice(b"data");
+		packet::update_checksums(&mut packet);
+		packet
+	}
+
+	#[test]
+	fn outbound_mapping() {
+		let (mut table, _clock) = nat_table(NatMode::FullCone);
+		let (private, remote) = (endpoint([10, 0, 0, 2], 5000), endpoint([198, 51, 100, 7], 80));
+		let public = send_out(&mut table, private, remote);
+		assert_eq!(public, SocketAddrV4::new(PUBLIC, FIRST_MAPPED_PORT));
+		// Same private endpoint keeps its mapping, another one gets the next port
+		assert_eq!(send_out(&mut table, private, endpoint([198, 51, 100, 8], 80)), public);
+		assert_eq!(send_out(&mut table, endpoint([10, 0, 0, 3], 5000), remote).port(), FIRST_MAPPED_PORT + 1);
+		assert_eq!(table.mappings(), 2);
+
+		// Traffic from outside the private range passes untouched
+		let mut transit = udp(endpoint([192, 0, 2, 1], 1), remote);
+		let original = transit.clone();
+		assert!(table.translate_outbound(&mut transit));
+		assert_eq!(transit, original);
+	}
+
+	#[test]
+	fn inbound_filtering() {
+		let private = endpoint([10, 0, 0, 2], 5000);
+		let contacted = endpoint([198, 51, 100, 7], 80);
+		let same_address = endpoint([198, 51, 100, 7], 81);
+		let stranger = endpoint([198, 51, 100, 9], 80);
+		// Which of (contacted, same address other port, other address) may send to the mapping
+		for (mode, allowed) in [
+			(NatMode::FullCone, [true, true, true]),
+			(NatMode::AddressRestricted, [true, true, false]),
+			(NatMode::PortRestricted, [true, false, false]),
+			(NatMode::Symmetric, [true, false, false]),
+		] {
+			let (mut table, _clock) = nat_table(mode);
+			let public = send_out(&mut table, private, contacted);
+			for (remote, allowed) in [contacted, same_address, stranger].into_iter().zip(allowed) {
+				assert_eq!(receive(&mut table, remote, public), allowed, "{:?} from {}", mode, remote);
+			}
+			// Ports without a mapping are always closed
+			assert!(!receive(&mut table, contacted, SocketAddrV4::new(PUBLIC, public.port() + 1)), "{:?}", mode);
+		}
+	}
+
+	#[test]
+	fn symmetric_mapping_per_remote() {
+		let (mut table, _clock) = nat_table(NatMode::Symmetric);
+		let private = endpoint([10, 0, 0, 2], 5000);
+		let (remote1, remote2) = (endpoint([198, 51, 100, 7], 80), endpoint([198, 51, 100, 8], 80));
+		let (public1, public2) = (send_out(&mut table, private, remote1), send_out(&mut table, private, remote2));
+		assert_ne!(public1, public2);
+		assert!(receive(&mut table, remote1, public1));
+		assert!(!receive(&mut table, remote2, public1));
+		assert!(receive(&mut table, remote2, public2));
+
+		// Other NATs reuse the mapping for every remote
+		let (mut table, _clock) = nat_table(NatMode::PortRestricted);
+		assert_eq!(send_out(&mut table, private, remote1), send_out(&mut table, private, remote2));
+	}
+
+	#[test]
+	fn mapping_expiry() {
+		let (mut table, clock) = nat_table(NatMode::FullCone);
+		let (private, remote) = (endpoint([10, 0, 0, 2], 5000), endpoint([198, 51, 100, 7], 80));
+		let public = send_out(&mut table, private, remote);
+
+		// Inbound traffic keeps the mapping alive
+		clock.advance(DEFAULT_MAPPING_TIMEOUT - Duration::from_secs(1));
+		assert!(receive(&mut table, remote, public));
+		clock.advance(DEFAULT_MAPPING_TIMEOUT - Duration::from_secs(1));
+		assert!(receive(&mut table, remote, public));
+
+		clock.advance(DEFAULT_MAPPING_TIMEOUT);
+		assert!(!receive(&mut table, remote, public));
+		assert_eq!(table.mappings(), 0);
+		// Sending again opens a new mapping
+		let public = send_out(&mut table, private, remote);
+		assert!(receive(&mut table, remote, public));
+	}
+
+	#[test]
+	fn expiry_follows_paused_clock() {
+		let (mut table, clock) = nat_table(NatMode::FullCone);
+		let (private, remote) = (endpoint([10, 0, 0, 2], 5000), endpoint([198, 51, 100, 7], 80));
+		let public = send_out(&mut table, private, remote);
+		// No virtual time passes while paused, however long the wall-clock wait
+		std::thread::sleep(Duration::from_millis(20));
+		assert!(clock.is_paused());
+		assert!(receive(&mut table, remote, public));
+	}
+
+	#[test]
+	fn translation_checksums() {
+		let (mut table, _clock) = nat_table(NatMode::FullCone);
+		let (private, remote) = (endpoint([10, 0, 0, 2], 5000), endpoint([198, 51, 100, 7], 80));
+		for build in [udp as fn(SocketAddrV4, SocketAddrV4) -> Vec<u8>, tcp] {
+			let mut outbound = build(private, remote);
+			assert!(checksums_valid(&outbound));
+			assert!(table.translate_outbound(&mut outbound));
+			assert!(checksums_valid(&outbound));
+			let public = PacketHeader::parse(&outbound).unwrap().src_endpoint().unwrap();
+
+			let mut inbound = build(remote, public);
+			assert!(table.translate_inbound(&mut inbound));
+			assert_eq!(PacketHeader::parse(&inbound).unwrap().dst_endpoint(), Some(private));
+			assert!(checksums_valid(&inbound));
+		}
+
+		// UDP packets without a checksum keep going without one
+		let mut unchecked = udp(private, remote);
+		unchecked[26..28].copy_from_slice(&[0, 0]);
+		packet::update_checksums(&mut unchecked);
+		assert!(table.translate_outbound(&mut unchecked));
+		assert_eq!(unchecked[26..28], [0, 0]);
+		assert_eq!(ones_complement_sum(&unchecked[..20], 0), 0xffff);
+	}
+
+	#[test]
+	fn header_checksum_reference() {
+		// Commonly used example header, its checksum is 0xb861
+		let mut header = [0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0x00, 0x00, 0xc0, 0xa8, 0x00, 0x01, 0xc0, 0xa8, 0x00, 0xc7];
+		packet::update_checksums(&mut header);
+		assert_eq!(header[10..12], [0xb8, 0x61]);
+	}
+
+	#[test]
+	fn zero_mapping_timeout() {
+		assert!(NatConfig::new(NatMode::FullCone).validate().is_ok());
+		let config = NatConfig { mapping_timeout: Duration::ZERO, ..NatConfig::new(NatMode::FullCone) };
+		assert!(matches!(config.validate(), Err(NatError::ZeroMappingTimeout)));
+	}
+}
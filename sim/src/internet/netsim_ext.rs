@@ -2,12 +2,14 @@ use std::{sync::Arc, time::Duration};
 use std::mem;
 
 use async_std::{self, task::{self, JoinHandle}};
-use futures::{SinkExt, StreamExt, channel::mpsc, select};
+use futures::{FutureExt, SinkExt, StreamExt, channel::mpsc, pin_mut, select};
 use netsim_embed::Plug;
 
-use futures_delay_queue::delay_queue;
+use super::clock::{self, DelayQueue, SimClock};
 enum WireAction {
-	SetDelay(Duration),
+	SetDelays(Duration, Duration),
+	/// Drop all packets entering the wire while suspended
+	SetSuspended(bool),
 	SwapPlugA(Plug),
 	SwapPlugB(Plug),
 
@@ -20,16 +22,24 @@ enum WireReturn {
 }
 
 pub struct Wire {
-    pub delay: Duration,
+	/// Delay of packets travelling from plug A to plug B
+    pub a_to_b: Duration,
+	/// Delay of packets travelling from plug B to plug A
+	pub b_to_a: Duration,
 }
 
 impl Wire {
-	pub fn new(delay: Duration) -> (Plug, Plug, Arc<WireHandle>) {
+	pub fn new(delay: Duration, clock: SimClock) -> (Plug, Plug, Arc<WireHandle>) {
 		let (plug_in_ret, plug_in_wire) = netsim_embed::wire();
 		let (plug_out_wire, plug_out_ret) = netsim_embed::wire();
-		(plug_in_ret, plug_out_ret, Arc::new(Wire { delay }.connect(plug_in_wire, plug_out_wire)))
+		(plug_in_ret, plug_out_ret, Arc::new(Wire::symmetric(delay).connect(clock, plug_in_wire, plug_out_wire)))
 	}
-	pub fn connect(mut self, plug_a: Plug, plug_b: Plug) -> WireHandle {
+	/// Wire with the same delay in both directions
+	pub fn symmetric(delay: Duration) -> Self {
+		Wire { a_to_b: delay, b_to_a: delay }
+	}
+	/// Run wire between two plugs, delays are measured in the clock's virtual time
+	pub fn connect(mut self, clock: SimClock, plug_a: Plug, plug_b: Plug) -> WireHandle {
 		let (action_sender, mut action_receiver) = mpsc::channel(5);
 		let (mut return_sender, return_receiver) = mpsc::channel(1);
 
@@ -37,73 +47,61 @@ impl Wire {
 		let (mut b_tx, mut b_rx) = plug_b.split();
 
 		let join_handle = task::spawn(async move {
-			let (delay_queue_a_to_b, packet_to_b) = delay_queue::<Vec<u8>>();
-			let (delay_queue_b_to_a, packet_to_a) = delay_queue::<Vec<u8>>();
+			let mut to_b = DelayQueue::<Vec<u8>>::new(clock.clone());
+			let mut to_a = DelayQueue::<Vec<u8>>::new(clock.clone());
 
 			let mut disconnecting = false;
+			let mut suspended = false;
 			loop {
+				let due = clock::sleep_until_any(clock.clone(), [to_a.next_deadline(), to_b.next_deadline()]).fuse();
+				pin_mut!(due);
 				select! {
 					action = action_receiver.next() => {
-						if let Some(action) = action {
-							match action {
-								WireAction::SetDelay(delay) => self.delay = delay,
-								WireAction::SwapPlugA(new_plug) => {
-									let (mut tx, mut rx) = new_plug.split();
-									mem::swap(&mut tx, &mut a_tx); mem::swap(&mut rx, &mut a_rx);
-									let old_plug = Plug::join(tx, rx);
-									return_sender.send(WireReturn::SwappedPlugA(old_plug)).await.unwrap();
-								},
-								WireAction::SwapPlugB(new_plug) => {
-									let (mut tx, mut rx) = new_plug.split();
-									mem::swap(&mut tx, &mut b_tx); mem::swap(&mut rx, &mut b_rx);
-									let old_plug = Plug::join(tx, rx);
-									return_sender.send(WireReturn::SwappedPlugB(old_plug)).await.unwrap();
-								},
-								WireAction::Disconnect => { disconnecting = true; break },
-								WireAction::ForceDisconnect => break,
-							}
+						// Handle was dropped, nothing can control this wire anymore
+						let action = action.unwrap_or(WireAction::ForceDisconnect);
+						match action {
+							WireAction::SetDelays(a_to_b, b_to_a) => { self.a_to_b = a_to_b; self.b_to_a = b_to_a; },
+							WireAction::SetSuspended(value) => suspended = value,
+							WireAction::SwapPlugA(new_plug) => {
+								let (mut tx, mut rx) = new_plug.split();
+								mem::swap(&mut tx, &mut a_tx); mem::swap(&mut rx, &mut a_rx);
+								let old_plug = Plug::join(tx, rx);
+								return_sender.send(WireReturn::SwappedPlugA(old_plug)).await.unwrap();
+							},
+							WireAction::SwapPlugB(new_plug) => {
+								let (mut tx, mut rx) = new_plug.split();
+								mem::swap(&mut tx, &mut b_tx); mem::swap(&mut rx, &mut b_rx);
+								let old_plug = Plug::join(tx, rx);
+								return_sender.send(WireReturn::SwappedPlugB(old_plug)).await.unwrap();
+							},
+							WireAction::Disconnect => { disconnecting = true; break },
+							WireAction::ForceDisconnect => break,
 						}
 					}
 					a_incoming_data = a_rx.next() => {
 						if let Some(data) = a_incoming_data {
-							delay_queue_a_to_b.insert(data, self.delay);
+							if !suspended { to_b.insert(data, self.a_to_b); }
 						}
 					}
 					b_incoming_data = b_rx.next() => {
 						if let Some(data) = b_incoming_data {
-							delay_queue_b_to_a.insert(data, self.delay);
+							if !suspended { to_a.insert(data, self.b_to_a); }
 						}
 					}
-					a_outgoing_data = packet_to_a.receive() => {
-						if let Some(data) = a_outgoing_data {
-							a_tx.send(data).await.unwrap();
-						}
-					}
-					b_outgoing_data = packet_to_b.receive() => {
-						if let Some(data) = b_outgoing_data {
-							b_tx.send(data).await.unwrap();
-						}
+					// Plugs may be detached (e.g. of a crashed or disconnected machine), packets sent to them are dropped
+					_ = due => {
+						while let Some(data) = to_a.pop_due() { let _ = a_tx.send(data).await; }
+						while let Some(data) = to_b.pop_due() { let _ = b_tx.send(data).await; }
 					}
 				}
 			}
 			
-			// TODO: This one_is_done, two_is_done thing feels really janky, there has got to be a better way to do this
-			let mut one_is_done = false;
-			let mut two_is_done = false;
 			if disconnecting {
-				loop {
-					select! {
-						outgoing_a = packet_to_a.receive() => {
-							if let Some(data) = outgoing_a {
-								a_tx.send(data).await.unwrap();
-							} else { if two_is_done { break } else { one_is_done = true; } }
-						}
-						outgoing_b = packet_to_b.receive() => {
-							if let Some(data) = outgoing_b {
-								b_tx.send(data).await.unwrap();
-							} else { if one_is_done { break } else { two_is_done = true; } }
-						}
-					}
+				// Deliver packets still on the wire
+				while !to_a.is_empty() || !to_b.is_empty() {
+					clock::sleep_until_any(clock.clone(), [to_a.next_deadline(), to_b.next_deadline()]).await;
+					while let Some(data) = to_a.pop_due() { let _ = a_tx.send(data).await; }
+					while let Some(data) = to_b.pop_due() { let _ = b_tx.send(data).await; }
 				}
 			}
 			
@@ -138,7 +136,15 @@ impl WireHandle {
 		} else { None }
 	}
 	pub async fn set_delay(&mut self, delay: Duration) {
-		self.action(WireAction::SetDelay(delay)).await;
+		self.set_delays(delay, delay).await;
+	}
+	/// Set delay of each direction separately
+	pub async fn set_delays(&mut self, a_to_b: Duration, b_to_a: Duration) {
+		self.action(WireAction::SetDelays(a_to_b, b_to_a)).await;
+	}
+	/// Blackhole wire (packets already in flight are still delivered) or restore it
+	pub async fn set_suspended(&mut self, suspended: bool) {
+		self.action(WireAction::SetSuspended(suspended)).await;
 	}
 	pub async fn disconnect(mut self) -> (Wire, Plug, Plug) {
 		self.action(WireAction::Disconnect).await;
@@ -148,4 +154,61 @@ impl WireHandle {
 		self.action(WireAction::ForceDisconnect).await;
 		self.join_handle.await
 	}
-}
\ No newline at end of file
+}
+/// Direction of a packet passing through a [`PacketStage`], relative to the router the stage is attached to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Direction {
+	/// Packet is leaving the router
+	Outbound,
+	/// Packet is entering the router
+	Inbound,
+}
+
+/// What a [`PacketStage`] decided to do with a packet
+pub enum Verdict {
+	/// Pass (possibly modified) packet on in the direction it was travelling
+	Forward(Vec<u8>),
+	/// Drop packet
+	Drop,
+	/// Drop packet and send a response back towards where it came from
+	Reply(Vec<u8>),
+}
+
+/// Something that inspects or rewrites packets passing between a router and a wire (e.g. a NAT)
+pub trait PacketStage: Send + 'static {
+	fn process(&mut self, direction: Direction, packet: Vec<u8>) -> Verdict;
+
+	/// Place stage in front of a router-facing plug, returns the plug to connect to the outgoing wire.
+	/// The stage task exits once either side is closed.
+	fn attach(mut self, router_plug: Plug) -> Plug where Self: Sized {
+		let (outgoing_plug, stage_plug) = netsim_embed::wire();
+		let (mut router_tx, mut router_rx) = router_plug.split();
+		let (mut wire_tx, mut wire_rx) = stage_plug.split();
+		task::spawn(async move {
+			loop {
+				let result = select! {
+					outbound = router_rx.next() => {
+						if let Some(packet) = outbound {
+							match self.process(Direction::Outbound, packet) {
+								Verdict::Forward(packet) => wire_tx.send(packet).await,
+								Verdict::Reply(packet) => router_tx.send(packet).await,
+								Verdict::Drop => Ok(()),
+							}
+						} else { break }
+					}
+					inbound = wire_rx.next() => {
+						if let Some(packet) = inbound {
+							match self.process(Direction::Inbound, packet) {
+								Verdict::Forward(packet) => router_tx.send(packet).await,
+								Verdict::Reply(packet) => wire_tx.send(packet).await,
+								Verdict::Drop => Ok(()),
+							}
+						} else { break }
+					}
+				};
+				if result.is_err() { break }
+			}
+		});
+		outgoing_plug
+	}
+}
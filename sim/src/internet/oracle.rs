@@ -0,0 +1,121 @@
+//! Oracle answering devices' questions with ground truth: true positions, live peers and every node's route coordinate.
+//! Baseline coordinates are kept as route coordinates too, so stretch and embedding analyses cover baselines alike.
+//! Idealized experiments use it to take discovery out of the picture and measure routing on its own.
+
+use std::net::{IpAddr, SocketAddr};
+
+use device::{BaselineInfo, DeviceCommand, OracleRequest, OracleResponse};
+use rand::{SeedableRng, rngs::SmallRng, seq::IteratorRandom};
+use slotmap::SecondaryMap;
+
+use super::{FieldPosition, Internet, InternetError, InternetRuntime, NodeID, NodeIdx, RouteCoord, DEVICE_PORT};
+
+/// What a machine's device told about itself
+struct OracleEntry {
+	/// Dither node ID, baselines don't have one
+	node_id: Option<NodeID>,
+	route_coord: Option<RouteCoord>,
+}
+
+/// Planar part of a baseline's coordinate (milliseconds) as a route coordinate in microseconds, the height is dropped
+fn baseline_route_coord(coord: &[f64; 3]) -> RouteCoord {
+	RouteCoord::new((coord[0] * 1000.0).round() as _, (coord[1] * 1000.0).round() as _)
+}
+
+/// Knowledge of the oracle beyond the Internet itself, shared by both backends
+pub(crate) struct Oracle {
+	rng: SmallRng,
+	entries: SecondaryMap<NodeIdx, OracleEntry>,
+}
+impl Oracle {
+	pub fn new(seed: u64) -> Self {
+		Oracle { rng: SmallRng::seed_from_u64(seed), entries: SecondaryMap::new() }
+	}
+	/// Remember node ID and route coordinate reported by a machine's device
+	pub fn node_info(&mut self, machine_idx: NodeIdx, node_id: NodeID, route_coord: RouteCoord) {
+		self.entries.insert(machine_idx, OracleEntry { node_id: Some(node_id), route_coord: Some(route_coord) });
+	}
+	/// Remember coordinate reported by a machine's baseline, so analyses treat it like a Dither route coordinate
+	pub fn baseline_info(&mut self, machine_idx: NodeIdx, info: &BaselineInfo) {
+		self.entries.insert(machine_idx, OracleEntry { node_id: None, route_coord: info.coord.as_ref().map(baseline_route_coord) });
+	}
+	pub fn remove(&mut self, machine_idx: NodeIdx) {
+		self.entries.remove(machine_idx);
+	}
+	/// Route coordinates of all machines whose device reported or published one
+	pub fn route_coords(&self) -> impl Iterator<Item = (NodeIdx, &RouteCoord)> {
+		self.entries.iter().filter_map(|(idx, entry)|Some((idx, entry.route_coord.as_ref()?)))
+	}
+	/// Answer request of a machine given its true position and the device addresses of the running machines, the asking machine is never picked as peer
+	pub fn answer(&mut self, machine_idx: NodeIdx, request: OracleRequest, position: FieldPosition, peers: Vec<(NodeIdx, SocketAddr)>) -> OracleResponse {
+		match request {
+			OracleRequest::Position => OracleResponse::Position(position.x, position.y),
+			OracleRequest::RandomPeer => {
+				let peer = peers.into_iter().filter(|(idx, _)|*idx != machine_idx).choose(&mut self.rng);
+				OracleResponse::RandomPeer(peer.map(|(idx, addr)|(addr, self.entries.get(idx).and_then(|entry|entry.node_id.clone()))))
+			}
+			OracleRequest::RouteCoord(node_id) => {
+				let route_coord = self.entries.values().find(|entry|entry.node_id.as_ref() == Some(&node_id)).and_then(|entry|entry.route_coord.clone());
+				OracleResponse::RouteCoord(node_id, route_coord)
+			}
+			OracleRequest::RouteCoordTable => OracleResponse::RouteCoordTable(
+				self.entries.values().filter_map(|entry|Some((entry.node_id.clone()?, entry.route_coord.clone()?))).collect()
+			),
+			OracleRequest::PublishRouteCoord(node_id, route_coord) => {
+				let previous = self.entries.insert(machine_idx, OracleEntry { node_id: Some(node_id), route_coord: Some(route_coord) });
+				OracleResponse::Published(previous.and_then(|entry|entry.route_coord))
+			}
+		}
+	}
+}
+
+impl Internet {
+	/// Answer oracle request of a machine's device, peers are running machines reachable at an address
+	pub(crate) fn answer_oracle(&self, runtime: &mut InternetRuntime, machine_idx: NodeIdx, id: u64, request: OracleRequest) -> Result<(), InternetError> {
+		let position = self.node(machine_idx)?.position;
+		let peers = self.nodes.iter().filter_map(|(idx, node)|{
+			let machine = node.machine().filter(|machine|machine.powered && machine.is_running())?;
+			let ip = machine.connection_ip().map(IpAddr::from).or(machine.connection_ip6().map(IpAddr::from))?;
+			Some((idx, SocketAddr::new(ip, DEVICE_PORT)))
+		}).collect();
+		log::debug!("Machine {} asked the oracle for {:?}", machine_idx, request);
+		let response = runtime.oracle.answer(machine_idx, request, position, peers);
+		self.machine(machine_idx)?.device_command(DeviceCommand::OracleResponse(id, response))?;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::net::Ipv4Addr;
+
+	use slotmap::SlotMap;
+
+	use super::*;
+
+	#[test]
+	fn random_peer_excludes_asker() {
+		let mut keys = SlotMap::<NodeIdx, ()>::with_key();
+		let machines: Vec<NodeIdx> = (0..4).map(|_|keys.insert(())).collect();
+		let addr = |i: usize|SocketAddr::new(IpAddr::V4(Ipv4Addr::new(100, 64, 0, i as u8 + 1)), DEVICE_PORT);
+		let peers: Vec<(NodeIdx, SocketAddr)> = machines.iter().enumerate().map(|(i, &idx)|(idx, addr(i))).collect();
+		let mut oracle = Oracle::new(1);
+
+		for (i, &asker) in machines.iter().enumerate() {
+			let mut picked = Vec::new();
+			for _ in 0..64 {
+				match oracle.answer(asker, OracleRequest::RandomPeer, FieldPosition::new(0, 0), peers.clone()) {
+					OracleResponse::RandomPeer(Some((peer, None))) => picked.push(peer),
+					response => panic!("unexpected response {:?}", response),
+				}
+			}
+			assert!(!picked.contains(&addr(i)));
+			// Every other machine gets picked eventually
+			assert!((0..machines.len()).filter(|&j|j != i).all(|j|picked.contains(&addr(j))));
+		}
+
+		// A machine alone has no peer
+		let alone = &peers[..1];
+		assert!(matches!(oracle.answer(machines[0], OracleRequest::RandomPeer, FieldPosition::new(0, 0), alone.to_vec()), OracleResponse::RandomPeer(None)));
+	}
+}
@@ -0,0 +1,234 @@
+//! Minimal IPv4 / IPv6 / TCP / UDP header access.
+//! Used by packet stages (NAT, etc.) that need to inspect or rewrite packets as they pass between a router and a wire.
+
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4};
+
+/// Transport protocol carried by an IP packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Protocol {
+	Icmp,
+	Tcp,
+	Udp,
+	Other(u8),
+}
+impl From<u8> for Protocol {
+	fn from(num: u8) -> Self {
+		match num { 1 => Protocol::Icmp, 6 => Protocol::Tcp, 17 => Protocol::Udp, other => Protocol::Other(other) }
+	}
+}
+
+/// Parsed addressing information of an IPv4 packet
+#[derive(Debug, Clone, Copy)]
+pub struct PacketHeader {
+	pub src: Ipv4Addr,
+	pub dst: Ipv4Addr,
+	pub protocol: Protocol,
+	/// Source and destination ports, only set for unfragmented TCP & UDP packets
+	pub ports: Option<(u16, u16)>,
+}
+
+impl PacketHeader {
+	/// Parse header of an IPv4 packet, returns None if packet is malformed or not IPv4
+	pub fn parse(packet: &[u8]) -> Option<PacketHeader> {
+		if packet.len() < 20 || packet[0] >> 4 != 4 { return None }
+		let header_len = ((packet[0] & 0x0f) as usize) * 4;
+		if header_len < 20 || packet.len() < header_len { return None }
+
+		let protocol = Protocol::from(packet[9]);
+		let src = Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]);
+		let dst = Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]);
+		let fragment_offset = u16::from_be_bytes([packet[6], packet[7]]) & 0x1fff;
+
+		let ports = match protocol {
+			Protocol::Tcp | Protocol::Udp if fragment_offset == 0 && packet.len() >= header_len + 4 => {
+				let transport = &packet[header_len..];
+				Some((u16::from_be_bytes([transport[0], transport[1]]), u16::from_be_bytes([transport[2], transport[3]])))
+			}
+			_ => None,
+		};
+		Some(PacketHeader { src, dst, protocol, ports })
+	}
+	pub fn src_endpoint(&self) -> Option<SocketAddrV4> {
+		self.ports.map(|(src_port, _)|SocketAddrV4::new(self.src, src_port))
+	}
+	pub fn dst_endpoint(&self) -> Option<SocketAddrV4> {
+		self.ports.map(|(_, dst_port)|SocketAddrV4::new(self.dst, dst_port))
+	}
+}
+
+/// Parsed addressing information of an IPv6 packet
+#[derive(Debug, Clone, Copy)]
+pub struct Ipv6Header {
+	pub src: Ipv6Addr,
+	pub dst: Ipv6Addr,
+	/// Protocol of the first next header, packets with extension headers report the extension header
+	pub protocol: Protocol,
+	/// Source and destination ports, only set for TCP & UDP packets without extension headers
+	pub ports: Option<(u16, u16)>,
+}
+
+impl Ipv6Header {
+	/// Parse header of an IPv6 packet, returns None if packet is malformed or not IPv6
+	pub fn parse(packet: &[u8]) -> Option<Ipv6Header> {
+		if packet.len() < 40 || packet[0] >> 4 != 6 { return None }
+		let protocol = match packet[6] { 58 => Protocol::Icmp, next => Protocol::from(next) };
+		let address = |start: usize|{
+			let mut octets = [0u8; 16];
+			octets.copy_from_slice(&packet[start..start + 16]);
+			Ipv6Addr::from(octets)
+		};
+		let ports = match protocol {
+			Protocol::Tcp | Protocol::Udp if packet.len() >= 44 => Some((u16::from_be_bytes([packet[40], packet[41]]), u16::from_be_bytes([packet[42], packet[43]]))),
+			_ => None,
+		};
+		Some(Ipv6Header { src: address(8), dst: address(24), protocol, ports })
+	}
+}
+
+/// Rewrite source address (and port if TCP/UDP) of a packet, fixes up checksums.
+pub fn set_source(packet: &mut [u8], endpoint: SocketAddrV4) {
+	packet[12..16].copy_from_slice(&endpoint.ip().octets());
+	set_port(packet, 0, endpoint.port());
+	update_checksums(packet);
+}
+
+/// Rewrite destination address (and port if TCP/UDP) of a packet, fixes up checksums.
+pub fn set_destination(packet: &mut [u8], endpoint: SocketAddrV4) {
+	packet[16..20].copy_from_slice(&endpoint.ip().octets());
+	set_port(packet, 2, endpoint.port());
+	update_checksums(packet);
+}
+
+fn set_port(packet: &mut [u8], offset: usize, port: u16) {
+	if let Some(PacketHeader { ports: Some(_), .. }) = PacketHeader::parse(packet) {
+		let start = header_len(packet) + offset;
+		packet[start..start + 2].copy_from_slice(&port.to_be_bytes());
+	}
+}
+
+fn header_len(packet: &[u8]) -> usize { ((packet[0] & 0x0f) as usize) * 4 }
+
+/// Recalculate IPv4 header checksum and TCP/UDP checksum
+pub fn update_checksums(packet: &mut [u8]) {
+	let header_len = header_len(packet);
+	packet[10..12].copy_from_slice(&[0, 0]);
+	let header_checksum = checksum(&packet[..header_len], 0);
+	packet[10..12].copy_from_slice(&header_checksum.to_be_bytes());
+
+	let header = match PacketHeader::parse(packet) { Some(header) => header, None => return };
+	if header.ports.is_none() { return }
+
+	let total_len = (u16::from_be_bytes([packet[2], packet[3]]) as usize).min(packet.len());
+	if total_len < header_len { return }
+	let checksum_offset = match header.protocol {
+		Protocol::Tcp if total_len >= header_len + 18 => header_len + 16,
+		// UDP checksum of zero means no checksum was calculated
+		Protocol::Udp if total_len >= header_len + 8 && packet[header_len + 6..header_len + 8] != [0, 0] => header_len + 6,
+		_ => return,
+	};
+	packet[checksum_offset..checksum_offset + 2].copy_from_slice(&[0, 0]);
+
+	let transport_len = (total_len - header_len) as u32;
+	let pseudo_header = sum_words(&packet[12..20]) + packet[9] as u32 + transport_len;
+	let mut transport_checksum = checksum(&packet[header_len..total_len], pseudo_header);
+	if header.protocol == Protocol::Udp && transport_checksum == 0 { transport_checksum = 0xffff }
+	packet[checksum_offset..checksum_offset + 2].copy_from_slice(&transport_checksum.to_be_bytes());
+}
+
+/// Payload of an unfragmented UDP packet
+pub fn udp_payload(packet: &[u8]) -> Option<&[u8]> {
+	match PacketHeader::parse(packet)? {
+		PacketHeader { protocol: Protocol::Udp, ports: Some(_), .. } => {
+			let start = header_len(packet) + 8;
+			let total_len = (u16::from_be_bytes([packet[2], packet[3]]) as usize).min(packet.len());
+			packet.get(start..total_len)
+		}
+		_ => None,
+	}
+}
+
+/// Build UDP packet carrying a payload, returns None if the payload doesn't fit into a single packet
+pub fn udp_packet(src: SocketAddrV4, dst: SocketAddrV4, payload: &[u8]) -> Option<Vec<u8>> {
+	let total_len = u16::try_from(20 + 8 + payload.len()).ok()?;
+	let mut packet = vec![0u8; total_len as usize];
+	packet[0] = 0x45;
+	packet[2..4].copy_from_slice(&total_len.to_be_bytes());
+	packet[8] = 64; // TTL
+	packet[9] = 17; // UDP
+	packet[12..16].copy_from_slice(&src.ip().octets());
+	packet[16..20].copy_from_slice(&dst.ip().octets());
+	packet[20..22].copy_from_slice(&src.port().to_be_bytes());
+	packet[22..24].copy_from_slice(&dst.port().to_be_bytes());
+	packet[24..26].copy_from_slice(&(total_len - 20).to_be_bytes());
+	// Non-zero placeholder so the checksum gets calculated
+	packet[26..28].copy_from_slice(&[0xff, 0xff]);
+	packet[28..].copy_from_slice(payload);
+	update_checksums(&mut packet);
+	Some(packet)
+}
+
+/// Build ICMP destination unreachable message in response to a packet, sent from `from`.
+/// Returns None if the packet is malformed or is itself an ICMP message.
+pub fn icmp_unreachable(original: &[u8], from: Ipv4Addr, code: u8) -> Option<Vec<u8>> {
+	let header = PacketHeader::parse(original)?;
+	if header.protocol == Protocol::Icmp { return None }
+	// Quote original IP header and first 8 bytes of payload
+	let quoted = &original[..(header_len(original) + 8).min(original.len())];
+	let total_len = 20 + 8 + quoted.len();
+
+	let mut packet = vec![0u8; total_len];
+	packet[0] = 0x45;
+	packet[2..4].copy_from_slice(&(total_len as u16).to_be_bytes());
+	packet[8] = 64; // TTL
+	packet[9] = 1; // ICMP
+	packet[12..16].copy_from_slice(&from.octets());
+	packet[16..20].copy_from_slice(&header.src.octets());
+	packet[20] = 3; // Destination Unreachable
+	packet[21] = code;
+	packet[28..].copy_from_slice(quoted);
+	let icmp_checksum = checksum(&packet[20..], 0);
+	packet[22..24].copy_from_slice(&icmp_checksum.to_be_bytes());
+	update_checksums(&mut packet);
+	Some(packet)
+}
+
+/// Build ICMPv6 destination unreachable message in response to an IPv6 packet, sent from `from`.
+/// Returns None if the packet is malformed or is itself an ICMPv6 message.
+pub fn icmp6_unreachable(original: &[u8], from: Ipv6Addr, code: u8) -> Option<Vec<u8>> {
+	let header = Ipv6Header::parse(original)?;
+	if header.protocol == Protocol::Icmp { return None }
+	// Quote as much of the original packet as fits into the minimum IPv6 MTU
+	let quoted = &original[..original.len().min(1280 - 48)];
+	let icmp_len = 8 + quoted.len();
+
+	let mut packet = vec![0u8; 40 + icmp_len];
+	packet[0] = 0x60;
+	packet[4..6].copy_from_slice(&(icmp_len as u16).to_be_bytes());
+	packet[6] = 58; // ICMPv6
+	packet[7] = 64; // Hop limit
+	packet[8..24].copy_from_slice(&from.octets());
+	packet[24..40].copy_from_slice(&header.src.octets());
+	packet[40] = 1; // Destination Unreachable
+	packet[41] = code;
+	packet[48..].copy_from_slice(quoted);
+	// Checksum covers a pseudo header of both addresses, the ICMPv6 length and next header
+	let pseudo_header = sum_words(&packet[8..40]) + icmp_len as u32 + 58;
+	let icmp_checksum = checksum(&packet[40..], pseudo_header);
+	packet[42..44].copy_from_slice(&icmp_checksum.to_be_bytes());
+	Some(packet)
+}
+
+fn sum_words(data: &[u8]) -> u32 {
+	data.chunks(2).map(|chunk| match chunk {
+		[high, low] => u16::from_be_bytes([*high, *low]) as u32,
+		[high] => u16::from_be_bytes([*high, 0]) as u32,
+		_ => 0,
+	}).sum()
+}
+
+/// Internet checksum (RFC 1071)
+fn checksum(data: &[u8], initial: u32) -> u16 {
+	let mut sum = initial + sum_words(data);
+	while sum >> 16 != 0 { sum = (sum & 0xffff) + (sum >> 16); }
+	!(sum as u16)
+}
@@ -0,0 +1,672 @@
+//! Route computation between InternetNetworks.
+//! Routes are either precomputed (every network routes each other network's address range down the first hop of the lowest-latency path towards it)
+//! or learned through a simulated path-vector protocol that exchanges advertisements between neighbouring networks with realistic delays.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::time::Duration;
+
+use netsim_embed::{Ipv4Range, Ipv4Route};
+use petgraph::{Graph, Undirected, algo::bellman_ford, graph::NodeIndex};
+
+use super::{Internet, InternetAction, InternetError, InternetEvent, InternetRuntime, Latency, NodeIdx, WireIdx};
+use super::ipv6::Ipv6Range;
+
+/// Routes to install on each uplink of each network
+pub type RouteTable = HashMap<NodeIdx, HashMap<WireIdx, ConnectionRoutes>>;
+
+/// Routes a router sends down a connection, for both IP versions
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ConnectionRoutes {
+	pub v4: Vec<Ipv4Route>,
+	pub v6: Vec<Ipv6Range>,
+}
+impl ConnectionRoutes {
+	pub fn new(v4: Vec<Ipv4Route>, v6: Vec<Ipv6Range>) -> Self { ConnectionRoutes { v4, v6 } }
+	pub fn push(&mut self, v4: impl Into<Ipv4Route>, v6: Ipv6Range) {
+		self.v4.push(v4.into());
+		self.v6.push(v6);
+	}
+	/// Whether both route lists lead to the same destinations
+	pub fn same_destinations(&self, other: &ConnectionRoutes) -> bool {
+		self.v4.iter().map(|route|route.dest()).eq(other.v4.iter().map(|route|route.dest())) && self.v6 == other.v6
+	}
+}
+
+/// How routes between networks are determined
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum RoutingMode {
+	/// Shortest-path routes computed from the whole topology, updated instantly
+	Static,
+	/// Simplified BGP-like path-vector protocol run between networks
+	PathVector(PathVectorConfig),
+}
+impl Default for RoutingMode {
+	fn default() -> Self { RoutingMode::Static }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PathVectorConfig {
+	/// Time a network takes to process an update before advertising changes (added on top of wire latency)
+	pub processing_delay: Duration,
+	/// Time it takes a network to notice that a wire went down
+	pub failure_detection: Duration,
+}
+impl Default for PathVectorConfig {
+	fn default() -> Self {
+		PathVectorConfig { processing_delay: Duration::from_millis(50), failure_detection: Duration::from_secs(3) }
+	}
+}
+
+/// Route to a network as advertised by the path-vector protocol
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PathRoute {
+	pub range: Ipv4Range,
+	pub range6: Ipv6Range,
+	/// Networks along the path, starting with the advertising network and ending with the destination
+	pub path: Vec<NodeIdx>,
+	/// Sum of wire latencies along the path
+	pub latency: Latency,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum RouteAdvertisement {
+	Announce(NodeIdx, PathRoute),
+	Withdraw(NodeIdx),
+}
+
+#[derive(Debug, Clone)]
+struct SelectedRoute {
+	wire: WireIdx,
+	route: PathRoute,
+	/// How many times the selected route for this destination has changed
+	changes: u32,
+}
+
+/// Routing information base of a single network
+#[derive(Debug, Default)]
+pub struct Rib {
+	/// Routes learned from each neighbour, by destination network. Ordered so selection and advertisements don't depend on hashing.
+	learned: BTreeMap<NodeIdx, BTreeMap<WireIdx, PathRoute>>,
+	/// Currently selected route per destination
+	selected: BTreeMap<NodeIdx, SelectedRoute>,
+	changes: HashMap<NodeIdx, u32>,
+	/// Destinations lost since the last advertisements were sent
+	withdrawn: BTreeSet<NodeIdx>,
+}
+
+/// Entry of the routing table reported in NetworkInfo
+#[derive(Debug, Clone)]
+pub struct RouteTableEntry {
+	pub range: Ipv4Range,
+	pub range6: Ipv6Range,
+	pub wire: WireIdx,
+	/// Network the wire leads to
+	pub next_hop: NodeIdx,
+	/// Set if route was learned by the routing protocol
+	pub path: Option<Vec<NodeIdx>>,
+	pub latency: Option<Latency>,
+	/// Number of times the protocol changed its selected route for this destination
+	pub changes: u32,
+}
+
+impl Rib {
+	pub fn clear(&mut self) { *self = Rib::default(); }
+	/// Process advertisement received on a wire, returns true if selected route changed.
+	fn receive(&mut self, own: NodeIdx, wire: WireIdx, wire_latency: Latency, advertisement: RouteAdvertisement) -> bool {
+		let dest = match advertisement {
+			// Routes that would loop through us are treated as withdrawals
+			RouteAdvertisement::Announce(dest, route) if !route.path.contains(&own) => {
+				let route = PathRoute { latency: route.latency + wire_latency, ..route };
+				self.learned.entry(dest).or_default().insert(wire, route);
+				dest
+			}
+			RouteAdvertisement::Announce(dest, _) | RouteAdvertisement::Withdraw(dest) => {
+				if let Some(routes) = self.learned.get_mut(&dest) { routes.remove(&wire); }
+				dest
+			}
+		};
+		self.select(dest)
+	}
+	/// Forget all routes learned through a wire, returns whether any selected route changed
+	fn link_down(&mut self, wire: WireIdx) -> bool {
+		let dests: Vec<NodeIdx> = self.learned.iter_mut()
+			.filter_map(|(dest, routes)|routes.remove(&wire).map(|_|*dest)).collect();
+		dests.into_iter().fold(false, |changed, dest|self.select(dest) || changed)
+	}
+	/// Pick lowest latency (then shortest, then lowest wire) route to destination, returns true if selection changed
+	fn select(&mut self, dest: NodeIdx) -> bool {
+		let best = self.learned.get(&dest).and_then(|routes|{
+			routes.iter().min_by_key(|(wire, route)|(route.latency, route.path.len(), **wire)).map(|(wire, route)|(*wire, route.clone()))
+		});
+		let current = self.selected.get(&dest).map(|selected|(selected.wire, &selected.route));
+		if current == best.as_ref().map(|(wire, route)|(*wire, route)) { return false }
+
+		let changes = self.changes.entry(dest).or_default();
+		*changes += 1;
+		match best {
+			Some((wire, route)) => {
+				self.selected.insert(dest, SelectedRoute { wire, route, changes: *changes });
+				self.withdrawn.remove(&dest);
+			}
+			None => {
+				self.selected.remove(&dest);
+				self.withdrawn.insert(dest);
+			}
+		}
+		true
+	}
+	/// Advertisements of the network's own range and all selected routes, plus withdrawals of destinations lost since the last call
+	fn advertisements(&mut self, own: NodeIdx, own_range: Ipv4Range, own_range6: Ipv6Range) -> Vec<RouteAdvertisement> {
+		let mut advertisements = vec![RouteAdvertisement::Announce(own, PathRoute { range: own_range, range6: own_range6, path: vec![own], latency: 0 })];
+		for (dest, selected) in self.selected.iter() {
+			let mut path = vec![own];
+			path.extend_from_slice(&selected.route.path);
+			advertisements.push(RouteAdvertisement::Announce(*dest, PathRoute { path, ..selected.route.clone() }));
+		}
+		advertisements.extend(std::mem::take(&mut self.withdrawn).into_iter().map(RouteAdvertisement::Withdraw));
+		advertisements
+	}
+	/// Routes to install on each wire
+	fn wire_routes(&self) -> HashMap<WireIdx, ConnectionRoutes> {
+		let mut wire_routes = HashMap::<WireIdx, ConnectionRoutes>::new();
+		for selected in self.selected.values() {
+			wire_routes.entry(selected.wire).or_default().push(selected.route.range, selected.route.range6);
+		}
+		wire_routes
+	}
+	/// Protocol information about a route to a given range
+	pub fn route_info(&self, range: &Ipv4Range) -> Option<(Vec<NodeIdx>, Latency, u32)> {
+		self.selected.values().find(|selected|&selected.route.range == range)
+			.map(|selected|(selected.route.path.clone(), selected.route.latency, selected.changes))
+	}
+}
+
+impl Internet {
+	/// Compute shortest-path routes for every uplink of every network, weighted by wire latency.
+	pub(crate) fn compute_routes(&self) -> Result<RouteTable, InternetError> {
+		let mut graph = Graph::<NodeIdx, f64, Undirected>::new_undirected();
+		let mut indices = HashMap::<NodeIdx, NodeIndex>::new();
+		for (node_idx, node) in self.nodes.iter() {
+			if node.network().is_some() { indices.insert(node_idx, graph.add_node(node_idx)); }
+		}
+
+		// Lowest-latency wire between each pair of connected networks
+		let mut links = HashMap::<(NodeIdx, NodeIdx), (WireIdx, Latency)>::new();
+		for (wire_idx, wire) in self.wires.iter() {
+			let (node1, node2) = wire.nodes();
+			if !wire.is_up() || !indices.contains_key(&node1) || !indices.contains_key(&node2) { continue }
+			let latency = self.wire_latency(wire_idx)?;
+			for key in [(node1, node2), (node2, node1)] {
+				let link = links.entry(key).or_insert((wire_idx, latency));
+				if latency < link.1 { *link = (wire_idx, latency); }
+			}
+		}
+		for (&(node1, node2), &(_, latency)) in links.iter() {
+			if node1 < node2 { graph.add_edge(indices[&node1], indices[&node2], latency as f64); }
+		}
+
+		let mut table = RouteTable::new();
+		for (&source, &source_index) in indices.iter() {
+			let paths = bellman_ford(&graph, source_index).expect("wire latencies should never be negative");
+			let mut wire_routes: HashMap<WireIdx, ConnectionRoutes> = self.network(source)?.connections.iter()
+				.filter(|(_, connection)|connection.uplink)
+				.map(|(wire_idx, _)|(wire_idx, ConnectionRoutes::default())).collect();
+
+			for (&dest, &dest_index) in indices.iter() {
+				if dest == source { continue }
+				// Walk predecessors back towards source to find the first hop
+				let mut hop = dest_index;
+				let first_hop = loop {
+					match paths.predecessors[hop.index()] {
+						Some(prev) if prev == source_index => break Some(hop),
+						Some(prev) => hop = prev,
+						None => break None, // Unreachable
+					}
+				};
+				if let Some(first_hop) = first_hop {
+					let (wire_idx, _) = links[&(source, graph[first_hop])];
+					let dest = self.network(dest)?;
+					wire_routes.entry(wire_idx).or_default().push(dest.range(), dest.range6());
+				}
+			}
+			table.insert(source, wire_routes);
+		}
+		Ok(table)
+	}
+	/// Recompute routes between all networks and install them into each network's router.
+	pub(crate) fn update_routes(&mut self) -> Result<(), InternetError> {
+		for (network_idx, wire_routes) in self.compute_routes()? {
+			let network = self.network_mut(network_idx)?;
+			for (wire_idx, routes) in wire_routes {
+				network.set_routes(wire_idx, routes)?;
+			}
+		}
+		Ok(())
+	}
+
+	/// Switch routing mode, restarting route computation from scratch. Routes learned by the protocol are forgotten.
+	pub(crate) fn set_routing_mode(&mut self, runtime: &mut InternetRuntime, mode: RoutingMode) -> Result<(), InternetError> {
+		self.routing_mode = mode;
+		for node in self.nodes.values_mut() {
+			if let Some(network) = node.network_mut() { network.rib.clear(); }
+		}
+		self.start_routing(runtime)
+	}
+	/// Install initial routes, for the path-vector protocol every network starts out only knowing about itself
+	pub(crate) fn start_routing(&mut self, runtime: &mut InternetRuntime) -> Result<(), InternetError> {
+		if let RoutingMode::PathVector(_) = self.routing_mode {
+			let networks: Vec<NodeIdx> = self.nodes.iter().filter(|(_, node)|node.network().is_some()).map(|(idx, _)|idx).collect();
+			for &network_idx in &networks {
+				self.network_mut(network_idx)?.rib.clear();
+				self.install_learned_routes(network_idx)?;
+			}
+			for network_idx in networks { self.advertise(runtime, network_idx, None)?; }
+			Ok(())
+		} else { self.update_routes() }
+	}
+	/// A wire between two networks was added
+	pub(crate) fn routing_link_up(&mut self, runtime: &mut InternetRuntime, wire_idx: WireIdx) -> Result<(), InternetError> {
+		match self.routing_mode {
+			RoutingMode::Static => self.update_routes(),
+			RoutingMode::PathVector(_) => {
+				let (node1, node2) = self.wires[wire_idx].nodes();
+				self.advertise(runtime, node1, Some(wire_idx))?;
+				self.advertise(runtime, node2, Some(wire_idx))
+			}
+		}
+	}
+	/// A wire between two networks was removed, the protocol only notices after the failure detection delay
+	pub(crate) fn routing_link_down(&mut self, runtime: &mut InternetRuntime, wire_idx: WireIdx, node1: NodeIdx, node2: NodeIdx) -> Result<(), InternetError> {
+		match &self.routing_mode {
+			RoutingMode::Static => self.update_routes(),
+			RoutingMode::PathVector(config) => {
+				runtime.schedule(config.failure_detection, InternetAction::RoutingLinkDown(node1, wire_idx));
+				runtime.schedule(config.failure_detection, InternetAction::RoutingLinkDown(node2, wire_idx));
+				Ok(())
+			}
+		}
+	}
+	/// Latency of a network's uplinks changed
+	pub(crate) fn routing_latency_changed(&mut self, runtime: &mut InternetRuntime, network_idx: NodeIdx) -> Result<(), InternetError> {
+		match self.routing_mode {
+			RoutingMode::Static => self.update_routes(),
+			RoutingMode::PathVector(_) => {
+				// Exchange full tables with all neighbours so they pick up the new latencies
+				let neighbours: Vec<(WireIdx, NodeIdx)> = self.network(network_idx)?.connections.iter()
+					.filter(|(_, connection)|connection.uplink).map(|(wire_idx, connection)|(wire_idx, connection.node)).collect();
+				for (wire_idx, neighbour) in neighbours {
+					self.advertise(runtime, network_idx, Some(wire_idx))?;
+					self.advertise(runtime, neighbour, Some(wire_idx))?;
+				}
+				Ok(())
+			}
+		}
+	}
+	/// Handle advertisements arriving at a network
+	pub(crate) fn handle_route_advertisements(&mut self, runtime: &mut InternetRuntime, network_idx: NodeIdx, wire_idx: WireIdx, advertisements: Vec<RouteAdvertisement>) -> Result<(), InternetError> {
+		// Updates still in flight when the protocol was switched off are dropped
+		if !matches!(self.routing_mode, RoutingMode::PathVector(_)) { return Ok(()) }
+		// Wire may have been removed or suspended while the update was in flight
+		if !self.wires.get(wire_idx).map_or(false, |wire|wire.is_up()) { return Ok(()) }
+		let wire_latency = self.wire_latency(wire_idx)?;
+		let network = self.network_mut(network_idx)?;
+		let mut changed = false;
+		for advertisement in advertisements {
+			changed |= network.rib.receive(network_idx, wire_idx, wire_latency, advertisement);
+		}
+		if changed { self.routes_changed(runtime, network_idx)?; }
+		Ok(())
+	}
+	/// Handle a network noticing that a wire is down
+	pub(crate) fn handle_route_link_down(&mut self, runtime: &mut InternetRuntime, network_idx: NodeIdx, wire_idx: WireIdx) -> Result<(), InternetError> {
+		if !matches!(self.routing_mode, RoutingMode::PathVector(_)) { return Ok(()) }
+		// Suspended wire may have come back up before the failure was noticed
+		if self.wires.get(wire_idx).map_or(false, |wire|wire.is_up()) { return Ok(()) }
+		// Network may have been removed in the meantime
+		let network = match self.node_mut(network_idx).ok().and_then(|node|node.network_mut()) { Some(network) => network, None => return Ok(()) };
+		if network.rib.link_down(wire_idx) { self.routes_changed(runtime, network_idx)?; }
+		Ok(())
+	}
+	fn routes_changed(&mut self, runtime: &mut InternetRuntime, network_idx: NodeIdx) -> Result<(), InternetError> {
+		self.install_learned_routes(network_idx)?;
+		self.advertise(runtime, network_idx, None)?;
+		runtime.send_event(InternetEvent::NetworkInfo(network_idx, self.network(network_idx)?.network_info()))
+	}
+	/// Install routes selected by the protocol into the network's router
+	fn install_learned_routes(&mut self, network_idx: NodeIdx) -> Result<(), InternetError> {
+		let network = self.network_mut(network_idx)?;
+		let mut wire_routes = network.rib.wire_routes();
+		let uplinks: Vec<WireIdx> = network.connections.iter().filter(|(_, connection)|connection.uplink).map(|(wire_idx, _)|wire_idx).collect();
+		for wire_idx in uplinks {
+			network.set_routes(wire_idx, wire_routes.remove(&wire_idx).unwrap_or_default())?;
+		}
+		Ok(())
+	}
+	/// Send network's routes to its neighbours (or only down a single wire), arriving after wire latency and processing delay
+	fn advertise(&mut self, runtime: &mut InternetRuntime, network_idx: NodeIdx, only_wire: Option<WireIdx>) -> Result<(), InternetError> {
+		let processing_delay = match &self.routing_mode { RoutingMode::PathVector(config) => config.processing_delay, _ => return Ok(()) };
+		let network = self.network_mut(network_idx)?;
+		let (range, range6) = (network.range(), network.range6());
+		let advertisements = network.rib.advertisements(network_idx, range, range6);
+		let network = self.network(network_idx)?;
+		for (wire_idx, connection) in network.connections.iter() {
+			if !connection.uplink || only_wire.map_or(false, |only|only != wire_idx) { continue }
+			if !self.wire(wire_idx)?.is_up() { continue }
+			let delay = Duration::from_micros(self.wire_latency(wire_idx)?) + processing_delay;
+			runtime.schedule(delay, InternetAction::RoutingUpdate(connection.node, wire_idx, advertisements.clone()));
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::net::{Ipv4Addr, Ipv6Addr};
+
+	use slotmap::SlotMap;
+
+	use super::*;
+	use super::super::{FieldPosition, InternetNetwork, InternetNode, InternetWire, NetworkConnection, SuspendReason, WireLatency};
+
+	/// Internet of networks at positions along the x axis, without any running routers
+	fn networks(positions: &[i32]) -> (Internet, Vec<NodeIdx>) {
+		let mut internet = Internet::new("device");
+		let indices = positions.iter().enumerate().map(|(i, &x)|{
+			let range = Ipv4Range::new(Ipv4Addr::new(100, 64 + i as u8, 0, 0), 16);
+			let range6 = Ipv6Range::new(Ipv6Addr::new(0xfd00, 0xd17e, 0, i as u16, 0, 0, 0, 0), 64);
+			internet.nodes.insert_with_key(|key|InternetNode::from_network(InternetNetwork::new(key, range, range6), FieldPosition::new(x, 0), key))
+		}).collect();
+		(internet, indices)
+	}
+	fn link(internet: &mut Internet, a: NodeIdx, b: NodeIdx) -> WireIdx {
+		let wire_idx = internet.wires.insert(InternetWire::new(a, b));
+		for (from, to) in [(a, b), (b, a)] {
+			internet.network_mut(from).unwrap().connections.insert(wire_idx, NetworkConnection { node: to, uplink: true, routes: ConnectionRoutes::default() });
+		}
+		wire_idx
+	}
+	/// Networks whose ranges a network routes down a wire
+	fn routed(internet: &Internet, table: &RouteTable, from: NodeIdx, wire: WireIdx) -> Vec<NodeIdx> {
+		let mut dests: Vec<NodeIdx> = table[&from][&wire].v4.iter().map(|route|{
+			internet.nodes.iter().find(|(_, node)|node.network().map_or(false, |network|network.range() == route.dest())).expect("route to unknown range").0
+		}).collect();
+		dests.sort();
+		dests
+	}
+
+	#[test]
+	fn static_routes_line() {
+		let (mut internet, nodes) = networks(&[0, 1000, 2000]);
+		let (a, b, c) = (nodes[0], nodes[1], nodes[2]);
+		let (ab, bc) = (link(&mut internet, a, b), link(&mut internet, b, c));
+		let table = internet.compute_routes().unwrap();
+
+		assert_eq!(routed(&internet, &table, a, ab), vec![b, c]);
+		assert_eq!(routed(&internet, &table, b, ab), vec![a]);
+		assert_eq!(routed(&internet, &table, b, bc), vec![c]);
+		assert_eq!(routed(&internet, &table, c, bc), vec![a, b]);
+		// IPv6 ranges take the same first hop as the IPv4 ranges
+		let routes = &table[&a][&ab];
+		assert_eq!(routes.v6.len(), 2);
+		for node in [b, c] { assert!(routes.v6.contains(&internet.network(node).unwrap().range6())); }
+	}
+
+	#[test]
+	fn static_routes_triangle() {
+		let (mut internet, nodes) = networks(&[0, 1000, 2000]);
+		let (a, b, c) = (nodes[0], nodes[1], nodes[2]);
+		let (ab, bc, ac) = (link(&mut internet, a, b), link(&mut internet, b, c), link(&mut internet, a, c));
+
+		// Direct wire slower than the detour
+		internet.wires[ac].latency = WireLatency { a_to_b: Some(5000), b_to_a: Some(5000) };
+		let table = internet.compute_routes().unwrap();
+		assert_eq!(routed(&internet, &table, a, ab), vec![b, c]);
+		assert!(routed(&internet, &table, a, ac).is_empty());
+		assert_eq!(routed(&internet, &table, c, bc), vec![a, b]);
+
+		// Direct wire faster
+		internet.wires[ac].latency = WireLatency { a_to_b: Some(500), b_to_a: Some(500) };
+		let table = internet.compute_routes().unwrap();
+		assert_eq!(routed(&internet, &table, a, ab), vec![b]);
+		assert_eq!(routed(&internet, &table, a, ac), vec![c]);
+		assert_eq!(routed(&internet, &table, b, ab), vec![a]);
+		assert_eq!(routed(&internet, &table, b, bc), vec![c]);
+	}
+
+	#[test]
+	fn static_routes_partitioned() {
+		let (mut internet, nodes) = networks(&[0, 1000, 5000, 6000]);
+		let (a, b, c, d) = (nodes[0], nodes[1], nodes[2], nodes[3]);
+		let (ab, cd) = (link(&mut internet, a, b), link(&mut internet, c, d));
+		let table = internet.compute_routes().unwrap();
+		assert_eq!(routed(&internet, &table, a, ab), vec![b]);
+		assert_eq!(routed(&internet, &table, d, cd), vec![c]);
+
+		// Suspended wires don't carry routes, their uplinks are left without any
+		let bc = link(&mut internet, b, c);
+		internet.wires[bc].suspended.insert(SuspendReason::Partition);
+		let table = internet.compute_routes().unwrap();
+		assert!(routed(&internet, &table, b, bc).is_empty());
+		assert_eq!(routed(&internet, &table, b, ab), vec![a]);
+
+		internet.wires[bc].suspended.clear();
+		let table = internet.compute_routes().unwrap();
+		assert_eq!(routed(&internet, &table, a, ab), vec![b, c, d]);
+	}
+
+	fn announce(internet: &Internet, path: &[NodeIdx], latency: Latency) -> RouteAdvertisement {
+		let dest = *path.last().unwrap();
+		let network = internet.network(dest).unwrap();
+		RouteAdvertisement::Announce(dest, PathRoute { range: network.range(), range6: network.range6(), path: path.to_vec(), latency })
+	}
+	/// Exchange advertisements over all working wires until no network changes its selected routes
+	fn converge(internet: &Internet, ribs: &mut HashMap<NodeIdx, Rib>) {
+		for _ in 0..16 {
+			let mut changed = false;
+			// Every network sends the same advertisements to all neighbours, withdrawals only once
+			let sent: HashMap<NodeIdx, Vec<RouteAdvertisement>> = ribs.iter_mut().map(|(&from, rib)|{
+				let network = internet.network(from).unwrap();
+				(from, rib.advertisements(from, network.range(), network.range6()))
+			}).collect();
+			for (wire_idx, wire) in internet.wires.iter().filter(|(_, wire)|wire.is_up()) {
+				let latency = internet.wire_latency(wire_idx).unwrap();
+				for (from, to) in [(wire.a, wire.b), (wire.b, wire.a)] {
+					for advertisement in sent[&from].clone() {
+						changed |= ribs.get_mut(&to).unwrap().receive(to, wire_idx, latency, advertisement);
+					}
+				}
+			}
+			if !changed { return }
+		}
+		panic!("routes did not converge");
+	}
+	/// Take a wire down and let the networks at both of its ends notice
+	fn fail(internet: &mut Internet, ribs: &mut HashMap<NodeIdx, Rib>, wire_idx: WireIdx) {
+		internet.wires[wire_idx].suspended.insert(SuspendReason::Partition);
+		let (a, b) = internet.wires[wire_idx].nodes();
+		for node in [a, b] { ribs.get_mut(&node).unwrap().link_down(wire_idx); }
+	}
+	fn route(internet: &Internet, ribs: &HashMap<NodeIdx, Rib>, from: NodeIdx, to: NodeIdx) -> Option<(Vec<NodeIdx>, Latency)> {
+		ribs[&from].route_info(&internet.network(to).unwrap().range()).map(|(path, latency, _)|(path, latency))
+	}
+
+	#[test]
+	fn rib_selection() {
+		let (internet, nodes) = networks(&[0, 0, 0, 0]);
+		let (own, b, c, dest) = (nodes[0], nodes[1], nodes[2], nodes[3]);
+		let mut wires = SlotMap::<WireIdx, ()>::with_key();
+		let (via_b, via_c) = (wires.insert(()), wires.insert(()));
+		let range = internet.network(dest).unwrap().range();
+		let mut rib = Rib::default();
+
+		assert!(rib.receive(own, via_b, 100, announce(&internet, &[b, c, dest], 200)));
+		assert_eq!(rib.route_info(&range), Some((vec![b, c, dest], 300, 1)));
+		// Lower latency wins
+		assert!(rib.receive(own, via_c, 50, announce(&internet, &[c, dest], 100)));
+		assert_eq!(rib.route_info(&range), Some((vec![c, dest], 150, 2)));
+		// Same latency over a longer path doesn't replace it, neither does a repeated announcement
+		assert!(!rib.receive(own, via_b, 100, announce(&internet, &[b, c, dest], 50)));
+		assert!(!rib.receive(own, via_c, 50, announce(&internet, &[c, dest], 100)));
+		assert_eq!(rib.wire_routes()[&via_c].v4.len(), 1);
+
+		// Selected routes are advertised with the own network prepended, after the own range
+		let own_network = internet.network(own).unwrap();
+		let advertisements = rib.advertisements(own, own_network.range(), own_network.range6());
+		assert!(matches!(&advertisements[0], RouteAdvertisement::Announce(node, route) if *node == own && route.path == vec![own] && route.latency == 0));
+		assert!(advertisements.iter().any(|advertisement|matches!(advertisement, RouteAdvertisement::Announce(node, route) if *node == dest && route.path == vec![own, c, dest] && route.latency == 150)));
+	}
+
+	#[test]
+	fn rib_withdrawal_and_loops() {
+		let (internet, nodes) = networks(&[0, 0, 0, 0]);
+		let (own, b, c, dest) = (nodes[0], nodes[1], nodes[2], nodes[3]);
+		let mut wires = SlotMap::<WireIdx, ()>::with_key();
+		let (via_b, via_c) = (wires.insert(()), wires.insert(()));
+		let range = internet.network(dest).unwrap().range();
+		let mut rib = Rib::default();
+
+		// Routes through the own network are never learned
+		assert!(!rib.receive(own, via_b, 10, announce(&internet, &[b, own, dest], 10)));
+		assert_eq!(rib.route_info(&range), None);
+
+		rib.receive(own, via_b, 10, announce(&internet, &[b, dest], 100));
+		rib.receive(own, via_c, 10, announce(&internet, &[c, dest], 10));
+		assert_eq!(rib.route_info(&range), Some((vec![c, dest], 20, 2)));
+		// Withdrawal falls back to the remaining route
+		assert!(rib.receive(own, via_c, 10, RouteAdvertisement::Withdraw(dest)));
+		assert_eq!(rib.route_info(&range), Some((vec![b, dest], 110, 3)));
+		// A looping announcement replaces the route learned on its wire, like a withdrawal
+		assert!(rib.receive(own, via_b, 10, announce(&internet, &[b, own, dest], 10)));
+		assert_eq!(rib.route_info(&range), None);
+		assert!(rib.wire_routes().is_empty());
+
+		// Lost destinations are withdrawn from neighbours
+		let own_network = internet.network(own).unwrap();
+		let advertisements = rib.advertisements(own, own_network.range(), own_network.range6());
+		assert!(advertisements.iter().any(|advertisement|matches!(advertisement, RouteAdvertisement::Withdraw(node) if *node == dest)));
+	}
+
+	#[test]
+	fn rib_withdrawals_sent_once() {
+		let (internet, nodes) = networks(&[0, 0, 0]);
+		let (own, b, dest) = (nodes[0], nodes[1], nodes[2]);
+		let mut wires = SlotMap::<WireIdx, ()>::with_key();
+		let via_b = wires.insert(());
+		let own_network = internet.network(own).unwrap();
+		let withdrawals = |rib: &mut Rib|rib.advertisements(own, own_network.range(), own_network.range6()).into_iter()
+			.filter_map(|advertisement|match advertisement { RouteAdvertisement::Withdraw(node) => Some(node), _ => None }).collect::<Vec<_>>();
+		let mut rib = Rib::default();
+
+		rib.receive(own, via_b, 10, announce(&internet, &[b, dest], 10));
+		assert!(withdrawals(&mut rib).is_empty());
+		rib.receive(own, via_b, 10, RouteAdvertisement::Withdraw(dest));
+		assert_eq!(withdrawals(&mut rib), vec![dest]);
+		assert!(withdrawals(&mut rib).is_empty());
+
+		// A destination lost and found again before the next advertisements isn't withdrawn
+		rib.receive(own, via_b, 10, announce(&internet, &[b, dest], 10));
+		rib.link_down(via_b);
+		rib.receive(own, via_b, 10, announce(&internet, &[b, dest], 10));
+		assert!(withdrawals(&mut rib).is_empty());
+	}
+
+	#[test]
+	fn rib_tie_break() {
+		let (internet, nodes) = networks(&[0, 0, 0, 0]);
+		let (own, b, c, dest) = (nodes[0], nodes[1], nodes[2], nodes[3]);
+		let mut wires = SlotMap::<WireIdx, ()>::with_key();
+		let (first, second) = (wires.insert(()), wires.insert(()));
+		let range = internet.network(dest).unwrap().range();
+
+		// Equal latency and length picks the lower wire, whichever was learned first
+		for order in [[(first, b), (second, c)], [(second, c), (first, b)]] {
+			let mut rib = Rib::default();
+			for (wire, via) in order { rib.receive(own, wire, 10, announce(&internet, &[via, dest], 10)); }
+			assert_eq!(rib.route_info(&range).map(|(path, ..)|path), Some(vec![b, dest]));
+			assert_eq!(rib.wire_routes().keys().collect::<Vec<_>>(), vec![&first]);
+		}
+	}
+
+	#[test]
+	fn rib_link_down() {
+		let (internet, nodes) = networks(&[0, 0, 0, 0]);
+		let (own, b, c, dest) = (nodes[0], nodes[1], nodes[2], nodes[3]);
+		let mut wires = SlotMap::<WireIdx, ()>::with_key();
+		let (via_b, via_c) = (wires.insert(()), wires.insert(()));
+		let mut rib = Rib::default();
+
+		rib.receive(own, via_b, 10, announce(&internet, &[b, dest], 10));
+		rib.receive(own, via_c, 10, announce(&internet, &[c], 0));
+		assert!(rib.link_down(via_b));
+		assert!(!rib.link_down(via_b));
+		assert_eq!(rib.route_info(&internet.network(dest).unwrap().range()), None);
+		assert_eq!(rib.route_info(&internet.network(c).unwrap().range()), Some((vec![c], 10, 1)));
+		assert_eq!(rib.wire_routes().keys().collect::<Vec<_>>(), vec![&via_c]);
+	}
+
+	#[test]
+	fn path_vector_line() {
+		let (mut internet, nodes) = networks(&[0, 1000, 2000]);
+		let (a, b, c) = (nodes[0], nodes[1], nodes[2]);
+		let (ab, bc) = (link(&mut internet, a, b), link(&mut internet, b, c));
+		let mut ribs: HashMap<NodeIdx, Rib> = nodes.iter().map(|&node|(node, Rib::default())).collect();
+		converge(&internet, &mut ribs);
+
+		assert_eq!(route(&internet, &ribs, a, c), Some((vec![b, c], 2000)));
+		assert_eq!(route(&internet, &ribs, c, a), Some((vec![b, a], 2000)));
+		assert_eq!(route(&internet, &ribs, b, a), Some((vec![a], 1000)));
+		assert_eq!(ribs[&a].wire_routes()[&ab].v4.len(), 2);
+
+		// Breaking the line withdraws everything behind the break
+		fail(&mut internet, &mut ribs, bc);
+		converge(&internet, &mut ribs);
+		assert_eq!(route(&internet, &ribs, a, c), None);
+		assert_eq!(route(&internet, &ribs, c, b), None);
+		assert_eq!(route(&internet, &ribs, a, b), Some((vec![b], 1000)));
+	}
+
+	#[test]
+	fn path_vector_triangle() {
+		let (mut internet, nodes) = networks(&[0, 1000, 2000]);
+		let (a, b, c) = (nodes[0], nodes[1], nodes[2]);
+		let (ab, _, ac) = (link(&mut internet, a, b), link(&mut internet, b, c), link(&mut internet, a, c));
+		internet.wires[ac].latency = WireLatency { a_to_b: Some(5000), b_to_a: Some(5000) };
+		let mut ribs: HashMap<NodeIdx, Rib> = nodes.iter().map(|&node|(node, Rib::default())).collect();
+		converge(&internet, &mut ribs);
+		assert_eq!(route(&internet, &ribs, a, c), Some((vec![b, c], 2000)));
+		assert_eq!(route(&internet, &ribs, c, a), Some((vec![b, a], 2000)));
+
+		// Fails over to the slow wire
+		fail(&mut internet, &mut ribs, ab);
+		converge(&internet, &mut ribs);
+		assert_eq!(route(&internet, &ribs, a, c), Some((vec![c], 5000)));
+		assert_eq!(route(&internet, &ribs, a, b), Some((vec![c, b], 6000)));
+		assert_eq!(route(&internet, &ribs, b, a), Some((vec![c, a], 6000)));
+	}
+
+	#[test]
+	fn path_vector_partitioned() {
+		let (mut internet, nodes) = networks(&[0, 1000, 5000, 6000]);
+		let (a, b, c, d) = (nodes[0], nodes[1], nodes[2], nodes[3]);
+		link(&mut internet, a, b);
+		link(&mut internet, c, d);
+		let mut ribs: HashMap<NodeIdx, Rib> = nodes.iter().map(|&node|(node, Rib::default())).collect();
+		converge(&internet, &mut ribs);
+		assert_eq!(route(&internet, &ribs, a, b), Some((vec![b], 1000)));
+		assert_eq!(route(&internet, &ribs, a, c), None);
+		assert_eq!(route(&internet, &ribs, d, b), None);
+
+		// Joining the partitions
+		let bc = link(&mut internet, b, c);
+		converge(&internet, &mut ribs);
+		assert_eq!(route(&internet, &ribs, a, d), Some((vec![b, c, d], 6000)));
+
+		// Splitting them again
+		fail(&mut internet, &mut ribs, bc);
+		converge(&internet, &mut ribs);
+		for (from, to) in [(a, c), (a, d), (b, c), (d, a), (d, b)] {
+			assert_eq!(route(&internet, &ribs, from, to), None);
+		}
+		assert_eq!(route(&internet, &ribs, d, c), Some((vec![c], 1000)));
+	}
+}
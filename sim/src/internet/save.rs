@@ -0,0 +1,53 @@
+//! Save files: a header naming the saved type and the version of its layout, followed by the bincode-encoded value.
+//! Bincode stores neither field names nor defaults, so a value only decodes into the exact layout it was saved from.
+//! Changing the layout of a saved type means bumping its version, older versions are then migrated in [`load`] or rejected.
+
+use std::fs::File;
+use std::io::{BufReader, Read, Write};
+
+use serde::{Serialize, de::DeserializeOwned};
+
+/// Saved type and layout version
+#[derive(Debug, Clone, Copy)]
+pub struct SaveFormat {
+	pub magic: [u8; 4],
+	pub version: u32,
+}
+
+/// Namespace backend, see [`super::Internet`]
+pub const INTERNET_FORMAT: SaveFormat = SaveFormat { magic: *b"DSIN", version: 1 };
+/// Deterministic backend, see [`super::DesInternet`]
+pub const DES_FORMAT: SaveFormat = SaveFormat { magic: *b"DSDE", version: 1 };
+
+#[derive(Debug, Error)]
+pub enum SaveError {
+	#[error("failed to access save file: {0}")]
+	Io(#[from] std::io::Error),
+	#[error("{0} isn't a save of this backend, or was written before saves were versioned and has to be recreated")]
+	UnknownFormat(String),
+	#[error("{path} was saved with layout version {found}, this build only reads version {expected}")]
+	UnsupportedVersion { path: String, found: u32, expected: u32 },
+	#[error("failed to encode or decode save: {0}")]
+	Encoding(#[from] bincode::Error),
+}
+
+pub fn save(filepath: &str, format: SaveFormat, value: &impl Serialize) -> Result<(), SaveError> {
+	let mut file = File::create(filepath)?;
+	file.write_all(&format.magic)?;
+	file.write_all(&format.version.to_le_bytes())?;
+	bincode::serialize_into(&mut file, value)?;
+	Ok(())
+}
+
+pub fn load<T: DeserializeOwned>(filepath: &str, format: SaveFormat) -> Result<T, SaveError> {
+	let mut reader = BufReader::new(File::open(filepath)?);
+	let mut header = [0; 8];
+	if reader.read_exact(&mut header).is_err() || header[..4] != format.magic {
+		return Err(SaveError::UnknownFormat(filepath.to_string()))
+	}
+	match u32::from_le_bytes([header[4], header[5], header[6], header[7]]) {
+		version if version == format.version => Ok(bincode::deserialize_from(reader)?),
+		// Migrations from older layouts go here once there are any
+		found => Err(SaveError::UnsupportedVersion { path: filepath.to_string(), found, expected: format.version }),
+	}
+}
@@ -0,0 +1,252 @@
+//! Route stretch, how much longer the path Dither routes along is than the physically shortest path.
+//! Dither's path is approximated like the old CLI's `test sample-artificial` did, by greedy next-hop over route coordinates:
+//! every machine peers with its closest machines by route coordinate and forwards to whichever peer is closest to the destination's coordinate.
+//! Overlay hops follow the shortest path through the wire graph, so a stretch of 1 means Dither's route is optimal.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+
+use anyhow::Context;
+use petgraph::{Graph, Undirected, algo::dijkstra, graph::NodeIndex};
+use rand::{SeedableRng, rngs::SmallRng, seq::SliceRandom};
+
+use super::{Internet, InternetError, InternetEvent, InternetRuntime, Latency, NodeIdx, RouteCoord};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StretchConfig {
+	/// Machine pairs sampled
+	pub samples: usize,
+	/// Peers of every machine, its closest machines by route coordinate
+	pub neighbors: usize,
+	/// Greedy routes taking more hops than this count as failed
+	pub max_hops: u32,
+	pub seed: u64,
+	/// CSV file every sample is written to
+	pub csv_path: Option<String>,
+}
+impl Default for StretchConfig {
+	fn default() -> Self {
+		StretchConfig { samples: 1000, neighbors: 8, max_hops: 32, seed: 0, csv_path: None }
+	}
+}
+
+/// Greedy route between two machines compared with the shortest path, latencies are measured in microseconds
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StretchSample {
+	pub from: NodeIdx,
+	pub to: NodeIdx,
+	/// Overlay hops of the greedy route, None if it got stuck or ran out of hops
+	pub hops: Option<u32>,
+	pub routed_latency: Option<Latency>,
+	pub shortest_latency: Latency,
+}
+impl StretchSample {
+	pub fn stretch(&self) -> Option<f64> {
+		self.routed_latency.map(|routed|routed as f64 / self.shortest_latency.max(1) as f64)
+	}
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StretchReport {
+	pub samples: Vec<StretchSample>,
+	/// Stretch of the routes that arrived
+	pub mean: Option<f64>,
+	pub median: Option<f64>,
+	pub p90: Option<f64>,
+	pub p99: Option<f64>,
+	/// Share of routes that got stuck or ran out of hops
+	pub failure_rate: f64,
+	/// Sampled pairs without any path between them, they say nothing about routing and aren't part of the samples
+	pub unreachable: usize,
+}
+impl StretchReport {
+	fn new(samples: Vec<StretchSample>, unreachable: usize) -> Self {
+		let mut stretches: Vec<f64> = samples.iter().filter_map(StretchSample::stretch).collect();
+		stretches.sort_by(f64::total_cmp);
+		let percentile = |p: f64|(!stretches.is_empty()).then(||stretches[((stretches.len() - 1) as f64 * p).round() as usize]);
+		StretchReport {
+			mean: (!stretches.is_empty()).then(||stretches.iter().sum::<f64>() / stretches.len() as f64),
+			median: percentile(0.5),
+			p90: percentile(0.9),
+			p99: percentile(0.99),
+			failure_rate: if samples.is_empty() { 0.0 } else { 1.0 - stretches.len() as f64 / samples.len() as f64 },
+			samples,
+			unreachable,
+		}
+	}
+	fn write_csv(&self, path: &str) -> anyhow::Result<()> {
+		let mut file = File::create(path)?;
+		writeln!(file, "from,to,hops,routed_latency_us,shortest_latency_us,stretch")?;
+		for sample in &self.samples {
+			let optional = |value: Option<String>|value.unwrap_or_default();
+			writeln!(file, "{},{},{},{},{},{}", sample.from, sample.to,
+				optional(sample.hops.map(|hops|hops.to_string())),
+				optional(sample.routed_latency.map(|latency|latency.to_string())),
+				sample.shortest_latency,
+				optional(sample.stretch().map(|stretch|stretch.to_string())),
+			)?;
+		}
+		Ok(())
+	}
+}
+
+pub(crate) fn route_distance(a: &RouteCoord, b: &RouteCoord) -> f64 {
+	(a.map(|c|c as f64) - b.map(|c|c as f64)).norm()
+}
+
+/// Shortest-path latencies through the wire graph, computed once per source
+pub(crate) struct ShortestPaths {
+	graph: Graph<NodeIdx, Latency, Undirected>,
+	indices: HashMap<NodeIdx, NodeIndex>,
+	cache: HashMap<NodeIdx, HashMap<NodeIndex, Latency>>,
+}
+impl ShortestPaths {
+	pub fn latency(&mut self, from: NodeIdx, to: NodeIdx) -> Option<Latency> {
+		let (graph, indices) = (&self.graph, &self.indices);
+		let distances = self.cache.entry(from).or_insert_with(||dijkstra(graph, indices[&from], None, |edge|*edge.weight()));
+		distances.get(self.indices.get(&to)?).copied()
+	}
+}
+
+impl Internet {
+	/// Shortest paths over wires that are up, weighted by wire latency
+	pub(crate) fn shortest_paths(&self) -> Result<ShortestPaths, InternetError> {
+		let mut graph = Graph::new_undirected();
+		let indices: HashMap<NodeIdx, NodeIndex> = self.nodes.keys().map(|idx|(idx, graph.add_node(idx))).collect();
+		for (wire_idx, wire) in self.wires.iter() {
+			if !wire.is_up() { continue }
+			let (node1, node2) = wire.nodes();
+			if let (Some(&index1), Some(&index2)) = (indices.get(&node1), indices.get(&node2)) {
+				graph.add_edge(index1, index2, self.wire_latency(wire_idx)?);
+			}
+		}
+		Ok(ShortestPaths { graph, indices, cache: HashMap::new() })
+	}
+	/// Route coordinates last reported by devices of machines that still exist, baselines count with the plane of their coordinate (Vivaldi)
+	pub(crate) fn reported_route_coords(&self, runtime: &InternetRuntime) -> HashMap<NodeIdx, RouteCoord> {
+		runtime.oracle.route_coords().filter(|(idx, _)|self.machine(*idx).is_ok()).map(|(idx, coord)|(idx, coord.clone())).collect()
+	}
+	/// Sample machine pairs and compare greedy routes over the route coordinates devices last reported with shortest paths.
+	/// Machines whose device never reported a route coordinate (or baseline coordinate) are left out, request their machine info first.
+	pub(crate) fn measure_stretch(&self, runtime: &mut InternetRuntime, config: StretchConfig) -> Result<(), InternetError> {
+		let coords = self.reported_route_coords(runtime);
+		let mut machines: Vec<NodeIdx> = coords.keys().copied().collect();
+		machines.sort();
+		let neighbors: HashMap<NodeIdx, Vec<NodeIdx>> = machines.iter().map(|&machine|{
+			let mut others: Vec<NodeIdx> = machines.iter().copied().filter(|&other|other != machine).collect();
+			others.sort_by(|a, b|route_distance(&coords[&machine], &coords[a]).total_cmp(&route_distance(&coords[&machine], &coords[b])));
+			others.truncate(config.neighbors);
+			(machine, others)
+		}).collect();
+
+		let mut paths = self.shortest_paths()?;
+		let mut rng = SmallRng::seed_from_u64(config.seed);
+		let mut samples = Vec::with_capacity(config.samples);
+		let mut unreachable = 0;
+		if machines.len() >= 2 {
+			for _ in 0..config.samples {
+				let (from, to) = match machines.choose_multiple(&mut rng, 2).copied().collect::<Vec<_>>()[..] { [from, to] => (from, to), _ => break };
+				let shortest_latency = match paths.latency(from, to) { Some(latency) => latency, None => { unreachable += 1; continue } };
+				let route = greedy_route(&coords, &neighbors, from, to, config.max_hops);
+				let routed_latency = route.as_ref().and_then(|route|route.windows(2).map(|hop|paths.latency(hop[0], hop[1])).sum::<Option<Latency>>());
+				let hops = route.filter(|_|routed_latency.is_some()).map(|route|route.len() as u32 - 1);
+				samples.push(StretchSample { from, to, hops, routed_latency, shortest_latency });
+			}
+		}
+
+		let report = StretchReport::new(samples, unreachable);
+		log::info!("Route stretch over {} samples: median {:?}, p90 {:?}, p99 {:?}, {:.1}% failed, {} pairs unreachable", report.samples.len(), report.median, report.p90, report.p99, report.failure_rate * 100.0, report.unreachable);
+		if let Some(path) = &config.csv_path { report.write_csv(path).context("failed to write stretch samples")?; }
+		runtime.send_event(InternetEvent::Stretch(runtime.clock.now(), report))
+	}
+}
+
+/// Machines a greedy route visits, None if it reaches a machine without a peer closer to the destination or runs out of hops
+fn greedy_route(coords: &HashMap<NodeIdx, RouteCoord>, neighbors: &HashMap<NodeIdx, Vec<NodeIdx>>, from: NodeIdx, to: NodeIdx, max_hops: u32) -> Option<Vec<NodeIdx>> {
+	let target = &coords[&to];
+	let mut route = vec![from];
+	let mut current = from;
+	while current != to {
+		if route.len() as u32 > max_hops { return None }
+		let distance = route_distance(&coords[&current], target);
+		let next = neighbors[&current].iter().copied()
+			.min_by(|a, b|route_distance(&coords[a], target).total_cmp(&route_distance(&coords[b], target)))?;
+		if next != to && route_distance(&coords[&next], target) >= distance { return None }
+		route.push(next);
+		current = next;
+	}
+	Some(route)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use slotmap::SlotMap;
+
+	fn machines(count: usize) -> Vec<NodeIdx> {
+		let mut nodes = SlotMap::<NodeIdx, ()>::with_key();
+		(0..count).map(|_|nodes.insert(())).collect()
+	}
+
+	#[test]
+	fn greedy_route_follows_closer_peers() {
+		let m = machines(4);
+		// Machines on a line, each only peering with its direct neighbors
+		let coords: HashMap<NodeIdx, RouteCoord> = m.iter().enumerate().map(|(i, &idx)|(idx, RouteCoord::new((i * 10) as _, 0))).collect();
+		let mut neighbors: HashMap<NodeIdx, Vec<NodeIdx>> = HashMap::from([
+			(m[0], vec![m[1]]), (m[1], vec![m[0], m[2]]), (m[2], vec![m[1], m[3]]), (m[3], vec![m[2]]),
+		]);
+		assert_eq!(greedy_route(&coords, &neighbors, m[0], m[3], 3), Some(m.clone()));
+		assert_eq!(greedy_route(&coords, &neighbors, m[3], m[0], 3), Some(m.iter().rev().copied().collect()));
+		assert_eq!(greedy_route(&coords, &neighbors, m[2], m[2], 0), Some(vec![m[2]]));
+		// Route needs 3 hops
+		assert_eq!(greedy_route(&coords, &neighbors, m[0], m[3], 2), None);
+		// Machine 1 only knows a peer farther from the destination than itself
+		neighbors.insert(m[1], vec![m[0]]);
+		assert_eq!(greedy_route(&coords, &neighbors, m[0], m[3], 3), None);
+	}
+
+	#[test]
+	fn report_percentiles() {
+		let sample = |shortest_latency: Latency, routed_latency: Option<Latency>|StretchSample {
+			from: NodeIdx::default(), to: NodeIdx::default(), hops: routed_latency.map(|_|1), routed_latency, shortest_latency,
+		};
+		// Stretches 1 to 10, plus two failed routes
+		let mut samples: Vec<StretchSample> = (1..=10).rev().map(|stretch|sample(10, Some(stretch * 10))).collect();
+		samples.extend([sample(10, None), sample(10, None)]);
+		let report = StretchReport::new(samples, 3);
+		assert_eq!(report.mean, Some(5.5));
+		assert_eq!(report.median, Some(6.0));
+		assert_eq!(report.p90, Some(9.0));
+		assert_eq!(report.p99, Some(10.0));
+		assert!((report.failure_rate - 2.0 / 12.0).abs() < 1e-9);
+		assert_eq!(report.unreachable, 3);
+
+		// Zero shortest latency is treated as 1us instead of dividing by zero
+		assert_eq!(sample(0, Some(5)).stretch(), Some(5.0));
+
+		let empty = StretchReport::new(Vec::new(), 0);
+		assert_eq!((empty.mean, empty.median, empty.p90, empty.p99), (None, None, None, None));
+		assert_eq!(empty.failure_rate, 0.0);
+	}
+
+	#[test]
+	fn shortest_path_latency() {
+		let m = machines(4);
+		let mut graph = Graph::new_undirected();
+		let indices: HashMap<NodeIdx, NodeIndex> = m.iter().map(|&idx|(idx, graph.add_node(idx))).collect();
+		// Detour over machine 1 is faster than the direct wire, machine 3 is isolated
+		graph.add_edge(indices[&m[0]], indices[&m[1]], 5);
+		graph.add_edge(indices[&m[1]], indices[&m[2]], 7);
+		graph.add_edge(indices[&m[0]], indices[&m[2]], 20);
+		let mut paths = ShortestPaths { graph, indices, cache: HashMap::new() };
+		assert_eq!(paths.latency(m[0], m[2]), Some(12));
+		assert_eq!(paths.latency(m[2], m[0]), Some(12));
+		assert_eq!(paths.latency(m[0], m[0]), Some(0));
+		assert_eq!(paths.latency(m[0], m[3]), None);
+		// Cached distances give the same answers
+		assert_eq!(paths.latency(m[0], m[1]), Some(5));
+		assert_eq!(paths.cache.len(), 2);
+	}
+}
@@ -0,0 +1,149 @@
+//! Time-varying wire latencies.
+//! A trace runs as a background task that periodically samples it and feeds the latency back into the simulation, where it replaces the latency model's value for the wire.
+
+use std::f64::consts::PI;
+use std::fs;
+use std::time::Duration;
+
+use async_std::task;
+use futures::{SinkExt, channel::mpsc, future::{self, AbortHandle}};
+use rand::{Rng, SeedableRng, rngs::SmallRng};
+
+use super::{InternetAction, Latency, WireIdx};
+use super::clock::SimClock;
+
+/// Where a trace's latencies come from, all latencies are measured in microseconds
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum TraceSource {
+	/// Measured samples of (seconds since start, latency), linearly interpolated. The last latency is kept once the samples run out.
+	Samples(Vec<(f64, Latency)>),
+	/// Sinusoid around a base latency, e.g. daily load patterns
+	Diurnal { base: Latency, amplitude: Latency, period: Duration },
+	/// Latency moves up or down by at most `step` every sample, staying within `min..=max`
+	RandomWalk { start: Latency, step: Latency, min: Latency, max: Latency, seed: u64 },
+}
+
+/// Latency trace driving a wire's delay
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WireTrace {
+	pub source: TraceSource,
+	/// Time between delay updates
+	pub interval: Duration,
+}
+
+#[derive(Debug, Error)]
+pub enum TraceError {
+	#[error("failed to read trace file: {0}")]
+	Io(#[from] std::io::Error),
+	#[error("invalid trace sample on line {0}, expected: seconds,milliseconds")]
+	InvalidSample(usize),
+	#[error("trace has no samples")]
+	Empty,
+	#[error("trace interval must be longer than zero")]
+	ZeroInterval,
+	#[error("diurnal trace period must be longer than zero")]
+	ZeroPeriod,
+	#[error("random walk minimum {min} is above its maximum {max}")]
+	InvalidBounds { min: Latency, max: Latency },
+}
+
+impl WireTrace {
+	/// Reject traces whose task would spin without ever sleeping or that can't be sampled
+	pub fn validate(&self) -> Result<(), TraceError> {
+		if self.interval.is_zero() { return Err(TraceError::ZeroInterval) }
+		match self.source {
+			TraceSource::Samples(ref samples) if samples.is_empty() => Err(TraceError::Empty),
+			TraceSource::Diurnal { period, .. } if period.is_zero() => Err(TraceError::ZeroPeriod),
+			TraceSource::RandomWalk { min, max, .. } if min > max => Err(TraceError::InvalidBounds { min, max }),
+			_ => Ok(()),
+		}
+	}
+}
+
+impl TraceSource {
+	/// Load samples from a CSV file of `seconds,milliseconds` rows, an optional header line is skipped
+	pub fn load_csv(path: &str) -> Result<Self, TraceError> {
+		let data = fs::read_to_string(path)?;
+		let mut samples = Vec::new();
+		for (index, line) in data.lines().enumerate() {
+			let line = line.trim();
+			if line.is_empty() { continue }
+			let mut fields = line.split(',').map(|field|field.trim().parse::<f64>());
+			match (fields.next(), fields.next()) {
+				(Some(Ok(time)), Some(Ok(latency))) => samples.push((time, (latency * 1000.0) as Latency)),
+				_ if index == 0 => continue, // Header
+				_ => return Err(TraceError::InvalidSample(index + 1)),
+			}
+		}
+		if samples.is_empty() { return Err(TraceError::Empty) }
+		samples.sort_by(|a, b|a.0.total_cmp(&b.0));
+		Ok(TraceSource::Samples(samples))
+	}
+}
+
+/// Running state of a trace
+pub(super) struct TraceState {
+	source: TraceSource,
+	current: Latency,
+	rng: SmallRng,
+}
+impl TraceState {
+	pub(super) fn new(source: TraceSource) -> Self {
+		let (current, seed) = match &source {
+			TraceSource::RandomWalk { start, seed, .. } => (*start, *seed),
+			_ => (0, 0),
+		};
+		TraceState { source, current, rng: SmallRng::seed_from_u64(seed) }
+	}
+	/// Latency at a point in time, None once the trace has ended
+	pub(super) fn sample(&mut self, elapsed: Duration) -> Option<Latency> {
+		let secs = elapsed.as_secs_f64();
+		match &self.source {
+			TraceSource::Samples(samples) => {
+				let next = samples.iter().position(|(time, _)|*time > secs);
+				match next {
+					Some(0) => Some(samples[0].1),
+					Some(next) => {
+						let ((t0, l0), (t1, l1)) = (samples[next - 1], samples[next]);
+						let progress = (secs - t0) / (t1 - t0);
+						Some((l0 as f64 + (l1 as f64 - l0 as f64) * progress) as Latency)
+					}
+					None => None,
+				}
+			}
+			TraceSource::Diurnal { base, amplitude, period } => {
+				let phase = 2.0 * PI * secs / period.as_secs_f64();
+				Some((*base as f64 + *amplitude as f64 * phase.sin()).max(0.0) as Latency)
+			}
+			&TraceSource::RandomWalk { step, min, max, .. } => {
+				let step = self.rng.gen_range(-(step as i64)..=step as i64);
+				self.current = (self.current as i64 + step).clamp(min as i64, max as i64) as Latency;
+				Some(self.current)
+			}
+		}
+	}
+	pub(super) fn final_latency(&self) -> Option<Latency> {
+		match &self.source { TraceSource::Samples(samples) => samples.last().map(|(_, latency)|*latency), _ => None }
+	}
+}
+
+/// Spawn task sampling a trace and sending the latencies to the simulation as [`InternetAction::TraceSample`]s
+pub fn spawn(wire_idx: WireIdx, trace: WireTrace, mut action_sender: mpsc::Sender<InternetAction>, clock: SimClock) -> AbortHandle {
+	let (task, abort_handle) = future::abortable(async move {
+		let start = clock.now();
+		let mut state = TraceState::new(trace.source);
+		loop {
+			let latency = match state.sample(clock.now() - start) {
+				Some(latency) => latency,
+				None => {
+					if let Some(latency) = state.final_latency() { let _ = action_sender.send(InternetAction::TraceSample(wire_idx, latency)).await; }
+					break
+				}
+			};
+			if action_sender.send(InternetAction::TraceSample(wire_idx, latency)).await.is_err() { break }
+			clock.sleep(trace.interval).await;
+		}
+	});
+	task::spawn(task);
+	abort_handle
+}
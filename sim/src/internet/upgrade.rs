@@ -0,0 +1,87 @@
+//! Rolling upgrades, machines are switched to another device build one after the other like a staged release.
+//! Mixing builds in one Internet catches wire-protocol incompatibilities between releases.
+
+use std::time::Duration;
+
+use rand::{SeedableRng, rngs::SmallRng, seq::{IteratorRandom, SliceRandom}};
+
+use super::{DeviceConfig, DeviceRegistry, Internet, InternetAction, InternetError, InternetEvent, InternetRuntime, NodeIdx, StdioProtocol};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RollingUpgrade {
+	/// Build machines are switched to
+	pub build: String,
+	/// Only upgrade machines running this build, None upgrades machines running any other registered Dither build.
+	/// Machines running other programs, in-process apps or the default device aren't picked.
+	pub from: Option<String>,
+	/// Share of eligible machines upgraded, between 0 and 1
+	pub fraction: f64,
+	/// Time between restarting two machines
+	pub interval: Duration,
+	/// Seed machines are picked with
+	pub seed: u64,
+}
+
+/// Whether a device runs a registered build speaking the device protocol, rather than some other program
+fn is_dither_build(registry: &DeviceRegistry, device: &DeviceConfig) -> bool {
+	match device {
+		DeviceConfig::Build(name) => registry.build(name).map_or(false, |program|program.protocol == StdioProtocol::Device),
+		_ => false,
+	}
+}
+
+impl Internet {
+	/// Pick machines to upgrade and schedule their restarts, the first one is upgraded right away
+	pub(crate) fn rolling_upgrade(&mut self, runtime: &mut InternetRuntime, upgrade: RollingUpgrade) -> Result<(), InternetError> {
+		self.registry.build(&upgrade.build)?;
+		let target = DeviceConfig::Build(upgrade.build.clone());
+		let from = upgrade.from.map(DeviceConfig::Build);
+		let eligible = self.nodes.iter().filter_map(|(idx, node)|{
+			let machine = node.machine()?;
+			let upgradable = match &from { Some(from) => &machine.device == from, None => is_dither_build(&self.registry, &machine.device) };
+			(machine.device != target && upgradable).then(||idx)
+		});
+		let eligible_count = eligible.clone().count();
+		let count = (eligible_count as f64 * upgrade.fraction.clamp(0.0, 1.0)).round() as usize;
+		let mut rng = SmallRng::seed_from_u64(upgrade.seed);
+		// Picked machines come out in node order, shuffle them so the order of restarts is random too
+		let mut machines = eligible.choose_multiple(&mut rng, count);
+		machines.shuffle(&mut rng);
+		log::info!("Rolling upgrade to {:?}: {} of {} eligible machines", upgrade.build, machines.len(), eligible_count);
+		for (i, &machine_idx) in machines.iter().enumerate() {
+			runtime.schedule(upgrade.interval * i as u32, InternetAction::UpgradeMachine(machine_idx, upgrade.build.clone()));
+		}
+		runtime.send_event(InternetEvent::UpgradeScheduled(runtime.clock.now(), upgrade.build, machines))
+	}
+	/// Upgrade machine picked by a rolling upgrade, machines removed in the meantime are skipped
+	pub(crate) fn upgrade_machine(&mut self, runtime: &mut InternetRuntime, machine_idx: NodeIdx, build: String) -> Result<(), InternetError> {
+		if self.machine(machine_idx).is_err() { return Ok(()) }
+		self.set_machine_device(runtime, machine_idx, DeviceConfig::Build(build))
+	}
+	/// Switch machine to another device, a running device is restarted and needs its IPv6 configuration again
+	pub(crate) fn set_machine_device(&mut self, runtime: &mut InternetRuntime, machine_idx: NodeIdx, device: DeviceConfig) -> Result<(), InternetError> {
+		self.registry.validate(&device)?;
+		self.machine_mut(machine_idx)?.set_device(device.clone(), runtime.action_sender.clone())?;
+		let machine = self.machine(machine_idx)?;
+		if machine.is_running() { self.configure_machine_ipv6(machine)?; }
+		runtime.send_event(InternetEvent::DeviceChanged(runtime.clock.now(), machine_idx, device))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::internet::MachineProgram;
+
+	#[test]
+	fn dither_builds() {
+		let registry = DeviceRegistry::default();
+		registry.register_build("v1".into(), MachineProgram::device("device-v1"));
+		registry.register_build("iperf".into(), MachineProgram { protocol: StdioProtocol::None, ..MachineProgram::device("iperf3") });
+		assert!(is_dither_build(&registry, &DeviceConfig::Build("v1".into())));
+		assert!(!is_dither_build(&registry, &DeviceConfig::Build("iperf".into())));
+		assert!(!is_dither_build(&registry, &DeviceConfig::Build("unregistered".into())));
+		assert!(!is_dither_build(&registry, &DeviceConfig::Process(MachineProgram::device("device"))));
+		assert!(!is_dither_build(&registry, &DeviceConfig::InProcess { app: "v1".into() }));
+	}
+}